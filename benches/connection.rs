@@ -0,0 +1,57 @@
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use handshake::bench::{AckPath, Buffers, DataPath};
+
+// A data segment of the peer, received in order, by how many octets it carries
+fn on_segment(c: &mut Criterion) {
+    let mut group = c.benchmark_group("on_segment");
+
+    for len in [0, 536, 1460] {
+        let mut path = DataPath::new(len);
+
+        group.throughput(Throughput::Bytes(len as u64));
+        group.bench_function(BenchmarkId::from_parameter(len), |b| b.iter(|| path.feed()));
+    }
+
+    group.finish();
+}
+
+// An acknowledgment of everything in flight, by how many segments are in flight
+fn ack(c: &mut Criterion) {
+    let mut group = c.benchmark_group("ack");
+
+    for segments in [16, 128, 1024] {
+        let path = AckPath::new(segments, 32);
+        assert_eq!(path.queued(), segments);
+
+        group.throughput(Throughput::Elements(segments as u64));
+        group.bench_function(BenchmarkId::from_parameter(segments), |b| {
+            b.iter_batched(
+                || path.clone(),
+                |mut path| path.ack(),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+
+    group.finish();
+}
+
+// Data written to a connection and read out at the other end, by the size of the write
+fn buffers(c: &mut Criterion) {
+    let mut group = c.benchmark_group("buffers");
+
+    for len in [1460, 16 * 1024, 256 * 1024] {
+        let mut buffers = Buffers::new(len);
+        let data = vec![0xa5; len];
+
+        group.throughput(Throughput::Bytes(len as u64));
+        group.bench_function(BenchmarkId::from_parameter(len), |b| {
+            b.iter(|| buffers.cycle(&data))
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, on_segment, ack, buffers);
+criterion_main!(benches);
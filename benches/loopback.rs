@@ -0,0 +1,56 @@
+use std::io::{Read, Write};
+use std::net::Ipv4Addr;
+use std::sync::mpsc;
+use std::thread;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use handshake::NetStack;
+
+const ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+const MASK: Ipv4Addr = Ipv4Addr::new(255, 255, 255, 0);
+const PORT: u16 = 5000;
+
+// What each iteration writes, and waits for the other end to have read
+const TRANSFER: usize = 1024 * 1024;
+
+// A connection of a stack to itself, through the loopback device and every loop of the stack
+fn throughput(c: &mut Criterion) {
+    let mut stack = NetStack::loopback(ADDR, MASK).unwrap();
+    let listener = stack.bind(PORT).unwrap();
+    let mut client = stack.connect(ADDR, PORT).unwrap();
+    let mut server = listener.accept().unwrap();
+
+    // Says whenever another transfer has been read in full
+    let (done, transfers) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buf = vec![0; 64 * 1024];
+        let mut read = 0;
+
+        while let Ok(n @ 1..) = server.read(&mut buf) {
+            read += n;
+
+            while read >= TRANSFER {
+                read -= TRANSFER;
+                if done.send(()).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+
+    let data = vec![0xa5; TRANSFER];
+
+    let mut group = c.benchmark_group("loopback");
+    group.throughput(Throughput::Bytes(TRANSFER as u64));
+    group.sample_size(10);
+    group.bench_function("throughput", |b| {
+        b.iter(|| {
+            client.write_all(&data).unwrap();
+            transfers.recv().unwrap();
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, throughput);
+criterion_main!(benches);
@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// Whatever a device may deliver, as validated by the segment loop
+fuzz_target!(|buf: &[u8]| {
+    handshake::fuzz::datagram(buf);
+});
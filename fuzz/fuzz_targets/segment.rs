@@ -0,0 +1,11 @@
+#![no_main]
+
+use handshake::fuzz::Op;
+use libfuzzer_sys::fuzz_target;
+
+// A connection in any state, then whatever the peer, the application and the timers do
+fuzz_target!(|input: (u8, Vec<Op>)| {
+    let (state, ops) = input;
+
+    handshake::fuzz::run(state, &ops);
+});
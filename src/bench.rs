@@ -0,0 +1,209 @@
+use std::io::{self, IoSlice};
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+
+use etherparse::{PacketBuilder, TcpOptionElement};
+
+use crate::tcp::{
+    AckThrottle, IpOpts, IssGenerator, MemoryPool, RecvBuffer, SendBuffer, SystemClock, TcpOptions,
+};
+use crate::{parse_datagram, Dual, Emitter, Inbound, Quad, TCB};
+
+/*
+What the benchmarks under benches/ measure, on connections driven the way
+the segment loop and the timer loop drive them, minus the devices and the
+locks: segments are parsed from datagrams and handed to the connection, and
+whatever it sends is thrown away. Run them with cargo bench --features bench.
+*/
+const LOCAL: Dual = Dual::new(Ipv4Addr::new(10, 0, 0, 1), 80);
+const REMOTE: Dual = Dual::new(Ipv4Addr::new(10, 0, 0, 2), 4001);
+const PEER_ISS: u32 = 1000;
+
+// Where the sequence number is in the datagrams built below, which carry no IPv4 options
+const SEQ: usize = 20 + 4;
+
+// An established connection that segments carrying len octets each are fed to, in order
+#[derive(Debug)]
+pub struct DataPath {
+    tcb: TCB,
+    datagram: Vec<u8>,
+    seq: u32,
+    buf: Vec<u8>,
+}
+
+impl DataPath {
+    pub fn new(len: usize) -> Self {
+        let tcb = established();
+        let datagram = datagram(PEER_ISS + 1, tcb.iss().wrapping_add(1), &vec![0; len]);
+
+        DataPath {
+            tcb,
+            datagram,
+            seq: PEER_ISS + 1,
+            buf: vec![0; len],
+        }
+    }
+
+    // Feeds the next segment, then reads its data so the window never closes
+    pub fn feed(&mut self) {
+        self.datagram[SEQ..SEQ + 4].copy_from_slice(&self.seq.to_be_bytes());
+
+        // Checksums are not verified, so the one of the first segment does for all
+        let Ok(Some(Inbound::Segment(ip4h, tcph, data))) = parse_datagram(&self.datagram, false)
+        else {
+            unreachable!("the datagram was built valid");
+        };
+
+        self.tcb.on_segment(ip4h, tcph, data, &mut Discard);
+        self.seq = self.seq.wrapping_add(data.len() as u32);
+
+        self.tcb.recv(&mut self.buf);
+    }
+}
+
+/*
+An established connection with segments of len octets each sent and yet to
+be acknowledged, all of which fit in the window of the peer.
+*/
+#[derive(Debug, Clone)]
+pub struct AckPath {
+    tcb: TCB,
+    datagram: Vec<u8>, // Acknowledges everything sent at once
+}
+
+impl AckPath {
+    pub fn new(segments: usize, len: usize) -> Self {
+        let total = segments * len;
+        assert!(
+            total <= u16::MAX as usize,
+            "{} octets do not fit in the window",
+            total
+        );
+
+        let mut tcb = established();
+        tcb.set_mss(len as u16);
+        // Large enough not to hold anything back, with room to grow as acknowledgments come in
+        tcb.cwnd = u32::MAX / 2;
+        tcb.outgoing.set_capacity(total);
+        tcb.outgoing.extend_from_slice(&vec![0; total]);
+
+        // Each tick only sends a turn's worth
+        for _ in 0..segments {
+            if tcb.segments.len() == segments {
+                break;
+            }
+
+            tcb.on_tick(&mut Discard);
+        }
+        assert_eq!(tcb.segments.len(), segments);
+
+        let acked = tcb.iss().wrapping_add(1 + total as u32);
+        let datagram = datagram(PEER_ISS + 1, acked, &[]);
+
+        AckPath { tcb, datagram }
+    }
+
+    pub fn queued(&self) -> usize {
+        self.tcb.segments.len()
+    }
+
+    pub fn ack(&mut self) {
+        let Ok(Some(Inbound::Segment(ip4h, tcph, data))) = parse_datagram(&self.datagram, true)
+        else {
+            unreachable!("the datagram was built valid");
+        };
+
+        self.tcb.on_segment(ip4h, tcph, data, &mut Discard);
+    }
+}
+
+// The buffers of a connection, which data goes through from a write to a read
+#[derive(Debug)]
+pub struct Buffers {
+    send: SendBuffer,
+    recv: RecvBuffer,
+    buf: Vec<u8>,
+}
+
+impl Buffers {
+    pub fn new(capacity: usize) -> Self {
+        let memory = Arc::new(MemoryPool::default());
+
+        let mut send = SendBuffer::new(memory.clone());
+        send.set_capacity(capacity);
+        let mut recv = RecvBuffer::new(memory);
+        recv.set_capacity(capacity);
+
+        Buffers {
+            send,
+            recv,
+            buf: vec![0; capacity],
+        }
+    }
+
+    /*
+    Queues data to be sent, takes it out as segments would be and delivers
+    it to the receiving side, to be read out again. Data must fit in the
+    capacity of the buffers.
+    */
+    pub fn cycle(&mut self, data: &[u8]) {
+        self.send.extend_from_slice(data);
+
+        for slice in self.send.slices(0, data.len()) {
+            self.recv.extend_from_slice(slice);
+        }
+        self.send.advance(data.len());
+
+        self.recv.read(&mut self.buf);
+    }
+}
+
+// Opened actively, with a window of the peer as large as it gets
+fn established() -> TCB {
+    let mut tcb = TCB::syn_sent(
+        Quad::new(LOCAL, REMOTE),
+        &IssGenerator::default(),
+        Arc::new(AckThrottle::default()),
+        Arc::new(MemoryPool::default()),
+        Arc::new(SystemClock),
+        IpOpts::default(),
+        TcpOptions::default(),
+    );
+    tcb.on_tick(&mut Discard);
+
+    let syn_ack = PacketBuilder::ipv4(REMOTE.ipv4.octets(), LOCAL.ipv4.octets(), 64)
+        .tcp(REMOTE.port, LOCAL.port, PEER_ISS, u16::MAX)
+        .syn()
+        .ack(tcb.iss().wrapping_add(1))
+        .options(&[TcpOptionElement::MaximumSegmentSize(1460)])
+        .unwrap();
+    let mut buf = Vec::with_capacity(syn_ack.size(0));
+    syn_ack.write(&mut buf, &[]).unwrap();
+
+    let Ok(Some(Inbound::Segment(ip4h, tcph, data))) = parse_datagram(&buf, true) else {
+        unreachable!("the datagram was built valid");
+    };
+    tcb.on_segment(ip4h, tcph, data, &mut Discard);
+
+    tcb
+}
+
+// A segment of the peer acknowledging ack
+fn datagram(seq: u32, ack: u32, data: &[u8]) -> Vec<u8> {
+    let builder = PacketBuilder::ipv4(REMOTE.ipv4.octets(), LOCAL.ipv4.octets(), 64)
+        .tcp(REMOTE.port, LOCAL.port, seq, u16::MAX)
+        .ack(ack);
+
+    let mut buf = Vec::with_capacity(builder.size(data.len()));
+    builder.write(&mut buf, data).unwrap();
+
+    buf
+}
+
+struct Discard;
+
+impl Emitter for Discard {
+    fn emit(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        Ok(bufs.iter().map(|b| b.len()).sum())
+    }
+}
@@ -0,0 +1,109 @@
+// Not every binary uses every helper
+#![allow(dead_code)]
+
+use std::env;
+use std::fmt::Display;
+use std::net::Ipv4Addr;
+use std::process;
+use std::str::FromStr;
+
+use handshake::NetStack;
+
+const STACK_FLAGS: [&str; 4] = ["--iface", "--addr", "--mask", "--control"];
+
+/*
+Flags shared by the binaries, given as `--name value` pairs. Flags may be
+repeated, and every binary understands the ones setting up the stack:
+
+    --iface <name>     TUN interface to create (tun0)
+    --addr <ipv4>      Address of the stack (10.10.10.10)
+    --mask <ipv4>      Netmask of the interface (255.255.255.0)
+    --control <path>   Unix socket to answer handshake-ss on (none)
+*/
+pub struct Args {
+    usage: &'static str,
+    flags: Vec<(String, String)>,
+}
+
+impl Args {
+    /*
+    Exits with the usage if a flag is not among `known` or the interface
+    flags, if it has no value, or if help was asked for.
+    */
+    pub fn parse(usage: &'static str, known: &[&str]) -> Self {
+        let mut args = env::args().skip(1);
+        let mut flags = Vec::new();
+
+        while let Some(flag) = args.next() {
+            if flag == "-h" || flag == "--help" {
+                println!("{}", usage);
+                process::exit(0);
+            }
+
+            if !STACK_FLAGS.contains(&flag.as_str()) && !known.contains(&flag.as_str()) {
+                Args::fail_with(usage, format!("unknown flag: {}", flag));
+            }
+
+            let Some(value) = args.next() else {
+                Args::fail_with(usage, format!("{} needs a value", flag))
+            };
+
+            flags.push((flag, value));
+        }
+
+        Args { usage, flags }
+    }
+
+    pub fn get<T: FromStr>(&self, name: &str) -> Option<T> {
+        self.all(name).pop()
+    }
+
+    pub fn get_or<T: FromStr>(&self, name: &str, default: T) -> T {
+        self.get(name).unwrap_or(default)
+    }
+
+    // All values of a flag that may be repeated, in the order they were given
+    pub fn all<T: FromStr>(&self, name: &str) -> Vec<T> {
+        self.flags
+            .iter()
+            .filter(|(flag, _)| flag == name)
+            .map(|(_, value)| {
+                value
+                    .parse()
+                    .unwrap_or_else(|_| self.fail(format!("invalid value for {}: {}", name, value)))
+            })
+            .collect()
+    }
+
+    // Exits with the usage unless the flag was given
+    pub fn require<T: FromStr>(&self, name: &str) -> T {
+        self.get(name)
+            .unwrap_or_else(|| self.fail(format!("{} is required", name)))
+    }
+
+    pub fn netstack(&self) -> NetStack {
+        let iface: String = self.get_or("--iface", "tun0".to_string());
+        let addr = self.get_or("--addr", Ipv4Addr::new(10, 10, 10, 10));
+        let mask = self.get_or("--mask", Ipv4Addr::new(255, 255, 255, 0));
+
+        let netstack = NetStack::new(&iface, addr, mask)
+            .unwrap_or_else(|err| self.fail(format!("cannot set up {}: {}", iface, err)));
+
+        if let Some(path) = self.get::<String>("--control") {
+            netstack
+                .serve_control(&path)
+                .unwrap_or_else(|err| self.fail(format!("cannot listen on {}: {}", path, err)));
+        }
+
+        netstack
+    }
+
+    pub fn fail(&self, err: impl Display) -> ! {
+        Args::fail_with(self.usage, err)
+    }
+
+    fn fail_with(usage: &str, err: impl Display) -> ! {
+        eprintln!("{}\n\n{}", err, usage);
+        process::exit(2);
+    }
+}
@@ -0,0 +1,65 @@
+use std::io::{Read, Write};
+use std::net::Ipv4Addr;
+
+mod args;
+use args::Args;
+
+mod signals;
+use signals::{Signals, LINGER};
+
+const USAGE: &str = "usage: client --peer <ipv4> [--iface <name>] [--addr <ipv4>] [--mask <ipv4>]
+              [--port <port>] [--size <bytes>] [--count <messages>]
+
+Sends --count messages (1) of --size bytes (64) to an echo server at --peer
+on --port (9090), and checks that each one comes back.";
+
+fn main() {
+    let args = Args::parse(USAGE, &["--peer", "--port", "--size", "--count"]);
+    let peer: Ipv4Addr = args.require("--peer");
+    let port = args.get_or("--port", 9090);
+    let size = args.get_or("--size", 64);
+    let count = args.get_or("--count", 1);
+
+    let signals = Signals::block();
+    let mut netstack = args.netstack();
+
+    let handle = netstack.shutdown_handle();
+    signals.handle(move || handle.shutdown(LINGER));
+
+    let mut stream = match netstack.connect(peer, port) {
+        Ok(stream) => stream,
+        Err(err) => {
+            println!(">>> Connecting to {}:{} failed: {}", peer, port, err);
+            netstack.shutdown(LINGER);
+
+            return netstack.join();
+        }
+    };
+    println!(">>> Connected to {}:{}", peer, port);
+
+    for i in 0..count {
+        let msg: Vec<u8> = (0..size).map(|j| b'a' + ((i + j) % 26) as u8).collect();
+
+        let mut echo = vec![0u8; size];
+        if let Err(err) = stream
+            .write_all(&msg)
+            .and_then(|_| stream.read_exact(&mut echo))
+        {
+            println!(">>> Message {} failed: {}", i, err);
+            break;
+        }
+
+        if echo != msg {
+            println!(">>> Message {} came back corrupted", i);
+            break;
+        }
+
+        println!(">>> Message {} echoed", i);
+    }
+
+    // Returns once the server has acknowledged our FIN
+    drop(stream);
+
+    netstack.shutdown(LINGER);
+    netstack.join();
+}
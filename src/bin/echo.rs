@@ -0,0 +1,105 @@
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use handshake::Overflow;
+
+mod args;
+use args::Args;
+
+mod signals;
+use signals::{Signals, LINGER};
+
+const USAGE: &str = "usage: echo [--iface <name>] [--addr <ipv4>] [--mask <ipv4>] [--port <port>]
+            [--backlog <connections>] [--size <bytes>] [--report <seconds>]
+
+Echoes back whatever is sent to it on any number of connections at once,
+each served by a thread of its own, on --port (9090). At most --backlog
+(128) connections wait to be accepted, and --size bytes (16384) are read at
+a time. Every --report seconds (5), the connections and bytes served so far
+are printed.";
+
+#[derive(Default)]
+struct Counters {
+    active: AtomicUsize,
+    accepted: AtomicUsize,
+    failed: AtomicUsize,
+    bytes: AtomicU64,
+}
+
+fn main() {
+    let args = Args::parse(USAGE, &["--port", "--backlog", "--size", "--report"]);
+    let port = args.get_or("--port", 9090);
+    let backlog = args.get_or("--backlog", 128);
+    let size = args.get_or("--size", 16384);
+    let report = args.get_or("--report", 5);
+
+    let signals = Signals::block();
+    let mut netstack = args.netstack();
+
+    let handle = netstack.shutdown_handle();
+    signals.handle(move || handle.shutdown(LINGER));
+
+    let listener = netstack
+        .bind_with_backlog(port, backlog, Overflow::Drop)
+        .unwrap();
+
+    let counters = Arc::new(Counters::default());
+
+    {
+        let counters = counters.clone();
+
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(report));
+
+            println!(
+                ">>> Active: {}, accepted: {}, failed: {}, echoed: {} bytes",
+                counters.active.load(Ordering::Relaxed),
+                counters.accepted.load(Ordering::Relaxed),
+                counters.failed.load(Ordering::Relaxed),
+                counters.bytes.load(Ordering::Relaxed),
+            );
+        });
+    }
+
+    println!(">>> Echoing on port {}", port);
+
+    while let Ok(mut stream) = listener.accept() {
+        counters.accepted.fetch_add(1, Ordering::Relaxed);
+        counters.active.fetch_add(1, Ordering::Relaxed);
+
+        let counters = counters.clone();
+
+        thread::spawn(move || {
+            let mut buf = vec![0u8; size];
+
+            let result = loop {
+                let n = match stream.read(&mut buf) {
+                    Ok(0) => break Ok(()),
+                    Ok(n) => n,
+                    Err(err) => break Err(err),
+                };
+
+                if let Err(err) = stream.write_all(&buf[..n]) {
+                    break Err(err);
+                }
+
+                counters.bytes.fetch_add(n as u64, Ordering::Relaxed);
+            };
+
+            if let Err(err) = result {
+                println!(">>> Connection failed: {}", err);
+                counters.failed.fetch_add(1, Ordering::Relaxed);
+            }
+
+            // Waits for our FIN to be acknowledged
+            drop(stream);
+
+            counters.active.fetch_sub(1, Ordering::Relaxed);
+        });
+    }
+
+    netstack.join();
+}
@@ -0,0 +1,216 @@
+use std::io::{self, Read, Write};
+use std::net::{self, Ipv4Addr, Shutdown};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+mod args;
+use args::Args;
+
+mod signals;
+use signals::{Signals, LINGER};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+const USAGE: &str = "usage: forward [--iface <name>] [--addr <ipv4>] [--mask <ipv4>]
+               [-L <port>:<addr>:<port>]... [-R <port>:<host>:<port>]...
+
+Forwards ports between the kernel and the stack, like ssh does:
+
+    -L    Connections accepted by the kernel on the first port are made
+          again over the stack
+    -R    Connections accepted by the stack on the first port are made
+          again by the kernel
+
+So an application that only knows the kernel can have its traffic carried by
+the stack, and the other way around. Any number of forwards can be given.";
+
+enum Forward {
+    Local {
+        port: u16,
+        addr: Ipv4Addr,
+        dport: u16,
+    },
+    Remote {
+        port: u16,
+        host: String,
+        dport: u16,
+    },
+}
+
+fn main() {
+    let args = Args::parse(USAGE, &["-L", "-R"]);
+
+    let mut forwards = Vec::new();
+    for spec in args.all::<String>("-L") {
+        let (port, addr, dport) = parse_forward(&spec).unwrap_or_else(|err| args.fail(err));
+        let addr = addr
+            .parse()
+            .unwrap_or_else(|_| args.fail(format!("invalid IPv4 address: {}", addr)));
+
+        forwards.push(Forward::Local { port, addr, dport });
+    }
+    for spec in args.all::<String>("-R") {
+        let (port, host, dport) = parse_forward(&spec).unwrap_or_else(|err| args.fail(err));
+
+        forwards.push(Forward::Remote {
+            port,
+            host: host.to_string(),
+            dport,
+        });
+    }
+
+    if forwards.is_empty() {
+        args.fail("no forwards given");
+    }
+
+    let signals = Signals::block();
+    let mut netstack = args.netstack();
+
+    /*
+    Connecting through the stack needs the NetStack itself, so the kernel
+    listeners hand their connections over to the main thread. None tells it
+    that the stack has been shut down.
+    */
+    let (tx, rx) = mpsc::channel::<Option<(net::TcpStream, Ipv4Addr, u16)>>();
+
+    {
+        let handle = netstack.shutdown_handle();
+        let tx = tx.clone();
+
+        signals.handle(move || {
+            handle.shutdown(LINGER);
+            tx.send(None).unwrap();
+        });
+    }
+
+    for forward in forwards {
+        match forward {
+            Forward::Local { port, addr, dport } => {
+                let listener = net::TcpListener::bind(("0.0.0.0", port)).unwrap();
+                let tx = tx.clone();
+
+                println!(">>> Forwarding kernel port {} to {}:{}", port, addr, dport);
+
+                thread::spawn(move || {
+                    for local in listener.incoming() {
+                        match local {
+                            Ok(local) => tx.send(Some((local, addr, dport))).unwrap(),
+                            Err(err) => println!(">>> Kernel accept failed: {}", err),
+                        }
+                    }
+                });
+            }
+            Forward::Remote { port, host, dport } => {
+                let listener = netstack.bind(port).unwrap();
+
+                println!(">>> Forwarding stack port {} to {}:{}", port, host, dport);
+
+                thread::spawn(move || loop {
+                    let Ok(remote) = listener.accept() else { break };
+
+                    let host = host.clone();
+                    thread::spawn(move || {
+                        let local = match net::TcpStream::connect((host.as_str(), dport)) {
+                            Ok(local) => local,
+                            Err(err) => {
+                                println!(">>> Connecting to {}:{} failed: {}", host, dport, err);
+                                return;
+                            }
+                        };
+
+                        if let Err(err) = relay(remote, local, |s| s.shutdown(Shutdown::Write)) {
+                            println!(">>> Relay failed: {}", err);
+                        }
+                    });
+                });
+            }
+        }
+    }
+
+    while let Ok(Some((local, addr, dport))) = rx.recv() {
+        let mut connecting = match netstack.connect_start(addr, dport) {
+            Ok(connecting) => connecting,
+            Err(err) => {
+                println!(">>> Connecting to {}:{} failed: {}", addr, dport, err);
+                continue;
+            }
+        };
+
+        // The handshake completes on the relay thread, so others are not held up
+        thread::spawn(move || {
+            let remote = match connecting.wait(CONNECT_TIMEOUT) {
+                Ok(Some(remote)) => remote,
+                Ok(None) => {
+                    println!(">>> Connecting to {}:{} timed out", addr, dport);
+                    return;
+                }
+                Err(err) => {
+                    println!(">>> Connecting to {}:{} failed: {}", addr, dport, err);
+                    return;
+                }
+            };
+
+            if let Err(err) = relay(remote, local, |s| s.shutdown(Shutdown::Write)) {
+                println!(">>> Relay failed: {}", err);
+            }
+        });
+    }
+
+    netstack.join();
+}
+
+// Splits `<port>:<host>:<port>`
+fn parse_forward(spec: &str) -> Result<(u16, &str, u16), String> {
+    let parts: Vec<&str> = spec.split(':').collect();
+    let [port, host, dport] = parts[..] else {
+        return Err(format!("malformed forward: {}", spec));
+    };
+
+    let port = port
+        .parse()
+        .map_err(|_| format!("invalid port: {}", port))?;
+    let dport = dport
+        .parse()
+        .map_err(|_| format!("invalid port: {}", dport))?;
+
+    Ok((port, host, dport))
+}
+
+/*
+Copies each direction on its own thread and passes the end of one direction
+on as a half-close, so the other keeps flowing until its own end.
+*/
+fn relay<S, F>(remote: S, local: net::TcpStream, close_write: F) -> io::Result<()>
+where
+    S: Send + Sync + 'static,
+    for<'a> &'a S: Read + Write,
+    F: Fn(&S) -> io::Result<()> + Send + 'static,
+{
+    let remote = Arc::new(remote);
+    let local_rx = local.try_clone()?;
+
+    let outbound = {
+        let remote = remote.clone();
+
+        thread::spawn(move || -> io::Result<u64> {
+            let n = io::copy(&mut &local_rx, &mut &*remote)?;
+            close_write(&remote)?;
+
+            Ok(n)
+        })
+    };
+
+    let inbound = io::copy(&mut &*remote, &mut &local)?;
+    local.shutdown(Shutdown::Write)?;
+
+    let outbound = outbound.join().unwrap()?;
+
+    println!(
+        ">>> Relayed {} bytes out and {} bytes in",
+        outbound, inbound
+    );
+
+    Ok(())
+}
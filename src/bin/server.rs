@@ -1,40 +1,62 @@
 use std::io::{Read, Write};
-use std::net::Ipv4Addr;
-use std::str::FromStr;
 
-use handshake::NetStack;
+mod args;
+use args::Args;
+
+mod signals;
+use signals::{Signals, LINGER};
+
+const USAGE: &str = "usage: server [--iface <name>] [--addr <ipv4>] [--mask <ipv4>]
+              [--port <port>] [--size <bytes>] [--count <connections>]
+
+Echoes back whatever is sent to it, reading up to --size bytes (1500) at a
+time, for --count connections (1) one after the other on --port (9090).";
 
 fn main() {
-    let mut netstack = NetStack::new(
-        "tun0",
-        Ipv4Addr::from_str("10.10.10.10").unwrap(),
-        Ipv4Addr::from_str("255.255.255.0").unwrap(),
-    )
-    .unwrap();
+    let args = Args::parse(USAGE, &["--port", "--size", "--count"]);
+    let port = args.get_or("--port", 9090);
+    let size = args.get_or("--size", 1500);
+    let count = args.get_or("--count", 1);
 
-    let listener = netstack.bind(9090).unwrap();
+    let signals = Signals::block();
+    let mut netstack = args.netstack();
 
-    println!(">>> Waiting for incoming connections...");
-    let mut stream = listener.accept().unwrap();
-    println!(">>> Connection accepted");
+    let handle = netstack.shutdown_handle();
+    signals.handle(move || handle.shutdown(LINGER));
 
-    loop {
-        let mut buf = [0u8; 1500];
-        let n = stream.read(&mut buf[..]).unwrap();
+    let listener = netstack.bind(port).unwrap();
 
-        if n == 0 {
+    for _ in 0..count {
+        println!(">>> Waiting for incoming connections...");
+        let Ok(mut stream) = listener.accept() else {
             break;
+        };
+        println!(">>> Connection accepted");
+
+        loop {
+            let mut buf = vec![0u8; size];
+            let Ok(n) = stream.read(&mut buf[..]) else {
+                break;
+            };
+
+            if n == 0 {
+                break;
+            }
+
+            if stream.write(&buf[..n]).is_err() {
+                break;
+            }
+
+            println!(
+                "\n>>> Read: {:?}\n",
+                String::from_iter(buf[..n].iter().map(|c| *c as char))
+            );
         }
 
-        stream.write(&buf[..n]).unwrap();
-
-        println!(
-            "\n>>> Read: {:?}\n",
-            String::from_iter(buf[..n].iter().map(|c| *c as char))
-        );
+        drop(stream);
     }
 
-    drop(stream);
-
+    // Unless a signal got here first
+    netstack.shutdown(LINGER);
     netstack.join();
 }
@@ -0,0 +1,44 @@
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use nix::sys::signal::{SigSet, Signal};
+
+// How long peers are given to acknowledge our FINs on the way out
+pub const LINGER: Duration = Duration::from_secs(5);
+
+/*
+SIGINT and SIGTERM are blocked in every thread and taken by one thread
+waiting for them instead, so that the stack can be shut down from an
+ordinary thread rather than from a signal handler.
+*/
+pub struct Signals {
+    set: SigSet,
+}
+
+impl Signals {
+    // Must come before any thread is spawned, as threads inherit the mask
+    pub fn block() -> Self {
+        let mut set = SigSet::empty();
+        set.add(Signal::SIGINT);
+        set.add(Signal::SIGTERM);
+        set.thread_block().unwrap();
+
+        Signals { set }
+    }
+
+    // Runs `handler` on the first signal, and exits right away on the second
+    pub fn handle(self, handler: impl FnOnce() + Send + 'static) {
+        thread::spawn(move || {
+            let signal = self.set.wait().unwrap();
+            println!(">>> Shutting down on {}, again to exit right away", signal);
+
+            thread::spawn(handler);
+
+            let signal = self.set.wait().unwrap();
+            println!(">>> Exiting on {}", signal);
+
+            process::exit(128 + signal as i32);
+        });
+    }
+}
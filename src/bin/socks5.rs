@@ -0,0 +1,247 @@
+use std::io::{self, Read, Write};
+use std::net::{self, Ipv4Addr, Shutdown, SocketAddr};
+use std::sync::Arc;
+use std::thread;
+
+mod args;
+use args::Args;
+
+mod signals;
+use signals::{Signals, LINGER};
+
+const SOCKS_VERSION: u8 = 5;
+
+const NO_AUTH: u8 = 0x00;
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+
+const CMD_CONNECT: u8 = 0x01;
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+const REP_SUCCEEDED: u8 = 0x00;
+const REP_GENERAL_FAILURE: u8 = 0x01;
+const REP_NETWORK_UNREACHABLE: u8 = 0x03;
+const REP_HOST_UNREACHABLE: u8 = 0x04;
+const REP_CONNECTION_REFUSED: u8 = 0x05;
+const REP_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const REP_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+
+const USAGE: &str = "usage: socks5 [--iface <name>] [--addr <ipv4>] [--mask <ipv4>] [--port <port>]
+
+Accepts SOCKS5 clients on the stack and relays each of them to the
+destination it asks for, which is reached through the kernel. Pointing a
+client at the address of the stack and --port (1080) thus carries its
+traffic through the TUN.";
+
+fn main() {
+    let args = Args::parse(USAGE, &["--port"]);
+    let port = args.get_or("--port", 1080);
+
+    let signals = Signals::block();
+    let mut netstack = args.netstack();
+
+    let handle = netstack.shutdown_handle();
+    signals.handle(move || handle.shutdown(LINGER));
+
+    let listener = netstack.bind(port).unwrap();
+
+    println!(">>> SOCKS5 proxy listening on port {}", port);
+
+    loop {
+        let Ok(client) = listener.accept() else { break };
+
+        thread::spawn(move || {
+            if let Err(err) = serve(client, |client| client.shutdown(Shutdown::Write)) {
+                println!(">>> Client failed: {}", err);
+            }
+        });
+    }
+
+    netstack.join();
+}
+
+/*
+The stream type of the stack cannot be named here, so the half-close of the
+client is handed in by the caller.
+*/
+fn serve<S, F>(mut client: S, close_write: F) -> io::Result<()>
+where
+    S: Read + Write + Send + Sync + 'static,
+    for<'a> &'a S: Read + Write,
+    F: Fn(&S) -> io::Result<()>,
+{
+    /*
+            RFC 1928 - S3. Procedure for TCP-based clients
+
+    The client connects to the server, and sends a version
+    identifier/method selection message:
+
+                    +----+----------+----------+
+                    |VER | NMETHODS | METHODS  |
+                    +----+----------+----------+
+                    | 1  |    1     | 1 to 255 |
+                    +----+----------+----------+
+
+    The server selects from one of the methods given in METHODS, and
+    sends a METHOD selection message. If the selected METHOD is X'FF',
+    none of the methods listed by the client are acceptable, and the
+    client MUST close the connection.
+    */
+    let mut hdr = [0u8; 2];
+    client.read_exact(&mut hdr)?;
+    if hdr[0] != SOCKS_VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not SOCKS5"));
+    }
+
+    let mut methods = vec![0u8; hdr[1] as usize];
+    client.read_exact(&mut methods)?;
+
+    if !methods.contains(&NO_AUTH) {
+        client.write_all(&[SOCKS_VERSION, NO_ACCEPTABLE_METHODS])?;
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "client requires authentication",
+        ));
+    }
+    client.write_all(&[SOCKS_VERSION, NO_AUTH])?;
+
+    /*
+            RFC 1928 - S4. Requests
+
+        +----+-----+-------+------+----------+----------+
+        |VER | CMD |  RSV  | ATYP | DST.ADDR | DST.PORT |
+        +----+-----+-------+------+----------+----------+
+        | 1  |  1  | X'00' |  1   | Variable |    2     |
+        +----+-----+-------+------+----------+----------+
+    */
+    let mut req = [0u8; 4];
+    client.read_exact(&mut req)?;
+
+    let host = match req[3] {
+        ATYP_IPV4 => {
+            let mut addr = [0u8; 4];
+            client.read_exact(&mut addr)?;
+
+            Ipv4Addr::from(addr).to_string()
+        }
+        ATYP_DOMAIN => {
+            let mut len = [0u8; 1];
+            client.read_exact(&mut len)?;
+
+            let mut name = vec![0u8; len[0] as usize];
+            client.read_exact(&mut name)?;
+
+            String::from_utf8_lossy(&name).into_owned()
+        }
+        ATYP_IPV6 => {
+            let mut addr = [0u8; 16];
+            client.read_exact(&mut addr)?;
+
+            net::Ipv6Addr::from(addr).to_string()
+        }
+        _ => {
+            reply(&mut client, REP_ADDRESS_TYPE_NOT_SUPPORTED, None)?;
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "address type"));
+        }
+    };
+
+    let mut port = [0u8; 2];
+    client.read_exact(&mut port)?;
+    let port = u16::from_be_bytes(port);
+
+    if req[1] != CMD_CONNECT {
+        reply(&mut client, REP_COMMAND_NOT_SUPPORTED, None)?;
+        return Err(io::Error::new(io::ErrorKind::Unsupported, "only CONNECT"));
+    }
+
+    println!(">>> Connecting to {}:{}", host, port);
+
+    let dest = match net::TcpStream::connect((host.as_str(), port)) {
+        Ok(dest) => dest,
+        Err(err) => {
+            let rep = match err.kind() {
+                io::ErrorKind::ConnectionRefused => REP_CONNECTION_REFUSED,
+                io::ErrorKind::NetworkUnreachable => REP_NETWORK_UNREACHABLE,
+                io::ErrorKind::HostUnreachable | io::ErrorKind::NotFound => REP_HOST_UNREACHABLE,
+                _ => REP_GENERAL_FAILURE,
+            };
+            reply(&mut client, rep, None)?;
+
+            return Err(err);
+        }
+    };
+
+    reply(&mut client, REP_SUCCEEDED, dest.local_addr().ok())?;
+
+    relay(client, dest, close_write)
+}
+
+/*
+        RFC 1928 - S6. Replies
+
+    +----+-----+-------+------+----------+----------+
+    |VER | REP |  RSV  | ATYP | BND.ADDR | BND.PORT |
+    +----+-----+-------+------+----------+----------+
+    | 1  |  1  | X'00' |  1   | Variable |    2     |
+    +----+-----+-------+------+----------+----------+
+*/
+fn reply(client: &mut impl Write, rep: u8, bound: Option<SocketAddr>) -> io::Result<()> {
+    let mut msg = vec![SOCKS_VERSION, rep, 0x00];
+
+    match bound {
+        Some(SocketAddr::V6(addr)) => {
+            msg.push(ATYP_IPV6);
+            msg.extend_from_slice(&addr.ip().octets());
+            msg.extend_from_slice(&addr.port().to_be_bytes());
+        }
+        Some(SocketAddr::V4(addr)) => {
+            msg.push(ATYP_IPV4);
+            msg.extend_from_slice(&addr.ip().octets());
+            msg.extend_from_slice(&addr.port().to_be_bytes());
+        }
+        None => {
+            msg.push(ATYP_IPV4);
+            msg.extend_from_slice(&[0; 6]);
+        }
+    }
+
+    client.write_all(&msg)
+}
+
+/*
+Each direction is copied on its own thread, and the end of one direction
+is passed on as a half-close, so the other one keeps flowing until its own
+end. A slow reader on either side holds the writer back through the
+window of its connection.
+*/
+fn relay<S, F>(client: S, dest: net::TcpStream, close_write: F) -> io::Result<()>
+where
+    S: Send + Sync + 'static,
+    for<'a> &'a S: Read + Write,
+    F: Fn(&S) -> io::Result<()>,
+{
+    let client = Arc::new(client);
+    let dest_rx = dest.try_clone()?;
+
+    let upstream = {
+        let client = client.clone();
+
+        thread::spawn(move || -> io::Result<u64> {
+            let n = io::copy(&mut &*client, &mut &dest)?;
+            dest.shutdown(Shutdown::Write)?;
+
+            Ok(n)
+        })
+    };
+
+    let down = io::copy(&mut &dest_rx, &mut &*client)?;
+    close_write(&client)?;
+
+    let up = upstream.join().unwrap()?;
+
+    println!(">>> Relayed {} bytes up and {} bytes down", up, down);
+
+    Ok(())
+}
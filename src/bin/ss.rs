@@ -0,0 +1,44 @@
+use std::time::Duration;
+
+use handshake::{ControlClient, DEFAULT_CONTROL_PATH};
+
+mod args;
+use args::Args;
+
+const USAGE: &str = "usage: handshake-ss [--control <path>]
+
+Lists the connections of a running stack, like ss does, by asking it over
+the unix socket it was told to answer on with --control (/tmp/handshake.sock).
+RTTs are smoothed, along with their variation, in milliseconds.";
+
+fn main() {
+    let args = Args::parse(USAGE, &[]);
+    let path: String = args.get_or("--control", DEFAULT_CONTROL_PATH.to_string());
+
+    let conns = ControlClient::connect(&path)
+        .and_then(|mut client| client.connections())
+        .unwrap_or_else(|err| args.fail(format!("cannot ask {}: {}", path, err)));
+
+    let millis = |d: Duration| d.as_secs_f64() * 1000.0;
+
+    println!(
+        "{:<10} {:>7} {:>7} {:<21} {:<21} {:>6} {:>10} rtt",
+        "State", "Recv-Q", "Send-Q", "Local Address:Port", "Peer Address:Port", "cwnd", "ssthresh"
+    );
+    for conn in conns {
+        let info = conn.info;
+
+        println!(
+            "{:<10} {:>7} {:>7} {:<21} {:<21} {:>6} {:>10} {:.3}/{:.3}",
+            format!("{:?}", info.state),
+            conn.recv_queue,
+            conn.send_queue,
+            conn.local,
+            conn.remote,
+            info.cwnd,
+            info.ssthresh,
+            millis(info.srtt),
+            millis(info.rttvar),
+        );
+    }
+}
@@ -0,0 +1,33 @@
+use std::sync::Mutex;
+
+// The most buffers kept for reuse, past which those given back are freed
+const MAX_POOLED: usize = 1024;
+
+/*
+Buffers that datagrams are handed over in, from the segment loop to the
+workers and from the workers to the timer loop. Each is given back once its
+datagram has been processed or sent and is taken again for another, so the
+data path stops allocating once it has warmed up.
+*/
+#[derive(Debug, Default)]
+pub struct BufPool {
+    free: Mutex<Vec<Vec<u8>>>,
+}
+
+impl BufPool {
+    // A buffer of len octets, holding whatever its last user left in it
+    pub fn take(&self, len: usize) -> Vec<u8> {
+        let mut buf = self.free.lock().unwrap().pop().unwrap_or_default();
+        buf.resize(len, 0);
+
+        buf
+    }
+
+    pub fn give(&self, buf: Vec<u8>) {
+        let mut free = self.free.lock().unwrap();
+
+        if free.len() < MAX_POOLED {
+            free.push(buf);
+        }
+    }
+}
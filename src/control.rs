@@ -0,0 +1,234 @@
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::{connections, ConnState, Connection, Manager, TcpInfo};
+
+// Where the binaries answer, and handshake-ss asks, unless told otherwise
+pub const DEFAULT_CONTROL_PATH: &str = "/tmp/handshake.sock";
+
+// A client that stops talking in the middle of a request is let go after this long
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+const CONN_STATES: [ConnState; 11] = [
+    ConnState::Closed,
+    ConnState::Listen,
+    ConnState::SynRcvd,
+    ConnState::SynSent,
+    ConnState::Estab,
+    ConnState::FinWait1,
+    ConnState::FinWait2,
+    ConnState::Closing,
+    ConnState::TimeWait,
+    ConnState::CloseWait,
+    ConnState::LastAck,
+];
+
+/*
+The control channel of a stack, a unix socket through which tools like
+handshake-ss look into it while it runs. Requests and responses are lines
+of text: a request is a single word, and its response is a line per item
+followed by an empty line. A request that is not understood is answered
+with a single line starting with "error".
+
+    connections     Every connection, as listed by NetStack::connections
+
+A connection is a line of fields separated by spaces, in the order of
+Connection and TcpInfo. Durations are in microseconds, and timers that are
+not running are a dash.
+
+Clients are answered one at a time, on a thread of the channel's own, which
+stops at the first client to come after the stack has been shut down.
+*/
+pub(crate) fn serve(path: &Path, manager: Arc<Mutex<Manager>>) -> io::Result<()> {
+    // A socket left behind by a stack that is gone is taken over
+    if path.exists() && UnixStream::connect(path).is_err() {
+        fs::remove_file(path)?;
+    }
+
+    let listener = UnixListener::bind(path)?;
+    let path = path.to_path_buf();
+
+    thread::spawn(move || {
+        for client in listener.incoming() {
+            if manager.lock().unwrap().shut_down.load(Ordering::Acquire) {
+                break;
+            }
+
+            let Ok(client) = client else { continue };
+            if let Err(err) = answer(client, &manager) {
+                println!("Control client failed: {}", err);
+            }
+        }
+
+        let _ = fs::remove_file(&path);
+    });
+
+    Ok(())
+}
+
+fn answer(client: UnixStream, manager: &Mutex<Manager>) -> io::Result<()> {
+    client.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut out = client.try_clone()?;
+
+    for request in BufReader::new(client).lines() {
+        match request?.trim() {
+            "connections" => {
+                let conns = connections(&manager.lock().unwrap());
+
+                for conn in conns {
+                    writeln!(out, "{}", encode(&conn))?;
+                }
+            }
+            request => writeln!(out, "error unknown request: {}", request)?,
+        }
+
+        writeln!(out)?;
+    }
+
+    Ok(())
+}
+
+fn encode(conn: &Connection) -> String {
+    let micros = |d: Duration| d.as_micros().to_string();
+    let timer = |t: Option<Duration>| t.map_or_else(|| "-".to_string(), micros);
+    let info = &conn.info;
+
+    [
+        conn.local.to_string(),
+        conn.remote.to_string(),
+        format!("{:?}", info.state),
+        micros(info.srtt),
+        micros(info.rttvar),
+        micros(info.rto),
+        info.cwnd.to_string(),
+        info.ssthresh.to_string(),
+        info.snd_wnd.to_string(),
+        info.rcv_wnd.to_string(),
+        info.snd_mss.to_string(),
+        info.bytes_in_flight.to_string(),
+        info.retransmits.to_string(),
+        conn.recv_queue.to_string(),
+        conn.send_queue.to_string(),
+        conn.segments.to_string(),
+        timer(conn.retransmit_timer),
+        timer(conn.probe_timer),
+        timer(conn.keepalive_timer),
+        timer(conn.time_wait_timer),
+    ]
+    .join(" ")
+}
+
+fn decode(line: &str) -> Option<Connection> {
+    fn field<T: FromStr>(s: &str) -> Option<T> {
+        s.parse().ok()
+    }
+    fn micros(s: &str) -> Option<Duration> {
+        field(s).map(Duration::from_micros)
+    }
+    fn timer(s: &str) -> Option<Option<Duration>> {
+        if s == "-" {
+            return Some(None);
+        }
+
+        micros(s).map(Some)
+    }
+
+    // Fields are taken in the order they are written in
+    let mut fields = line.split_whitespace();
+    let mut next = || fields.next();
+
+    let conn = Connection {
+        local: field(next()?)?,
+        remote: field(next()?)?,
+        info: TcpInfo {
+            state: {
+                let state = next()?;
+                CONN_STATES
+                    .into_iter()
+                    .find(|s| format!("{:?}", s) == state)?
+            },
+            srtt: micros(next()?)?,
+            rttvar: micros(next()?)?,
+            rto: micros(next()?)?,
+            cwnd: field(next()?)?,
+            ssthresh: field(next()?)?,
+            snd_wnd: field(next()?)?,
+            rcv_wnd: field(next()?)?,
+            snd_mss: field(next()?)?,
+            bytes_in_flight: field(next()?)?,
+            retransmits: field(next()?)?,
+        },
+        recv_queue: field(next()?)?,
+        send_queue: field(next()?)?,
+        segments: field(next()?)?,
+        retransmit_timer: timer(next()?)?,
+        probe_timer: timer(next()?)?,
+        keepalive_timer: timer(next()?)?,
+        time_wait_timer: timer(next()?)?,
+    };
+
+    fields.next().is_none().then_some(conn)
+}
+
+// Asks a stack over its control channel, see NetStack::serve_control
+#[derive(Debug)]
+pub struct ControlClient {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+}
+
+impl ControlClient {
+    pub fn connect(path: impl AsRef<Path>) -> io::Result<Self> {
+        let writer = UnixStream::connect(path)?;
+
+        Ok(ControlClient {
+            reader: BufReader::new(writer.try_clone()?),
+            writer,
+        })
+    }
+
+    pub fn connections(&mut self) -> io::Result<Vec<Connection>> {
+        self.request("connections")?
+            .iter()
+            .map(|line| {
+                decode(line).ok_or_else(|| {
+                    let err = format!("invalid connection: {}", line);
+                    io::Error::new(io::ErrorKind::InvalidData, err)
+                })
+            })
+            .collect()
+    }
+
+    // The lines of the response, without the empty one ending it
+    fn request(&mut self, request: &str) -> io::Result<Vec<String>> {
+        writeln!(self.writer, "{}", request)?;
+
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+
+            lines.push(line.to_string());
+        }
+
+        // An error is the only line of its response
+        match lines.first().and_then(|line| line.strip_prefix("error ")) {
+            Some(err) => Err(io::Error::other(err.to_string())),
+            None => Ok(lines),
+        }
+    }
+}
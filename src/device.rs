@@ -0,0 +1,212 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, IoSlice, Read, Write};
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixDatagram;
+
+use nix::libc;
+use nix::sys::uio::writev;
+use tidy_tuntap::Tun;
+
+/*
+Everything the stack needs from the link it runs on. Each call to recv must
+yield, and each call to send must take, exactly one IPv4 datagram. The file
+descriptor is only used to poll for readability.
+*/
+pub trait Device: Send {
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize>;
+
+    /*
+    The slices make up a single datagram. Devices that can gather them
+    directly avoid copying the payload out of the send buffer.
+    */
+    fn send_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        let buf: Vec<u8> = bufs.iter().flat_map(|b| b.iter()).copied().collect();
+
+        self.send(&buf)
+    }
+
+    fn mtu(&self) -> io::Result<usize>;
+
+    /*
+    Devices with segmentation offload take TCP segments carrying up to this
+    many octets of data and cut them into segments of at most mss octets.
+    The first slice handed to send_gso holds the IPv4 and TCP headers.
+    */
+    fn gso_max_size(&self) -> Option<usize> {
+        None
+    }
+
+    fn send_gso(&mut self, bufs: &[IoSlice<'_>], mss: u16) -> io::Result<usize> {
+        let _ = (bufs, mss);
+
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    // Devices that batch transmissions push out whatever is queued
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn raw_fd(&self) -> RawFd;
+}
+
+// Lets whatever holds the devices of a stack derive Debug
+impl fmt::Debug for dyn Device {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Device")
+            .field("fd", &self.raw_fd())
+            .finish()
+    }
+}
+
+impl Device for Tun {
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read(buf)
+    }
+
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write(buf)
+    }
+
+    fn send_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        Ok(writev(self.as_raw_fd(), bufs)?)
+    }
+
+    fn mtu(&self) -> io::Result<usize> {
+        Ok(self.get_mtu()? as usize)
+    }
+
+    fn raw_fd(&self) -> RawFd {
+        self.as_raw_fd()
+    }
+}
+
+/*
+A TUN device opened by someone else, e.g. a privileged launcher that passes
+the descriptor down. Creating the device and assigning its address are left
+to whoever opened it, so the process driving it needs no privileges. The
+device must have been opened without packet information.
+*/
+#[derive(Debug)]
+pub struct FdDevice {
+    file: File,
+    sock: OwnedFd, // Only used for querying the interface
+    ifreq_name: [libc::c_char; libc::IFNAMSIZ],
+}
+
+impl FdDevice {
+    pub fn new(fd: OwnedFd) -> io::Result<Self> {
+        let mut ifreq: libc::ifreq = unsafe { mem::zeroed() };
+
+        if unsafe { libc::ioctl(fd.as_raw_fd(), libc::TUNGETIFF, &mut ifreq) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let sock = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+        if sock < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(FdDevice {
+            file: File::from(fd),
+            sock: unsafe { OwnedFd::from_raw_fd(sock) },
+            ifreq_name: ifreq.ifr_name,
+        })
+    }
+
+    pub fn name(&self) -> String {
+        self.ifreq_name
+            .iter()
+            .map_while(|&c| (c != 0).then_some(c as u8 as char))
+            .collect()
+    }
+}
+
+impl Device for FdDevice {
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn send_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        self.file.write_vectored(bufs)
+    }
+
+    fn mtu(&self) -> io::Result<usize> {
+        let mut ifreq: libc::ifreq = unsafe { mem::zeroed() };
+        ifreq.ifr_name = self.ifreq_name;
+
+        if unsafe { libc::ioctl(self.sock.as_raw_fd(), libc::SIOCGIFMTU, &mut ifreq) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(unsafe { ifreq.ifr_ifru.ifru_mtu } as usize)
+    }
+
+    fn raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+pub const LOOPBACK_MTU: usize = 65535;
+
+/*
+Every datagram sent is received again, which lets a single stack talk to
+itself without a kernel device. The pair of datagram sockets keeps the
+datagram boundaries and gives the segment loop something to poll.
+*/
+#[derive(Debug)]
+pub struct LoopbackDevice {
+    tx: UnixDatagram,
+    rx: UnixDatagram,
+    mtu: usize,
+}
+
+impl LoopbackDevice {
+    pub fn new() -> io::Result<Self> {
+        Self::with_mtu(LOOPBACK_MTU)
+    }
+
+    pub fn with_mtu(mtu: usize) -> io::Result<Self> {
+        let (tx, rx) = UnixDatagram::pair()?;
+        tx.set_nonblocking(true)?;
+
+        Ok(LoopbackDevice { tx, rx, mtu })
+    }
+}
+
+impl Device for LoopbackDevice {
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.rx.recv(buf)
+    }
+
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() > self.mtu {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "datagram exceeds the MTU",
+            ));
+        }
+
+        // The same thread sends and receives, so a full queue drops like a real link would
+        match self.tx.send(buf) {
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(buf.len()),
+            res => res,
+        }
+    }
+
+    fn mtu(&self) -> io::Result<usize> {
+        Ok(self.mtu)
+    }
+
+    fn raw_fd(&self) -> RawFd {
+        self.rx.as_raw_fd()
+    }
+}
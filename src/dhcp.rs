@@ -0,0 +1,284 @@
+use std::io::{self, Cursor, Write};
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+use etherparse::{ip_number, Ipv4Header, Ipv4HeaderSlice, SerializedSize, UdpHeader};
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags};
+
+use crate::tcp::IpOpts;
+use crate::{Device, Error};
+
+const CLIENT_PORT: u16 = 68;
+const SERVER_PORT: u16 = 67;
+
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+
+const OPT_PAD: u8 = 0;
+const OPT_SUBNET_MASK: u8 = 1;
+const OPT_ROUTER: u8 = 3;
+const OPT_REQUESTED_ADDR: u8 = 50;
+const OPT_LEASE_TIME: u8 = 51;
+const OPT_MESSAGE_TYPE: u8 = 53;
+const OPT_SERVER_ID: u8 = 54;
+const OPT_PARAMETER_LIST: u8 = 55;
+const OPT_END: u8 = 255;
+
+const DHCPDISCOVER: u8 = 1;
+const DHCPOFFER: u8 = 2;
+const DHCPREQUEST: u8 = 3;
+const DHCPACK: u8 = 5;
+const DHCPNAK: u8 = 6;
+
+const RETRANSMIT: Duration = Duration::from_secs(2);
+const ATTEMPTS: u32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lease {
+    pub addr: Ipv4Addr,
+    pub mask: Ipv4Addr,
+    pub router: Option<Ipv4Addr>,
+    pub server: Ipv4Addr,
+    pub lease_time: Duration,
+}
+
+#[derive(Debug)]
+struct Reply {
+    kind: u8,
+    yiaddr: Ipv4Addr,
+    mask: Option<Ipv4Addr>,
+    router: Option<Ipv4Addr>,
+    server: Option<Ipv4Addr>,
+    lease_time: Option<u32>,
+}
+
+/*
+        RFC 2131 - S4.4.1 Initialization and allocation of network address
+
+The client begins in INIT state and forms a DHCPDISCOVER message. The
+client MAY suggest a network address and/or lease time by including the
+'requested IP address' and 'IP address lease time' options.
+
+The client receives one or more DHCPOFFER messages from one or more
+servers. The client chooses one server from which to request
+configuration parameters, based on the configuration parameters offered in
+the DHCPOFFER messages. The client broadcasts a DHCPREQUEST message that
+MUST include the 'server identifier' option to indicate which server it
+has selected, and that MAY include other options specifying desired
+configuration values. The 'requested IP address' option MUST be set to the
+value of 'yiaddr' in the DHCPOFFER message from the server.
+
+The client receives a DHCPACK message with configuration parameters. If
+the client receives a DHCPNAK message, the client restarts the
+configuration process.
+
+A TUN device has no hardware address, so the transaction id doubles as
+the client hardware address.
+*/
+pub fn acquire(tun: &mut dyn Device) -> Result<Lease, Error> {
+    for _ in 0..ATTEMPTS {
+        let xid = rand::random::<u32>();
+
+        let discover = message(xid, DHCPDISCOVER, &[]);
+        let Some(offer) = transact(tun, xid, &discover, DHCPOFFER)? else {
+            continue;
+        };
+        let Some(server) = offer.server else { continue };
+
+        let mut opts = vec![];
+        opts.extend_from_slice(&[OPT_REQUESTED_ADDR, 4]);
+        opts.extend_from_slice(&offer.yiaddr.octets());
+        opts.extend_from_slice(&[OPT_SERVER_ID, 4]);
+        opts.extend_from_slice(&server.octets());
+
+        let request = message(xid, DHCPREQUEST, &opts);
+        let Some(ack) = transact(tun, xid, &request, DHCPACK)? else {
+            continue;
+        };
+
+        return Ok(Lease {
+            addr: ack.yiaddr,
+            mask: ack
+                .mask
+                .or(offer.mask)
+                .unwrap_or(Ipv4Addr::new(255, 255, 255, 0)),
+            router: ack.router.or(offer.router),
+            server,
+            lease_time: Duration::from_secs(ack.lease_time.unwrap_or(u32::MAX) as u64),
+        });
+    }
+
+    Err(Error::NoLease)
+}
+
+/*
+   0                   1                   2                   3
+   0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+   +---------------+---------------+---------------+---------------+
+   |     op (1)    |   htype (1)   |   hlen (1)    |   hops (1)    |
+   +---------------+---------------+---------------+---------------+
+   |                            xid (4)                            |
+   +-------------------------------+-------------------------------+
+   |           secs (2)            |           flags (2)           |
+   +-------------------------------+-------------------------------+
+   |                          ciaddr  (4)                          |
+   +---------------------------------------------------------------+
+   |                          yiaddr  (4)                          |
+   +---------------------------------------------------------------+
+   |                          siaddr  (4)                          |
+   +---------------------------------------------------------------+
+   |                          giaddr  (4)                          |
+   +---------------------------------------------------------------+
+   |                          chaddr  (16)                         |
+   +---------------------------------------------------------------+
+   |                          sname   (64)                         |
+   +---------------------------------------------------------------+
+   |                          file    (128)                        |
+   +---------------------------------------------------------------+
+   |                          options (variable)                   |
+   +---------------------------------------------------------------+
+*/
+fn message(xid: u32, kind: u8, opts: &[u8]) -> Vec<u8> {
+    let mut msg = vec![0u8; 236];
+
+    msg[0] = 1; // BOOTREQUEST
+    msg[1] = 1; // Ethernet
+    msg[2] = 6;
+    msg[4..8].copy_from_slice(&xid.to_be_bytes());
+    msg[10] = 0x80; // Ask for broadcast replies since we have no address yet
+    msg[28..32].copy_from_slice(&xid.to_be_bytes());
+
+    msg.extend_from_slice(&MAGIC_COOKIE);
+    msg.extend_from_slice(&[OPT_MESSAGE_TYPE, 1, kind]);
+    msg.extend_from_slice(&[
+        OPT_PARAMETER_LIST,
+        3,
+        OPT_SUBNET_MASK,
+        OPT_ROUTER,
+        OPT_LEASE_TIME,
+    ]);
+    msg.extend_from_slice(opts);
+    msg.push(OPT_END);
+
+    msg
+}
+
+/*
+Returns None if no reply of the expected kind came in time, which is retried,
+and the error of the device if it failed, which is not.
+*/
+fn transact(tun: &mut dyn Device, xid: u32, msg: &[u8], expected: u8) -> io::Result<Option<Reply>> {
+    send(tun, msg)?;
+
+    let deadline = Instant::now() + RETRANSMIT;
+
+    loop {
+        let Some(left) = deadline.checked_duration_since(Instant::now()) else {
+            return Ok(None);
+        };
+
+        let mut pfd = [PollFd::new(tun.raw_fd(), PollFlags::POLLIN)];
+        match poll(&mut pfd[..], left.as_millis() as i32) {
+            Ok(0) => return Ok(None),
+            Ok(_) => {}
+            Err(Errno::EINTR) => continue,
+            Err(err) => return Err(err.into()),
+        }
+
+        let mut buf = [0u8; 1500];
+        let n = match tun.recv(&mut buf) {
+            Ok(n) => n,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(err) => return Err(err),
+        };
+
+        let Some(reply) = parse(&buf[..n], xid) else {
+            continue;
+        };
+
+        if reply.kind == DHCPNAK {
+            return Ok(None);
+        }
+        if reply.kind == expected {
+            return Ok(Some(reply));
+        }
+    }
+}
+
+fn send(tun: &mut dyn Device, msg: &[u8]) -> io::Result<()> {
+    let ip4h = Ipv4Header::new(
+        (UdpHeader::SERIALIZED_SIZE + msg.len()) as u16,
+        IpOpts::default().ttl,
+        ip_number::UDP,
+        Ipv4Addr::UNSPECIFIED.octets(),
+        Ipv4Addr::BROADCAST.octets(),
+    );
+    let udph = UdpHeader::with_ipv4_checksum(CLIENT_PORT, SERVER_PORT, &ip4h, msg).unwrap();
+
+    let mut cursor = Cursor::new([0u8; 1500]);
+    ip4h.write(&mut cursor).unwrap();
+    udph.write(&mut cursor).unwrap();
+    cursor.write_all(msg).unwrap();
+
+    let buf = cursor.get_ref();
+    let pos = cursor.position() as usize;
+
+    tun.send(&buf[..pos]).map(drop)
+}
+
+fn parse(datagram: &[u8], xid: u32) -> Option<Reply> {
+    let ip4h = Ipv4HeaderSlice::from_slice(datagram).ok()?;
+    if ip4h.protocol() != ip_number::UDP {
+        return None;
+    }
+
+    let (udph, msg) = UdpHeader::from_slice(&datagram[ip4h.slice().len()..]).ok()?;
+    if udph.destination_port != CLIENT_PORT || msg.len() < 240 {
+        return None;
+    }
+
+    // Only BOOTREPLYs to our transaction are of interest
+    if msg[0] != 2 || msg[4..8] != xid.to_be_bytes() || msg[236..240] != MAGIC_COOKIE {
+        return None;
+    }
+
+    let addr = |b: &[u8]| Ipv4Addr::new(b[0], b[1], b[2], b[3]);
+
+    let mut reply = Reply {
+        kind: 0,
+        yiaddr: addr(&msg[16..20]),
+        mask: None,
+        router: None,
+        server: None,
+        lease_time: None,
+    };
+
+    let mut opts = &msg[240..];
+    while let Some((&code, rest)) = opts.split_first() {
+        if code == OPT_END {
+            break;
+        }
+        if code == OPT_PAD {
+            opts = rest;
+            continue;
+        }
+
+        let (&len, rest) = rest.split_first()?;
+        let val = rest.get(..len as usize)?;
+
+        match code {
+            OPT_MESSAGE_TYPE if len == 1 => reply.kind = val[0],
+            OPT_SUBNET_MASK if len == 4 => reply.mask = Some(addr(val)),
+            OPT_ROUTER if len >= 4 => reply.router = Some(addr(val)),
+            OPT_SERVER_ID if len == 4 => reply.server = Some(addr(val)),
+            OPT_LEASE_TIME if len == 4 => {
+                reply.lease_time = Some(u32::from_be_bytes([val[0], val[1], val[2], val[3]]))
+            }
+            _ => {}
+        }
+
+        opts = &rest[len as usize..];
+    }
+
+    Some(reply)
+}
@@ -0,0 +1,40 @@
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+use nix::sys::eventfd::{eventfd, EfdFlags};
+use nix::unistd::{read, write};
+
+/*
+Wakes a loop of the stack out of its poll, like the timer loop when another
+thread has queued something for it to send, e.g. written data, a FIN, a SYN
+or a reset. Rings that come in before the loop answers are collapsed into one.
+*/
+#[derive(Debug)]
+pub struct Doorbell {
+    fd: OwnedFd,
+}
+
+impl Doorbell {
+    pub fn new() -> io::Result<Self> {
+        let fd = eventfd(0, EfdFlags::EFD_NONBLOCK | EfdFlags::EFD_CLOEXEC)?;
+
+        Ok(Doorbell {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        })
+    }
+
+    // Only fails once the counter is about to overflow, when it is ringing anyway
+    pub fn ring(&self) {
+        let _ = write(self.fd.as_raw_fd(), &1u64.to_ne_bytes());
+    }
+
+    pub fn answer(&self) {
+        let _ = read(self.fd.as_raw_fd(), &mut [0u8; 8]);
+    }
+}
+
+impl AsRawFd for Doorbell {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
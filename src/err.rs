@@ -1,24 +1,135 @@
 use std::io;
+use std::net::Ipv4Addr;
+use std::time::Duration;
 
-use crate::tcp::Dual;
+use crate::tcp::{ConnContext, ConnState};
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("Tun error: {0}")]
     TunError(#[from] tidy_tuntap::error::Error),
 
+    #[error("IO error: {0}")]
+    IoError(#[from] io::Error),
+
     #[error("Port: {0} has been unexpectedly closed")]
     PortClosed(u16),
 
     #[error("Port: {0} already in use")]
     PortInUse(u16),
 
-    #[error("Stream: {0:?} has been unexpectedly closed")]
-    StreamClosed(Dual),
+    #[error("Stream: {0} has been unexpectedly closed")]
+    StreamClosed(ConnContext),
+
+    #[error("Connection: {0} has been refused")]
+    ConnectionRefused(ConnContext),
+
+    #[error("TTL: {0} is out of range")]
+    InvalidTtl(u32),
+
+    #[error("TOS: {0} is out of range")]
+    InvalidTos(u32),
+
+    #[error("MSS: {0} is out of range")]
+    InvalidMss(u32),
+
+    #[error("Address: {0} is not assigned to any interface")]
+    AddrNotAvailable(Ipv4Addr),
+
+    #[error("Buffer size: {0} is out of range")]
+    InvalidBufferSize(usize),
+
+    #[error("Keep-alive interval: {0:?} is out of range")]
+    InvalidKeepalive(Duration),
+
+    #[error("Rate limit: {0} octets per second in bursts of {1} is out of range")]
+    InvalidRateLimit(u64, u64),
+
+    #[error("Weight: {0} is out of range")]
+    InvalidWeight(u16),
+
+    #[error("No ephemeral port is free")]
+    NoEphemeralPort,
+
+    #[error("No route to host: {0}")]
+    NoRoute(Ipv4Addr),
+
+    #[error("No DHCP lease could be acquired")]
+    NoLease,
+
+    #[error("Connection: {0} has timed out")]
+    ConnectTimeout(ConnContext),
+
+    #[error("Could not resolve host: {0}")]
+    UnresolvedHost(String),
+
+    #[error("Connection: {0} has been reset")]
+    ConnectionReset(ConnContext),
+
+    #[error("Connection: {0} has been dropped after excessive retransmissions")]
+    RetransmitTimeout(ConnContext),
+
+    #[error("Connection: {0} has been dropped after unanswered keep-alives")]
+    KeepaliveTimeout(ConnContext),
+
+    #[error("Connection: {0} has been dropped after the stack failed to process it")]
+    ConnectionFailed(ConnContext),
+
+    #[error("Connection: {0} could not reach its destination ({1})")]
+    Unreachable(ConnContext, String),
+
+    #[error("Snapshot: a connection in {0:?} cannot be restored")]
+    InvalidSnapshot(ConnState),
+
+    #[error("Token: {0} is already registered")]
+    TokenInUse(usize),
+
+    #[cfg(feature = "tls")]
+    #[error("TLS error: {0}")]
+    TlsError(#[from] rustls::Error),
+
+    #[cfg(feature = "tls")]
+    #[error("Server name: {0} is not valid")]
+    InvalidServerName(String),
+
+    #[error("All {} connection attempts have failed", .0.len())]
+    AttemptsExhausted(Vec<Error>),
+
+    #[error("The stack has been shut down")]
+    ShutDown,
+
+    #[error("The stack keeps as many connections as it may")]
+    TooManyConnections,
 }
 
 impl From<Error> for io::Error {
     fn from(value: Error) -> Self {
-        io::Error::new(io::ErrorKind::Other, value)
+        let kind = match value {
+            Error::IoError(err) => return err,
+            Error::ConnectionRefused(_) => io::ErrorKind::ConnectionRefused,
+            Error::PortInUse(_) => io::ErrorKind::AddrInUse,
+            Error::AddrNotAvailable(_) | Error::NoEphemeralPort => io::ErrorKind::AddrNotAvailable,
+            Error::ConnectionReset(_) => io::ErrorKind::ConnectionReset,
+            Error::ConnectionFailed(_) => io::ErrorKind::ConnectionAborted,
+            Error::Unreachable(..) => io::ErrorKind::HostUnreachable,
+            Error::ShutDown => io::ErrorKind::NotConnected,
+            Error::InvalidTtl(_)
+            | Error::InvalidTos(_)
+            | Error::InvalidMss(_)
+            | Error::InvalidBufferSize(_)
+            | Error::InvalidKeepalive(_)
+            | Error::InvalidRateLimit(..)
+            | Error::InvalidWeight(_)
+            | Error::InvalidSnapshot(_) => io::ErrorKind::InvalidInput,
+            #[cfg(feature = "tls")]
+            Error::InvalidServerName(_) => io::ErrorKind::InvalidInput,
+            Error::NoLease
+            | Error::ConnectTimeout(_)
+            | Error::RetransmitTimeout(_)
+            | Error::KeepaliveTimeout(_) => io::ErrorKind::TimedOut,
+            _ => io::ErrorKind::Other,
+        };
+
+        io::Error::new(kind, value)
     }
 }
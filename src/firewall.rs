@@ -0,0 +1,117 @@
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+
+// Which way the datagrams a rule applies to travel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+    Both,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuleAction {
+    Allow,
+    Deny, // Drop the datagram silently
+}
+
+/*
+Matches datagrams by their source address and, for TCP segments, their
+destination port, both as found in the datagram: the peer and the local
+port for inbound ones, the stack and the port of the peer for outbound
+ones. A mask of 0.0.0.0 matches any source, and no port any port.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rule {
+    pub action: RuleAction,
+    pub direction: Direction,
+    pub src: Ipv4Addr,
+    pub mask: Ipv4Addr,
+    pub dst_port: Option<u16>,
+}
+
+impl Rule {
+    fn matches(&self, direction: Direction, src: Ipv4Addr, dst_port: Option<u16>) -> bool {
+        let mask = u32::from(self.mask);
+
+        (self.direction == Direction::Both || self.direction == direction)
+            && u32::from(src) & mask == u32::from(self.src) & mask
+            && (self.dst_port.is_none() || self.dst_port == dst_port)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RuleId(u64);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RuleStats {
+    pub id: RuleId,
+    pub rule: Rule,
+    pub hits: u64, // Datagrams the rule decided on
+}
+
+#[derive(Debug, Default)]
+struct Table {
+    rules: Vec<RuleStats>,
+    next_id: u64,
+}
+
+/*
+The rules of a stack, evaluated in the order they were added, the first one
+that matches deciding. Datagrams that no rule matches are allowed. Inbound
+segments are screened by the segment loop before they are matched to a
+connection, outbound datagrams by the devices before they are sent.
+*/
+#[derive(Debug, Default)]
+pub(crate) struct Firewall {
+    table: Mutex<Table>,
+}
+
+impl Firewall {
+    pub(crate) fn add(&self, rule: Rule) -> RuleId {
+        let mut table = self.table.lock().unwrap();
+
+        let id = RuleId(table.next_id);
+        table.next_id += 1;
+        table.rules.push(RuleStats { id, rule, hits: 0 });
+
+        id
+    }
+
+    pub(crate) fn remove(&self, id: RuleId) -> bool {
+        let mut table = self.table.lock().unwrap();
+
+        let len = table.rules.len();
+        table.rules.retain(|rule| rule.id != id);
+
+        table.rules.len() < len
+    }
+
+    pub(crate) fn rules(&self) -> Vec<RuleStats> {
+        self.table.lock().unwrap().rules.clone()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.table.lock().unwrap().rules.is_empty()
+    }
+
+    pub(crate) fn admits(
+        &self,
+        direction: Direction,
+        src: Ipv4Addr,
+        dst_port: Option<u16>,
+    ) -> bool {
+        let mut table = self.table.lock().unwrap();
+
+        let Some(matched) = table
+            .rules
+            .iter_mut()
+            .find(|stats| stats.rule.matches(direction, src, dst_port))
+        else {
+            return true;
+        };
+        matched.hits += 1;
+
+        matched.rule.action == RuleAction::Allow
+    }
+}
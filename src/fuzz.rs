@@ -0,0 +1,230 @@
+use std::cmp;
+use std::io::{self, IoSlice};
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use arbitrary::Arbitrary;
+use etherparse::{PacketBuilder, TcpOptionElement};
+
+use crate::tcp::{AckThrottle, IpOpts, IssGenerator, MemoryPool, MockClock, TcpOptions};
+use crate::{parse_datagram, Dual, Emitter, Inbound, Quad, State, TCB};
+
+/*
+What the fuzz targets under fuzz/ drive: the validation the segment loop
+puts every datagram read from a device through, and the state machine of a
+connection, taken to each of its states by a regular handshake and close
+before the fuzzed segments are fed to it. Time only passes when an op says
+so, in milliseconds.
+
+The sequence number of the peer starts close to wrapping around, so that
+arithmetic on sequence numbers that does not wrap is caught early.
+*/
+const LOCAL: Dual = Dual {
+    ipv4: Ipv4Addr::new(10, 0, 0, 1),
+    port: 80,
+};
+const REMOTE: Dual = Dual {
+    ipv4: Ipv4Addr::new(10, 0, 0, 2),
+    port: 4001,
+};
+const PEER_ISS: u32 = u32::MAX - 16;
+
+const STATES: [State; 10] = [
+    State::Listen,
+    State::SynRcvd,
+    State::SynSent,
+    State::Estab,
+    State::FinWait1,
+    State::FinWait2,
+    State::Closing,
+    State::TimeWait,
+    State::CloseWait,
+    State::LastAck,
+];
+
+pub fn datagram(buf: &[u8]) {
+    let _ = parse_datagram(buf, true);
+}
+
+// A segment of the peer, with its numbers relative to the ISS of either side
+#[derive(Debug, Clone, Default, Arbitrary)]
+pub struct FuzzSegment {
+    pub seq: i16,
+    pub ack: Option<i16>,
+    pub syn: bool,
+    pub fin: bool,
+    pub rst: bool,
+    pub psh: bool,
+    pub urg: Option<u16>,
+    pub window: u16,
+    pub mss: Option<u16>,
+    pub data: Vec<u8>,
+}
+
+// What may happen to a connection, from the peer, the application or the timers
+#[derive(Debug, Clone, Arbitrary)]
+pub enum Op {
+    Segment(FuzzSegment),
+    Tick,
+    Advance(u16),
+    Read(u16),
+    Write(Vec<u8>),
+    Close,
+}
+
+// Takes a connection to the state picked by state, then applies ops to it
+pub fn run(state: u8, ops: &[Op]) {
+    let clock = MockClock::new();
+    let mut tcb = tcb_in(STATES[state as usize % STATES.len()], &clock);
+
+    for op in ops {
+        match op {
+            Op::Segment(seg) => {
+                let seq = PEER_ISS.wrapping_add(seg.seq as i32 as u32);
+                let ack = seg.ack.map(|ack| tcb.iss().wrapping_add(ack as i32 as u32));
+
+                feed(&mut tcb, seq, ack, seg);
+            }
+            Op::Tick => {
+                tcb.on_tick(&mut Discard);
+            }
+            Op::Advance(ms) => {
+                clock.advance(Duration::from_millis(*ms as u64));
+                tcb.on_tick(&mut Discard);
+            }
+            Op::Read(len) => {
+                tcb.recv(&mut vec![0u8; *len as usize]);
+            }
+            // The stream only writes and closes while the write half is open
+            Op::Write(data) if matches!(tcb.state, State::Estab | State::CloseWait) => {
+                let len = cmp::min(data.len(), tcb.outgoing.room());
+                tcb.outgoing.extend_from_slice(&data[..len]);
+            }
+            Op::Close if matches!(tcb.state, State::Estab | State::CloseWait) => tcb.close(),
+            Op::Write(_) | Op::Close => {}
+        }
+    }
+}
+
+fn open(active: bool, clock: &MockClock) -> TCB {
+    let quad = Quad {
+        src: LOCAL,
+        dst: REMOTE,
+    };
+    let iss = IssGenerator::default();
+    let ack_throttle = Arc::new(AckThrottle::default());
+    let memory = Arc::new(MemoryPool::default());
+    let clock = Arc::new(clock.clone());
+    let (ip_opts, opts) = (IpOpts::default(), TcpOptions::default());
+
+    if active {
+        let mut tcb = TCB::syn_sent(quad, &iss, ack_throttle, memory, clock, ip_opts, opts);
+        tcb.on_tick(&mut Discard);
+
+        tcb
+    } else {
+        TCB::listen(quad, &iss, ack_throttle, memory, clock, ip_opts, opts)
+    }
+}
+
+fn tcb_in(state: State, clock: &MockClock) -> TCB {
+    let peer = PEER_ISS.wrapping_add(1);
+    let syn = FuzzSegment {
+        syn: true,
+        window: u16::MAX,
+        ..FuzzSegment::default()
+    };
+    let ack = FuzzSegment {
+        window: u16::MAX,
+        ..FuzzSegment::default()
+    };
+    let fin = FuzzSegment {
+        fin: true,
+        window: u16::MAX,
+        ..FuzzSegment::default()
+    };
+
+    let mut tcb = match state {
+        State::Listen => return open(false, clock),
+        State::SynSent => return open(true, clock),
+        State::SynRcvd => open(false, clock),
+        State::Estab => open(true, clock),
+        State::CloseWait | State::FinWait1 => tcb_in(State::Estab, clock),
+        State::LastAck => tcb_in(State::CloseWait, clock),
+        State::FinWait2 | State::Closing => tcb_in(State::FinWait1, clock),
+        State::TimeWait => tcb_in(State::FinWait2, clock),
+    };
+
+    // Acknowledges the SYN of the TCB, or its FIN as well
+    let syn_acked = Some(tcb.iss().wrapping_add(1));
+    let fin_acked = Some(tcb.iss().wrapping_add(2));
+
+    match state {
+        State::SynRcvd => feed(&mut tcb, PEER_ISS, None, &syn),
+        State::Estab => feed(&mut tcb, PEER_ISS, syn_acked, &syn),
+        State::CloseWait | State::Closing => feed(&mut tcb, peer, syn_acked, &fin),
+        State::FinWait2 => feed(&mut tcb, peer, fin_acked, &ack),
+        State::TimeWait => feed(&mut tcb, peer, fin_acked, &fin),
+        State::FinWait1 | State::LastAck => tcb.close(),
+        State::Listen | State::SynSent => {}
+    }
+    tcb.on_tick(&mut Discard);
+
+    tcb
+}
+
+// Puts seg through the same parsing a segment read from a device goes through
+fn feed(tcb: &mut TCB, seq: u32, ack: Option<u32>, seg: &FuzzSegment) {
+    let mut builder = PacketBuilder::ipv4(REMOTE.ipv4.octets(), LOCAL.ipv4.octets(), 64).tcp(
+        REMOTE.port,
+        LOCAL.port,
+        seq,
+        seg.window,
+    );
+
+    if seg.syn {
+        builder = builder.syn();
+    }
+    if seg.fin {
+        builder = builder.fin();
+    }
+    if seg.rst {
+        builder = builder.rst();
+    }
+    if seg.psh {
+        builder = builder.psh();
+    }
+    if let Some(ack) = ack {
+        builder = builder.ack(ack);
+    }
+    if let Some(urg) = seg.urg {
+        builder = builder.urg(urg);
+    }
+    if let Some(mss) = seg.mss {
+        let Ok(with_mss) = builder.options(&[TcpOptionElement::MaximumSegmentSize(mss)]) else {
+            return;
+        };
+        builder = with_mss;
+    }
+
+    let data = &seg.data[..cmp::min(seg.data.len(), 1400)];
+    let mut buf = Vec::with_capacity(builder.size(data.len()));
+    if builder.write(&mut buf, data).is_err() {
+        return;
+    }
+
+    let Ok(Some(Inbound::Segment(ip4h, tcph, data))) = parse_datagram(&buf, true) else {
+        return;
+    };
+
+    tcb.on_segment(ip4h, tcph, data, &mut Discard);
+}
+
+struct Discard;
+
+impl Emitter for Discard {
+    fn emit(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        Ok(bufs.iter().map(|b| b.len()).sum())
+    }
+}
@@ -0,0 +1,239 @@
+use std::fmt;
+use std::io::{self, IoSlice};
+use std::mem;
+use std::os::fd::RawFd;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use etherparse::{ip_number, Ipv4HeaderSlice, TcpHeaderSlice};
+
+use crate::{Device, Direction, Firewall};
+
+// What a hook decides to do with a datagram
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookVerdict {
+    Pass, // Carry on with the datagram, as the hook left it
+    Drop, // Act as if it never arrived, or as if it was sent
+}
+
+/*
+A datagram handed to a hook, with the headers parsed on demand. A hook that
+changes the headers or the data calls update_checksums afterwards, unless
+it means to corrupt them. Datagrams may be cut short, but not extended.
+*/
+#[derive(Debug)]
+pub struct Packet<'a> {
+    buf: &'a mut [u8],
+}
+
+impl Packet<'_> {
+    pub fn bytes(&self) -> &[u8] {
+        self.buf
+    }
+
+    pub fn bytes_mut(&mut self) -> &mut [u8] {
+        self.buf
+    }
+
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    pub fn truncate(&mut self, len: usize) {
+        if len < self.buf.len() {
+            self.buf = &mut mem::take(&mut self.buf)[..len];
+        }
+    }
+
+    pub fn ipv4(&self) -> Option<Ipv4HeaderSlice<'_>> {
+        Ipv4HeaderSlice::from_slice(self.buf).ok()
+    }
+
+    // Only of datagrams carrying TCP, whose total length is within what they hold
+    pub fn tcp(&self) -> Option<TcpHeaderSlice<'_>> {
+        let ip4h = self
+            .ipv4()
+            .filter(|ip4h| ip4h.protocol() == ip_number::TCP)?;
+        let ip_payload = self
+            .buf
+            .get(ip4h.slice().len()..ip4h.total_len() as usize)?;
+
+        TcpHeaderSlice::from_slice(ip_payload).ok()
+    }
+
+    // The data of a TCP segment, otherwise whatever the datagram carries
+    pub fn payload(&self) -> &[u8] {
+        let Some(ip4h) = self.ipv4() else { return &[] };
+        let start = ip4h.slice().len();
+        let end = (ip4h.total_len() as usize).clamp(start, self.buf.len());
+
+        match self.tcp() {
+            Some(tcph) => &self.buf[start + tcph.slice().len()..end],
+            None => &self.buf[start..end],
+        }
+    }
+
+    pub fn update_checksums(&mut self) {
+        let Some(ip4h) = self.ipv4() else { return };
+        let ihl = ip4h.slice().len();
+
+        let Ok(ip_checksum) = ip4h.to_header().calc_header_checksum() else {
+            return;
+        };
+        let tcp_checksum = self
+            .tcp()
+            .and_then(|tcph| tcph.calc_checksum_ipv4(&ip4h, self.payload()).ok());
+
+        self.buf[10..12].copy_from_slice(&ip_checksum.to_be_bytes());
+        if let Some(checksum) = tcp_checksum {
+            self.buf[ihl + 16..ihl + 18].copy_from_slice(&checksum.to_be_bytes());
+        }
+    }
+}
+
+pub(crate) struct Hook(pub(crate) Box<dyn FnMut(&mut Packet<'_>) -> HookVerdict + Send>);
+
+impl fmt::Debug for Hook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Hook")
+    }
+}
+
+/*
+The hooks of a stack, shared by every device it runs on. Ingress hooks see
+datagrams as read from a device, before they are validated; egress hooks
+see them right before they are sent, super-segments being seen whole.
+*/
+#[derive(Debug, Default)]
+pub(crate) struct Hooks {
+    pub(crate) ingress: Mutex<Option<Hook>>,
+    pub(crate) egress: Mutex<Option<Hook>>,
+    pub(crate) ingress_dropped: AtomicU64,
+    pub(crate) egress_dropped: AtomicU64,
+}
+
+// Runs hook, if there is one, over buf. Returns the length of the datagram left, if any
+fn apply(hook: &Mutex<Option<Hook>>, dropped: &AtomicU64, buf: &mut [u8]) -> Option<usize> {
+    let mut hook = hook.lock().unwrap();
+    let Some(Hook(hook)) = hook.as_mut() else {
+        return Some(buf.len());
+    };
+
+    let mut packet = Packet { buf };
+    if hook(&mut packet) == HookVerdict::Drop {
+        dropped.fetch_add(1, Ordering::Relaxed);
+
+        return None;
+    }
+
+    Some(packet.len())
+}
+
+/*
+Every device of a stack is wrapped in one, so hooks and rules can be set
+while it runs. Outbound datagrams go through the firewall first and then
+the hook, so the hook is the closest to the device either way.
+*/
+#[derive(Debug)]
+pub(crate) struct HookedDevice {
+    inner: Box<dyn Device>,
+    hooks: Arc<Hooks>,
+    firewall: Arc<Firewall>,
+}
+
+impl HookedDevice {
+    pub(crate) fn new(inner: Box<dyn Device>, hooks: Arc<Hooks>, firewall: Arc<Firewall>) -> Self {
+        HookedDevice {
+            inner,
+            hooks,
+            firewall,
+        }
+    }
+
+    /*
+    Returns None if the datagram made of bufs is dropped. It is only copied
+    out of its slices, into what is to be sent instead, if there is a hook
+    or a rule to look at it.
+    */
+    fn egress(&self, bufs: &[IoSlice<'_>]) -> Option<Option<Vec<u8>>> {
+        if self.hooks.egress.lock().unwrap().is_none() && self.firewall.is_empty() {
+            return Some(None);
+        }
+
+        let mut buf: Vec<u8> = bufs.iter().flat_map(|b| b.iter()).copied().collect();
+
+        let packet = Packet { buf: &mut buf };
+        let src = packet.ipv4().map(|ip4h| ip4h.source_addr());
+        let dst_port = packet.tcp().map(|tcph| tcph.destination_port());
+        if src.is_some_and(|src| !self.firewall.admits(Direction::Outbound, src, dst_port)) {
+            return None;
+        }
+
+        let len = apply(&self.hooks.egress, &self.hooks.egress_dropped, &mut buf)?;
+        buf.truncate(len);
+
+        Some(Some(buf))
+    }
+}
+
+impl Device for HookedDevice {
+    // A datagram that is dropped is followed by the next one, until the device would block
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.inner.recv(buf)?;
+
+            let hooks = &self.hooks;
+            if let Some(n) = apply(&hooks.ingress, &hooks.ingress_dropped, &mut buf[..n]) {
+                return Ok(n);
+            }
+        }
+    }
+
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.egress(&[IoSlice::new(buf)]) {
+            Some(Some(buf)) => self.inner.send(&buf),
+            Some(None) => self.inner.send(buf),
+            None => Ok(buf.len()),
+        }
+    }
+
+    fn send_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        let len = bufs.iter().map(|b| b.len()).sum();
+
+        match self.egress(bufs) {
+            Some(Some(buf)) => self.inner.send(&buf),
+            Some(None) => self.inner.send_vectored(bufs),
+            None => Ok(len),
+        }
+    }
+
+    fn mtu(&self) -> io::Result<usize> {
+        self.inner.mtu()
+    }
+
+    fn gso_max_size(&self) -> Option<usize> {
+        self.inner.gso_max_size()
+    }
+
+    fn send_gso(&mut self, bufs: &[IoSlice<'_>], mss: u16) -> io::Result<usize> {
+        let len = bufs.iter().map(|b| b.len()).sum();
+
+        match self.egress(bufs) {
+            Some(Some(buf)) => self.inner.send_gso(&[IoSlice::new(&buf)], mss),
+            Some(None) => self.inner.send_gso(bufs, mss),
+            None => Ok(len),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn raw_fd(&self) -> RawFd {
+        self.inner.raw_fd()
+    }
+}
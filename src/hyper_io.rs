@@ -0,0 +1,183 @@
+use std::future::Future;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+use futures_io::AsyncWrite;
+use http::Uri;
+use hyper::rt::{Read, ReadBufCursor, Write};
+use hyper_util::client::legacy::connect::{Connected, Connection};
+
+use crate::{start_connect, AsyncTcpListener, AsyncTcpStream, Error, Manager, TcpOptions};
+use crate::{ToIpv4Addrs, UNSPECIFIED};
+
+/*
+Lets hyper run over the stack: clients connect through a Connector, and
+servers hand the connections they accept to hyper wrapped in a HyperStream.
+*/
+#[derive(Debug)]
+pub struct HyperStream {
+    stream: AsyncTcpStream,
+}
+
+impl From<AsyncTcpStream> for HyperStream {
+    fn from(stream: AsyncTcpStream) -> Self {
+        HyperStream { stream }
+    }
+}
+
+impl HyperStream {
+    pub async fn accept(listener: &AsyncTcpListener) -> Result<Self, Error> {
+        Ok(listener.accept().await?.into())
+    }
+}
+
+impl Read for HyperStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        mut buf: ReadBufCursor<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.stream.poll_read_uninit(cx, unsafe { buf.as_mut() }) {
+            Poll::Ready(Ok(n)) => {
+                unsafe { buf.advance(n) };
+
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl Write for HyperStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_close(cx)
+    }
+}
+
+// Lets the pooling client of hyper-util take the connections of a Connector
+impl Connection for HyperStream {
+    fn connected(&self) -> Connected {
+        Connected::new()
+    }
+}
+
+/*
+A tower Service that connects to the host and port of a URI. Host names are
+looked up with the resolver of the system on a thread of their own, as it
+blocks, and the connection is opened without blocking once the address is
+known.
+*/
+#[derive(Debug, Clone)]
+pub struct Connector {
+    pub(crate) manager: Arc<Mutex<Manager>>,
+}
+
+impl tower_service::Service<Uri> for Connector {
+    type Response = HyperStream;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<HyperStream, Error>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let manager = self.manager.clone();
+
+        Box::pin(async move {
+            let (host, port) = host_port(&uri)?;
+
+            // Addresses are taken as they are, without a thread for nothing to look up
+            let addr = match host.parse() {
+                Ok(addr) => addr,
+                Err(_) => Lookup::spawn(host).await?[0],
+            };
+
+            let remote = SocketAddrV4::new(addr, port);
+            let connecting = start_connect(&manager, UNSPECIFIED, remote, TcpOptions::default())?;
+            let stream = connecting.await?;
+
+            Ok(HyperStream::from(AsyncTcpStream::from(stream)))
+        })
+    }
+}
+
+fn host_port(uri: &Uri) -> Result<(String, u16), Error> {
+    let host = uri
+        .host()
+        .ok_or_else(|| Error::UnresolvedHost(uri.to_string()))?;
+    let port = uri
+        .port_u16()
+        .unwrap_or(if uri.scheme_str() == Some("https") {
+            443
+        } else {
+            80
+        });
+
+    Ok((host.to_owned(), port))
+}
+
+// A host name being looked up, which wakes the task waiting on it once it has been
+#[derive(Debug)]
+struct Lookup {
+    shared: Arc<Mutex<Looked>>,
+}
+
+#[derive(Debug, Default)]
+struct Looked {
+    addrs: Option<Result<Vec<Ipv4Addr>, Error>>,
+    waker: Option<Waker>,
+}
+
+impl Lookup {
+    fn spawn(host: String) -> Self {
+        let shared = Arc::new(Mutex::new(Looked::default()));
+
+        let looked = shared.clone();
+        thread::spawn(move || {
+            let addrs = host.to_ipv4_addrs();
+
+            let mut looked = looked.lock().unwrap();
+            looked.addrs = Some(addrs);
+            if let Some(waker) = looked.waker.take() {
+                waker.wake();
+            }
+        });
+
+        Lookup { shared }
+    }
+}
+
+impl Future for Lookup {
+    type Output = Result<Vec<Ipv4Addr>, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut looked = self.shared.lock().unwrap();
+
+        match looked.addrs.take() {
+            Some(addrs) => Poll::Ready(addrs),
+            None => {
+                looked.waker = Some(cx.waker().clone());
+
+                Poll::Pending
+            }
+        }
+    }
+}
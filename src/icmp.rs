@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+use std::io::{self, Cursor, Write};
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+use etherparse::icmpv4::DestUnreachableHeader;
+use etherparse::{ip_number, Icmpv4Header, Icmpv4Slice, Icmpv4Type, Ipv4Header, Ipv4HeaderSlice};
+
+use crate::tcp::{AckThrottle, Dual, IpOpts, Quad, TokenBucket};
+use crate::Device;
+
+// ICMP errors sent to a single source per second, and how many may go out at once
+const ERRORS_PER_SOURCE: u64 = 10;
+const ERROR_BURST: u64 = 10;
+
+// ICMP errors sent per second to every source together
+const ERRORS_PER_SECOND: u32 = 100;
+
+// Sources whose errors are paced at a time
+const MAX_SOURCES: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Unreachable {
+    pub quad: Quad,
+    pub sqno: u32,
+    pub code: DestUnreachableHeader,
+}
+
+/*
+        RFC 792 - Destination Unreachable Message
+
+    0                   1                   2                   3
+    0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+   |     Type      |     Code      |          Checksum             |
+   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+   |                             unused                            |
+   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+   |      Internet Header + 64 bits of Original Data Datagram      |
+   +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+
+The internet header plus the first 64 bits of the original datagram's
+data. This data is used by the host to match the message to the
+appropriate process.
+
+The first 64 bits of a TCP header carry the ports and the sequence number,
+which is enough to find the connection that caused the error. Since the
+original datagram was sent by us, its source is our side of the quad.
+*/
+pub fn parse_unreachable(payload: &[u8]) -> Option<Unreachable> {
+    let icmp = Icmpv4Slice::from_slice(payload).ok()?;
+
+    let Icmpv4Type::DestinationUnreachable(code) = icmp.icmp_type() else {
+        return None;
+    };
+
+    let orig = Ipv4HeaderSlice::from_slice(icmp.payload()).ok()?;
+    if orig.protocol() != ip_number::TCP {
+        return None;
+    }
+
+    let hlen = orig.slice().len();
+    let tcp = icmp.payload().get(hlen..hlen + 8)?;
+
+    let quad = Quad {
+        src: Dual {
+            ipv4: orig.source_addr(),
+            port: u16::from_be_bytes([tcp[0], tcp[1]]),
+        },
+        dst: Dual {
+            ipv4: orig.destination_addr(),
+            port: u16::from_be_bytes([tcp[2], tcp[3]]),
+        },
+    };
+
+    Some(Unreachable {
+        quad,
+        sqno: u32::from_be_bytes([tcp[4], tcp[5], tcp[6], tcp[7]]),
+        code,
+    })
+}
+
+/*
+        RFC 1122 - S3.2.2. Internet Control Message Protocol -- ICMP
+
+An ICMP error message MUST NOT be sent as the result of receiving:
+
+*   an ICMP error message, or
+
+*   a datagram destined to an IP broadcast or IP multicast address, or
+
+*   a datagram sent as a link-layer broadcast, or
+
+*   a non-initial fragment, or
+
+*   a datagram whose source address does not define a single host --
+    e.g., a zero address, a loopback address, a broadcast address, a
+    multicast address, or a Class E address.
+*/
+fn may_send_error(ip4h: &Ipv4HeaderSlice) -> bool {
+    let src = ip4h.source_addr();
+    let dst = ip4h.destination_addr();
+
+    ip4h.protocol() != ip_number::ICMP
+        && ip4h.fragments_offset() == 0
+        && !dst.is_broadcast()
+        && !dst.is_multicast()
+        && !src.is_unspecified()
+        && !src.is_loopback()
+        && !src.is_broadcast()
+        && !src.is_multicast()
+        && src.octets()[0] < 240
+}
+
+/*
+Paces the ICMP errors the stack sends, so that a flood of datagrams nobody
+listens for does not turn the stack into a reflector of the same rate. Each
+source has a token bucket of its own, and all of them together are bounded
+as well, since sources are easily forged. Once MAX_SOURCES are paced, those
+whose bucket has filled up again are forgotten, and while none has, new
+sources are sent nothing.
+*/
+#[derive(Debug)]
+pub struct ErrorLimiter {
+    sources: HashMap<Ipv4Addr, TokenBucket>,
+    total: AckThrottle,
+}
+
+impl Default for ErrorLimiter {
+    fn default() -> Self {
+        ErrorLimiter {
+            sources: HashMap::new(),
+            total: AckThrottle::new(ERRORS_PER_SECOND, Duration::from_secs(1)),
+        }
+    }
+}
+
+impl ErrorLimiter {
+    pub fn allow(&mut self, src: Ipv4Addr, now: Instant) -> bool {
+        if !self.sources.contains_key(&src) && self.sources.len() >= MAX_SOURCES {
+            self.sources
+                .retain(|_, bucket| bucket.available(now) < ERROR_BURST as usize);
+
+            if self.sources.len() >= MAX_SOURCES {
+                return false;
+            }
+        }
+
+        let bucket = self
+            .sources
+            .entry(src)
+            .or_insert_with(|| TokenBucket::new(ERRORS_PER_SOURCE, ERROR_BURST, now));
+
+        if bucket.available(now) < 1 || !self.total.allow() {
+            return false;
+        }
+
+        bucket.consume(1, now);
+
+        true
+    }
+}
+
+/*
+The data of the error message carries the internet header plus the first
+64 bits of the original datagram's data.
+*/
+pub fn write_unreachable(
+    ip4h: &Ipv4HeaderSlice,
+    data: &[u8],
+    code: DestUnreachableHeader,
+    opts: IpOpts,
+    tun: &mut dyn Device,
+) -> io::Result<()> {
+    if !may_send_error(ip4h) {
+        return Ok(());
+    }
+
+    let mut orig = ip4h.slice().to_vec();
+    orig.extend_from_slice(&data[..data.len().min(8)]);
+
+    let icmph = Icmpv4Header::with_checksum(Icmpv4Type::DestinationUnreachable(code), &orig);
+
+    let mut ip4h = Ipv4Header::new(
+        (icmph.header_len() + orig.len()) as u16,
+        opts.ttl,
+        ip_number::ICMP,
+        ip4h.destination(),
+        ip4h.source(),
+    );
+    ip4h.differentiated_services_code_point = opts.tos >> 2;
+    ip4h.explicit_congestion_notification = opts.tos & 0b11;
+
+    // Fails only on headers too large for the buffer, which an error is not sent for
+    let invalid = |err| io::Error::new(io::ErrorKind::InvalidInput, err);
+
+    let mut cursor = Cursor::new([0u8; 1500]);
+    ip4h.write(&mut cursor).map_err(invalid)?;
+    icmph.write(&mut cursor).map_err(invalid)?;
+    cursor.write_all(&orig)?;
+
+    let buf = cursor.get_ref();
+    let pos = cursor.position() as usize;
+
+    tun.send(&buf[..pos]).map(drop)
+}
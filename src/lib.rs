@@ -1,26 +1,144 @@
+use std::cmp::Reverse;
 use std::collections::hash_map::Entry;
-use std::collections::{HashMap, HashSet};
-use std::io::Read;
-use std::net::Ipv4Addr;
-use std::os::fd::AsRawFd;
-use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{self, IoSlice};
+use std::mem;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::os::fd::{AsRawFd, OwnedFd, RawFd};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver};
 use std::sync::{Arc, Condvar, Mutex};
+use std::task::Waker;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use etherparse::{Ipv4HeaderSlice, TcpHeaderSlice};
+use etherparse::icmpv4::DestUnreachableHeader;
+use etherparse::{ip_number, Ipv4HeaderSlice, TcpHeaderSlice};
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
 use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::time::TimeSpec;
+use nix::sys::timerfd::{ClockId, Expiration, TimerFd, TimerFlags, TimerSetTimeFlags};
 use tidy_tuntap::Tun;
 
+mod control;
+pub use control::{ControlClient, DEFAULT_CONTROL_PATH};
+
+mod device;
+pub use device::*;
+
+mod dhcp;
+pub use dhcp::Lease;
+
+#[cfg(feature = "af_xdp")]
+mod xdp;
+#[cfg(feature = "af_xdp")]
+pub use xdp::*;
+
+mod doorbell;
+use doorbell::Doorbell;
+
 mod err;
 pub use err::*;
 
+#[cfg(feature = "bench")]
+pub mod bench;
+
+mod bufpool;
+use bufpool::BufPool;
+
+mod firewall;
+use firewall::Firewall;
+pub use firewall::{Direction, Rule, RuleAction, RuleId, RuleStats};
+
+#[cfg(feature = "fuzzing")]
+pub mod fuzz;
+
+mod hook;
+use hook::{Hook, HookedDevice, Hooks};
+pub use hook::{HookVerdict, Packet};
+
+#[cfg(feature = "hyper")]
+mod hyper_io;
+#[cfg(feature = "hyper")]
+pub use hyper_io::*;
+
+mod icmp;
+use icmp::ErrorLimiter;
+
+mod netem;
+pub use netem::*;
+
+mod pipe;
+pub use pipe::*;
+
+mod resolve;
+pub use resolve::*;
+
+mod route;
+pub use route::*;
+
+mod shutdown;
+pub use shutdown::*;
+
+mod stats;
+pub use stats::*;
+
 mod tcp;
-use tcp::{write_reset, Action, Dual, Quad, TcpListener, TcpStream, TCB};
+use tcp::{
+    write_reset, AckThrottle, Action, Binding, Connecting, Emitter, Filter, IpOpts, IssGenerator,
+    Kind, MemoryPool, Notifiers, Observer, QuadState, QuadTable, Selector, State, TcpListener,
+    TcpStream, TokenBucket, TCB,
+};
+#[cfg(feature = "async")]
+pub use tcp::{AsyncTcpListener, AsyncTcpStream, Incoming};
+pub use tcp::{
+    Clock, CloseReason, Congestion, ConnContext, ConnEvent, ConnState, ConnStats, Connection, Dual,
+    Event, Events, Interest, Limits, MockClock, Overflow, Priority, Quad, Ready, RetryPolicy,
+    SoftError, SystemClock, TcbSnapshot, TcpInfo, TcpOptions, Token, Verdict, DEFAULT_BACKLOG,
+    DEFAULT_MAX_CONNECTIONS, DEFAULT_MAX_CONNECTIONS_PER_PEER, DEFAULT_MAX_TIME_WAIT,
+    DEFAULT_MEMORY_HARD_LIMIT, DEFAULT_MEMORY_SOFT_LIMIT, DEFAULT_SYN_RECEIVED_LIFETIME,
+};
+
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(feature = "tls")]
+pub use tls::*;
+
+mod trace;
+pub use trace::*;
+
+#[cfg(feature = "io_uring")]
+mod uring;
+#[cfg(feature = "io_uring")]
+pub use uring::*;
+
+mod vnet;
+pub use vnet::*;
+
+mod wheel;
+use wheel::TimerWheel;
+
+mod worker;
+use worker::{Datagram, Job, Outbox, Work, Workers};
+
+const DEFAULT_MTU: usize = 1500;
+
+// The most datagrams read from a device per wakeup before the others are read
+const RX_BATCH: usize = 64;
+
+// The longest a loop waits after its waits on the devices and timers failed in a row
+const MAX_BACKOFF: Duration = Duration::from_secs(1);
+
+// Local ports of connections are picked from here on unless given explicitly
+const EPHEMERAL_PORT_START: u16 = 4001;
+const UNSPECIFIED: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0);
 
 #[derive(Debug)]
 pub struct EstabElement {
     quad: Quad,
+    entry: Arc<Mutex<StreamEntry>>,
     rvar: Arc<Condvar>,
     wvar: Arc<Condvar>,
     svar: Arc<Condvar>,
@@ -29,37 +147,129 @@ pub struct EstabElement {
     write_closed: Arc<AtomicBool>,
     read_closed: Arc<AtomicBool>,
     reset: Arc<AtomicBool>,
+    error: Arc<Mutex<Option<Error>>>,
 }
 
 #[derive(Debug)]
 pub struct EstabEntry {
     cvar: Arc<Condvar>,
-    elts: Vec<EstabElement>,
+    elts: VecDeque<EstabElement>, // Accepted in the order they were established
+    error: Option<Error>,
+    addr: Ipv4Addr, // Connections are only accepted to it, or to any local address if unspecified
+    connect: Option<Quad>, // The active open the port was taken for, if it was
+    backlog: usize,
+    overflow: Overflow,
+    paused: bool,
+    filter: Option<Filter>,
+    opts: TcpOptions,
 }
 
+/*
+Every connection has a lock of its own, which its stream takes without
+going through the manager, so connections are read from and written to in
+parallel. Whoever needs both takes the manager first.
+*/
 #[derive(Debug)]
 pub struct StreamEntry {
     tcb: TCB,
     rvar: Arc<Condvar>,
     wvar: Arc<Condvar>,
     svar: Arc<Condvar>,
+    notifiers: Notifiers,
+    deleted: bool,  // Taken out of the streams of the manager
+    detached: bool, // Dropped while our FIN was pending, so the stack removes it once acked
+}
+
+impl StreamEntry {
+    // Wakes whoever waits for what the connection may have become ready for
+    fn notify(&mut self, ready: Ready) {
+        if ready.read {
+            self.rvar.notify_all();
+        }
+        if ready.write {
+            self.wvar.notify_all();
+        }
+        if ready.close {
+            self.svar.notify_all();
+        }
+
+        self.notifiers.notify(ready);
+    }
+
+    // Called once the entry has been taken out of the streams of the manager
+    fn delete(&mut self, ready: Ready) {
+        self.deleted = true;
+        self.notify(ready);
+    }
 }
 
 #[derive(Debug, Default)]
 pub struct Manager {
-    iss: Arc<AtomicU32>,
+    iss: IssGenerator,
+    routes: RoutingTable,
+    links: Vec<Link>, // What the loops need to know of the device of each interface
+    ack_throttle: Arc<AckThrottle>,
+    memory: Arc<MemoryPool>, // Held by the buffers of every connection
+    clock: Arc<dyn Clock>,   // What every connection and the timer loop take the time from
+    manual_clock: bool,      // The clock only moves when told to, and says when it does
+    ip_opts: IpOpts,
+    verify_checksums: bool,
+    hooks: Arc<Hooks>,       // Shared with every device
+    observer: Arc<Observer>, // Shared with every connection
+    firewall: Arc<Firewall>, // Shared with every device, which screens what is sent
+    stats: Stats,
+    drop_log: Option<AckThrottle>, // Paces the logging of dropped datagrams, if they are logged
+    limits: Limits,
+    syn_bucket: Option<TokenBucket>, // Paces new handshakes if their rate is limited
+    icmp_errors: ErrorLimiter,       // Paces the ICMP errors sent to each source
     bounded: HashSet<u16>,
-    pending: HashMap<Quad, TCB>,
+    next_ephemeral: u16, // Where the search for a free ephemeral port starts
+    pending: QuadTable<TCB>,
     established: HashMap<u16, EstabEntry>,
-    streams: HashMap<Quad, StreamEntry>,
+    streams: QuadTable<Arc<Mutex<StreamEntry>>>,
+    aborted: Vec<TCB>,         // Aborted connections whose reset is yet to be sent
+    time_wait: VecDeque<Quad>, // Connections that have entered TIME-WAIT, oldest first
+    syn_received: VecDeque<(Instant, Quad, u32)>, // Passive opens by arrival, with their ISS
+    rx_bufs: Arc<BufPool>,     // What the segment loop receives into and the workers process
+    tx_bufs: Arc<BufPool>,     // What the workers write into and the timer loop sends
+    readiness: Arc<Condvar>,   // Wakes selectors whenever a connection may have become ready
+    wakers: Vec<Waker>,        // Tasks of the async API waiting on any connection
+    closing: bool,             // A shutdown is under way, no ports are handed out anymore
+    shut_down: Arc<AtomicBool>, // Once set, the loops of the stack stop
+    timers: TimerWheel<Quad, QuadState>, // When the timer loop next ticks each connection
+    doorbell: Arc<Doorbell>,   // Wakes the timer loop to send what has been queued
+    interrupt: Arc<Doorbell>,  // Wakes the segment loop when devices are added or on shutdown
+}
+
+// What the stack needs to know of a device without taking the devices
+#[derive(Debug, Clone, Copy)]
+struct Link {
+    mtu: usize,
+    gso: Option<usize>, // What the device takes in one go, if it cuts up super-segments
+}
+
+impl Link {
+    fn of(device: &dyn Device) -> Self {
+        Link {
+            mtu: device.mtu().unwrap_or(DEFAULT_MTU),
+            gso: device.gso_max_size(),
+        }
+    }
 }
 
+/*
+Shared by the segment loop, which receives from the devices, and the timer
+loop, which sends to them. Whoever needs both takes the manager first.
+*/
+type Devices = Arc<Mutex<Vec<Box<dyn Device>>>>;
+
 #[derive(Debug)]
 pub struct NetStack {
-    addr: Ipv4Addr,
     manager: Arc<Mutex<Manager>>,
+    devices: Devices,
+    lease: Option<Lease>,
     jh: thread::JoinHandle<()>,
-    ih: thread::JoinHandle<()>,
+    th: thread::JoinHandle<()>,
 }
 
 impl NetStack {
@@ -69,43 +279,380 @@ impl NetStack {
         tun.set_netmask(mask)?;
         tun.bring_up()?;
 
-        let iss = Arc::new(AtomicU32::new(0));
+        Self::start(Box::new(tun), name, addr, mask)
+    }
+
+    /*
+    Same as new, except that the device exchanges virtio-net headers with
+    the kernel, which lets large transfers move in super-segments of up to
+    64KiB instead of MTU sized datagrams.
+    */
+    pub fn with_offload(name: &str, addr: Ipv4Addr, mask: Ipv4Addr) -> Result<Self, Error> {
+        let tun = VnetTun::new(name)?;
+        tun.set_addr(addr)?;
+        tun.set_netmask(mask)?;
+        tun.bring_up()?;
+
+        Self::start(Box::new(tun), name, addr, mask)
+    }
+
+    /*
+    Same as new, except that the address, mask and default gateway of the
+    device are configured by a DHCP server reachable through it.
+    */
+    pub fn with_dhcp(name: &str) -> Result<Self, Error> {
+        let mut tun = Tun::new(name, false)?;
+        tun.bring_up()?;
+
+        let lease = dhcp::acquire(&mut tun)?;
 
-        let ih = {
-            let iss = iss.clone();
+        tun.set_addr(lease.addr)?;
+        tun.set_netmask(lease.mask)?;
 
-            thread::spawn(move || loop {
-                thread::sleep(Duration::from_millis(4));
+        let mut stack = Self::start(Box::new(tun), name, lease.addr, lease.mask)?;
+        stack.lease = Some(lease);
 
-                iss.fetch_add(1, Ordering::Release);
-            })
-        };
+        if let Some(router) = lease.router {
+            stack.set_default_gateway(router)?;
+        }
+
+        Ok(stack)
+    }
+
+    /*
+    Takes over a TUN device that has already been created and configured,
+    so that no privileges are needed. addr and mask must match what was
+    assigned to the device.
+    */
+    pub fn from_fd(fd: OwnedFd, addr: Ipv4Addr, mask: Ipv4Addr) -> Result<Self, Error> {
+        let device = FdDevice::new(fd)?;
+        let name = device.name();
+
+        Self::with_device(&name, device, addr, mask)
+    }
+
+    /*
+    A stack that only talks to itself, which needs neither root nor a TUN
+    device. Connecting to addr reaches the listeners of this very stack.
+    */
+    pub fn loopback(addr: Ipv4Addr, mask: Ipv4Addr) -> Result<Self, Error> {
+        Self::with_device("lo", LoopbackDevice::new()?, addr, mask)
+    }
+
+    /*
+    Two stacks joined by an in-memory link, e.g. to run a client and a
+    server of this stack against each other in tests. Both addresses must
+    be in the same subnet.
+    */
+    pub fn pair(
+        a: Ipv4Addr,
+        b: Ipv4Addr,
+        mask: Ipv4Addr,
+        link: LinkConfig,
+    ) -> Result<(Self, Self), Error> {
+        let (dev_a, dev_b) = PipeDevice::pair(link)?;
+
+        Ok((
+            Self::with_device("pipe0", dev_a, a, mask)?,
+            Self::with_device("pipe1", dev_b, b, mask)?,
+        ))
+    }
+
+    /*
+    Runs the stack on top of any device. The device is expected to be
+    configured already; addr and mask only describe it to the stack.
+    */
+    pub fn with_device<D: Device + 'static>(
+        name: &str,
+        device: D,
+        addr: Ipv4Addr,
+        mask: Ipv4Addr,
+    ) -> Result<Self, Error> {
+        Self::start(Box::new(device), name, addr, mask)
+    }
+
+    fn start(
+        tun: Box<dyn Device>,
+        name: &str,
+        addr: Ipv4Addr,
+        mask: Ipv4Addr,
+    ) -> Result<Self, Error> {
+        // Everything the loops wait on is set up before any of them starts
+        let flags = TimerFlags::TFD_NONBLOCK | TimerFlags::TFD_CLOEXEC;
+        let timer = TimerFd::new(ClockId::CLOCK_MONOTONIC, flags).map_err(io::Error::from)?;
+        let doorbell = Arc::new(Doorbell::new()?);
+        let interrupt = Arc::new(Doorbell::new()?);
+
+        let shut_down = Arc::new(AtomicBool::new(false));
+        let hooks = Arc::new(Hooks::default());
+        let firewall = Arc::new(Firewall::default());
+
+        // Shared by the tables of connections, so a quad is hashed once to look it up in both
+        let quads = QuadState::default();
+
+        let mut routes = RoutingTable::default();
+        routes.add_interface(Interface {
+            name: name.to_string(),
+            addr,
+            mask,
+        });
+        let links = vec![Link::of(tun.as_ref())];
 
         let manager = Arc::new(Mutex::new(Manager {
-            iss,
+            iss: IssGenerator::default(),
+            routes,
+            links,
+            ack_throttle: Arc::new(AckThrottle::default()),
+            memory: Arc::new(MemoryPool::default()),
+            clock: Arc::new(SystemClock),
+            manual_clock: false,
+            ip_opts: IpOpts::default(),
+            verify_checksums: true,
+            hooks: hooks.clone(),
+            observer: Arc::new(Observer::default()),
+            firewall: firewall.clone(),
+            stats: Stats::default(),
+            drop_log: None,
+            limits: Limits::default(),
+            syn_bucket: None,
+            icmp_errors: ErrorLimiter::default(),
             bounded: HashSet::new(),
-            pending: HashMap::new(),
+            next_ephemeral: EPHEMERAL_PORT_START,
+            pending: QuadTable::with_hasher(quads.clone()),
             established: HashMap::new(),
-            streams: HashMap::new(),
+            streams: QuadTable::with_hasher(quads),
+            aborted: Vec::new(),
+            time_wait: VecDeque::new(),
+            syn_received: VecDeque::new(),
+            rx_bufs: Arc::default(),
+            tx_bufs: Arc::default(),
+            readiness: Arc::new(Condvar::new()),
+            wakers: Vec::new(),
+            closing: false,
+            shut_down,
+            timers: TimerWheel::default(),
+            doorbell,
+            interrupt,
         }));
 
+        let tun = HookedDevice::new(tun, hooks, firewall);
+        let devices: Devices = Arc::new(Mutex::new(vec![Box::new(tun)]));
+
+        // Established connections are processed on the workers, the rest by the loops
+        let (outbox, datagrams) = mpsc::channel();
+        let workers = Workers::spawn(
+            thread::available_parallelism().map_or(1, |n| n.get()),
+            &manager,
+            outbox,
+        );
+
         let jh = {
+            let devices = devices.clone();
+            let manager = manager.clone();
+            let workers = workers.clone();
+
+            thread::spawn(move || segment_loop(devices, manager, workers))
+        };
+
+        let th = {
+            let devices = devices.clone();
             let manager = manager.clone();
 
-            thread::spawn(move || segment_loop(tun, manager.clone()))
+            thread::spawn(move || timer_loop(devices, manager, workers, timer, datagrams))
         };
 
         Ok(NetStack {
-            addr,
             manager,
+            devices,
+            lease: None,
             jh,
-            ih,
+            th,
         })
     }
 
+    pub fn lease(&self) -> Option<Lease> {
+        self.lease
+    }
+
+    /*
+    Every interface gets its own TUN device and a connected route for its
+    subnet. The returned index identifies the interface in the routing table.
+    */
+    pub fn add_interface(
+        &mut self,
+        name: &str,
+        addr: Ipv4Addr,
+        mask: Ipv4Addr,
+    ) -> Result<usize, Error> {
+        let tun = Tun::new(name, false)?;
+        tun.set_addr(addr)?;
+        tun.set_netmask(mask)?;
+        tun.bring_up()?;
+
+        // Holding the lock keeps the indices in line with the devices of the loops
+        let mut manager = self.manager.lock().unwrap();
+
+        // The devices have been released already
+        if manager.closing {
+            return Err(Error::ShutDown);
+        }
+
+        let idx = manager.routes.add_interface(Interface {
+            name: name.to_string(),
+            addr,
+            mask,
+        });
+        manager.links.push(Link::of(&tun));
+
+        let tun = HookedDevice::new(
+            Box::new(tun),
+            manager.hooks.clone(),
+            manager.firewall.clone(),
+        );
+        self.devices.lock().unwrap().push(Box::new(tun));
+        manager.interrupt.ring();
+
+        Ok(idx)
+    }
+
+    /*
+    The gateway must be reachable through one of the connected routes.
+    */
+    pub fn add_route(
+        &mut self,
+        dst: Ipv4Addr,
+        mask: Ipv4Addr,
+        gateway: Ipv4Addr,
+    ) -> Result<(), Error> {
+        let mut manager = self.manager.lock().unwrap();
+
+        let iface = manager
+            .routes
+            .lookup(gateway)
+            .filter(|route| route.gateway.is_none())
+            .ok_or(Error::NoRoute(gateway))?
+            .iface;
+
+        manager.routes.add_route(Route {
+            dst,
+            mask,
+            gateway: Some(gateway),
+            iface,
+        });
+
+        Ok(())
+    }
+
+    pub fn set_default_gateway(&mut self, gateway: Ipv4Addr) -> Result<(), Error> {
+        self.add_route(Ipv4Addr::UNSPECIFIED, Ipv4Addr::UNSPECIFIED, gateway)
+    }
+
+    pub fn route(&self, addr: Ipv4Addr) -> Option<Route> {
+        self.manager.lock().unwrap().routes.lookup(addr).copied()
+    }
+
+    pub fn interfaces(&self) -> Vec<Interface> {
+        self.manager.lock().unwrap().routes.interfaces().to_vec()
+    }
+
+    // Listens on the port at every local address, see bind_to
     pub fn bind(&mut self, port: u16) -> Result<TcpListener, Error> {
+        self.bind_with_backlog(port, DEFAULT_BACKLOG, Overflow::Drop)
+    }
+
+    /*
+    Listens on the port of `addr` for connections to its address only,
+    which must be that of an interface. If the address is 0.0.0.0, any
+    local address will do, including those of interfaces added later, and
+    each accepted stream tells which one it was reached at. A port is bound
+    at a single address, or at all of them, at a time.
+    */
+    pub fn bind_to(&mut self, addr: SocketAddrV4) -> Result<TcpListener, Error> {
+        self.bind_inner(
+            addr,
+            DEFAULT_BACKLOG,
+            Overflow::Drop,
+            None,
+            TcpOptions::default(),
+        )
+    }
+
+    /*
+    Binds the port and serves it with a worker per available CPU for as
+    long as the stack runs. TcpListener::serve is the one to use for a port
+    that is to be closed again.
+    */
+    pub fn serve<F>(&mut self, port: u16, handler: F) -> Result<(), Error>
+    where
+        F: Fn(TcpStream) + Send + Sync + 'static,
+    {
+        let workers = thread::available_parallelism().map_or(1, |n| n.get());
+
+        self.bind(port)?.serve(workers, handler);
+
+        Ok(())
+    }
+
+    /*
+    At most `backlog` connections of the port may be half-open, and at most
+    `backlog` may be established but not yet accepted. A SYN that arrives
+    while either queue is full is handled according to `overflow`.
+    */
+    pub fn bind_with_backlog(
+        &mut self,
+        port: u16,
+        backlog: usize,
+        overflow: Overflow,
+    ) -> Result<TcpListener, Error> {
+        self.bind_inner(any(port), backlog, overflow, None, TcpOptions::default())
+    }
+
+    // Every connection accepted on the port starts out with `opts`
+    pub fn bind_with_options(&mut self, port: u16, opts: TcpOptions) -> Result<TcpListener, Error> {
+        opts.validate()?;
+
+        self.bind_inner(any(port), DEFAULT_BACKLOG, Overflow::Drop, None, opts)
+    }
+
+    /*
+    Every SYN arriving at the port is handed to `filter` together with the
+    address of its sender before any state is kept for the connection. It
+    runs on the segment loop with the stack locked, so it must be quick and
+    must not call back into the stack.
+    */
+    pub fn bind_with_filter(
+        &mut self,
+        port: u16,
+        filter: impl Fn(&SocketAddrV4) -> Verdict + Send + 'static,
+    ) -> Result<TcpListener, Error> {
+        self.bind_inner(
+            any(port),
+            DEFAULT_BACKLOG,
+            Overflow::Drop,
+            Some(Filter(Box::new(filter))),
+            TcpOptions::default(),
+        )
+    }
+
+    fn bind_inner(
+        &mut self,
+        addr: SocketAddrV4,
+        backlog: usize,
+        overflow: Overflow,
+        filter: Option<Filter>,
+        opts: TcpOptions,
+    ) -> Result<TcpListener, Error> {
         let mut manager = self.manager.lock().unwrap();
 
+        if manager.closing {
+            return Err(Error::ShutDown);
+        }
+
+        let port = addr.port();
+        if !addr.ip().is_unspecified() && manager.routes.iface_of(*addr.ip()).is_none() {
+            return Err(Error::AddrNotAvailable(*addr.ip()));
+        }
+
         match manager.established.entry(port) {
             Entry::Occupied(_) => {
                 return Err(Error::PortInUse(port));
@@ -115,262 +662,1857 @@ impl NetStack {
 
                 v.insert(EstabEntry {
                     cvar: cvar.clone(),
-                    elts: Vec::new(),
+                    elts: VecDeque::new(),
+                    error: None,
+                    addr: *addr.ip(),
+                    connect: None,
+                    backlog,
+                    overflow,
+                    paused: false,
+                    filter,
+                    opts,
                 });
 
-                assert!(manager.bounded.insert(port));
+                manager.bounded.insert(port);
 
                 return Ok(TcpListener {
                     port,
+                    addr: *addr.ip(),
                     manager: self.manager.clone(),
-                    cvar,
+                    cvar: cvar.clone(),
+                    binding: Arc::new(Binding {
+                        port,
+                        manager: self.manager.clone(),
+                        cvar,
+                    }),
                 });
             }
         }
     }
 
     pub fn connect(&mut self, addr: Ipv4Addr, port: u16) -> Result<TcpStream, Error> {
-        let mut manager = self.manager.lock().unwrap();
+        self.connect_inner(
+            UNSPECIFIED,
+            SocketAddrV4::new(addr, port),
+            None,
+            TcpOptions::default(),
+        )
+    }
 
-        let local_port = manager.bounded.iter().max().copied().unwrap_or(4000) + 1;
+    pub fn connect_with_options(
+        &mut self,
+        addr: Ipv4Addr,
+        port: u16,
+        opts: TcpOptions,
+    ) -> Result<TcpStream, Error> {
+        opts.validate()?;
 
-        assert!(manager.bounded.insert(local_port));
+        self.connect_inner(UNSPECIFIED, SocketAddrV4::new(addr, port), None, opts)
+    }
 
-        let quad = Quad {
-            src: Dual {
-                ipv4: self.addr,
-                port: local_port,
-            },
-            dst: Dual { ipv4: addr, port },
-        };
+    /*
+    Gives up on the connection if it is not established within `timeout`.
+    */
+    pub fn connect_timeout(
+        &mut self,
+        addr: Ipv4Addr,
+        port: u16,
+        timeout: Duration,
+    ) -> Result<TcpStream, Error> {
+        self.connect_inner(
+            UNSPECIFIED,
+            SocketAddrV4::new(addr, port),
+            Some(timeout),
+            TcpOptions::default(),
+        )
+    }
 
-        let tcb = TCB::syn_sent(quad, manager.iss.load(Ordering::Acquire));
+    /*
+    Connects from the given local address and port instead of letting the
+    stack pick them. An unspecified address or a port of zero is filled in
+    as for connect.
+    */
+    pub fn connect_from(
+        &mut self,
+        local: SocketAddrV4,
+        remote: SocketAddrV4,
+    ) -> Result<TcpStream, Error> {
+        self.connect_inner(local, remote, None, TcpOptions::default())
+    }
 
-        manager.pending.insert(quad, tcb);
+    /*
+    Tries each address the host resolves to in turn, allowing every attempt
+    at most CONNECT_ATTEMPT_TIMEOUT. The error of the last attempt is
+    returned if none of them succeeds.
+    */
+    pub fn connect_host<A: ToIpv4Addrs + ?Sized>(
+        &mut self,
+        host: &A,
+        port: u16,
+    ) -> Result<TcpStream, Error> {
+        let mut last_err = None;
 
-        let cvar = Arc::new(Condvar::new());
+        for addr in host.to_ipv4_addrs()? {
+            match self.connect_timeout(addr, port, CONNECT_ATTEMPT_TIMEOUT) {
+                Ok(stream) => return Ok(stream),
+                Err(err) => {
+                    println!("Connecting to {}:{} failed: {}", addr, port, err);
+                    last_err = Some(err);
+                }
+            }
+        }
 
-        manager.established.insert(
-            local_port,
-            EstabEntry {
-                cvar: cvar.clone(),
-                elts: Vec::new(),
-            },
-        );
+        Err(last_err.unwrap_or(Error::UnresolvedHost(host.to_string())))
+    }
 
-        // Wait for it to reach established state
-        if manager.established[&local_port].elts.is_empty() {
-            manager = cvar
-                .wait_while(manager, |manager| {
-                    manager.established[&local_port].elts.is_empty()
-                })
-                .unwrap();
+    /*
+    Makes up to policy.attempts connection attempts, backing off between
+    them. Errors that another attempt would run into again, like an
+    invalid address, are returned right away. Otherwise the errors of all
+    attempts are returned, in order, if none of them succeeds.
+    */
+    pub fn connect_with_retry(
+        &mut self,
+        addr: Ipv4Addr,
+        port: u16,
+        policy: RetryPolicy,
+    ) -> Result<TcpStream, Error> {
+        let mut errs = Vec::new();
+
+        for attempt in 0..policy.attempts {
+            if attempt > 0 {
+                thread::sleep(policy.backoff(attempt - 1));
+            }
+
+            let res = self.connect_inner(
+                UNSPECIFIED,
+                SocketAddrV4::new(addr, port),
+                policy.attempt_timeout,
+                TcpOptions::default(),
+            );
+
+            match res {
+                Ok(stream) => return Ok(stream),
+                Err(
+                    err @ (Error::ConnectionRefused(_)
+                    | Error::ConnectTimeout(_)
+                    | Error::RetransmitTimeout(_)
+                    | Error::ConnectionReset(_)
+                    | Error::Unreachable(..)),
+                ) => {
+                    println!("Connection attempt {} failed: {}", attempt + 1, err);
+                    errs.push(err);
+                }
+                Err(err) => return Err(err),
+            }
         }
 
-        let establisheds = manager
-            .established
-            .get_mut(&local_port)
-            .ok_or(Error::PortClosed(local_port))?;
+        Err(Error::AttemptsExhausted(errs))
+    }
 
-        let EstabElement {
-            quad,
-            rvar,
-            wvar,
-            svar,
-            r2,
-            r2_syn,
-            write_closed,
-            read_closed,
-            reset,
-        } = establisheds.elts.pop().unwrap();
+    /*
+    Sends the SYN and returns right away, so that many connections can be
+    set up in parallel from a single thread.
+    */
+    pub fn connect_start(&mut self, addr: Ipv4Addr, port: u16) -> Result<Connecting, Error> {
+        self.start_connect(
+            UNSPECIFIED,
+            SocketAddrV4::new(addr, port),
+            TcpOptions::default(),
+        )
+    }
 
-        Ok(TcpStream {
-            manager: self.manager.clone(),
-            quad,
-            rvar,
-            wvar,
-            svar,
-            r2,
-            r2_syn,
-            write_closed,
-            read_closed,
-            reset,
-        })
+    fn connect_inner(
+        &mut self,
+        local: SocketAddrV4,
+        remote: SocketAddrV4,
+        timeout: Option<Duration>,
+        opts: TcpOptions,
+    ) -> Result<TcpStream, Error> {
+        let mut connecting = self.start_connect(local, remote, opts)?;
+
+        // Dropping the handle gives up on a connection that timed out
+        connecting
+            .complete(timeout)?
+            .ok_or(Error::ConnectTimeout(ConnContext::new(
+                &connecting.quad,
+                ConnState::SynSent,
+            )))
     }
 
-    pub fn join(self) {
-        self.jh.join().unwrap();
-        self.ih.join().unwrap();
+    fn start_connect(
+        &mut self,
+        local: SocketAddrV4,
+        remote: SocketAddrV4,
+        opts: TcpOptions,
+    ) -> Result<Connecting, Error> {
+        start_connect(&self.manager, local, remote, opts)
     }
-}
 
-fn segment_loop(mut tun: Tun, manager: Arc<Mutex<Manager>>) -> ! {
-    loop {
-        let mut buf = [0u8; 1500];
+    pub fn set_ttl(&self, ttl: u32) -> Result<(), Error> {
+        let ttl = u8::try_from(ttl).map_err(|_| Error::InvalidTtl(ttl))?;
 
-        let mut manager = manager.lock().unwrap();
+        self.manager.lock().unwrap().ip_opts.ttl = ttl;
 
-        let mut to_be_deleted = vec![];
-        for (quad, entry) in manager.streams.iter_mut() {
-            if entry.tcb.on_tick(&mut tun) {
-                to_be_deleted.push(*quad);
-            }
-        }
-        for quad in to_be_deleted {
-            manager.streams.remove(&quad).unwrap();
-        }
+        Ok(())
+    }
 
-        let mut to_be_deleted = vec![];
-        for (quad, tcb) in manager.pending.iter_mut() {
-            if tcb.on_tick(&mut tun) {
-                to_be_deleted.push(*quad);
-            }
-        }
-        for quad in to_be_deleted {
-            manager.streams.remove(&quad).unwrap();
+    pub fn ttl(&self) -> u32 {
+        self.manager.lock().unwrap().ip_opts.ttl as u32
+    }
+
+    /*
+    Checksum verification can be turned off when the device hands over
+    segments whose checksums are left to be computed by an offload engine.
+    */
+    pub fn set_verify_checksums(&self, verify: bool) {
+        self.manager.lock().unwrap().verify_checksums = verify;
+    }
+
+    /*
+    Logs datagrams that are dropped for making no sense to the stack, with
+    the reason they were, up to per_second of them a second. They are only
+    counted in stats otherwise, which they always are.
+    */
+    pub fn set_drop_log(&self, per_second: Option<u32>) {
+        self.manager.lock().unwrap().drop_log =
+            per_second.map(|limit| AckThrottle::new(limit, Duration::from_secs(1)));
+    }
+
+    pub fn stats(&self) -> Stats {
+        let manager = self.manager.lock().unwrap();
+
+        Stats {
+            ingress_hook_dropped: manager.hooks.ingress_dropped.load(Ordering::Relaxed),
+            egress_hook_dropped: manager.hooks.egress_dropped.load(Ordering::Relaxed),
+            ..manager.stats
         }
+    }
 
-        let mut pfd = [PollFd::new(tun.as_raw_fd(), PollFlags::POLLIN)];
-        if poll(&mut pfd[..], 1).unwrap() == 0 {
-            drop(manager);
-            thread::sleep(Duration::from_millis(250));
+    /*
+    Hands every datagram read from the devices to hook before the stack
+    looks at it, e.g. to build a firewall or to inject faults. The hook may
+    change the datagram or drop it. Replaces whatever hook was set before.
+    */
+    pub fn set_ingress_hook<F>(&self, hook: F)
+    where
+        F: FnMut(&mut Packet<'_>) -> HookVerdict + Send + 'static,
+    {
+        let hooks = self.manager.lock().unwrap().hooks.clone();
+        *hooks.ingress.lock().unwrap() = Some(Hook(Box::new(hook)));
+    }
 
-            continue;
+    // Same as set_ingress_hook, for every datagram right before it is sent
+    pub fn set_egress_hook<F>(&self, hook: F)
+    where
+        F: FnMut(&mut Packet<'_>) -> HookVerdict + Send + 'static,
+    {
+        let hooks = self.manager.lock().unwrap().hooks.clone();
+        *hooks.egress.lock().unwrap() = Some(Hook(Box::new(hook)));
+    }
+
+    pub fn clear_hooks(&self) {
+        let hooks = self.manager.lock().unwrap().hooks.clone();
+        *hooks.ingress.lock().unwrap() = None;
+        *hooks.egress.lock().unwrap() = None;
+    }
+
+    /*
+    Calls callback with every event of every connection: changes of state,
+    establishment, resets by the peer, soft errors and closure. The callback
+    runs on a thread of its own, so it may call back into the stack.
+    Replaces whatever callback was set before.
+    */
+    pub fn on_event<F>(&self, callback: F)
+    where
+        F: FnMut(ConnEvent) + Send + 'static,
+    {
+        self.manager.lock().unwrap().observer.set(callback);
+    }
+
+    pub fn clear_on_event(&self) {
+        self.manager.lock().unwrap().observer.clear();
+    }
+
+    // The soft errors of every connection, see TcpStream::soft_errors
+    pub fn soft_errors(&self) -> Receiver<(Quad, SoftError)> {
+        self.manager.lock().unwrap().observer.soft_errors()
+    }
+
+    /*
+    Rules are evaluated in the order they were added, the first one that
+    matches deciding what happens to a datagram. Datagrams no rule matches
+    are allowed.
+    */
+    pub fn add_rule(&self, rule: Rule) -> RuleId {
+        self.manager.lock().unwrap().firewall.add(rule)
+    }
+
+    // Returns whether there was such a rule
+    pub fn remove_rule(&self, id: RuleId) -> bool {
+        self.manager.lock().unwrap().firewall.remove(id)
+    }
+
+    // Every rule in the order they are evaluated, along with how often each has matched
+    pub fn rules(&self) -> Vec<RuleStats> {
+        self.manager.lock().unwrap().firewall.rules()
+    }
+
+    // Lowering a limit does not evict what is kept already
+    pub fn set_limits(&self, limits: Limits) {
+        let mut manager = self.manager.lock().unwrap();
+
+        manager
+            .memory
+            .set_limits(limits.memory_soft_limit, limits.memory_hard_limit);
+
+        if limits.syn_rate_limit != manager.limits.syn_rate_limit {
+            let now = manager.clock.now();
+            manager.syn_bucket = limits
+                .syn_rate_limit
+                .map(|(rate, burst)| TokenBucket::new(rate, burst, now));
         }
+        manager.limits = limits;
+    }
 
-        let n = tun.read(&mut buf).unwrap();
+    // Octets held in the buffers of all the connections
+    pub fn memory_used(&self) -> usize {
+        self.manager.lock().unwrap().memory.used()
+    }
 
-        let Ok(ip4h) = Ipv4HeaderSlice::from_slice(&buf[..n]) else { continue };
-        let Ok(tcph) = TcpHeaderSlice::from_slice(&buf[(ip4h.ihl() * 4) as usize..n]) else { continue };
-        let data = &buf[(ip4h.ihl() * 4 + tcph.data_offset() * 4) as usize..n];
+    pub fn limits(&self) -> Limits {
+        self.manager.lock().unwrap().limits
+    }
 
-        let src = Dual {
-            ipv4: ip4h.destination_addr(),
-            port: tcph.destination_port(),
-        };
-        let dst = Dual {
-            ipv4: ip4h.source_addr(),
-            port: tcph.source_port(),
-        };
+    /*
+    Takes the time from clock from now on, e.g. from a MockClock to run
+    through retransmissions or TIME-WAIT in a test without waiting on them.
+    Connections that exist already keep the clock they were opened with.
+    */
+    pub fn set_clock(&self, clock: Arc<dyn Clock>) {
+        let mut manager = self.manager.lock().unwrap();
 
-        let quad = Quad { src, dst };
-
-        let action = if let Some(StreamEntry { tcb, .. }) = manager.streams.get_mut(&quad) {
-            println!("Process stream quad: {:?}", quad);
-            tcb.on_segment(ip4h, tcph, data, &mut tun)
-        } else if let Some(tcb) = manager.pending.get_mut(&quad) {
-            println!("Process pending quad: {:?}", quad);
-            tcb.on_segment(ip4h, tcph, data, &mut tun)
-        } else if manager.bounded.contains(&src.port) {
-            println!("Process bounded quad: {:?}", quad);
-            let mut tcb = TCB::listen(quad, manager.iss.load(Ordering::Acquire));
-
-            tcb.on_segment(ip4h, tcph, data, &mut tun)
-        } else {
-            println!("Invalid quad: {:?}", quad);
-            /*
-            If the connection does not exist (CLOSED), then a reset is sent
-            in response to any incoming segment except another reset. A SYN
-            segment that does not match an existing connection is rejected
-            by this means.
-
-            If the incoming segment has the ACK bit set, the reset takes its
-            sequence number from the ACK field of the segment; otherwise,
-            the reset has sequence number zero and the ACK field is set to
-            the sum of the sequence number and segment length of the
-            incoming segment. The connection remains in the CLOSED state.
-            */
-
-            if tcph.rst() {
-                continue;
+        // The timer loop looks at its timers again whenever the clock moves
+        let doorbell = Arc::downgrade(&manager.doorbell);
+        manager.manual_clock = clock.on_advance(Box::new(move || {
+            if let Some(doorbell) = doorbell.upgrade() {
+                doorbell.ring();
             }
+        }));
+        manager.clock = clock;
+        manager.doorbell.ring();
+    }
 
-            write_reset(&ip4h, &tcph, data, &mut tun);
+    /*
+    A snapshot of every connection the stack keeps state for, including
+    those still in the handshake and those no longer held by a stream.
+    */
+    pub fn connections(&self) -> Vec<Connection> {
+        connections(&self.manager.lock().unwrap())
+    }
 
-            Action::Noop
-        };
+    /*
+    Answers tools like handshake-ss on a unix socket at path, through which
+    they list the connections of the stack as ControlClient does. The
+    socket is removed once the stack has been shut down.
+    */
+    pub fn serve_control(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        Ok(control::serve(path.as_ref(), self.manager.clone())?)
+    }
 
-        println!("\nDoing action: {:?}", action);
-        match action {
-            Action::Noop => {}
-            Action::AddToPending(tcb) => {
-                manager.pending.insert(quad, tcb);
-            }
-            Action::RemoveFromPending => {
-                manager.pending.remove(&quad);
-            }
-            Action::IsEstablished => {
-                let tcb = manager.pending.remove(&quad).unwrap();
+    /*
+    Takes a connection over from a snapshot of it, as a stream of this stack.
+    Only synchronized connections can be restored, the handshake being left
+    to whoever started it, and only on an address of the stack. The stream
+    carries on without a word to the peer, which cannot tell the difference
+    as long as the snapshot is the last state the connection was in.
+    */
+    pub fn restore(&self, snapshot: &TcbSnapshot) -> Result<TcpStream, Error> {
+        let mut manager = self.manager.lock().unwrap();
 
-                let rvar = Arc::new(Condvar::new());
-                let wvar = Arc::new(Condvar::new());
-                let svar = Arc::new(Condvar::new());
-                let r2 = tcb.r2.clone();
-                let r2_syn = tcb.r2_syn.clone();
+        if manager.closing {
+            return Err(Error::ShutDown);
+        }
 
-                let reset = tcb.reset.clone();
-                let read_closed = tcb.read_closed.clone();
-                let write_closed = tcb.write_closed.clone();
+        let state = snapshot.state();
+        if matches!(
+            state,
+            ConnState::Listen | ConnState::SynRcvd | ConnState::SynSent
+        ) {
+            return Err(Error::InvalidSnapshot(state));
+        }
 
-                manager.streams.insert(
-                    quad,
-                    StreamEntry {
-                        tcb,
-                        rvar: rvar.clone(),
-                        wvar: wvar.clone(),
-                        svar: svar.clone(),
-                    },
-                );
-
-                let EstabEntry { cvar, elts } = manager.established.get_mut(&src.port).unwrap();
-                elts.push(EstabElement {
-                    quad,
-                    rvar,
-                    wvar,
-                    svar,
-                    r2,
-                    r2_syn,
-                    write_closed,
-                    read_closed,
-                    reset,
-                });
-                cvar.notify_one();
-            }
-            Action::Reset => {
-                let stream = manager.streams.remove(&quad).unwrap();
+        let local = snapshot.local();
+        manager
+            .routes
+            .iface_of(*local.ip())
+            .ok_or(Error::AddrNotAvailable(*local.ip()))?;
 
-                stream.rvar.notify_one();
-                stream.wvar.notify_one();
-                stream.svar.notify_one();
-            }
-            Action::Wakeup {
-                wake_up_reader,
-                wake_up_writer,
-                wake_up_closer,
-            } => {
-                let StreamEntry {
-                    rvar, wvar, svar, ..
-                } = &manager.streams[&quad];
-
-                if wake_up_reader {
-                    println!("Noifying reader");
-                    rvar.notify_one();
-                }
-                if wake_up_writer {
-                    println!("Noifying writer");
-                    wvar.notify_one();
-                }
-                if wake_up_closer {
-                    println!("Noifying closer");
-                    svar.notify_one();
-                }
-            }
-            Action::DeleteTCB => {
-                manager.streams.remove(&quad).unwrap();
-            }
-            Action::ConnectionRefused => {
-                todo!()
-            }
+        let mut tcb = TCB::restore(
+            snapshot,
+            manager.ack_throttle.clone(),
+            manager.memory.clone(),
+            manager.clock.clone(),
+        );
+        tcb.observer = manager.observer.clone();
+        let quad = tcb.quad;
+
+        if manager.streams.contains_key(&quad) || manager.pending.contains_key(&quad) {
+            return Err(Error::PortInUse(quad.src.port));
+        }
+
+        if !has_room(&mut manager) {
+            return Err(Error::TooManyConnections);
+        }
+
+        let rvar = Arc::new(Condvar::new());
+        let wvar = Arc::new(Condvar::new());
+        let svar = Arc::new(Condvar::new());
+        let r2 = tcb.r2.clone();
+        let r2_syn = tcb.r2_syn.clone();
+
+        let reset = tcb.reset.clone();
+        let read_closed = tcb.read_closed.clone();
+        let write_closed = tcb.write_closed.clone();
+        let error = tcb.error.clone();
+
+        let entry = Arc::new(Mutex::new(StreamEntry {
+            tcb,
+            rvar: rvar.clone(),
+            wvar: wvar.clone(),
+            svar: svar.clone(),
+            notifiers: Notifiers::default(),
+            deleted: false,
+            detached: false,
+        }));
+        manager.streams.insert(quad, entry.clone());
+
+        if state == ConnState::TimeWait {
+            enter_time_wait(&mut manager, quad);
         }
+
+        // The timers are armed, and whatever is queued goes out
+        kick(&mut manager, quad);
+
+        Ok(TcpStream {
+            manager: self.manager.clone(),
+            entry,
+            quad,
+            rvar,
+            wvar,
+            svar,
+            r2,
+            r2_syn,
+            write_closed,
+            read_closed,
+            reset,
+            error,
+        })
+    }
+
+    #[cfg(feature = "hyper")]
+    pub fn connector(&self) -> Connector {
+        Connector {
+            manager: self.manager.clone(),
+        }
+    }
+
+    pub fn selector(&self) -> Selector {
+        let readiness = self.manager.lock().unwrap().readiness.clone();
+
+        Selector::new(self.manager.clone(), readiness)
+    }
+
+    // See ShutdownHandle::shutdown
+    pub fn shutdown(&self, linger: Duration) {
+        shutdown::shutdown(&self.manager, linger);
+    }
+
+    // Lets another thread shut the stack down, e.g. on a signal
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            manager: self.manager.clone(),
+        }
+    }
+
+    // Returns once the stack has been shut down
+    pub fn join(self) {
+        self.jh.join().unwrap();
+        self.th.join().unwrap();
+    }
+}
+
+fn connections(manager: &Manager) -> Vec<Connection> {
+    let streams = manager
+        .streams
+        .values()
+        .map(|entry| entry.lock().unwrap().tcb.connection());
+
+    streams
+        .chain(manager.pending.values().map(TCB::connection))
+        .collect()
+}
+
+fn start_connect(
+    shared: &Arc<Mutex<Manager>>,
+    local: SocketAddrV4,
+    remote: SocketAddrV4,
+    opts: TcpOptions,
+) -> Result<Connecting, Error> {
+    let mut manager = shared.lock().unwrap();
+
+    if manager.closing {
+        return Err(Error::ShutDown);
+    }
+
+    if !has_room(&mut manager) {
+        return Err(Error::TooManyConnections);
+    }
+
+    let (addr, port) = (*remote.ip(), remote.port());
+
+    let route = *manager.routes.lookup(addr).ok_or(Error::NoRoute(addr))?;
+    let local_addr = if local.ip().is_unspecified() {
+        manager.routes.interface(route.iface).addr
+    } else {
+        manager
+            .routes
+            .iface_of(*local.ip())
+            .ok_or(Error::AddrNotAvailable(*local.ip()))?;
+
+        *local.ip()
+    };
+
+    let quad_of = |local_port| Quad {
+        src: Dual {
+            ipv4: local_addr,
+            port: local_port,
+        },
+        dst: Dual { ipv4: addr, port },
+    };
+
+    /*
+    The port is where the connection is handed over once established,
+    so it must be free as a whole, and the quad must not be taken by a
+    connection that outlived the listener it was accepted from.
+    */
+    let is_free = |manager: &Manager, local_port| {
+        !manager.bounded.contains(&local_port)
+            && !manager.streams.contains_key(&quad_of(local_port))
+            && !manager.pending.contains_key(&quad_of(local_port))
+    };
+
+    /*
+    Ephemeral ports are handed out in turn, so that finding one does not
+    walk past every port taken before it, and a port that was just let go
+    of is the last to be reused.
+    */
+    let local_port = if local.port() == 0 {
+        let start = manager.next_ephemeral.max(EPHEMERAL_PORT_START);
+        let local_port = (start..=u16::MAX)
+            .chain(EPHEMERAL_PORT_START..start)
+            .find(|&local_port| is_free(&manager, local_port))
+            .ok_or(Error::NoEphemeralPort)?;
+
+        manager.next_ephemeral = local_port.wrapping_add(1);
+        local_port
+    } else if is_free(&manager, local.port()) {
+        local.port()
+    } else {
+        return Err(Error::PortInUse(local.port()));
+    };
+
+    manager.bounded.insert(local_port);
+
+    let quad = quad_of(local_port);
+
+    let mut tcb = TCB::syn_sent(
+        quad,
+        &manager.iss,
+        manager.ack_throttle.clone(),
+        manager.memory.clone(),
+        manager.clock.clone(),
+        manager.ip_opts,
+        opts,
+    );
+    tcb.observer = manager.observer.clone();
+    tcb.set_rcv_mss(mss_of(&manager, route.iface));
+
+    manager.pending.insert(quad, tcb);
+    kick(&mut manager, quad);
+
+    let cvar = Arc::new(Condvar::new());
+
+    manager.established.insert(
+        local_port,
+        EstabEntry {
+            cvar: cvar.clone(),
+            elts: VecDeque::new(),
+            error: None,
+            addr: local_addr,
+            connect: Some(quad),
+            backlog: 1,
+            overflow: Overflow::Reset,
+            paused: false,
+            filter: None,
+            opts,
+        },
+    );
+
+    Ok(Connecting {
+        manager: shared.clone(),
+        quad,
+        cvar,
+        done: false,
+    })
+}
+
+// What is announced to peers reached through iface, which leaves room for the IPv4 and TCP headers
+fn mss_of(manager: &Manager, iface: usize) -> u16 {
+    let mtu = manager
+        .links
+        .get(iface)
+        .map_or(DEFAULT_MTU, |link| link.mtu);
+
+    mtu.saturating_sub(40).clamp(536, u16::MAX as usize) as u16
+}
+
+// Has the timer loop tick the connection on its next round
+fn tick_soon(manager: &mut Manager, quad: Quad) {
+    if manager.streams.contains_key(&quad) || manager.pending.contains_key(&quad) {
+        let now = manager.clock.now();
+        manager.timers.schedule(quad, now);
+    }
+}
+
+// The same from another thread, e.g. to send what it has just queued
+fn kick(manager: &mut Manager, quad: Quad) {
+    tick_soon(manager, quad);
+    manager.doorbell.ring();
+}
+
+// A zero expiration would disarm the timer instead
+fn arm(timer: &TimerFd, deadline: Option<Instant>, now: Instant) -> nix::Result<()> {
+    let Some(deadline) = deadline else {
+        return timer.unset();
+    };
+
+    let wait = deadline
+        .saturating_duration_since(now)
+        .max(Duration::from_nanos(1));
+
+    timer.set(
+        Expiration::OneShot(TimeSpec::from_duration(wait)),
+        TimerSetTimeFlags::empty(),
+    )
+}
+
+/*
+Waits twice as long after each failed wait of a loop in a row, up to
+MAX_BACKOFF, so that a failure that persists is not spun on.
+*/
+#[derive(Debug, Default)]
+struct Backoff {
+    failures: u32,
+}
+
+impl Backoff {
+    fn failed(&mut self) {
+        let wait = Duration::from_millis(1 << self.failures.min(10));
+        self.failures = self.failures.saturating_add(1);
+
+        thread::sleep(wait.min(MAX_BACKOFF));
+    }
+
+    fn succeeded(&mut self) {
+        self.failures = 0;
+    }
+}
+
+/*
+The devices are indexed in the same order as the interfaces of the routing
+table. A connection is always served by the device its local address lives on.
+
+Ticks the connections whose timers have fired and sends what the workers
+and the application have queued, apart from receiving, so that a burst of
+retransmissions does not hold up the segments coming in and vice versa.
+*/
+fn timer_loop(
+    devices: Devices,
+    shared: Arc<Mutex<Manager>>,
+    workers: Workers,
+    timer: TimerFd,
+    outbox: Receiver<Vec<Datagram>>,
+) {
+    let (doorbell, interrupt, tx_bufs) = {
+        let manager = shared.lock().unwrap();
+
+        (
+            manager.doorbell.clone(),
+            manager.interrupt.clone(),
+            manager.tx_bufs.clone(),
+        )
+    };
+
+    // What is sent on each round, by the loop itself and by the workers
+    let mut datagrams = vec![];
+    let mut backoff = Backoff::default();
+
+    loop {
+        let mut manager = shared.lock().unwrap();
+
+        let now = manager.clock.now();
+        let Manager {
+            routes,
+            links,
+            streams,
+            pending,
+            timers,
+            stats,
+            ..
+        } = &mut *manager;
+
+        // Only the connections whose timers have fired, or that were kicked, are ticked
+        let mut to_be_deleted = vec![];
+        let mut lost = vec![]; // Jobs no worker was left to take
+        for quad in timers.expire(now) {
+            let iface = routes.iface_of(quad.src.ipv4).unwrap_or(0);
+            let gso = links.get(iface).and_then(|link| link.gso);
+
+            let hash = streams.hash(&quad);
+            if let Some(entry) = streams.get_hashed(hash, &quad) {
+                let job = Job {
+                    quad,
+                    hash,
+                    entry: entry.clone(),
+                    iface,
+                    gso,
+                    work: Work::Tick,
+                };
+                if let Err(job) = workers.dispatch(job) {
+                    lost.push(job);
+                }
+
+                continue;
+            }
+
+            let Some(tcb) = pending.get_mut_hashed(hash, &quad) else {
+                continue;
+            };
+
+            let mut out = Outbox::new(iface, gso, tcb.opts.priority.class, &tx_bufs);
+            let expired = tcb.on_tick(&mut out);
+            datagrams.append(&mut out.datagrams);
+
+            if tcb.send_error.take().is_some() {
+                stats.device_errors += 1;
+            }
+
+            if expired {
+                to_be_deleted.push(quad);
+            } else if let Some(at) = tcb.deadline() {
+                timers.schedule(quad, at);
+            }
+        }
+        for job in lost {
+            worker::fail(&mut manager, job.quad, &job.entry);
+        }
+        for quad in to_be_deleted {
+            let Some(tcb) = manager.pending.remove(&quad) else {
+                continue;
+            };
+
+            // Giving up on the SYN of an active open fails the connect waiting for it
+            if tcb.kind == Kind::Active {
+                if let Some(EstabEntry { cvar, error, .. }) =
+                    manager.established.get_mut(&quad.src.port)
+                {
+                    *error = Some(Error::ConnectTimeout(tcb.context()));
+                    cvar.notify_one();
+                }
+
+                notify_ready(&mut manager);
+            }
+        }
+
+        sweep_syn_received(&mut manager, now);
+
+        let Manager {
+            routes, aborted, ..
+        } = &mut *manager;
+
+        for tcb in aborted.drain(..) {
+            let iface = routes.iface_of(tcb.quad.src.ipv4).unwrap_or(0);
+            let mut out = Outbox::new(iface, None, tcb.opts.priority.class, &tx_bufs);
+
+            tcb.write_abort(&mut out);
+            datagrams.append(&mut out.datagrams);
+        }
+
+        let shut_down = manager.shut_down.load(Ordering::Acquire);
+
+        /*
+        Sleeps until the next timer is due or another thread has queued
+        something to send, without holding up the stack.
+        */
+        let lifetime = manager.limits.syn_received_lifetime;
+        let deadline = manager
+            .syn_received
+            .front()
+            .map(|&(at, ..)| at + lifetime)
+            .into_iter()
+            .chain(manager.timers.next_deadline())
+            .min();
+
+        // A clock that only moves when told to wakes the loop itself, unless a timer is due
+        let now = manager.clock.now();
+        let deadline = deadline.filter(|&at| !manager.manual_clock || at <= now);
+        drop(manager);
+
+        // The devices are written to without the manager, so the rest of the stack carries on
+        for mut handed in outbox.try_iter() {
+            datagrams.append(&mut handed);
+        }
+
+        // Higher classes go first, each in the order its datagrams were queued
+        datagrams.sort_by_key(|datagram| Reverse(datagram.class));
+
+        let mut tuns = devices.lock().unwrap();
+        let mut errors = 0;
+
+        for Datagram {
+            iface, buf, mss, ..
+        } in datagrams.drain(..)
+        {
+            let res = match (tuns.get_mut(iface), mss) {
+                (Some(tun), Some(mss)) => tun.send_gso(&[IoSlice::new(&buf)], mss),
+                (Some(tun), None) => tun.send(&buf),
+                (None, _) => Err(io::ErrorKind::NotFound.into()),
+            };
+            tx_bufs.give(buf);
+
+            // Lost like any other datagram, and retransmitted if it has to be
+            if res.is_err() {
+                errors += 1;
+            }
+        }
+
+        for tun in tuns.iter_mut() {
+            if tun.flush().is_err() {
+                errors += 1;
+            }
+        }
+
+        // The devices are closed on the way out
+        if shut_down {
+            tuns.clear();
+            interrupt.ring();
+
+            return;
+        }
+        drop(tuns);
+
+        if errors > 0 {
+            shared.lock().unwrap().stats.device_errors += errors;
+        }
+
+        // Should the timer fail to be armed, the poll times out on the deadline itself
+        let timeout = match arm(&timer, deadline, now) {
+            Ok(()) => -1,
+            Err(_) => {
+                shared.lock().unwrap().stats.poll_errors += 1;
+
+                deadline.map_or(-1, |at| {
+                    let wait = at.saturating_duration_since(now).as_millis() + 1;
+                    wait.min(i32::MAX as u128) as i32
+                })
+            }
+        };
+
+        let mut pfds = [
+            PollFd::new(timer.as_raw_fd(), PollFlags::POLLIN),
+            PollFd::new(doorbell.as_raw_fd(), PollFlags::POLLIN),
+        ];
+        match poll(&mut pfds, timeout) {
+            Ok(_) | Err(Errno::EINTR) => backoff.succeeded(),
+            Err(_) => {
+                shared.lock().unwrap().stats.poll_errors += 1;
+                backoff.failed();
+            }
+        };
+
+        if ready(&pfds[0]) {
+            let _ = timer.wait();
+        }
+        if ready(&pfds[1]) {
+            doorbell.answer();
+        }
+    }
+}
+
+// Fails the connection of the job right away if no worker is left to take it
+fn dispatch(workers: &Workers, manager: &mut Manager, job: Job) {
+    if let Err(job) = workers.dispatch(job) {
+        worker::fail(manager, job.quad, &job.entry);
+    }
+}
+
+fn ready(pfd: &PollFd) -> bool {
+    pfd.revents()
+        .is_some_and(|ev| ev.contains(PollFlags::POLLIN))
+}
+
+fn set_nonblocking(fd: RawFd) {
+    let res = fcntl(fd, FcntlArg::F_GETFL)
+        .map(OFlag::from_bits_truncate)
+        .and_then(|flags| fcntl(fd, FcntlArg::F_SETFL(flags | OFlag::O_NONBLOCK)));
+
+    if let Err(err) = res {
+        println!("Could not make device {} non-blocking: {}", fd, err);
+    }
+}
+
+// Receives from the devices and hands what concerns established connections to the workers
+fn segment_loop(devices: Devices, shared: Arc<Mutex<Manager>>, workers: Workers) {
+    let (interrupt, rx_bufs) = {
+        let manager = shared.lock().unwrap();
+        (manager.interrupt.clone(), manager.rx_bufs.clone())
+    };
+
+    let mut buf = rx_bufs.take(DEFAULT_MTU);
+
+    let mut nonblocking = 0;
+    let mut backoff = Backoff::default();
+
+    loop {
+        // Devices added since the last round are polled from now on
+        let fds: Vec<RawFd> = devices
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|tun| tun.raw_fd())
+            .collect();
+
+        // Devices are drained until they would block
+        for &fd in fds.iter().skip(nonblocking) {
+            set_nonblocking(fd);
+        }
+        nonblocking = nonblocking.max(fds.len());
+
+        let mut pfds: Vec<_> = fds
+            .iter()
+            .map(|&fd| PollFd::new(fd, PollFlags::POLLIN))
+            .chain([PollFd::new(interrupt.as_raw_fd(), PollFlags::POLLIN)])
+            .collect();
+        match poll(&mut pfds[..], -1) {
+            Ok(_) => backoff.succeeded(),
+            Err(Errno::EINTR) => continue,
+            Err(_) => {
+                let mut manager = shared.lock().unwrap();
+                manager.stats.poll_errors += 1;
+
+                // Without the interrupt to wake it, the loop checks on its own
+                if manager.shut_down.load(Ordering::Acquire) {
+                    return;
+                }
+                drop(manager);
+
+                backoff.failed();
+                continue;
+            }
+        };
+
+        if ready(&pfds[fds.len()]) {
+            interrupt.answer();
+        }
+
+        let mut manager = shared.lock().unwrap();
+
+        // The timer loop releases the devices once the stack has been shut down
+        if manager.shut_down.load(Ordering::Acquire) {
+            return;
+        }
+
+        if !pfds[..fds.len()].iter().any(ready) {
+            continue;
+        }
+
+        let mut tuns = devices.lock().unwrap();
+
+        // Whatever is sent in response is flushed, and any timer set is armed, by the timer loop
+        manager.doorbell.ring();
+
+        for idx in (0..fds.len()).filter(|&idx| ready(&pfds[idx])) {
+            let tun = tuns[idx].as_mut();
+
+            // Size the buffer from the device so datagrams above 1500 bytes are not truncated
+            let mtu = tun.mtu().unwrap_or(DEFAULT_MTU);
+            if buf.len() < mtu {
+                buf.resize(mtu, 0);
+            }
+
+            // Drains the device a batch at a time, so that the other devices get their turn
+            for _ in 0..RX_BATCH {
+                // A device that fails gets its turn again on the next wakeup
+                let n = match tun.recv(&mut buf) {
+                    Ok(n) => n,
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(_) => {
+                        manager.stats.device_errors += 1;
+
+                        break;
+                    }
+                };
+
+                let inbound = match parse_datagram(&buf[..n], manager.verify_checksums) {
+                    Ok(Some(inbound)) => inbound,
+                    Ok(None) => continue,
+                    Err(reason) => {
+                        count_drop(&mut manager, reason, None);
+                        continue;
+                    }
+                };
+
+                let (ip4h, tcph, data) = match inbound {
+                    Inbound::Segment(ip4h, tcph, data) => (ip4h, tcph, data),
+                    Inbound::Unreachable(unreachable) => {
+                        let quad = unreachable.quad;
+                        let hash = manager.streams.hash(&quad);
+
+                        if let Some(entry) = manager.streams.get_hashed(hash, &quad) {
+                            println!("Process unreachable stream quad: {:?}", quad);
+                            let job = Job {
+                                quad,
+                                hash,
+                                entry: entry.clone(),
+                                iface: idx,
+                                gso: tun.gso_max_size(),
+                                work: Work::Unreachable(unreachable.sqno, unreachable.code),
+                            };
+                            dispatch(&workers, &mut manager, job);
+
+                            continue;
+                        }
+
+                        let action = if let Some(tcb) = manager.pending.get_mut_hashed(hash, &quad)
+                        {
+                            println!("Process unreachable quad: {:?}", quad);
+                            tcb.on_unreachable(unreachable.sqno, unreachable.code)
+                        } else {
+                            Action::Noop
+                        };
+
+                        apply_action(&mut manager, quad, action);
+                        tick_soon(&mut manager, quad);
+
+                        continue;
+                    }
+                    Inbound::Other(ip4h, data) => {
+                        // Datagrams for addresses that are not ours are not ours to answer
+                        if manager.routes.iface_of(ip4h.destination_addr()).is_none() {
+                            continue;
+                        }
+
+                        let now = manager.clock.now();
+                        if !manager.icmp_errors.allow(ip4h.source_addr(), now) {
+                            continue;
+                        }
+
+                        // There are no UDP endpoints, so every port is unreachable
+                        let code = if ip4h.protocol() == ip_number::UDP {
+                            DestUnreachableHeader::Port
+                        } else {
+                            DestUnreachableHeader::Protocol
+                        };
+
+                        println!("Unreachable protocol: {}", ip4h.protocol());
+                        let res = icmp::write_unreachable(&ip4h, data, code, manager.ip_opts, tun);
+                        if res.is_err() {
+                            manager.stats.device_errors += 1;
+                        }
+
+                        continue;
+                    }
+                };
+
+                // Ignore any padding the device delivered past the datagram
+                let n = ip4h.total_len() as usize;
+
+                let src = Dual {
+                    ipv4: ip4h.destination_addr(),
+                    port: tcph.destination_port(),
+                };
+                let dst = Dual {
+                    ipv4: ip4h.source_addr(),
+                    port: tcph.source_port(),
+                };
+
+                let quad = Quad { src, dst };
+
+                // Hashed once, for every table it is looked up in and to pick its worker
+                let hash = manager.streams.hash(&quad);
+
+                // Denied segments are dropped before they reach any connection
+                if !manager
+                    .firewall
+                    .admits(Direction::Inbound, dst.ipv4, Some(src.port))
+                {
+                    println!("Firewall denied quad: {:?}", quad);
+
+                    continue;
+                }
+
+                if let Some(entry) = manager.streams.get_hashed(hash, &quad) {
+                    println!("Process stream quad: {:?}", quad);
+
+                    // The worker takes the buffer as it is, the next datagram is read into another
+                    let datagram = mem::replace(&mut buf, rx_bufs.take(mtu));
+                    let job = Job {
+                        quad,
+                        hash,
+                        entry: entry.clone(),
+                        iface: idx,
+                        gso: tun.gso_max_size(),
+                        work: Work::Segment(datagram, n),
+                    };
+                    dispatch(&workers, &mut manager, job);
+
+                    continue;
+                }
+
+                // Set if the connection, pending or new, dropped the segment or failed to send
+                let mut dropped = None;
+                let mut failed = false;
+
+                let action = if let Some(tcb) = manager.pending.get_mut_hashed(hash, &quad) {
+                    println!("Process pending quad: {:?}", quad);
+                    let action = tcb.on_segment(ip4h, tcph, data, tun);
+                    dropped = tcb.dropped.take();
+                    failed = tcb.send_error.take().is_some();
+
+                    action
+                } else if listens(&manager, src) {
+                    println!("Process bounded quad: {:?}", quad);
+                    if tcph.syn() && !tcph.ack() && !tcph.rst() {
+                        if let Some(overflow) = paused(&manager, src.port) {
+                            println!("Port {} is paused", src.port);
+
+                            if overflow == Overflow::Reset {
+                                write_reset(&ip4h, &tcph, data, manager.ip_opts, tun);
+                            }
+
+                            continue;
+                        }
+
+                        match screen(&manager, src.port, dst) {
+                            Verdict::Accept => {}
+                            Verdict::Ignore => {
+                                println!("Filter of port {} ignored {:?}", src.port, dst);
+                                manager.stats.listen_filtered += 1;
+
+                                continue;
+                            }
+                            Verdict::Reset => {
+                                println!("Filter of port {} refused {:?}", src.port, dst);
+                                manager.stats.listen_filtered += 1;
+                                write_reset(&ip4h, &tcph, data, manager.ip_opts, tun);
+
+                                continue;
+                            }
+                        }
+
+                        // A flood of SYNs is dropped here, before anything is set up for it
+                        if !take_syn(&mut manager) {
+                            println!("Rate of SYNs is over the limit");
+                            manager.stats.syn_rate_limited += 1;
+
+                            continue;
+                        }
+
+                        if let Some(overflow) = backlog_overflow(&manager, src.port) {
+                            println!("Backlog of port {} is full", src.port);
+                            manager.stats.listen_overflows += 1;
+
+                            if overflow == Overflow::Reset {
+                                write_reset(&ip4h, &tcph, data, manager.ip_opts, tun);
+                            }
+
+                            continue;
+                        }
+
+                        if peer_full(&manager, dst.ipv4) {
+                            println!("Peer {} holds too many connections", dst.ipv4);
+                            manager.stats.peer_limited += 1;
+
+                            if overflow_of(&manager, src.port) == Overflow::Reset {
+                                write_reset(&ip4h, &tcph, data, manager.ip_opts, tun);
+                            }
+
+                            continue;
+                        }
+
+                        if !has_room(&mut manager) {
+                            println!("Connection table is full");
+                            manager.stats.conn_table_full += 1;
+
+                            if overflow_of(&manager, src.port) == Overflow::Reset {
+                                write_reset(&ip4h, &tcph, data, manager.ip_opts, tun);
+                            }
+
+                            continue;
+                        }
+                    }
+
+                    let opts = manager
+                        .established
+                        .get(&src.port)
+                        .map_or_else(TcpOptions::default, |entry| entry.opts);
+
+                    let mut tcb = TCB::listen(
+                        quad,
+                        &manager.iss,
+                        manager.ack_throttle.clone(),
+                        manager.memory.clone(),
+                        manager.clock.clone(),
+                        manager.ip_opts,
+                        opts,
+                    );
+                    tcb.observer = manager.observer.clone();
+                    tcb.set_rcv_mss(mss_of(&manager, idx));
+
+                    let action = tcb.on_segment(ip4h, tcph, data, tun);
+                    dropped = tcb.dropped.take();
+                    failed = tcb.send_error.take().is_some();
+
+                    // The listening TCB becomes the new connection, without being copied
+                    if matches!(action, Action::AddToPending) {
+                        let now = manager.clock.now();
+                        manager.syn_received.push_back((now, quad, tcb.iss()));
+                        manager.pending.insert_hashed(hash, quad, tcb);
+                    }
+
+                    action
+                } else {
+                    count_drop(&mut manager, DropReason::UnknownQuad, Some(quad));
+                    /*
+                    If the connection does not exist (CLOSED), then a reset is sent
+                    in response to any incoming segment except another reset. A SYN
+                    segment that does not match an existing connection is rejected
+                    by this means.
+
+                    If the incoming segment has the ACK bit set, the reset takes its
+                    sequence number from the ACK field of the segment; otherwise,
+                    the reset has sequence number zero and the ACK field is set to
+                    the sum of the sequence number and segment length of the
+                    incoming segment. The connection remains in the CLOSED state.
+                    */
+
+                    if tcph.rst() {
+                        continue;
+                    }
+
+                    write_reset(&ip4h, &tcph, data, manager.ip_opts, tun);
+
+                    Action::Noop
+                };
+
+                if let Some(reason) = dropped {
+                    count_drop(&mut manager, reason, Some(quad));
+                }
+                if failed {
+                    manager.stats.device_errors += 1;
+                }
+
+                apply_action(&mut manager, quad, action);
+
+                // Whatever the segment changed, like the window or the timers, is acted upon
+                tick_soon(&mut manager, quad);
+            }
+        }
+    }
+}
+
+// What the segment loop makes of a datagram that has been validated
+enum Inbound<'a> {
+    Segment(Ipv4HeaderSlice<'a>, TcpHeaderSlice<'a>, &'a [u8]),
+    Unreachable(icmp::Unreachable),
+    Other(Ipv4HeaderSlice<'a>, &'a [u8]), // Neither TCP nor ICMP, with its payload
+}
+
+/*
+Validates a datagram as read from a device, returning what is wrong with it
+if anything is. Whatever lies past its total length, like padding the device
+delivered, is left out of the payload. ICMP messages other than destination
+unreachables are ignored, without being counted as dropped.
+*/
+fn parse_datagram(buf: &[u8], verify_checksums: bool) -> Result<Option<Inbound<'_>>, DropReason> {
+    let ip4h = Ipv4HeaderSlice::from_slice(buf).map_err(|_| DropReason::IpBadHeader)?;
+
+    // The total length must cover the header and must not exceed what was read
+    let total_len = ip4h.total_len() as usize;
+    if total_len < ip4h.slice().len() || total_len > buf.len() {
+        return Err(DropReason::IpBadLength);
+    }
+
+    if ip4h.to_header().calc_header_checksum().ok() != Some(ip4h.header_checksum()) {
+        return Err(DropReason::IpBadChecksum);
+    }
+
+    let payload = &buf[ip4h.slice().len()..total_len];
+
+    if ip4h.protocol() == ip_number::ICMP {
+        return Ok(icmp::parse_unreachable(payload).map(Inbound::Unreachable));
+    }
+
+    if ip4h.protocol() != ip_number::TCP {
+        return Ok(Some(Inbound::Other(ip4h, payload)));
+    }
+
+    let tcph = TcpHeaderSlice::from_slice(payload).map_err(|_| DropReason::TcpBadHeader)?;
+    let data = &payload[tcph.slice().len()..];
+
+    if verify_checksums && tcph.calc_checksum_ipv4(&ip4h, data).ok() != Some(tcph.checksum()) {
+        return Err(DropReason::TcpBadChecksum);
+    }
+
+    Ok(Some(Inbound::Segment(ip4h, tcph, data)))
+}
+
+// Counts a dropped datagram, logging it too if the drop log allows
+pub(crate) fn count_drop(manager: &mut Manager, reason: DropReason, quad: Option<Quad>) {
+    manager.stats.count(reason);
+
+    if !manager.drop_log.as_ref().is_some_and(|log| log.allow()) {
+        return;
+    }
+
+    match quad {
+        Some(quad) => println!("Dropped segment of {}: {}", quad, reason),
+        None => println!("Dropped datagram: {}", reason),
+    }
+}
+
+// The policy to apply if a new connection on port would not fit in its backlog
+// A paused listener treats new SYNs the same way as a full backlog
+fn paused(manager: &Manager, port: u16) -> Option<Overflow> {
+    let entry = manager.established.get(&port)?;
+
+    entry.paused.then_some(entry.overflow)
+}
+
+// The wildcard address with the port, which binds it at every local address
+fn any(port: u16) -> SocketAddrV4 {
+    SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port)
+}
+
+/*
+Whether the port of local is bound at its address, or at every local address.
+The port of an active open is only there for the connection it was taken
+for, which never gets this far, so it accepts nothing.
+*/
+fn listens(manager: &Manager, local: Dual) -> bool {
+    let Some(entry) = manager.established.get(&local.port) else {
+        return false;
+    };
+
+    if entry.connect.is_some() {
+        false
+    } else if entry.addr.is_unspecified() {
+        manager.routes.iface_of(local.ipv4).is_some()
+    } else {
+        entry.addr == local.ipv4
+    }
+}
+
+fn screen(manager: &Manager, port: u16, peer: Dual) -> Verdict {
+    let Some(Filter(filter)) = manager
+        .established
+        .get(&port)
+        .and_then(|entry| entry.filter.as_ref())
+    else {
+        return Verdict::Accept;
+    };
+
+    filter(&peer.into())
+}
+
+fn backlog_overflow(manager: &Manager, port: u16) -> Option<Overflow> {
+    let entry = manager.established.get(&port)?;
+
+    let syn_rcvd = manager.pending.on_port(port);
+
+    (syn_rcvd >= entry.backlog || entry.elts.len() >= entry.backlog).then_some(entry.overflow)
+}
+
+fn overflow_of(manager: &Manager, port: u16) -> Overflow {
+    manager
+        .established
+        .get(&port)
+        .map_or(Overflow::Drop, |entry| entry.overflow)
+}
+
+// Whether another handshake may begin, within the rate of them, counting it if so
+fn take_syn(manager: &mut Manager) -> bool {
+    let now = manager.clock.now();
+    let Some(bucket) = manager.syn_bucket.as_mut() else {
+        return true;
+    };
+
+    // The bucket is only brought up to date when a SYN is let through, so none is lost
+    if bucket.available(now) == 0 {
+        return false;
+    }
+    bucket.consume(1, now);
+
+    true
+}
+
+// Whether peer holds as many connections as any peer may, established or not
+fn peer_full(manager: &Manager, peer: Ipv4Addr) -> bool {
+    let held = manager.streams.of_peer(peer) + manager.pending.of_peer(peer);
+
+    held >= manager.limits.max_connections_per_peer
+}
+
+// Whether another connection may be set up, making room for it if need be
+fn has_room(manager: &mut Manager) -> bool {
+    if manager.streams.len() + manager.pending.len() < manager.limits.max_connections {
+        return true;
+    }
+
+    evict_time_wait(manager)
+}
+
+fn enter_time_wait(manager: &mut Manager, quad: Quad) {
+    manager.time_wait.push_back(quad);
+
+    while manager.time_wait.len() > manager.limits.max_time_wait && evict_time_wait(manager) {}
+}
+
+/*
+Drops the passive opens that have stayed in SYN-RECEIVED for longer than
+their lifetime. Those that have left it since, or whose quad now belongs to
+a newer connection, are forgotten on the way.
+*/
+fn sweep_syn_received(manager: &mut Manager, now: Instant) {
+    let lifetime = manager.limits.syn_received_lifetime;
+
+    while let Some(&(at, quad, iss)) = manager.syn_received.front() {
+        if now < at + lifetime {
+            break;
+        }
+        manager.syn_received.pop_front();
+
+        let Some(tcb) = manager.pending.get(&quad) else {
+            continue;
+        };
+
+        if tcb.state != State::SynRcvd || tcb.iss() != iss {
+            continue;
+        }
+
+        println!("Handshake of quad {:?} expired", quad);
+        tcb.closed(CloseReason::TimedOut);
+        manager.pending.remove(&quad);
+        manager.stats.syn_received_expired += 1;
+    }
+}
+
+/*
+Deletes the connection that entered TIME-WAIT first and is still in it, if
+there is one. Connections that have left TIME-WAIT since are forgotten on
+the way.
+*/
+fn evict_time_wait(manager: &mut Manager) -> bool {
+    while let Some(quad) = manager.time_wait.pop_front() {
+        let Some(entry) = manager.streams.get(&quad) else {
+            continue;
+        };
+        let mut entry = entry.lock().unwrap();
+
+        if entry.tcb.state != State::TimeWait {
+            continue;
+        }
+
+        println!("Evicting TIME-WAIT quad: {:?}", quad);
+        entry.tcb.closed(CloseReason::Graceful);
+        entry.delete(Ready::ALL);
+        drop(entry);
+
+        remove_stream(manager, &quad);
+        manager.stats.time_wait_evicted += 1;
+        notify_ready(manager);
+
+        return true;
+    }
+
+    false
+}
+
+/*
+Removes the stream of the quad, if there is one. The port of an active open
+stays taken for as long as its connection exists, and is let go of with it.
+*/
+fn remove_stream(manager: &mut Manager, quad: &Quad) -> Option<Arc<Mutex<StreamEntry>>> {
+    let entry = manager.streams.remove(quad)?;
+    let port = quad.src.port;
+
+    if manager
+        .established
+        .get(&port)
+        .is_some_and(|entry| entry.connect == Some(*quad))
+    {
+        manager.established.remove(&port);
+        manager.bounded.remove(&port);
+    }
+
+    Some(entry)
+}
+
+fn notify_ready(manager: &mut Manager) {
+    manager.readiness.notify_all();
+
+    for waker in manager.wakers.drain(..) {
+        waker.wake();
+    }
+}
+
+fn apply_action(manager: &mut Manager, quad: Quad, action: Action) {
+    println!("\nDoing action: {:?}", action);
+
+    // Selectors and tasks only look once the manager has been released
+    if !matches!(action, Action::Noop) {
+        notify_ready(manager);
+    }
+
+    match action {
+        Action::Noop => {}
+        // Moved in by the segment loop already
+        Action::AddToPending => {}
+        Action::RemoveFromPending => {
+            manager.pending.remove(&quad);
+        }
+        Action::IsEstablished => {
+            let Some(tcb) = manager.pending.remove(&quad) else {
+                return;
+            };
+
+            // The port may have been unbound since the SYN arrived
+            if !manager.established.contains_key(&quad.src.port) {
+                manager.aborted.push(tcb);
+                return;
+            }
+
+            let rvar = Arc::new(Condvar::new());
+            let wvar = Arc::new(Condvar::new());
+            let svar = Arc::new(Condvar::new());
+            let r2 = tcb.r2.clone();
+            let r2_syn = tcb.r2_syn.clone();
+
+            let reset = tcb.reset.clone();
+            let read_closed = tcb.read_closed.clone();
+            let write_closed = tcb.write_closed.clone();
+            let error = tcb.error.clone();
+
+            let entry = Arc::new(Mutex::new(StreamEntry {
+                tcb,
+                rvar: rvar.clone(),
+                wvar: wvar.clone(),
+                svar: svar.clone(),
+                notifiers: Notifiers::default(),
+                deleted: false,
+                detached: false,
+            }));
+            manager.streams.insert(quad, entry.clone());
+
+            let Some(EstabEntry { cvar, elts, .. }) = manager.established.get_mut(&quad.src.port)
+            else {
+                return;
+            };
+            elts.push_back(EstabElement {
+                quad,
+                entry,
+                rvar,
+                wvar,
+                svar,
+                r2,
+                r2_syn,
+                write_closed,
+                read_closed,
+                reset,
+                error,
+            });
+            cvar.notify_one();
+        }
+        Action::Reset => {
+            let Some(entry) = remove_stream(manager, &quad) else {
+                return;
+            };
+
+            entry.lock().unwrap().delete(Ready::ALL);
+        }
+        Action::Wakeup {
+            wake_up_reader,
+            wake_up_writer,
+            wake_up_closer,
+        } => {
+            if wake_up_reader {
+                println!("Noifying reader");
+            }
+            if wake_up_writer {
+                println!("Noifying writer");
+            }
+            if wake_up_closer {
+                println!("Noifying closer");
+            }
+
+            let ready = Ready {
+                read: wake_up_reader,
+                write: wake_up_writer,
+                close: wake_up_closer,
+            };
+
+            let Some(stream) = manager.streams.get(&quad).cloned() else {
+                return;
+            };
+            let mut entry = stream.lock().unwrap();
+
+            // Nobody is left to remove a detached stream once our FIN has been acknowledged
+            if entry.detached && !entry.tcb.is_fin_pending() {
+                remove_stream(manager, &quad);
+                entry.delete(ready);
+            } else {
+                entry.notify(ready);
+            }
+        }
+        Action::DeleteTCB => {
+            let Some(entry) = remove_stream(manager, &quad) else {
+                return;
+            };
+
+            // A closer may be waiting for our FIN to be acknowledged
+            entry.lock().unwrap().delete(Ready::CLOSE);
+        }
+        Action::ConnectionRefused => {
+            let Some(tcb) = manager.pending.remove(&quad) else {
+                return;
+            };
+
+            if let Some(EstabEntry { cvar, error, .. }) =
+                manager.established.get_mut(&quad.src.port)
+            {
+                *error = Some(Error::ConnectionRefused(tcb.context()));
+                cvar.notify_one();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use etherparse::{PacketBuilder, PacketBuilderStep, TcpHeader, TcpOptionElement};
+
+    use super::*;
+
+    const STACK: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+    const PEER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+    const MASK: Ipv4Addr = Ipv4Addr::new(255, 255, 255, 0);
+    const PORT: u16 = 80;
+    const PEER_PORT: u16 = 4001;
+    const PEER_ISS: u32 = 1000;
+
+    const JUMBO: usize = 9000;
+    const JUMBO_MSS: u16 = JUMBO as u16 - 40;
+
+    /*
+    A connection accepted by the stack from a peer that is driven by hand,
+    on the other end of a link that carries jumbo frames. Congestion control
+    is off, so what is sent is only bounded by the MSS of the peer.
+    */
+    struct Jumbo {
+        _stack: NetStack,
+        _listener: TcpListener,
+        peer: PipeDevice,
+        stream: TcpStream,
+        nxt: u32,               // What the peer acknowledges of what the stack sent
+        announced: Option<u16>, // The MSS of the SYN-ACK
+    }
+
+    impl Jumbo {
+        fn open(mss: u16) -> Self {
+            let (device, mut peer) = jumbo_pair();
+
+            let mut stack = NetStack::with_device("pipe0", device, STACK, MASK).unwrap();
+            let opts = TcpOptions {
+                congestion: Congestion::None,
+                ..TcpOptions::default()
+            };
+            let listener = stack.bind_with_options(PORT, opts).unwrap();
+
+            let syn = builder(PEER_ISS)
+                .syn()
+                .options(&[TcpOptionElement::MaximumSegmentSize(mss)])
+                .unwrap();
+            send(&mut peer, syn, &[]);
+
+            let syn_ack = recv(&mut peer);
+            let Ok(Some(Inbound::Segment(_, tcph, _))) = parse_datagram(&syn_ack, true) else {
+                panic!("the stack answered with something other than a segment");
+            };
+            assert!(tcph.syn() && tcph.ack());
+            let nxt = tcph.sequence_number().wrapping_add(1);
+            let announced = mss_option(&tcph);
+
+            send(&mut peer, builder(PEER_ISS + 1).ack(nxt), &[]);
+            let stream = listener.accept().unwrap();
+
+            Jumbo {
+                _stack: stack,
+                _listener: listener,
+                peer,
+                stream,
+                nxt,
+                announced,
+            }
+        }
+
+        // The lengths of the segments the stack sends until len octets of data have come in
+        fn segments(&mut self, len: usize) -> Vec<usize> {
+            let mut lens = vec![];
+
+            while lens.iter().sum::<usize>() < len {
+                let datagram = recv(&mut self.peer);
+                assert!(datagram.len() <= JUMBO);
+
+                let Ok(Some(Inbound::Segment(_, _, data))) = parse_datagram(&datagram, true) else {
+                    panic!("the stack sent something other than a segment");
+                };
+                if !data.is_empty() {
+                    lens.push(data.len());
+                }
+            }
+
+            lens
+        }
+    }
+
+    // The peer never acknowledges the FIN of a close, which would wait for it
+    impl Drop for Jumbo {
+        fn drop(&mut self) {
+            let _ = self.stream.abort();
+        }
+    }
+
+    fn jumbo_pair() -> (PipeDevice, PipeDevice) {
+        let cfg = LinkConfig {
+            mtu: JUMBO,
+            ..LinkConfig::default()
+        };
+
+        PipeDevice::pair(cfg).unwrap()
+    }
+
+    fn mss_option(tcph: &TcpHeaderSlice) -> Option<u16> {
+        tcph.options_iterator().find_map(|option| match option {
+            Ok(TcpOptionElement::MaximumSegmentSize(mss)) => Some(mss),
+            _ => None,
+        })
+    }
+
+    fn builder(seq: u32) -> PacketBuilderStep<TcpHeader> {
+        PacketBuilder::ipv4(PEER.octets(), STACK.octets(), 64).tcp(PEER_PORT, PORT, seq, u16::MAX)
+    }
+
+    fn send(peer: &mut PipeDevice, builder: PacketBuilderStep<TcpHeader>, data: &[u8]) {
+        let mut buf = Vec::with_capacity(builder.size(data.len()));
+        builder.write(&mut buf, data).unwrap();
+
+        peer.send(&buf).unwrap();
+    }
+
+    // The next datagram the stack sends, which must not take long
+    fn recv(peer: &mut PipeDevice) -> Vec<u8> {
+        let mut pfd = [PollFd::new(peer.raw_fd(), PollFlags::POLLIN)];
+        assert_eq!(poll(&mut pfd, 5000).unwrap(), 1, "the stack sent nothing");
+
+        let mut buf = vec![0; JUMBO + 1];
+        let n = peer.recv(&mut buf).unwrap();
+        buf.truncate(n);
+
+        buf
+    }
+
+    #[test]
+    fn datagrams_up_to_the_mtu_are_received_whole() {
+        let mut jumbo = Jumbo::open(JUMBO_MSS);
+
+        let data: Vec<u8> = (0..JUMBO_MSS as usize).map(|i| i as u8).collect();
+        let segment = builder(PEER_ISS + 1).ack(jumbo.nxt).psh();
+        send(&mut jumbo.peer, segment, &data);
+
+        // A truncated datagram would be dropped, and never acknowledged
+        let acked = PEER_ISS + 1 + data.len() as u32;
+        loop {
+            let datagram = recv(&mut jumbo.peer);
+            let Ok(Some(Inbound::Segment(_, tcph, _))) = parse_datagram(&datagram, true) else {
+                continue;
+            };
+
+            if tcph.ack() && tcph.acknowledgment_number() == acked {
+                break;
+            }
+        }
+
+        let mut buf = vec![0; data.len()];
+        jumbo.stream.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, data);
+    }
+
+    #[test]
+    fn segments_fill_jumbo_frames() {
+        let mut jumbo = Jumbo::open(JUMBO_MSS);
+
+        let len = 2 * JUMBO_MSS as usize;
+        jumbo.stream.write_all(&vec![0; len]).unwrap();
+
+        let lens = jumbo.segments(len);
+        assert_eq!(lens, [JUMBO_MSS as usize; 2]);
+    }
+
+    #[test]
+    fn segments_are_clamped_to_the_mss_of_the_peer() {
+        let mut jumbo = Jumbo::open(1460);
+
+        let len = 4 * 1460;
+        jumbo.stream.write_all(&vec![0; len]).unwrap();
+
+        let lens = jumbo.segments(len);
+        assert!(lens.iter().all(|&len| len <= 1460));
+        assert_eq!(lens.iter().max(), Some(&1460));
+    }
+
+    #[test]
+    fn syns_announce_the_mss_of_the_device() {
+        let (device, mut peer) = jumbo_pair();
+        let mut stack = NetStack::with_device("pipe0", device, STACK, MASK).unwrap();
+
+        let _connecting = stack.connect_start(PEER, PORT).unwrap();
+
+        let syn = recv(&mut peer);
+        let Ok(Some(Inbound::Segment(_, tcph, _))) = parse_datagram(&syn, true) else {
+            panic!("the stack sent something other than a segment");
+        };
+        assert!(tcph.syn() && !tcph.ack());
+        assert_eq!(mss_option(&tcph), Some(JUMBO_MSS));
+    }
+
+    #[test]
+    fn syn_acks_announce_the_mss_of_the_device() {
+        let jumbo = Jumbo::open(1460);
+
+        // Whatever the peer announced itself
+        assert_eq!(jumbo.announced, Some(JUMBO_MSS));
     }
 }
@@ -0,0 +1,178 @@
+use std::io;
+use std::os::fd::RawFd;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::Device;
+
+/*
+What happens to the datagrams sent through a NetemDevice, each drawn on its
+own for every datagram:
+
+    loss        The datagram is dropped.
+    duplicate   The datagram is sent twice.
+    corrupt     A random bit of the datagram is flipped.
+    reorder     The datagram skips the delay, overtaking those held back.
+    delay       The datagram is held back this long...
+    jitter      ...give or take up to this long, drawn uniformly.
+
+Probabilities range from 0 to 1. Like netem, datagrams held back keep their
+order, so reordering only takes effect along with a delay.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Impairments {
+    pub loss: f64,
+    pub duplicate: f64,
+    pub corrupt: f64,
+    pub reorder: f64,
+    pub delay: Duration,
+    pub jitter: Duration,
+}
+
+// Changes the impairments of a NetemDevice while the stack runs on it
+#[derive(Debug, Clone)]
+pub struct NetemHandle {
+    impairments: Arc<Mutex<Impairments>>,
+}
+
+impl NetemHandle {
+    pub fn set(&self, impairments: Impairments) {
+        *self.impairments.lock().unwrap() = impairments;
+    }
+
+    pub fn get(&self) -> Impairments {
+        *self.impairments.lock().unwrap()
+    }
+}
+
+/*
+Sits between the stack and a device, impairing what the stack sends, e.g.
+to exercise retransmissions and congestion control. Only the sending side
+is impaired; the receiving side is impaired by emulating the device of the
+peer. Draws are made by a seeded generator, so the same seed and the same
+traffic impair the same datagrams on every run.
+
+Segmentation offload is not passed through, so that each segment is
+impaired on its own.
+*/
+#[derive(Debug)]
+pub struct NetemDevice {
+    inner: Arc<Mutex<Box<dyn Device>>>, // Shared with the thread sending what is held back
+    fd: RawFd,
+    tx: Sender<(Instant, Vec<u8>)>,
+    rng: StdRng,
+    impairments: Arc<Mutex<Impairments>>,
+}
+
+impl NetemDevice {
+    pub fn new<D: Device + 'static>(inner: D, impairments: Impairments, seed: u64) -> Self {
+        let fd = inner.raw_fd();
+        let inner: Arc<Mutex<Box<dyn Device>>> = Arc::new(Mutex::new(Box::new(inner)));
+
+        let (tx, queue) = mpsc::channel::<(Instant, Vec<u8>)>();
+        let out = inner.clone();
+
+        // Whatever is still held back is sent before the thread stops
+        thread::spawn(move || {
+            for (due, datagram) in queue {
+                if let Some(left) = due.checked_duration_since(Instant::now()) {
+                    thread::sleep(left);
+                }
+
+                let mut out = out.lock().unwrap();
+                let _ = out.send(&datagram);
+                let _ = out.flush();
+            }
+        });
+
+        NetemDevice {
+            inner,
+            fd,
+            tx,
+            rng: StdRng::seed_from_u64(seed),
+            impairments: Arc::new(Mutex::new(impairments)),
+        }
+    }
+
+    pub fn handle(&self) -> NetemHandle {
+        NetemHandle {
+            impairments: self.impairments.clone(),
+        }
+    }
+
+    fn hits(&mut self, prob: f64) -> bool {
+        prob > 0.0 && self.rng.gen::<f64>() < prob
+    }
+
+    fn delay(&mut self, impairments: &Impairments) -> Duration {
+        if impairments.jitter.is_zero() {
+            return impairments.delay;
+        }
+
+        let jitter = impairments.jitter.as_nanos() as i128;
+        let offset = self.rng.gen_range(-jitter..=jitter);
+        let delay = (impairments.delay.as_nanos() as i128 + offset).max(0);
+
+        Duration::from_nanos(delay as u64)
+    }
+}
+
+impl Device for NetemDevice {
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.lock().unwrap().recv(buf)
+    }
+
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let impairments = *self.impairments.lock().unwrap();
+
+        if self.hits(impairments.loss) {
+            println!("\t\t\t!!!Datagram is dropped by netem!!!");
+
+            return Ok(buf.len());
+        }
+
+        let copies = if self.hits(impairments.duplicate) {
+            2
+        } else {
+            1
+        };
+
+        for _ in 0..copies {
+            let mut datagram = buf.to_vec();
+
+            if !datagram.is_empty() && self.hits(impairments.corrupt) {
+                let bit = self.rng.gen_range(0..datagram.len() * 8);
+                datagram[bit / 8] ^= 1 << (bit % 8);
+            }
+
+            let delay = self.delay(&impairments);
+
+            if delay.is_zero() || self.hits(impairments.reorder) {
+                self.inner.lock().unwrap().send(&datagram)?;
+            } else {
+                self.tx
+                    .send((Instant::now() + delay, datagram))
+                    .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+            }
+        }
+
+        Ok(buf.len())
+    }
+
+    fn mtu(&self) -> io::Result<usize> {
+        self.inner.lock().unwrap().mtu()
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.lock().unwrap().flush()
+    }
+
+    fn raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
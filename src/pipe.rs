@@ -0,0 +1,118 @@
+use std::io;
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::net::UnixDatagram;
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::Device;
+
+/*
+Impairments of one direction of a pipe. Losses are drawn from a seeded
+generator, so the same seed drops the same datagrams on every run.
+*/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LinkConfig {
+    pub loss: f64,
+    pub latency: Duration,
+    pub seed: u64,
+    pub mtu: usize,
+}
+
+impl Default for LinkConfig {
+    fn default() -> Self {
+        LinkConfig {
+            loss: 0.0,
+            latency: Duration::ZERO,
+            seed: 0,
+            mtu: 1500,
+        }
+    }
+}
+
+/*
+One end of an in-memory point to point link: what one end sends, the other
+receives. Each direction is carried by a thread that holds datagrams back
+for the configured latency, which also keeps them in order.
+*/
+#[derive(Debug)]
+pub struct PipeDevice {
+    rx: UnixDatagram,
+    tx: Sender<(Instant, Vec<u8>)>,
+    rng: StdRng,
+    cfg: LinkConfig,
+}
+
+impl PipeDevice {
+    pub fn pair(cfg: LinkConfig) -> io::Result<(PipeDevice, PipeDevice)> {
+        let (a, b) = UnixDatagram::pair()?;
+
+        let a = PipeDevice::new(a, cfg, cfg.seed)?;
+        let b = PipeDevice::new(b, cfg, cfg.seed.wrapping_add(1))?;
+
+        Ok((a, b))
+    }
+
+    fn new(sock: UnixDatagram, cfg: LinkConfig, seed: u64) -> io::Result<PipeDevice> {
+        // Sending on either end of the socket pair delivers to the other one
+        let out = sock.try_clone()?;
+
+        let (tx, queue) = mpsc::channel::<(Instant, Vec<u8>)>();
+
+        thread::spawn(move || {
+            for (due, datagram) in queue {
+                if let Some(left) = due.checked_duration_since(Instant::now()) {
+                    thread::sleep(left);
+                }
+
+                let _ = out.send(&datagram);
+            }
+        });
+
+        Ok(PipeDevice {
+            rx: sock,
+            tx,
+            rng: StdRng::seed_from_u64(seed),
+            cfg,
+        })
+    }
+}
+
+impl Device for PipeDevice {
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.rx.recv(buf)
+    }
+
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() > self.cfg.mtu {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "datagram exceeds the MTU",
+            ));
+        }
+
+        if self.cfg.loss > 0.0 && self.rng.gen::<f64>() < self.cfg.loss {
+            println!("\t\t\t!!!Datagram is dropped by the pipe!!!");
+
+            return Ok(buf.len());
+        }
+
+        let due = Instant::now() + self.cfg.latency;
+        self.tx
+            .send((due, buf.to_vec()))
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+
+        Ok(buf.len())
+    }
+
+    fn mtu(&self) -> io::Result<usize> {
+        Ok(self.cfg.mtu)
+    }
+
+    fn raw_fd(&self) -> RawFd {
+        self.rx.as_raw_fd()
+    }
+}
@@ -0,0 +1,50 @@
+use std::fmt::Display;
+use std::net::{IpAddr, Ipv4Addr, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::Error;
+
+pub const CONNECT_ATTEMPT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/*
+Like std's ToSocketAddrs, but only yields IPv4 addresses since that is all
+the stack speaks. Host names are looked up with the resolver of the system.
+*/
+pub trait ToIpv4Addrs: Display {
+    fn to_ipv4_addrs(&self) -> Result<Vec<Ipv4Addr>, Error>;
+}
+
+impl ToIpv4Addrs for Ipv4Addr {
+    fn to_ipv4_addrs(&self) -> Result<Vec<Ipv4Addr>, Error> {
+        Ok(vec![*self])
+    }
+}
+
+impl ToIpv4Addrs for str {
+    fn to_ipv4_addrs(&self) -> Result<Vec<Ipv4Addr>, Error> {
+        if let Ok(addr) = self.parse() {
+            return Ok(vec![addr]);
+        }
+
+        let addrs: Vec<_> = (self, 0)
+            .to_socket_addrs()
+            .map_err(|_| Error::UnresolvedHost(self.to_string()))?
+            .filter_map(|addr| match addr.ip() {
+                IpAddr::V4(addr) => Some(addr),
+                IpAddr::V6(_) => None,
+            })
+            .collect();
+
+        if addrs.is_empty() {
+            return Err(Error::UnresolvedHost(self.to_string()));
+        }
+
+        Ok(addrs)
+    }
+}
+
+impl ToIpv4Addrs for String {
+    fn to_ipv4_addrs(&self) -> Result<Vec<Ipv4Addr>, Error> {
+        self.as_str().to_ipv4_addrs()
+    }
+}
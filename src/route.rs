@@ -0,0 +1,85 @@
+use std::net::Ipv4Addr;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Interface {
+    pub name: String,
+    pub addr: Ipv4Addr,
+    pub mask: Ipv4Addr,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Route {
+    pub dst: Ipv4Addr,
+    pub mask: Ipv4Addr,
+    pub gateway: Option<Ipv4Addr>, // None if the destination is on-link
+    pub iface: usize,
+}
+
+impl Route {
+    fn prefix_len(&self) -> u32 {
+        u32::from(self.mask).count_ones()
+    }
+
+    fn matches(&self, addr: Ipv4Addr) -> bool {
+        let mask = u32::from(self.mask);
+
+        u32::from(addr) & mask == u32::from(self.dst) & mask
+    }
+
+    // The address the next datagram towards `addr` is handed to
+    pub fn next_hop(&self, addr: Ipv4Addr) -> Ipv4Addr {
+        self.gateway.unwrap_or(addr)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RoutingTable {
+    interfaces: Vec<Interface>,
+    routes: Vec<Route>,
+}
+
+impl RoutingTable {
+    /*
+    Adding an interface also installs the connected route for its subnet.
+    */
+    pub fn add_interface(&mut self, iface: Interface) -> usize {
+        let idx = self.interfaces.len();
+
+        self.routes.push(Route {
+            dst: iface.addr,
+            mask: iface.mask,
+            gateway: None,
+            iface: idx,
+        });
+        self.interfaces.push(iface);
+
+        idx
+    }
+
+    pub fn add_route(&mut self, route: Route) {
+        self.routes
+            .retain(|r| !(r.dst == route.dst && r.mask == route.mask));
+
+        self.routes.push(route);
+    }
+
+    pub fn interface(&self, idx: usize) -> &Interface {
+        &self.interfaces[idx]
+    }
+
+    pub fn interfaces(&self) -> &[Interface] {
+        &self.interfaces
+    }
+
+    pub fn iface_of(&self, local: Ipv4Addr) -> Option<usize> {
+        self.interfaces.iter().position(|iface| iface.addr == local)
+    }
+
+    // Longest prefix match
+    pub fn lookup(&self, addr: Ipv4Addr) -> Option<&Route> {
+        self.routes
+            .iter()
+            .filter(|route| route.matches(addr))
+            .max_by_key(|route| route.prefix_len())
+    }
+}
@@ -0,0 +1,117 @@
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::{kick, notify_ready, EstabElement, EstabEntry, Manager, Quad, Ready};
+
+/*
+Shuts the stack down from any thread, e.g. from one waiting for a signal
+while the main thread is blocked accepting or in NetStack::join.
+*/
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle {
+    pub(crate) manager: Arc<Mutex<Manager>>,
+}
+
+impl ShutdownHandle {
+    /*
+    Closes every listener, then closes every connection gracefully and
+    gives the peers up to `linger` to acknowledge our FINs. Whatever has
+    not been closed by then is reset. The stack then stops and releases its
+    devices, which takes a TUN interface down with it. Streams and
+    listeners that are still held fail from then on.
+    */
+    pub fn shutdown(&self, linger: Duration) {
+        shutdown(&self.manager, linger);
+    }
+}
+
+pub(crate) fn shutdown(shared: &Arc<Mutex<Manager>>, linger: Duration) {
+    let deadline = Instant::now() + linger;
+
+    let mut manager = shared.lock().unwrap();
+
+    // Whoever came first carries it out
+    if manager.closing {
+        return;
+    }
+    manager.closing = true;
+
+    // Listeners and connects in progress find their port gone
+    let entries: Vec<EstabEntry> = manager.established.drain().map(|(_, e)| e).collect();
+    manager.bounded.clear();
+
+    for EstabEntry { cvar, elts, .. } in entries {
+        for EstabElement { quad, .. } in elts {
+            let Some(entry) = manager.streams.remove(&quad) else {
+                continue;
+            };
+            let mut entry = entry.lock().unwrap();
+
+            if entry.tcb.abort() {
+                manager.aborted.push(entry.tcb.clone());
+            }
+        }
+
+        cvar.notify_all();
+    }
+
+    let half_open: Vec<_> = manager.pending.drain().map(|(_, tcb)| tcb).collect();
+    for mut tcb in half_open {
+        if tcb.abort() {
+            manager.aborted.push(tcb);
+        }
+    }
+
+    println!("Shutting down {} connections", manager.streams.len());
+
+    let quads: Vec<Quad> = manager.streams.keys().copied().collect();
+    for quad in quads {
+        let mut entry = manager.streams[&quad].lock().unwrap();
+
+        if !entry.tcb.write_closed.swap(true, Ordering::AcqRel) {
+            entry.tcb.close();
+        }
+
+        // Writers blocked on a full buffer fail right away
+        entry.notify(Ready::WRITE);
+        drop(entry);
+
+        kick(&mut manager, quad);
+    }
+
+    notify_ready(&mut manager);
+
+    let readiness = manager.readiness.clone();
+    loop {
+        let closing = manager
+            .streams
+            .values()
+            .any(|entry| entry.lock().unwrap().tcb.is_fin_pending());
+
+        let now = Instant::now();
+        if !closing || now >= deadline {
+            break;
+        }
+
+        manager = readiness.wait_timeout(manager, deadline - now).unwrap().0;
+    }
+
+    // Without the stack running, nobody would ever answer the peers again
+    let streams: Vec<_> = manager.streams.drain().map(|(_, entry)| entry).collect();
+    for entry in streams {
+        let mut entry = entry.lock().unwrap();
+
+        let send_reset = entry.tcb.abort();
+        entry.delete(Ready::ALL);
+
+        if send_reset {
+            manager.aborted.push(entry.tcb.clone());
+        }
+    }
+
+    // The timer loop sends the resets, releases the devices and stops
+    manager.shut_down.store(true, Ordering::Release);
+    notify_ready(&mut manager);
+    manager.doorbell.ring();
+}
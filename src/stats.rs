@@ -0,0 +1,70 @@
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub ip_bad_header: u64,
+    pub ip_bad_length: u64,
+    pub ip_bad_checksum: u64,
+    pub tcp_bad_header: u64,
+    pub tcp_bad_checksum: u64,
+    pub unknown_quad: u64,  // Segments for neither a connection nor a listener
+    pub invalid_state: u64, // Segments the state of their connection does not allow
+    pub out_of_window: u64, // Segments that were not acceptable to their connection
+    pub listen_overflows: u64,
+    pub listen_filtered: u64,
+    pub conn_table_full: u64,   // New connections refused for want of room
+    pub peer_limited: u64,      // New connections refused for a peer holding too many
+    pub syn_rate_limited: u64,  // SYNs dropped for arriving faster than allowed
+    pub time_wait_evicted: u64, // Connections deleted before TIME-WAIT was over
+    pub syn_received_expired: u64, // Handshakes abandoned by the peer
+    pub device_errors: u64,     // Receives, sends and flushes the devices failed
+    pub poll_errors: u64,       // Waits of the loops on their devices and timers that failed
+    pub worker_failures: u64,   // Connections failed for their processing panicking
+    pub ingress_hook_dropped: u64, // Datagrams received that the ingress hook dropped
+    pub egress_hook_dropped: u64, // Datagrams the egress hook kept from being sent
+}
+
+// Why a datagram received was dropped, for those that make no sense to the stack
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropReason {
+    IpBadHeader,
+    IpBadLength,
+    IpBadChecksum,
+    TcpBadHeader,
+    TcpBadChecksum,
+    UnknownQuad,
+    InvalidState,
+    OutOfWindow,
+}
+
+impl fmt::Display for DropReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DropReason::IpBadHeader => "bad IPv4 header",
+            DropReason::IpBadLength => "bad IPv4 total length",
+            DropReason::IpBadChecksum => "bad IPv4 checksum",
+            DropReason::TcpBadHeader => "bad TCP header",
+            DropReason::TcpBadChecksum => "bad TCP checksum",
+            DropReason::UnknownQuad => "no connection or listener",
+            DropReason::InvalidState => "not allowed in the state of the connection",
+            DropReason::OutOfWindow => "not acceptable to the connection",
+        })
+    }
+}
+
+impl Stats {
+    pub(crate) fn count(&mut self, reason: DropReason) {
+        let counter = match reason {
+            DropReason::IpBadHeader => &mut self.ip_bad_header,
+            DropReason::IpBadLength => &mut self.ip_bad_length,
+            DropReason::IpBadChecksum => &mut self.ip_bad_checksum,
+            DropReason::TcpBadHeader => &mut self.tcp_bad_header,
+            DropReason::TcpBadChecksum => &mut self.tcp_bad_checksum,
+            DropReason::UnknownQuad => &mut self.unknown_quad,
+            DropReason::InvalidState => &mut self.invalid_state,
+            DropReason::OutOfWindow => &mut self.out_of_window,
+        };
+
+        *counter += 1;
+    }
+}
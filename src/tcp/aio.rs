@@ -0,0 +1,278 @@
+use std::future::Future;
+use std::io::{self, Read, Write};
+#[cfg(feature = "hyper")]
+use std::mem::MaybeUninit;
+use std::pin::Pin;
+use std::sync::atomic::Ordering;
+use std::task::{Context, Poll};
+
+use futures_core::Stream;
+use futures_io::{AsyncRead, AsyncWrite};
+
+use crate::{Error, Manager, StreamEntry};
+
+use super::connect::Connecting;
+use super::listen::TcpListener;
+use super::stream::TcpStream;
+
+/*
+Tasks waiting to accept or connect leave their waker with the manager, and
+every such waker is woken whenever any connection may have become ready, the
+same way selectors are. A woken task polls again and goes back to sleep if
+it was not its connection that changed. Tasks waiting on a stream park on
+that connection alone.
+*/
+fn park(manager: &mut Manager, cx: &Context<'_>) {
+    if !manager.wakers.iter().any(|w| w.will_wake(cx.waker())) {
+        manager.wakers.push(cx.waker().clone());
+    }
+}
+
+// Completes once the handshake has, e.g. `netstack.connect_start(addr, port)?.await`
+impl Future for Connecting {
+    type Output = Result<TcpStream, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+
+        let mut manager = this.manager.lock().unwrap();
+
+        let connecting = manager
+            .established
+            .get(&this.quad.src.port)
+            .is_some_and(|entry| entry.elts.is_empty() && entry.error.is_none());
+        if !this.done && connecting {
+            park(&mut manager, cx);
+
+            return Poll::Pending;
+        }
+
+        drop(manager);
+
+        match this.try_complete() {
+            Ok(Some(stream)) => Poll::Ready(Ok(stream)),
+            Ok(None) => {
+                cx.waker().wake_by_ref();
+
+                Poll::Pending
+            }
+            Err(err) => Poll::Ready(Err(err)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct AsyncTcpListener {
+    listener: TcpListener,
+}
+
+impl From<TcpListener> for AsyncTcpListener {
+    fn from(listener: TcpListener) -> Self {
+        AsyncTcpListener { listener }
+    }
+}
+
+impl AsyncTcpListener {
+    pub async fn accept(&self) -> Result<AsyncTcpStream, Error> {
+        Accept { listener: self }.await
+    }
+
+    // Ends once the listener has been closed
+    pub fn incoming(&self) -> Incoming<'_> {
+        Incoming { listener: self }
+    }
+
+    pub fn close(&self) {
+        self.listener.close();
+    }
+
+    fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<Result<AsyncTcpStream, Error>> {
+        let mut manager = self.listener.manager.lock().unwrap();
+
+        // Checking and parking under the same lock, so no wakeup is missed
+        let waiting = self
+            .listener
+            .entry(&mut manager)
+            .map(|entry| entry.elts.is_empty());
+        if waiting == Some(true) {
+            park(&mut manager, cx);
+
+            return Poll::Pending;
+        }
+
+        drop(manager);
+
+        // Another clone of the listener may have taken the connection since
+        match self.listener.try_accept() {
+            Ok(Some(stream)) => Poll::Ready(Ok(stream.into())),
+            Ok(None) => {
+                cx.waker().wake_by_ref();
+
+                Poll::Pending
+            }
+            Err(err) => Poll::Ready(Err(err)),
+        }
+    }
+}
+
+struct Accept<'a> {
+    listener: &'a AsyncTcpListener,
+}
+
+impl Future for Accept<'_> {
+    type Output = Result<AsyncTcpStream, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.listener.poll_accept(cx)
+    }
+}
+
+#[derive(Debug)]
+pub struct Incoming<'a> {
+    listener: &'a AsyncTcpListener,
+}
+
+impl Stream for Incoming<'_> {
+    type Item = io::Result<AsyncTcpStream>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.listener.poll_accept(cx) {
+            Poll::Ready(Ok(stream)) => Poll::Ready(Some(Ok(stream))),
+            Poll::Ready(Err(Error::PortClosed(_))) => Poll::Ready(None),
+            Poll::Ready(Err(err)) => Poll::Ready(Some(Err(err.into()))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/*
+Reads and writes are handed to the blocking stream only once they cannot
+block anymore. Dropping the stream without closing it first sends our FIN
+and returns right away; the stack lingers until the FIN has been
+acknowledged and removes the connection then, so no task is blocked.
+*/
+#[derive(Debug)]
+pub struct AsyncTcpStream {
+    stream: TcpStream,
+}
+
+impl From<TcpStream> for AsyncTcpStream {
+    fn from(stream: TcpStream) -> Self {
+        AsyncTcpStream { stream }
+    }
+}
+
+impl AsyncTcpStream {
+    fn is_failed(&self) -> bool {
+        self.stream.reset.load(Ordering::Acquire)
+    }
+
+    // Parks the task unless `ready` holds or the connection is gone
+    fn poll_ready(
+        &self,
+        cx: &mut Context<'_>,
+        ready: impl Fn(&TcpStream, &StreamEntry) -> bool,
+    ) -> Poll<()> {
+        let mut entry = self.stream.entry.lock().unwrap();
+
+        if self.is_failed() || entry.deleted || ready(&self.stream, &entry) {
+            return Poll::Ready(());
+        }
+
+        entry.notifiers.park(cx.waker());
+
+        Poll::Pending
+    }
+}
+
+impl AsyncTcpStream {
+    fn poll_readable(&self, cx: &mut Context<'_>) -> Poll<()> {
+        self.poll_ready(cx, |stream, entry| {
+            stream.read_closed.load(Ordering::Acquire) || entry.tcb.is_readable()
+        })
+    }
+
+    /*
+    Like poll_read, but into a buffer that may be uninitialized. Only the
+    part that is read into is initialized, which is as much as has been
+    received once the stream is readable.
+    */
+    #[cfg(feature = "hyper")]
+    pub(crate) fn poll_read_uninit(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut [MaybeUninit<u8>],
+    ) -> Poll<io::Result<usize>> {
+        if self.poll_readable(cx).is_pending() {
+            return Poll::Pending;
+        }
+
+        let received = self.stream.entry.lock().unwrap().tcb.incoming.len();
+        let buf = &mut buf[..received.min(buf.len())];
+        buf.fill(MaybeUninit::new(0));
+        let buf = unsafe { &mut *(buf as *mut [MaybeUninit<u8>] as *mut [u8]) };
+
+        Poll::Ready((&self.stream).read(buf))
+    }
+}
+
+impl Drop for AsyncTcpStream {
+    fn drop(&mut self) {
+        self.stream.detach();
+    }
+}
+
+impl AsyncRead for AsyncTcpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.poll_readable(cx) {
+            Poll::Ready(()) => Poll::Ready((&self.stream).read(buf)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl AsyncWrite for AsyncTcpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let writable = |stream: &TcpStream, entry: &StreamEntry| {
+            stream.write_closed.load(Ordering::Acquire) || !entry.tcb.is_outgoing_full()
+        };
+
+        match self.poll_ready(cx, writable) {
+            Poll::Ready(()) => Poll::Ready((&self.stream).write(buf)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let flushed = |_: &TcpStream, entry: &StreamEntry| entry.tcb.outgoing.is_empty();
+
+        match self.poll_ready(cx, flushed) {
+            Poll::Ready(()) => Poll::Ready((&self.stream).flush()),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    // Sends our FIN and completes once it has been acknowledged
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.stream.close_write();
+
+        let fin_acked = |_: &TcpStream, entry: &StreamEntry| !entry.tcb.is_fin_pending();
+
+        match self.poll_ready(cx, fin_acked) {
+            Poll::Ready(()) if self.is_failed() => Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::ConnectionReset,
+                "Connection has been reset",
+            ))),
+            Poll::Ready(()) => Poll::Ready(Ok(())),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
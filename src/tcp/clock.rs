@@ -0,0 +1,87 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/*
+Where connections and the loops of the stack take the time from. Every
+timer, like the retransmission timeout, the zero window probe or TIME-WAIT,
+is an instant of the clock, so a clock that only moves when told to lets
+them be run through without waiting on any of them.
+*/
+pub trait Clock: Send + Sync + fmt::Debug {
+    fn now(&self) -> Instant;
+
+    /*
+    Clocks that only move when told to call wake whenever they do, and return
+    true. The stack then waits on them instead of sleeping until its next
+    timer is due.
+    */
+    fn on_advance(&self, wake: Box<dyn Fn() + Send + Sync>) -> bool {
+        let _ = wake;
+
+        false
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/*
+A clock that stands still until it is advanced, e.g. past a retransmission
+timeout in a test. Clones share the same time.
+*/
+#[derive(Clone)]
+pub struct MockClock {
+    now: Arc<Mutex<Instant>>,
+    wakers: Arc<Mutex<Vec<Box<dyn Fn() + Send + Sync>>>>,
+}
+
+impl MockClock {
+    // Starts at the current instant, from which on it only moves when advanced
+    pub fn new() -> Self {
+        MockClock {
+            now: Arc::new(Mutex::new(Instant::now())),
+            wakers: Arc::default(),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+
+        for wake in self.wakers.lock().unwrap().iter() {
+            wake();
+        }
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for MockClock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MockClock")
+            .field("now", &*self.now.lock().unwrap())
+            .finish()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+
+    fn on_advance(&self, wake: Box<dyn Fn() + Send + Sync>) -> bool {
+        self.wakers.lock().unwrap().push(wake);
+
+        true
+    }
+}
@@ -0,0 +1,171 @@
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use crate::{remove_stream, Error, EstabElement, EstabEntry, Manager};
+
+use super::stream::TcpStream;
+use super::{Quad, Ready};
+
+/*
+A connection whose SYN is out but whose handshake may not have completed
+yet. Dropping the handle before it has yielded its stream gives up on the
+connection.
+*/
+#[derive(Debug)]
+pub struct Connecting {
+    pub(crate) manager: Arc<Mutex<Manager>>,
+    pub(crate) quad: Quad,
+    pub(crate) cvar: Arc<Condvar>,
+    pub(crate) done: bool, // The stream has been handed out or the attempt failed
+}
+
+impl Connecting {
+    // The quad the connection will have, whose source port is taken already
+    pub fn quad(&self) -> Quad {
+        self.quad
+    }
+
+    // Returns None instead of blocking if the handshake is still in progress
+    pub fn try_complete(&mut self) -> Result<Option<TcpStream>, Error> {
+        self.complete(Some(Duration::ZERO))
+    }
+
+    /*
+    Gives up waiting after `timeout` and returns None, while the handshake
+    carries on in the background.
+    */
+    pub fn wait(&mut self, timeout: Duration) -> Result<Option<TcpStream>, Error> {
+        self.complete(Some(timeout))
+    }
+
+    pub(crate) fn complete(
+        &mut self,
+        timeout: Option<Duration>,
+    ) -> Result<Option<TcpStream>, Error> {
+        let port = self.quad.src.port;
+
+        if self.done {
+            return Err(Error::PortClosed(port));
+        }
+
+        let connecting = |manager: &mut Manager| {
+            self.entry(manager).is_some_and(|entry| {
+                !entry.elts.iter().any(|elt| elt.quad == self.quad) && entry.error.is_none()
+            })
+        };
+
+        // Wait for it to reach established state or fail
+        let manager = self.manager.lock().unwrap();
+        let mut manager = match timeout {
+            None => self.cvar.wait_while(manager, connecting).unwrap(),
+            Some(timeout) => {
+                self.cvar
+                    .wait_timeout_while(manager, timeout, connecting)
+                    .unwrap()
+                    .0
+            }
+        };
+
+        if connecting(&mut manager) {
+            return Ok(None);
+        }
+
+        self.done = true;
+
+        if let Some(err) = self
+            .entry(&mut manager)
+            .and_then(|entry| entry.error.take())
+        {
+            manager.established.remove(&port);
+            manager.bounded.remove(&port);
+
+            return Err(err);
+        }
+
+        // The port stays taken until the connection is gone, which it may be already
+        let establisheds = self.entry(&mut manager).ok_or(Error::PortClosed(port))?;
+
+        let EstabElement {
+            quad,
+            entry,
+            rvar,
+            wvar,
+            svar,
+            r2,
+            r2_syn,
+            write_closed,
+            read_closed,
+            reset,
+            error,
+        } = establisheds
+            .elts
+            .iter()
+            .position(|elt| elt.quad == self.quad)
+            .and_then(|idx| establisheds.elts.remove(idx))
+            .ok_or(Error::PortClosed(port))?;
+
+        Ok(Some(TcpStream {
+            manager: self.manager.clone(),
+            entry,
+            quad,
+            rvar,
+            wvar,
+            svar,
+            r2,
+            r2_syn,
+            write_closed,
+            read_closed,
+            reset,
+            error,
+        }))
+    }
+}
+
+impl Connecting {
+    // The port may have been let go of with the connection and taken by another since
+    fn entry<'a>(&self, manager: &'a mut Manager) -> Option<&'a mut EstabEntry> {
+        manager
+            .established
+            .get_mut(&self.quad.src.port)
+            .filter(|entry| Arc::ptr_eq(&entry.cvar, &self.cvar))
+    }
+}
+
+impl Drop for Connecting {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+
+        let mut manager = self.manager.lock().unwrap();
+        let port = self.quad.src.port;
+
+        manager.pending.remove(&self.quad);
+
+        if self.entry(&mut manager).is_none() {
+            return;
+        }
+        manager.bounded.remove(&port);
+
+        let Some(entry) = manager.established.remove(&port) else {
+            return;
+        };
+
+        // The handshake may have completed since it was last waited for
+        for EstabElement { quad, .. } in entry.elts {
+            let Some(entry) = remove_stream(&mut manager, &quad) else {
+                continue;
+            };
+            let mut entry = entry.lock().unwrap();
+
+            if entry.tcb.abort() {
+                manager.aborted.push(entry.tcb.clone());
+            }
+
+            // Keeps the workers from processing what is already queued for it
+            entry.delete(Ready::ALL);
+        }
+
+        manager.doorbell.ring();
+    }
+}
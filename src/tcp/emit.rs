@@ -0,0 +1,101 @@
+use std::cmp;
+use std::io::{self, IoSlice};
+
+use etherparse::{Ipv4HeaderSlice, TcpHeaderSlice};
+
+use crate::Device;
+
+/*
+Where a connection puts the segments it sends. The state machine never
+touches a device itself, so it runs the same against a device, a queue
+drained by another thread, or a test looking at what was sent.
+*/
+pub trait Emitter {
+    // The slices make up a single datagram
+    fn emit(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize>;
+
+    // Emitters that take super-segments, which are cut into segments of mss octets
+    fn gso_max_size(&self) -> Option<usize> {
+        None
+    }
+
+    fn emit_gso(&mut self, bufs: &[IoSlice<'_>], mss: u16) -> io::Result<usize> {
+        let _ = (bufs, mss);
+
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+impl<D: Device + ?Sized> Emitter for D {
+    fn emit(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        self.send_vectored(bufs)
+    }
+
+    fn gso_max_size(&self) -> Option<usize> {
+        Device::gso_max_size(self)
+    }
+
+    fn emit_gso(&mut self, bufs: &[IoSlice<'_>], mss: u16) -> io::Result<usize> {
+        self.send_gso(bufs, mss)
+    }
+}
+
+/*
+Counts the segments, and the octets of data, that go through to inner. The
+headers are expected in the first slice, as every segment the connection
+writes has them. A super-segment counts as the segments it is cut into.
+*/
+pub(crate) struct Counting<'a, E: Emitter + ?Sized> {
+    inner: &'a mut E,
+    pub(crate) segments: u64,
+    pub(crate) bytes: u64,
+}
+
+impl<'a, E: Emitter + ?Sized> Counting<'a, E> {
+    pub(crate) fn new(inner: &'a mut E) -> Self {
+        Counting {
+            inner,
+            segments: 0,
+            bytes: 0,
+        }
+    }
+
+    fn data_len(bufs: &[IoSlice<'_>]) -> usize {
+        let total: usize = bufs.iter().map(|b| b.len()).sum();
+        let Some(first) = bufs.first() else { return 0 };
+
+        let Ok(ip4h) = Ipv4HeaderSlice::from_slice(first) else {
+            return total;
+        };
+        let Ok(tcph) = TcpHeaderSlice::from_slice(&first[ip4h.slice().len()..]) else {
+            return total;
+        };
+
+        total.saturating_sub(ip4h.slice().len() + tcph.slice().len())
+    }
+}
+
+impl<E: Emitter + ?Sized> Emitter for Counting<'_, E> {
+    fn emit(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        let n = self.inner.emit(bufs)?;
+
+        self.segments += 1;
+        self.bytes += Self::data_len(bufs) as u64;
+
+        Ok(n)
+    }
+
+    fn gso_max_size(&self) -> Option<usize> {
+        self.inner.gso_max_size()
+    }
+
+    fn emit_gso(&mut self, bufs: &[IoSlice<'_>], mss: u16) -> io::Result<usize> {
+        let n = self.inner.emit_gso(bufs, mss)?;
+
+        let len = Self::data_len(bufs);
+        self.segments += cmp::max(len.div_ceil(cmp::max(mss, 1) as usize), 1) as u64;
+        self.bytes += len as u64;
+
+        Ok(n)
+    }
+}
@@ -0,0 +1,14 @@
+use hashbrown::HashMap;
+
+use super::Quad;
+
+// Of hashbrown rather than std, whose maps can be looked up by a hash computed beforehand
+pub type QuadMap<V> = HashMap<Quad, V, QuadState>;
+
+/*
+Keys the hashers of a map keyed by quads with random keys of its own, so that
+a peer cannot pick ports that all land in the same bucket, while being a lot
+cheaper than SipHash on every received segment. Maps that share a state hash
+a quad the same, so it is hashed once for all of them.
+*/
+pub type QuadState = ahash::RandomState;
@@ -1,59 +1,209 @@
-use std::io::{Cursor, Write};
+use std::io::{self, IoSlice};
 
 use etherparse::{Ipv4Header, Ipv4HeaderSlice, TcpHeader, TcpHeaderSlice, TcpOptionElement};
-use tidy_tuntap::Tun;
 
-use super::Quad;
+use super::{Emitter, Quad};
 
-// const FAIL_PROB: f64 = 0.5;
+pub const DEFAULT_TTL: u8 = 32;
 
-fn write(ip4h: &Ipv4Header, tcph: &TcpHeader, data: &[u8], tun: &mut Tun) {
-    // // Drop the segment randomly
-    // if rand::random::<f64>() < FAIL_PROB {
-    //     println!("\t\t\t!!!Segment is dropped!!!");
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpOpts {
+    pub ttl: u8,
+    pub tos: u8,
+}
+
+impl Default for IpOpts {
+    fn default() -> Self {
+        IpOpts {
+            ttl: DEFAULT_TTL,
+            tos: 0,
+        }
+    }
+}
+
+/*
+The TOS octet is split into the 6 bit DSCP and the 2 bit ECN fields.
+*/
+fn ipv4_header(payload_len: u16, opts: IpOpts, src: [u8; 4], dst: [u8; 4]) -> Ipv4Header {
+    let mut ip4h = Ipv4Header::new(payload_len, opts.ttl, 6, src, dst);
+
+    ip4h.differentiated_services_code_point = opts.tos >> 2;
+    ip4h.explicit_congestion_notification = opts.tos & 0b11;
+
+    ip4h
+}
+
+// Options make up at most 40 octets of each header
+const MAX_HEADERS_LEN: usize = 60 + 60;
+
+fn sum16(data: &[u8]) -> u32 {
+    let mut chunks = data.chunks_exact(2);
+    let mut sum = chunks
+        .by_ref()
+        .map(|c| u16::from_be_bytes([c[0], c[1]]) as u32)
+        .sum::<u32>();
+    if let [last] = chunks.remainder() {
+        sum += (*last as u32) << 8;
+    }
+
+    sum
+}
+
+fn fold(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    sum as u16
+}
+
+/*
+        RFC 9293 - S3.1 Header Format
+
+The checksum field is the 16-bit ones' complement of the ones' complement
+sum of all 16-bit words in the header and text. The checksum computation
+needs to ensure the 16-bit alignment of the data being summed. If a segment
+contains an odd number of header and text octets, alignment can be achieved
+by padding the last octet with zeros on its right to form a 16-bit word for
+checksum purposes.
+
+The data is summed one slice at a time. The sum is independent of byte
+order (RFC 1071), so a slice starting at an odd offset is summed as if it
+were aligned and its sum is byte-swapped. The checksum field of the header
+must still be zero.
+*/
+fn checksum(ip4h: &Ipv4Header, tcph: &TcpHeader, data: &[&[u8]]) -> u16 {
+    let mut hdr = Vec::with_capacity(tcph.header_len() as usize);
+    tcph.write(&mut hdr).unwrap();
+
+    let tcp_len = hdr.len() + data.iter().map(|d| d.len()).sum::<usize>();
+
+    let mut sum = sum16(&ip4h.source) + sum16(&ip4h.destination) + 6 + tcp_len as u32;
+    sum += sum16(&hdr);
+    sum += sum_data(hdr.len(), data.iter().copied());
+
+    !fold(sum)
+}
+
+fn sum_data<'a>(mut offset: usize, data: impl Iterator<Item = &'a [u8]>) -> u32 {
+    let mut sum = 0;
+
+    for d in data {
+        let part = fold(sum16(d));
+        let part = if offset % 2 == 0 {
+            part
+        } else {
+            part.swap_bytes()
+        };
 
-    //     return;
-    // }
+        sum += part as u32;
+        offset += d.len();
+    }
+
+    sum
+}
+
+fn headers(ip4h: &Ipv4Header, tcph: &TcpHeader) -> ([u8; MAX_HEADERS_LEN], usize) {
+    let mut hdrs = [0u8; MAX_HEADERS_LEN];
 
-    let mut cursor = Cursor::new([0u8; 1500]);
+    let mut cursor = &mut hdrs[..];
     ip4h.write(&mut cursor).unwrap();
     tcph.write(&mut cursor).unwrap();
-    cursor.write(data).unwrap();
+    let len = MAX_HEADERS_LEN - cursor.len();
+
+    (hdrs, len)
+}
+
+fn write(
+    ip4h: &Ipv4Header,
+    tcph: &TcpHeader,
+    data: &[&[u8]],
+    out: &mut (impl Emitter + ?Sized),
+) -> io::Result<()> {
+    let (hdrs, len) = headers(ip4h, tcph);
 
-    let buf = cursor.get_ref();
-    let pos = cursor.position() as usize;
+    // The payload is gathered by the device straight from the send buffer
+    let mut iov = Vec::with_capacity(data.len() + 1);
+    iov.push(IoSlice::new(&hdrs[..len]));
+    iov.extend(data.iter().map(|d| IoSlice::new(d)));
 
-    tun.write(&buf[..pos]).unwrap();
+    out.emit(&iov).map(drop)
 }
 
-pub fn write_reset(ip4h: &Ipv4HeaderSlice, tcph: &TcpHeaderSlice, data: &[u8], tun: &mut Tun) {
+/*
+A control segment that cannot be sent is as good as lost on the way, and is
+recovered from the same way, so the error is only reported. Segments that
+carry data return theirs, for the connection to account for.
+*/
+fn send(res: io::Result<()>) {
+    if let Err(err) = res {
+        println!("\t\t\t!!!Failed to send segment: {}!!!", err);
+    }
+}
+
+pub fn write_reset(
+    ip4h: &Ipv4HeaderSlice,
+    tcph: &TcpHeaderSlice,
+    data: &[u8],
+    opts: IpOpts,
+    out: &mut (impl Emitter + ?Sized),
+) {
     let sqno = if tcph.ack() {
         tcph.acknowledgment_number()
     } else {
         0
     };
 
-    let ackno = tcph.sequence_number() + data.len() as u32 + if tcph.syn() { 1 } else { 0 };
+    let ackno = tcph
+        .sequence_number()
+        .wrapping_add(data.len() as u32)
+        .wrapping_add(if tcph.syn() { 1 } else { 0 });
 
     let mut tcph = TcpHeader::new(tcph.destination_port(), tcph.source_port(), sqno, 1024);
 
-    let ip4h = Ipv4Header::new(tcph.header_len(), 32, 6, ip4h.destination(), ip4h.source());
+    let ip4h = ipv4_header(tcph.header_len(), opts, ip4h.destination(), ip4h.source());
 
     tcph.ack = true;
     tcph.rst = true;
     tcph.acknowledgment_number = ackno;
     tcph.checksum = tcph.calc_checksum_ipv4(&ip4h, &[]).unwrap();
 
-    write(&ip4h, &tcph, &[], tun);
+    send(write(&ip4h, &tcph, &[], out));
+}
+
+pub fn write_rst(quad: &Quad, sqno: u32, opts: IpOpts, out: &mut (impl Emitter + ?Sized)) {
+    let mut tcph = TcpHeader::new(quad.src.port, quad.dst.port, sqno, 0);
+
+    let ip4h = ipv4_header(
+        tcph.header_len(),
+        opts,
+        quad.src.ipv4.octets(),
+        quad.dst.ipv4.octets(),
+    );
+
+    tcph.rst = true;
+    tcph.checksum = tcph.calc_checksum_ipv4(&ip4h, &[]).unwrap();
+
+    send(write(&ip4h, &tcph, &[], out));
 }
 
-pub fn write_synack(quad: &Quad, sqno: u32, ackno: u32, wnd: u16, tun: &mut Tun) {
+pub fn write_synack(
+    quad: &Quad,
+    sqno: u32,
+    ackno: u32,
+    wnd: u16,
+    mss: u16,
+    opts: IpOpts,
+    out: &mut (impl Emitter + ?Sized),
+) {
     let mut tcph = TcpHeader::new(quad.src.port, quad.dst.port, sqno, 1024);
+    tcph.set_options(&[TcpOptionElement::MaximumSegmentSize(mss)])
+        .unwrap();
 
-    let ip4h = Ipv4Header::new(
+    let ip4h = ipv4_header(
         tcph.header_len(),
-        32,
-        6,
+        opts,
         quad.src.ipv4.octets(),
         quad.dst.ipv4.octets(),
     );
@@ -64,16 +214,22 @@ pub fn write_synack(quad: &Quad, sqno: u32, ackno: u32, wnd: u16, tun: &mut Tun)
     tcph.window_size = wnd;
     tcph.checksum = tcph.calc_checksum_ipv4(&ip4h, &[]).unwrap();
 
-    write(&ip4h, &tcph, &[], tun);
+    send(write(&ip4h, &tcph, &[], out));
 }
 
-pub fn write_ack(quad: &Quad, sqno: u32, ackno: u32, wnd: u16, tun: &mut Tun) {
+pub fn write_ack(
+    quad: &Quad,
+    sqno: u32,
+    ackno: u32,
+    wnd: u16,
+    opts: IpOpts,
+    out: &mut (impl Emitter + ?Sized),
+) {
     let mut tcph = TcpHeader::new(quad.src.port, quad.dst.port, sqno, 1024);
 
-    let ip4h = Ipv4Header::new(
+    let ip4h = ipv4_header(
         tcph.header_len(),
-        32,
-        6,
+        opts,
         quad.src.ipv4.octets(),
         quad.dst.ipv4.octets(),
     );
@@ -83,7 +239,7 @@ pub fn write_ack(quad: &Quad, sqno: u32, ackno: u32, wnd: u16, tun: &mut Tun) {
     tcph.window_size = wnd;
     tcph.checksum = tcph.calc_checksum_ipv4(&ip4h, &[]).unwrap();
 
-    write(&ip4h, &tcph, &[], tun);
+    send(write(&ip4h, &tcph, &[], out));
 }
 
 pub fn write_data(
@@ -91,13 +247,14 @@ pub fn write_data(
     sqno: u32,
     ackno: u32,
     wnd: u16,
-    tun: &mut Tun,
-    data: &[u8],
+    opts: IpOpts,
+    out: &mut (impl Emitter + ?Sized),
+    data: &[&[u8]],
     fin: bool,
     syn: bool,
     ack: bool,
     mss: Option<u16>,
-) {
+) -> io::Result<()> {
     let mut tcph = TcpHeader::new(quad.src.port, quad.dst.port, sqno, wnd);
 
     if let Some(mss) = mss {
@@ -105,10 +262,11 @@ pub fn write_data(
             .unwrap();
     }
 
-    let ip4h = Ipv4Header::new(
-        tcph.header_len() + data.len() as u16,
-        32,
-        6,
+    let data_len = data.iter().map(|d| d.len()).sum::<usize>();
+
+    let ip4h = ipv4_header(
+        tcph.header_len() + data_len as u16,
+        opts,
         quad.src.ipv4.octets(),
         quad.dst.ipv4.octets(),
     );
@@ -118,7 +276,153 @@ pub fn write_data(
     tcph.window_size = wnd;
     tcph.fin = fin;
     tcph.syn = syn;
-    tcph.checksum = tcph.calc_checksum_ipv4(&ip4h, data).unwrap();
+    tcph.checksum = checksum(&ip4h, &tcph, data);
+
+    write(&ip4h, &tcph, data, out)
+}
+
+/*
+Writes a segment carrying more than SMSS octets of data, which the device
+cuts into segments of at most mss octets.
+*/
+#[allow(clippy::too_many_arguments)]
+pub fn write_gso_data(
+    quad: Quad,
+    sqno: u32,
+    ackno: u32,
+    wnd: u16,
+    opts: IpOpts,
+    out: &mut (impl Emitter + ?Sized),
+    data: &[&[u8]],
+    fin: bool,
+    mss: u16,
+) -> io::Result<()> {
+    let mut tcph = TcpHeader::new(quad.src.port, quad.dst.port, sqno, wnd);
+
+    let data_len = data.iter().map(|d| d.len()).sum::<usize>();
+
+    let ip4h = ipv4_header(
+        tcph.header_len() + data_len as u16,
+        opts,
+        quad.src.ipv4.octets(),
+        quad.dst.ipv4.octets(),
+    );
+
+    tcph.ack = true;
+    tcph.acknowledgment_number = ackno;
+    tcph.fin = fin;
+    tcph.checksum = checksum(&ip4h, &tcph, data);
 
-    write(&ip4h, &tcph, data, tun);
+    let (hdrs, len) = headers(&ip4h, &tcph);
+
+    let mut iov = Vec::with_capacity(data.len() + 1);
+    iov.push(IoSlice::new(&hdrs[..len]));
+    iov.extend(data.iter().map(|d| IoSlice::new(d)));
+
+    out.emit_gso(&iov, mss).map(drop)
+}
+
+// The most data slices a segment written from a template is gathered from
+pub const MAX_IOV: usize = 16;
+
+const TEMPLATE_LEN: usize = 40;
+
+/*
+The IPv4 and TCP headers of the segments of a connection that carry no
+options, serialized once. Only the fields that differ from segment to
+segment are patched in before each write, and the data is gathered straight
+from the send buffer when it lies in at most MAX_IOV slices. Data in more
+slices is left to write_data, which collects them in a Vec, and an emitter
+that queues datagrams rather than sending them, like the outbox of a
+worker, still copies each into a buffer of its own.
+*/
+#[derive(Debug, Clone)]
+pub struct HeaderTemplate {
+    opts: IpOpts,
+    hdrs: [u8; TEMPLATE_LEN],
+}
+
+impl HeaderTemplate {
+    pub fn new(quad: &Quad, opts: IpOpts) -> Self {
+        let tcph = TcpHeader::new(quad.src.port, quad.dst.port, 0, 0);
+
+        let ip4h = ipv4_header(
+            tcph.header_len(),
+            opts,
+            quad.src.ipv4.octets(),
+            quad.dst.ipv4.octets(),
+        );
+
+        let (buf, len) = headers(&ip4h, &tcph);
+        assert_eq!(len, TEMPLATE_LEN);
+
+        let mut hdrs = [0u8; TEMPLATE_LEN];
+        hdrs.copy_from_slice(&buf[..len]);
+
+        HeaderTemplate { opts, hdrs }
+    }
+
+    pub fn opts(&self) -> IpOpts {
+        self.opts
+    }
+
+    /*
+    Writes an ACK segment carrying the data, cut by the device into segments
+    of at most gso octets if given. Returns false without writing anything
+    if the data lies in more than MAX_IOV slices, and the error of the
+    emitter if it failed to send the segment.
+    */
+    #[allow(clippy::too_many_arguments)]
+    pub fn write<'a>(
+        &mut self,
+        sqno: u32,
+        ackno: u32,
+        wnd: u16,
+        fin: bool,
+        data: impl Iterator<Item = &'a [u8]> + Clone,
+        out: &mut (impl Emitter + ?Sized),
+        gso: Option<u16>,
+    ) -> io::Result<bool> {
+        let mut iov = [IoSlice::new(&[]); MAX_IOV + 1];
+        let mut count = 1;
+        let mut data_len = 0;
+
+        for d in data.clone() {
+            if count > MAX_IOV {
+                return Ok(false);
+            }
+
+            iov[count] = IoSlice::new(d);
+            count += 1;
+            data_len += d.len();
+        }
+
+        let (ip, tcp) = self.hdrs.split_at_mut(20);
+
+        ip[2..4].copy_from_slice(&((TEMPLATE_LEN + data_len) as u16).to_be_bytes());
+        ip[10..12].fill(0);
+        let ip_checksum = !fold(sum16(ip));
+        ip[10..12].copy_from_slice(&ip_checksum.to_be_bytes());
+
+        tcp[4..8].copy_from_slice(&sqno.to_be_bytes());
+        tcp[8..12].copy_from_slice(&ackno.to_be_bytes());
+        tcp[13] = 0x10 | fin as u8; // ACK, and FIN if set
+        tcp[14..16].copy_from_slice(&wnd.to_be_bytes());
+        tcp[16..18].fill(0);
+
+        let tcp_len = tcp.len() + data_len;
+        let mut sum = sum16(&ip[12..20]) + 6 + tcp_len as u32;
+        sum += sum16(tcp);
+        sum += sum_data(tcp.len(), data);
+        tcp[16..18].copy_from_slice(&(!fold(sum)).to_be_bytes());
+
+        iov[0] = IoSlice::new(&self.hdrs);
+
+        match gso {
+            Some(mss) => out.emit_gso(&iov[..count], mss)?,
+            None => out.emit(&iov[..count])?,
+        };
+
+        Ok(true)
+    }
 }
@@ -0,0 +1,42 @@
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::time::Instant;
+
+use super::Quad;
+
+/*
+        RFC 6528 - S3. Proposed Initial Sequence Number (ISN) Generation Algorithm
+
+TCP SHOULD generate its Initial Sequence Numbers with the expression:
+
+    ISN = M + F(localip, localport, remoteip, remoteport, secretkey)
+
+where M is the 4 microsecond timer, and F() is a pseudorandom function
+(PRF) of the connection-id. F() MUST NOT be computable from the outside,
+or an attacker could still guess at sequence numbers from the ISN used for
+some other connection.
+
+The hasher of a RandomState is SipHash keyed with a random secret of its own.
+*/
+#[derive(Debug)]
+pub struct IssGenerator {
+    key: RandomState,
+    epoch: Instant,
+}
+
+impl Default for IssGenerator {
+    fn default() -> Self {
+        IssGenerator {
+            key: RandomState::new(),
+            epoch: Instant::now(),
+        }
+    }
+}
+
+impl IssGenerator {
+    pub fn generate(&self, quad: &Quad) -> u32 {
+        let m = (self.epoch.elapsed().as_micros() / 4) as u32;
+
+        m.wrapping_add(self.key.hash_one(quad) as u32)
+    }
+}
@@ -0,0 +1,62 @@
+use std::time::Duration;
+
+use super::{DEFAULT_MEMORY_HARD_LIMIT, DEFAULT_MEMORY_SOFT_LIMIT};
+
+pub const DEFAULT_MAX_CONNECTIONS: usize = 65536;
+pub const DEFAULT_MAX_CONNECTIONS_PER_PEER: usize = DEFAULT_MAX_CONNECTIONS;
+pub const DEFAULT_MAX_TIME_WAIT: usize = 4096;
+pub const DEFAULT_SYN_RECEIVED_LIFETIME: Duration = Duration::from_secs(30);
+
+/*
+Bounds on what the stack keeps state for, so that peers cannot grow it
+without end. The connections of a port that are established but not
+accepted yet are bounded by its backlog.
+
+Once max_connections are kept, including those still in the handshake, the
+connection that entered TIME-WAIT first is evicted to make room for a new
+one. Without any in TIME-WAIT, a new connection is refused: its SYN is
+handled according to the overflow policy of the port, and connect fails
+with TooManyConnections. Beyond max_time_wait connections in TIME-WAIT, the
+one that entered it first is evicted as well.
+
+A SYN from a peer that holds max_connections_per_peer connections already,
+established or still in the handshake, is handled according to the
+overflow policy of the port as well. Peers are told apart by their address
+only, and are not limited beyond max_connections by default.
+
+With syn_rate_limit, SYNs that would open a connection are let through at
+most at that rate per second, in bursts of up to the second number, and
+dropped beyond it, whatever port or peer they are for. A flood then costs
+little more than reading it.
+
+A connection opened by a SYN that is still in SYN-RECEIVED after
+syn_received_lifetime is dropped, whatever retransmissions of the SYN,ACK
+it has left.
+
+The octets held in the buffers of all the connections are kept within
+memory_soft_limit and memory_hard_limit as described in MemoryPool.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub max_connections: usize,
+    pub max_connections_per_peer: usize,
+    pub max_time_wait: usize,
+    pub syn_received_lifetime: Duration,
+    pub memory_soft_limit: usize,
+    pub memory_hard_limit: usize,
+    pub syn_rate_limit: Option<(u64, u64)>, // Rate and burst
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            max_connections_per_peer: DEFAULT_MAX_CONNECTIONS_PER_PEER,
+            max_time_wait: DEFAULT_MAX_TIME_WAIT,
+            syn_received_lifetime: DEFAULT_SYN_RECEIVED_LIFETIME,
+            memory_soft_limit: DEFAULT_MEMORY_SOFT_LIMIT,
+            memory_hard_limit: DEFAULT_MEMORY_HARD_LIMIT,
+            syn_rate_limit: None,
+        }
+    }
+}
@@ -1,36 +1,205 @@
+use std::fmt;
+use std::net::{Ipv4Addr, SocketAddrV4};
 use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
 
-use crate::{Error, EstabElement, Manager};
+use crate::{notify_ready, remove_stream, Error, EstabElement, EstabEntry, Manager};
 
 use super::stream::TcpStream;
+use super::{Quad, Ready};
 
-#[derive(Debug)]
+pub const DEFAULT_BACKLOG: usize = 128;
+
+// What happens to a SYN that arrives while the backlog of its port is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    Drop,  // Ignore it, so the peer retransmits it later
+    Reset, // Refuse the connection
+}
+
+// What an accept filter decides to do with a SYN from a given peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Accept,
+    Ignore, // Drop the SYN silently
+    Reset,  // Refuse the connection
+}
+
+pub struct Filter(pub(crate) Box<dyn Fn(&SocketAddrV4) -> Verdict + Send>);
+
+impl fmt::Debug for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Filter")
+    }
+}
+
+/*
+Clones of a listener share the port, which stays bound until the last of
+them is dropped or any of them is closed. Any number of threads may accept
+on it at once.
+*/
+#[derive(Debug, Clone)]
 pub struct TcpListener {
+    pub(crate) port: u16,
+    pub(crate) addr: Ipv4Addr, // Unspecified if bound at every local address
+    pub(crate) manager: Arc<Mutex<Manager>>,
+    pub(crate) cvar: Arc<Condvar>,
+    pub(crate) binding: Arc<Binding>, // Unbinds the port once the last clone is gone
+}
+
+#[derive(Debug)]
+pub struct Binding {
     pub(crate) port: u16,
     pub(crate) manager: Arc<Mutex<Manager>>,
     pub(crate) cvar: Arc<Condvar>,
 }
 
 impl TcpListener {
+    // What the listener was bound to, 0.0.0.0 standing for every local address
+    pub fn local_addr(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(self.addr, self.port)
+    }
+
     pub fn accept(&self) -> Result<TcpStream, Error> {
+        let manager = self.manager.lock().unwrap();
+
+        let mut manager = self
+            .cvar
+            .wait_while(manager, |manager| {
+                self.entry(manager)
+                    .is_some_and(|entry| entry.elts.is_empty())
+            })
+            .unwrap();
+
+        self.take(&mut manager)
+    }
+
+    // Returns None instead of blocking if no connection is waiting to be accepted
+    pub fn try_accept(&self) -> Result<Option<TcpStream>, Error> {
+        let mut manager = self.manager.lock().unwrap();
+
+        let establisheds = self
+            .entry(&mut manager)
+            .ok_or(Error::PortClosed(self.port))?;
+
+        if establisheds.elts.is_empty() {
+            return Ok(None);
+        }
+
+        self.take(&mut manager).map(Some)
+    }
+
+    /*
+    Gives up after `timeout` and returns None, so that a server can check
+    whether it should keep on accepting.
+    */
+    pub fn accept_timeout(&self, timeout: Duration) -> Result<Option<TcpStream>, Error> {
+        let manager = self.manager.lock().unwrap();
+
+        let (mut manager, _) = self
+            .cvar
+            .wait_timeout_while(manager, timeout, |manager| {
+                self.entry(manager)
+                    .is_some_and(|entry| entry.elts.is_empty())
+            })
+            .unwrap();
+
+        let establisheds = self
+            .entry(&mut manager)
+            .ok_or(Error::PortClosed(self.port))?;
+
+        if establisheds.elts.is_empty() {
+            return Ok(None);
+        }
+
+        self.take(&mut manager).map(Some)
+    }
+
+    /*
+    Stops completing handshakes for new connections until resume is called.
+    Their SYNs are dropped or refused as if the backlog was full, while
+    connections already in progress or waiting to be accepted are kept.
+    */
+    pub fn pause(&self) -> Result<(), Error> {
+        self.set_paused(true)
+    }
+
+    pub fn resume(&self) -> Result<(), Error> {
+        self.set_paused(false)
+    }
+
+    pub fn is_paused(&self) -> bool {
         let mut manager = self.manager.lock().unwrap();
 
-        if manager.established[&self.port].elts.is_empty() {
-            manager = self
-                .cvar
-                .wait_while(manager, |manager| {
-                    manager.established[&self.port].elts.is_empty()
+        self.entry(&mut manager).is_some_and(|entry| entry.paused)
+    }
+
+    /*
+    Unbinds the port for all clones of the listener. Threads blocked in
+    accept return PortClosed, and connections of the port that have not
+    been accepted yet are reset.
+    */
+    pub fn close(&self) {
+        self.binding.unbind();
+    }
+
+    /*
+    Accepts on `workers` threads, each of them running `handler` on the
+    connections it accepts one after the other. Returns once the listener
+    has been closed and every worker is done with its last connection.
+    */
+    pub fn serve<F>(&self, workers: usize, handler: F)
+    where
+        F: Fn(TcpStream) + Send + Sync + 'static,
+    {
+        let handler = Arc::new(handler);
+
+        let workers: Vec<_> = (0..workers.max(1))
+            .map(|_| {
+                let listener = self.clone();
+                let handler = handler.clone();
+
+                thread::spawn(move || {
+                    while let Ok(stream) = listener.accept() {
+                        handler(stream);
+                    }
                 })
-                .unwrap();
+            })
+            .collect();
+
+        for worker in workers {
+            if worker.join().is_err() {
+                println!("A worker serving port {} has panicked", self.port);
+            }
         }
+    }
+
+    fn set_paused(&self, paused: bool) -> Result<(), Error> {
+        let mut manager = self.manager.lock().unwrap();
+
+        let establisheds = self
+            .entry(&mut manager)
+            .ok_or(Error::PortClosed(self.port))?;
+        establisheds.paused = paused;
 
-        let establisheds = manager
+        Ok(())
+    }
+
+    // The port may have been closed and bound again by another listener since
+    pub(crate) fn entry<'a>(&self, manager: &'a mut Manager) -> Option<&'a mut EstabEntry> {
+        manager
             .established
             .get_mut(&self.port)
-            .ok_or(Error::PortClosed(self.port))?;
+            .filter(|entry| Arc::ptr_eq(&entry.cvar, &self.cvar))
+    }
+
+    fn take(&self, manager: &mut Manager) -> Result<TcpStream, Error> {
+        let establisheds = self.entry(manager).ok_or(Error::PortClosed(self.port))?;
 
         let EstabElement {
             quad,
+            entry,
             rvar,
             wvar,
             svar,
@@ -39,10 +208,17 @@ impl TcpListener {
             write_closed,
             read_closed,
             reset,
-        } = establisheds.elts.pop().unwrap();
+            error,
+        } = establisheds.elts.pop_front().unwrap();
+
+        // Hand over to the next acceptor in case the wakeup of another one was taken
+        if !establisheds.elts.is_empty() {
+            self.cvar.notify_one();
+        }
 
         Ok(TcpStream {
             manager: self.manager.clone(),
+            entry,
             quad,
             rvar,
             wvar,
@@ -52,14 +228,66 @@ impl TcpListener {
             write_closed,
             read_closed,
             reset,
+            error,
         })
     }
 }
 
-impl Drop for TcpListener {
-    fn drop(&mut self) {
+impl Binding {
+    fn unbind(&self) {
         let mut manager = self.manager.lock().unwrap();
 
-        assert!(manager.bounded.remove(&self.port));
+        let is_ours = manager
+            .established
+            .get(&self.port)
+            .is_some_and(|entry| Arc::ptr_eq(&entry.cvar, &self.cvar));
+        if !is_ours {
+            return;
+        }
+
+        let Some(entry) = manager.established.remove(&self.port) else {
+            return;
+        };
+        manager.bounded.remove(&self.port);
+
+        // Nobody holds a stream for connections that were never accepted
+        for EstabElement { quad, .. } in entry.elts {
+            let Some(entry) = remove_stream(&mut manager, &quad) else {
+                continue;
+            };
+            let mut entry = entry.lock().unwrap();
+
+            if entry.tcb.abort() {
+                manager.aborted.push(entry.tcb.clone());
+            }
+
+            // Keeps the workers from processing what is already queued for it
+            entry.delete(Ready::ALL);
+        }
+
+        let half_open: Vec<Quad> = manager
+            .pending
+            .keys()
+            .filter(|quad| quad.src.port == self.port)
+            .copied()
+            .collect();
+
+        for quad in half_open {
+            let mut tcb = manager.pending.remove(&quad).unwrap();
+
+            if tcb.abort() {
+                manager.aborted.push(tcb);
+            }
+        }
+
+        manager.doorbell.ring();
+        self.cvar.notify_all();
+        notify_ready(&mut manager);
+    }
+}
+
+impl Drop for Binding {
+    fn drop(&mut self) {
+        self.unbind();
     }
 }
@@ -0,0 +1,126 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+pub const DEFAULT_MEMORY_SOFT_LIMIT: usize = 64 << 20;
+pub const DEFAULT_MEMORY_HARD_LIMIT: usize = 128 << 20;
+
+/*
+The octets held in the send and receive buffers of all the connections of
+a stack. Below the soft limit, windows are offered whole. Between the soft
+and the hard limit, the windows offered from then on shrink in proportion
+to how close the pool is to the hard limit, and at the hard limit no window
+is opened at all. Once the hard limit is reached, writes to streams that
+still have data queued wait for it to drain.
+
+A window that has been offered is never taken back, so the data the peer
+sends within it is accepted even beyond the hard limit.
+*/
+#[derive(Debug)]
+pub struct MemoryPool {
+    used: AtomicUsize,
+    soft: AtomicUsize,
+    hard: AtomicUsize,
+}
+
+impl MemoryPool {
+    pub fn new(soft: usize, hard: usize) -> Self {
+        MemoryPool {
+            used: AtomicUsize::new(0),
+            soft: AtomicUsize::new(soft),
+            hard: AtomicUsize::new(hard.max(soft)),
+        }
+    }
+
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    pub fn set_limits(&self, soft: usize, hard: usize) {
+        self.soft.store(soft, Ordering::Relaxed);
+        self.hard.store(hard.max(soft), Ordering::Relaxed);
+    }
+
+    pub fn is_exhausted(&self) -> bool {
+        self.used() >= self.hard.load(Ordering::Relaxed)
+    }
+
+    // How much of a window of wnd octets may be offered
+    pub fn window(&self, wnd: usize) -> usize {
+        let used = self.used();
+        let soft = self.soft.load(Ordering::Relaxed);
+        let hard = self.hard.load(Ordering::Relaxed);
+
+        if used < soft {
+            wnd
+        } else if used >= hard {
+            0
+        } else {
+            (wnd as u128 * (hard - used) as u128 / (hard - soft) as u128) as usize
+        }
+    }
+
+    fn charge(&self, n: usize) {
+        self.used.fetch_add(n, Ordering::Relaxed);
+    }
+
+    fn release(&self, n: usize) {
+        self.used.fetch_sub(n, Ordering::Relaxed);
+    }
+}
+
+impl Default for MemoryPool {
+    fn default() -> Self {
+        MemoryPool::new(DEFAULT_MEMORY_SOFT_LIMIT, DEFAULT_MEMORY_HARD_LIMIT)
+    }
+}
+
+/*
+What a buffer holds of a pool. A copy of the buffer is charged on its own,
+and whatever is still held is given back when the buffer is dropped.
+*/
+#[derive(Debug, Default)]
+pub struct Charge {
+    pool: Option<Arc<MemoryPool>>,
+    held: usize,
+}
+
+impl Charge {
+    pub fn new(pool: Arc<MemoryPool>) -> Self {
+        Charge {
+            pool: Some(pool),
+            held: 0,
+        }
+    }
+
+    pub fn add(&mut self, n: usize) {
+        if let Some(pool) = &self.pool {
+            pool.charge(n);
+        }
+        self.held += n;
+    }
+
+    pub fn sub(&mut self, n: usize) {
+        if let Some(pool) = &self.pool {
+            pool.release(n);
+        }
+        self.held -= n;
+    }
+}
+
+impl Clone for Charge {
+    fn clone(&self) -> Self {
+        let mut charge = Charge {
+            pool: self.pool.clone(),
+            held: 0,
+        };
+        charge.add(self.held);
+
+        charge
+    }
+}
+
+impl Drop for Charge {
+    fn drop(&mut self) {
+        self.sub(self.held);
+    }
+}
@@ -1,9 +1,47 @@
+#[cfg(feature = "async")]
+mod aio;
+mod clock;
+mod connect;
+mod emit;
+mod hash;
 mod ioutil;
+mod iss;
+mod limits;
 mod listen;
+mod memory;
+mod notify;
+mod observe;
+mod opts;
+mod recvbuf;
+mod select;
+mod sendbuf;
+mod shaper;
+mod snapshot;
 mod stream;
+mod table;
 mod tcb;
+mod throttle;
 
+#[cfg(feature = "async")]
+pub use aio::*;
+pub use clock::*;
+pub use connect::*;
+pub use emit::*;
+pub use hash::*;
 pub use ioutil::*;
+pub use iss::*;
+pub use limits::*;
 pub use listen::*;
+pub use memory::*;
+pub use notify::*;
+pub use observe::*;
+pub use opts::*;
+pub use recvbuf::*;
+pub use select::*;
+pub use sendbuf::*;
+pub use shaper::*;
+pub use snapshot::*;
 pub use stream::*;
+pub use table::*;
 pub use tcb::*;
+pub use throttle::*;
@@ -0,0 +1,103 @@
+use std::fmt;
+use std::task::Waker;
+
+// What a connection may have become ready for
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Ready {
+    pub read: bool,
+    pub write: bool,
+    pub close: bool, // Our FIN has been acknowledged, or the connection is gone
+}
+
+impl Ready {
+    pub const READ: Ready = Ready {
+        read: true,
+        write: false,
+        close: false,
+    };
+    pub const WRITE: Ready = Ready {
+        read: false,
+        write: true,
+        close: false,
+    };
+    pub const CLOSE: Ready = Ready {
+        read: false,
+        write: false,
+        close: true,
+    };
+    pub const ALL: Ready = Ready {
+        read: true,
+        write: true,
+        close: true,
+    };
+
+    pub fn is_empty(&self) -> bool {
+        !self.read && !self.write && !self.close
+    }
+}
+
+pub type Callback = Box<dyn FnMut(Ready) + Send>;
+
+/*
+Who else is told when a connection may have become ready, besides the
+threads blocked on its condition variables. A waker is woken once and then
+dropped, while a callback stays until it is deregistered. Both run with the
+manager locked, so a callback must not call back into the stack.
+*/
+#[derive(Default)]
+pub struct Notifiers {
+    wakers: Vec<Waker>,
+    callbacks: Vec<(u64, Callback)>,
+    next_id: u64,
+}
+
+impl fmt::Debug for Notifiers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Notifiers")
+            .field("wakers", &self.wakers.len())
+            .field("callbacks", &self.callbacks.len())
+            .finish()
+    }
+}
+
+impl Notifiers {
+    // A task is woken at most once however often it parks in between
+    #[cfg(feature = "async")]
+    pub fn park(&mut self, waker: &Waker) {
+        if !self.wakers.iter().any(|w| w.will_wake(waker)) {
+            self.wakers.push(waker.clone());
+        }
+    }
+
+    // Returns the id to deregister the callback with
+    pub fn register(&mut self, callback: Callback) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.callbacks.push((id, callback));
+
+        id
+    }
+
+    // Returns whether a callback was registered under the id
+    pub fn deregister(&mut self, id: u64) -> bool {
+        let len = self.callbacks.len();
+        self.callbacks.retain(|(other, _)| *other != id);
+
+        self.callbacks.len() < len
+    }
+
+    pub fn notify(&mut self, ready: Ready) {
+        if ready.is_empty() {
+            return;
+        }
+
+        for waker in self.wakers.drain(..) {
+            waker.wake();
+        }
+
+        for (_, callback) in self.callbacks.iter_mut() {
+            callback(ready);
+        }
+    }
+}
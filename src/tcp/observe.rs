@@ -0,0 +1,122 @@
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use super::{ConnState, Quad};
+
+// Why a connection is gone
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    Graceful, // Both sides closed, and TIME-WAIT, if any, is over
+    Reset,    // The peer reset the connection
+    Refused,  // The peer refused the connection, by a reset or an ICMP error
+    TimedOut, // Retransmissions or keep-alives went unanswered for too long
+    Aborted,  // The connection was aborted on this side
+}
+
+/*
+Advice about a connection that is still alive, what RFC 9293 calls an
+asynchronous report: nothing has failed yet, but the application may want
+to act on it, e.g. by giving up on a path that keeps losing segments.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SoftError {
+    /*
+    A segment has gone unacknowledged for longer than R1, after which the
+    path to the peer is suspected to be failing. Raised once per segment,
+    elapsed being how long it has been retransmitted for.
+    */
+    RetransmitThreshold { syn: bool, elapsed: Duration },
+    // An ICMP destination unreachable, with its code, that did not end the connection
+    Unreachable { code: u8 },
+    // The peer has kept its window closed until the first probe, elapsed after it closed
+    ZeroWindow { elapsed: Duration },
+    // A retransmission turned out to be needless, the original having been acknowledged
+    SpuriousRetransmit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnEvent {
+    StateChanged {
+        quad: Quad,
+        from: ConnState,
+        to: ConnState,
+    },
+    Established {
+        quad: Quad,
+    },
+    // The peer sent a reset that was accepted
+    Reset {
+        quad: Quad,
+    },
+    SoftError {
+        quad: Quad,
+        error: SoftError,
+    },
+    Closed {
+        quad: Quad,
+        reason: CloseReason,
+    },
+}
+
+/*
+Hands the events of every connection of a stack to the callback set with
+NetStack::on_event. Events are sent over a channel to a thread of their own,
+so a callback may take its time or call back into the stack, and receives
+the events of each connection in the order they happened. Soft errors also
+go to the channel of the stack, see NetStack::soft_errors.
+*/
+#[derive(Debug, Default)]
+pub(crate) struct Observer {
+    sender: Mutex<Option<Sender<ConnEvent>>>,
+    soft_errors: Mutex<Option<Sender<(Quad, SoftError)>>>,
+}
+
+impl Observer {
+    // Replaces the callback set before, whose thread stops once it has caught up
+    pub(crate) fn set<F>(&self, mut callback: F)
+    where
+        F: FnMut(ConnEvent) + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+
+        thread::spawn(move || {
+            for event in receiver {
+                callback(event);
+            }
+        });
+
+        *self.sender.lock().unwrap() = Some(sender);
+    }
+
+    pub(crate) fn clear(&self) {
+        self.sender.lock().unwrap().take();
+    }
+
+    pub(crate) fn emit(&self, event: ConnEvent) {
+        if let Some(sender) = self.sender.lock().unwrap().as_ref() {
+            let _ = sender.send(event);
+        }
+    }
+
+    // Whoever held the receiver before stops receiving, once it has caught up
+    pub(crate) fn soft_errors(&self) -> Receiver<(Quad, SoftError)> {
+        let (sender, receiver) = mpsc::channel();
+        *self.soft_errors.lock().unwrap() = Some(sender);
+
+        receiver
+    }
+
+    pub(crate) fn advise(&self, quad: Quad, error: SoftError) {
+        self.emit(ConnEvent::SoftError { quad, error });
+
+        let mut soft_errors = self.soft_errors.lock().unwrap();
+        if soft_errors
+            .as_ref()
+            .is_some_and(|sender| sender.send((quad, error)).is_err())
+        {
+            soft_errors.take();
+        }
+    }
+}
@@ -0,0 +1,127 @@
+use std::time::Duration;
+
+use crate::{Error, CONNECT_ATTEMPT_TIMEOUT};
+
+pub const DEFAULT_RECV_BUFFER: usize = 64240;
+pub const DEFAULT_USER_TIMEOUT: Duration = Duration::from_secs(100);
+
+// Unanswered keep-alives after which the peer is considered gone
+pub const KEEPALIVE_PROBES: u32 = 9;
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Congestion {
+    Reno, // Slow start and congestion avoidance of RFC 5681
+    None, // Only the window of the peer limits what is sent
+}
+
+/*
+How the device is shared with other connections. Segments of a higher class
+are sent ahead of those of lower classes that are ready at the same time,
+so a control connection is not stuck behind bulk transfers. Within a class,
+connections share the device in proportion to their weight.
+*/
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Priority {
+    pub class: u8,
+    pub weight: u16,
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Priority {
+            class: 0,
+            weight: 1,
+        }
+    }
+}
+
+/*
+Policies of a single connection. Listeners hand theirs down to every
+connection they accept.
+*/
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpOptions {
+    pub nodelay: bool, // Send small segments without waiting for outstanding data to be acked
+    pub keepalive: Option<Duration>, // Probe the peer once the connection has been idle this long
+    pub send_buffer: Option<usize>, // Sized after the window of the peer if None
+    pub recv_buffer: usize,
+    pub user_timeout: Duration, // R2, how long data may go unacknowledged
+    pub congestion: Congestion,
+    pub priority: Priority,
+}
+
+impl Default for TcpOptions {
+    fn default() -> Self {
+        TcpOptions {
+            nodelay: true,
+            keepalive: None,
+            send_buffer: None,
+            recv_buffer: DEFAULT_RECV_BUFFER,
+            user_timeout: DEFAULT_USER_TIMEOUT,
+            congestion: Congestion::Reno,
+            priority: Priority::default(),
+        }
+    }
+}
+
+impl TcpOptions {
+    /*
+    Without window scaling, no more than 65535 octets can be advertised, so
+    a larger receive buffer would never be filled.
+    */
+    pub(crate) fn validate(&self) -> Result<(), Error> {
+        if self.recv_buffer == 0 || self.recv_buffer > u16::MAX as usize {
+            return Err(Error::InvalidBufferSize(self.recv_buffer));
+        }
+
+        if let Some(size @ 0) = self.send_buffer {
+            return Err(Error::InvalidBufferSize(size));
+        }
+
+        if let Some(idle) = self.keepalive.filter(|idle| idle.is_zero()) {
+            return Err(Error::InvalidKeepalive(idle));
+        }
+
+        if self.priority.weight == 0 {
+            return Err(Error::InvalidWeight(self.priority.weight));
+        }
+
+        Ok(())
+    }
+}
+
+/*
+How connect_with_retry spaces out whole connection attempts. Each attempt
+gets its own SYN retransmissions, and the backoff between failed attempts
+doubles up to max_backoff.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    pub attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub attempt_timeout: Option<Duration>, // Waits for the SYN retransmissions to give up if None
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 5,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            attempt_timeout: Some(CONNECT_ATTEMPT_TIMEOUT),
+        }
+    }
+}
+
+impl RetryPolicy {
+    // Backoff after the given failed attempt, counting from zero
+    pub(crate) fn backoff(&self, attempt: u32) -> Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_backoff)
+    }
+}
@@ -0,0 +1,109 @@
+use std::cmp;
+use std::sync::Arc;
+
+use super::{Charge, MemoryPool};
+
+/*
+The data received but not read yet, in a ring of fixed capacity. It lies in
+at most two contiguous slices, which segments are copied into and reads are
+copied out of directly. The capacity is exactly what has been asked for, as
+the receive window is derived from it. Only the octets held are charged to
+the memory pool, not the capacity.
+*/
+#[derive(Debug, Clone, Default)]
+pub struct RecvBuffer {
+    buf: Vec<u8>,
+    head: usize,
+    len: usize,
+    charge: Charge,
+}
+
+impl RecvBuffer {
+    pub fn new(pool: Arc<MemoryPool>) -> Self {
+        RecvBuffer {
+            charge: Charge::new(pool),
+            ..RecvBuffer::default()
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buf.len()
+    }
+
+    // Only called before anything has been received
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.buf = vec![0u8; capacity];
+        self.head = 0;
+        self.len = 0;
+    }
+
+    // The octets in the order they were received
+    pub fn as_slices(&self) -> (&[u8], &[u8]) {
+        let end = self.head + self.len;
+
+        if end <= self.buf.len() {
+            (&self.buf[self.head..end], &[])
+        } else {
+            (&self.buf[self.head..], &self.buf[..end - self.buf.len()])
+        }
+    }
+
+    // Returns how much of data fit, which is all of it within the window
+    pub fn extend_from_slice(&mut self, data: &[u8]) -> usize {
+        let cap = self.buf.len();
+        let len = cmp::min(data.len(), cap - self.len);
+        if len == 0 {
+            return 0;
+        }
+
+        let tail = (self.head + self.len) % cap;
+        let first = cmp::min(len, cap - tail);
+
+        self.buf[tail..tail + first].copy_from_slice(&data[..first]);
+        self.buf[..len - first].copy_from_slice(&data[first..len]);
+
+        self.len += len;
+        self.charge.add(len);
+
+        len
+    }
+
+    // Moves up to buf.len() octets from the front into buf
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let len = cmp::min(buf.len(), self.len);
+
+        let (front, back) = self.as_slices();
+        let first = cmp::min(len, front.len());
+
+        buf[..first].copy_from_slice(&front[..first]);
+        buf[first..len].copy_from_slice(&back[..len - first]);
+
+        self.advance(len);
+
+        len
+    }
+
+    fn advance(&mut self, n: usize) {
+        self.len -= n;
+        self.charge.sub(n);
+        self.head = if self.len == 0 {
+            0
+        } else {
+            (self.head + n) % self.buf.len()
+        };
+    }
+
+    pub fn clear(&mut self) {
+        self.charge.sub(self.len);
+        self.head = 0;
+        self.len = 0;
+    }
+}
@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::{Error, Manager};
+
+use super::listen::TcpListener;
+use super::stream::TcpStream;
+use super::Quad;
+
+// Picked by the user to tell the sources of events apart
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Token(pub usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interest {
+    pub readable: bool,
+    pub writable: bool,
+}
+
+impl Interest {
+    pub const READABLE: Interest = Interest {
+        readable: true,
+        writable: false,
+    };
+    pub const WRITABLE: Interest = Interest {
+        readable: false,
+        writable: true,
+    };
+    pub const BOTH: Interest = Interest {
+        readable: true,
+        writable: true,
+    };
+}
+
+/*
+Readiness means that the operation would not block, not that it would
+succeed: a stream that has been reset is readable and writable, and both
+fail with the reset.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Event {
+    pub token: Token,
+    pub readable: bool,
+    pub writable: bool,
+    pub acceptable: bool, // A connection is waiting to be accepted
+    pub error: bool,      // The connection has been reset or failed, or the listener closed
+}
+
+#[derive(Debug)]
+pub struct Events {
+    events: Vec<Event>,
+    capacity: usize,
+}
+
+impl Events {
+    // No more than `capacity` events are reported by a single select
+    pub fn with_capacity(capacity: usize) -> Self {
+        Events {
+            events: Vec::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Event> {
+        self.events.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+}
+
+#[derive(Debug)]
+enum Source {
+    Stream {
+        quad: Quad,
+        interest: Interest,
+        read_closed: Arc<AtomicBool>,
+        reset: Arc<AtomicBool>,
+        error: Arc<Mutex<Option<Error>>>,
+    },
+    Listener {
+        port: u16,
+        cvar: Arc<Condvar>,
+    },
+}
+
+impl Source {
+    fn event(&self, token: Token, manager: &Manager) -> Option<Event> {
+        let mut event = Event {
+            token,
+            readable: false,
+            writable: false,
+            acceptable: false,
+            error: false,
+        };
+
+        match self {
+            Source::Stream {
+                quad,
+                interest,
+                read_closed,
+                reset,
+                error,
+            } => {
+                let entry = manager.streams.get(quad).map(|entry| entry.lock().unwrap());
+                let entry = entry.as_deref();
+                let failed = reset.load(Ordering::Acquire) || error.lock().unwrap().is_some();
+
+                // Reads and writes on a deleted connection return right away
+                event.readable = interest.readable
+                    && (failed
+                        || read_closed.load(Ordering::Acquire)
+                        || entry.is_none_or(|entry| entry.tcb.is_readable()));
+                event.writable = interest.writable
+                    && (failed || entry.is_none_or(|entry| !entry.tcb.is_outgoing_full()));
+                event.error = failed;
+            }
+            Source::Listener { port, cvar } => {
+                let entry = manager
+                    .established
+                    .get(port)
+                    .filter(|entry| Arc::ptr_eq(&entry.cvar, cvar));
+
+                event.acceptable = entry.is_some_and(|entry| !entry.elts.is_empty());
+                event.error = entry.is_none();
+            }
+        }
+
+        (event.readable || event.writable || event.acceptable || event.error).then_some(event)
+    }
+}
+
+/*
+Waits on a single thread for any of the streams and listeners registered
+with it to become ready. Readiness is level-triggered, so a source is
+reported by every select until whatever made it ready has been consumed.
+All sources must belong to the stack the selector was created from.
+*/
+#[derive(Debug)]
+pub struct Selector {
+    manager: Arc<Mutex<Manager>>,
+    readiness: Arc<Condvar>,
+    sources: HashMap<Token, Source>,
+}
+
+impl Selector {
+    pub(crate) fn new(manager: Arc<Mutex<Manager>>, readiness: Arc<Condvar>) -> Self {
+        Selector {
+            manager,
+            readiness,
+            sources: HashMap::new(),
+        }
+    }
+
+    pub fn register_stream(
+        &mut self,
+        stream: &TcpStream,
+        token: Token,
+        interest: Interest,
+    ) -> Result<(), Error> {
+        self.register(
+            token,
+            Source::Stream {
+                quad: stream.quad,
+                interest,
+                read_closed: stream.read_closed.clone(),
+                reset: stream.reset.clone(),
+                error: stream.error.clone(),
+            },
+        )
+    }
+
+    pub fn register_listener(&mut self, listener: &TcpListener, token: Token) -> Result<(), Error> {
+        self.register(
+            token,
+            Source::Listener {
+                port: listener.port,
+                cvar: listener.cvar.clone(),
+            },
+        )
+    }
+
+    // Returns whether anything was registered under the token
+    pub fn deregister(&mut self, token: Token) -> bool {
+        self.sources.remove(&token).is_some()
+    }
+
+    /*
+    Blocks until at least one source is ready or `timeout` has passed, and
+    fills `events` with the ready ones, replacing what it held before.
+    */
+    pub fn select(&mut self, events: &mut Events, timeout: Option<Duration>) {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+        let mut manager = self.manager.lock().unwrap();
+
+        loop {
+            events.events.clear();
+            events.events.extend(
+                self.sources
+                    .iter()
+                    .filter_map(|(token, source)| source.event(*token, &manager))
+                    .take(events.capacity),
+            );
+
+            if !events.events.is_empty() {
+                return;
+            }
+
+            manager = match deadline {
+                None => self.readiness.wait(manager).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return;
+                    }
+
+                    self.readiness
+                        .wait_timeout(manager, deadline - now)
+                        .unwrap()
+                        .0
+                }
+            };
+        }
+    }
+
+    fn register(&mut self, token: Token, source: Source) -> Result<(), Error> {
+        if self.sources.contains_key(&token) {
+            return Err(Error::TokenInUse(token.0));
+        }
+
+        self.sources.insert(token, source);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,138 @@
+use std::cmp;
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use bytes::{Buf, Bytes, BytesMut};
+
+use super::{Charge, MemoryPool};
+
+/*
+The data written but not acknowledged yet, as a queue of reference-counted
+chunks. Buffers handed over as Bytes are queued as they are, while copied
+writes are gathered in a chunk of their own at the back. Segments, and their
+retransmissions, are cut out of the chunks without copying them.
+*/
+#[derive(Debug, Clone, Default)]
+pub struct SendBuffer {
+    chunks: VecDeque<Bytes>,
+    tail: BytesMut, // Copied writes that have not been followed by a Bytes yet
+    len: usize,
+    capacity: usize,
+    charge: Charge,
+}
+
+impl SendBuffer {
+    pub fn new(pool: Arc<MemoryPool>) -> Self {
+        SendBuffer {
+            charge: Charge::new(pool),
+            ..SendBuffer::default()
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len >= self.capacity
+    }
+
+    // How much can be written before the buffer is full
+    pub fn room(&self) -> usize {
+        self.capacity.saturating_sub(self.len)
+    }
+
+    pub fn extend_from_slice(&mut self, data: &[u8]) {
+        self.tail.extend_from_slice(data);
+        self.len += data.len();
+        self.charge.add(data.len());
+    }
+
+    pub fn push(&mut self, data: Bytes) {
+        if data.is_empty() {
+            return;
+        }
+
+        if !self.tail.is_empty() {
+            self.chunks.push_back(self.tail.split().freeze());
+        }
+
+        self.len += data.len();
+        self.charge.add(data.len());
+        self.chunks.push_back(data);
+    }
+
+    // The len octets starting at offset, as they lie in the chunks
+    pub fn range(&self, offset: usize, len: usize) -> Vec<&[u8]> {
+        self.slices(offset, len).collect()
+    }
+
+    // The same without collecting them, e.g. to gather them for a retransmission
+    pub fn slices(&self, offset: usize, len: usize) -> impl Iterator<Item = &[u8]> + Clone {
+        self.chunks
+            .iter()
+            .map(|c| &c[..])
+            .chain([&self.tail[..]])
+            .scan((offset, len), |(offset, len), chunk| {
+                if *len == 0 {
+                    return None;
+                }
+
+                if *offset >= chunk.len() {
+                    *offset -= chunk.len();
+                    return Some(&chunk[..0]);
+                }
+
+                let end = cmp::min(chunk.len(), *offset + *len);
+                let slice = &chunk[*offset..end];
+
+                *len -= end - *offset;
+                *offset = 0;
+
+                Some(slice)
+            })
+            .filter(|slice| !slice.is_empty())
+    }
+
+    // Drops the first n octets once they have been acknowledged
+    pub fn advance(&mut self, n: usize) {
+        let mut n = cmp::min(n, self.len);
+        self.len -= n;
+        self.charge.sub(n);
+
+        while n > 0 {
+            let Some(chunk) = self.chunks.front_mut() else {
+                break;
+            };
+
+            if n < chunk.len() {
+                chunk.advance(n);
+                return;
+            }
+
+            n -= chunk.len();
+            self.chunks.pop_front();
+        }
+
+        self.tail.advance(n);
+    }
+
+    pub fn clear(&mut self) {
+        self.chunks.clear();
+        self.tail.clear();
+        self.charge.sub(self.len);
+        self.len = 0;
+    }
+}
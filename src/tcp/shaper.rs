@@ -0,0 +1,66 @@
+use std::cmp;
+use std::time::{Duration, Instant};
+
+/*
+A token bucket that paces the new data of a connection. Tokens, one per
+octet, accrue at rate octets per second up to burst, and every octet sent
+for the first time takes one. Retransmissions, probes and control segments
+are not paced, so that the connection is never held up by its own shaper
+when recovering. The stack paces new handshakes with one as well, a token
+per SYN.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TokenBucket {
+    rate: u64,
+    burst: u64,
+    tokens: u64,
+    last: Instant, // When tokens was last brought up to date
+}
+
+impl TokenBucket {
+    // Starts full at now, so that the first burst goes out right away
+    pub fn new(rate: u64, burst: u64, now: Instant) -> Self {
+        TokenBucket {
+            rate,
+            burst,
+            tokens: burst,
+            last: now,
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    pub fn burst(&self) -> u64 {
+        self.burst
+    }
+
+    // How many octets may be sent at now
+    pub fn available(&self, now: Instant) -> usize {
+        let elapsed = now.saturating_duration_since(self.last).as_nanos();
+        let accrued = elapsed * self.rate as u128 / 1_000_000_000;
+
+        cmp::min(self.tokens as u128 + accrued, self.burst as u128) as usize
+    }
+
+    pub fn consume(&mut self, len: usize, now: Instant) {
+        let available = self.available(now) as u64;
+
+        self.tokens = available.saturating_sub(len as u64);
+        self.last = now;
+    }
+
+    // When len octets, or a whole burst if that is less, may be sent
+    pub fn ready_at(&self, len: usize, now: Instant) -> Instant {
+        let len = cmp::min(len as u64, self.burst);
+        let available = self.available(now) as u64;
+        if available >= len {
+            return now;
+        }
+
+        let nanos = ((len - available) as u128 * 1_000_000_000).div_ceil(self.rate as u128);
+
+        now + Duration::from_nanos(nanos as u64)
+    }
+}
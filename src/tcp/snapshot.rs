@@ -0,0 +1,219 @@
+use std::net::SocketAddrV4;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::*;
+
+/*
+The state of a connection taken out of the stack it lives in, so that it can
+be kept, compared or moved elsewhere, and restored later on. Timers are kept
+as the time left until they fire, and segments as how long ago they were
+sent, so a restored connection carries on as if no time had passed in
+between.
+
+What only makes sense within the stack is left out: a pending error is not
+kept, and the shaper is restored with a full bucket.
+*/
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TcbSnapshot {
+    pub(crate) local: SocketAddrV4,
+    pub(crate) remote: SocketAddrV4,
+    pub(crate) kind: Kind,
+    pub(crate) state: State,
+    pub(crate) reset: bool,
+    pub(crate) write_closed: bool,
+    pub(crate) read_closed: bool,
+
+    pub(crate) snd: SendSpace,
+    pub(crate) rcv: RecvSpace,
+
+    pub(crate) srtt: u128,
+    pub(crate) rttvar: u128,
+    pub(crate) rto: u128,
+    pub(crate) rtt_measured: bool,
+    pub(crate) r1: u128,
+    pub(crate) r2: u64,
+    pub(crate) r1_syn: u128,
+    pub(crate) r2_syn: u64,
+
+    pub(crate) cwnd: u32,
+    pub(crate) ssthresh: u32,
+    pub(crate) stats: ConnStats,
+
+    pub(crate) opts: TcpOptions,
+    pub(crate) keepalive_probes: u32,
+    pub(crate) ip_opts: IpOpts,
+    pub(crate) recv_tos: u8,
+
+    // Time left until each timer fires, if it is running
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) probe_timeout: Option<Duration>,
+    pub(crate) keepalive_timeout: Option<Duration>,
+    pub(crate) time_wait: Option<Duration>,
+
+    pub(crate) incoming: Vec<u8>,
+    pub(crate) recv_capacity: usize,
+    pub(crate) pushes: Vec<usize>,
+    pub(crate) records: bool,
+    pub(crate) corked: bool,
+    pub(crate) rate_limit: Option<(u64, u64)>, // Rate and burst of the shaper
+    pub(crate) deficit: usize,
+    pub(crate) outgoing: Vec<u8>,
+    pub(crate) send_capacity: usize,
+    pub(crate) segments: Vec<SegmentSnapshot>,
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SegmentSnapshot {
+    pub(crate) sno: u32,
+    pub(crate) una: u32,
+    pub(crate) len: u32,
+    pub(crate) fin: bool,
+    pub(crate) syn: bool,
+    pub(crate) ack: bool,
+    pub(crate) retry: bool,
+    pub(crate) total_ret_time: u128,
+    pub(crate) sent: Option<Duration>, // How long ago it was sent
+    pub(crate) mss: Option<u16>,
+}
+
+impl TcbSnapshot {
+    pub fn local(&self) -> SocketAddrV4 {
+        self.local
+    }
+
+    pub fn remote(&self) -> SocketAddrV4 {
+        self.remote
+    }
+
+    pub fn state(&self) -> ConnState {
+        self.state.into()
+    }
+}
+
+impl TCB {
+    pub fn snapshot(&self) -> TcbSnapshot {
+        let now = self.clock.now();
+        let left = |timer: Option<Instant>| timer.map(|at| at.saturating_duration_since(now));
+
+        let (front, back) = self.incoming.as_slices();
+        let outgoing = self.outgoing.slices(0, self.outgoing.len());
+
+        TcbSnapshot {
+            local: self.quad.local(),
+            remote: self.quad.remote(),
+            kind: self.kind,
+            state: self.state,
+            reset: self.reset.load(Ordering::Acquire),
+            write_closed: self.write_closed.load(Ordering::Acquire),
+            read_closed: self.read_closed.load(Ordering::Acquire),
+            snd: self.snd,
+            rcv: self.rcv,
+            srtt: self.srtt,
+            rttvar: self.rttvar,
+            rto: self.rto,
+            rtt_measured: self.rtt_measured,
+            r1: self.r1,
+            r2: self.r2.load(Ordering::Acquire),
+            r1_syn: self.r1_syn,
+            r2_syn: self.r2_syn.load(Ordering::Acquire),
+            cwnd: self.cwnd,
+            ssthresh: self.ssthresh,
+            stats: self.stats,
+            opts: self.opts,
+            keepalive_probes: self.keepalive_probes,
+            ip_opts: self.ip_opts,
+            recv_tos: self.recv_tos,
+            timeout: left(self.timeout),
+            probe_timeout: left(self.probe_timeout),
+            keepalive_timeout: left(self.keepalive_timeout),
+            time_wait: left(self.time_wait),
+            incoming: [front, back].concat(),
+            recv_capacity: self.incoming.capacity(),
+            pushes: self.pushes.iter().copied().collect(),
+            records: self.records,
+            corked: self.corked,
+            rate_limit: self.shaper.map(|shaper| (shaper.rate(), shaper.burst())),
+            deficit: self.deficit,
+            outgoing: outgoing.flatten().copied().collect(),
+            send_capacity: self.outgoing.capacity(),
+            segments: self.segments.iter().map(|seg| seg.snapshot(now)).collect(),
+        }
+    }
+
+    // The buffers restored are charged to memory like any others
+    pub(crate) fn restore(
+        snapshot: &TcbSnapshot,
+        ack_throttle: Arc<AckThrottle>,
+        memory: Arc<MemoryPool>,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        let now = clock.now();
+        let at = |left: Option<Duration>| left.map(|left| now + left);
+
+        let mut incoming = RecvBuffer::new(memory.clone());
+        incoming.set_capacity(snapshot.recv_capacity);
+        incoming.extend_from_slice(&snapshot.incoming);
+
+        let mut outgoing = SendBuffer::new(memory.clone());
+        outgoing.set_capacity(snapshot.send_capacity);
+        outgoing.extend_from_slice(&snapshot.outgoing);
+
+        TCB {
+            quad: Quad::from((snapshot.local, snapshot.remote)),
+            kind: snapshot.kind,
+            state: snapshot.state,
+            reset: Arc::new(AtomicBool::new(snapshot.reset)),
+            write_closed: Arc::new(AtomicBool::new(snapshot.write_closed)),
+            read_closed: Arc::new(AtomicBool::new(snapshot.read_closed)),
+            error: Arc::new(Mutex::new(None)),
+            time_wait: at(snapshot.time_wait),
+            snd: snapshot.snd,
+            rcv: snapshot.rcv,
+            srtt: snapshot.srtt,
+            rttvar: snapshot.rttvar,
+            rto: snapshot.rto,
+            rtt_measured: snapshot.rtt_measured,
+            timeout: at(snapshot.timeout),
+            r1: snapshot.r1,
+            r2: Arc::new(AtomicU64::new(snapshot.r2)),
+            r1_syn: snapshot.r1_syn,
+            r2_syn: Arc::new(AtomicU64::new(snapshot.r2_syn)),
+            cwnd: snapshot.cwnd,
+            ssthresh: snapshot.ssthresh,
+            probe_timeout: at(snapshot.probe_timeout),
+            stats: snapshot.stats,
+            dropped: None,
+            send_error: None,
+            opts: snapshot.opts,
+            keepalive_timeout: at(snapshot.keepalive_timeout),
+            keepalive_probes: snapshot.keepalive_probes,
+            ack_throttle,
+            memory,
+            clock,
+            observer: Arc::default(),
+            soft_errors: None,
+            zero_window_since: None,
+            ip_opts: snapshot.ip_opts,
+            recv_tos: snapshot.recv_tos,
+            template: None, // Built again by the next retransmission
+            incoming,
+            pushes: snapshot.pushes.iter().copied().collect(),
+            records: snapshot.records,
+            corked: snapshot.corked,
+            shaper: snapshot
+                .rate_limit
+                .map(|(rate, burst)| TokenBucket::new(rate, burst, now)),
+            deficit: snapshot.deficit,
+            outgoing,
+            segments: snapshot
+                .segments
+                .iter()
+                .map(|seg| Segment::restore(seg, now))
+                .collect(),
+        }
+    }
+}
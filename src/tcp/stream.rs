@@ -1,15 +1,26 @@
 use std::cmp;
+use std::fs::File;
 use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddrV4};
+use std::ops::Range;
+use std::os::unix::fs::FileExt;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
-use std::sync::{Arc, Condvar, Mutex};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
 
-use crate::{Error, Manager};
+use bytes::{Bytes, BytesMut};
 
-use super::Quad;
+use crate::{kick, remove_stream, Error, Manager, StreamEntry};
+
+use super::{
+    ConnContext, ConnState, ConnStats, Priority, Quad, Ready, SendBuffer, SoftError, TcpInfo,
+    TokenBucket,
+};
 
 #[derive(Debug)]
 pub struct TcpStream {
     pub(crate) manager: Arc<Mutex<Manager>>,
+    pub(crate) entry: Arc<Mutex<StreamEntry>>,
     pub(crate) quad: Quad,
     pub(crate) rvar: Arc<Condvar>,
     pub(crate) wvar: Arc<Condvar>,
@@ -19,32 +30,469 @@ pub struct TcpStream {
     pub(crate) write_closed: Arc<AtomicBool>,
     pub(crate) read_closed: Arc<AtomicBool>,
     pub(crate) reset: Arc<AtomicBool>,
+    pub(crate) error: Arc<Mutex<Option<Error>>>,
 }
 
 impl TcpStream {
+    // Only called once the connection has been deleted
+    fn closed(&self) -> Error {
+        Error::StreamClosed(ConnContext::new(&self.quad, ConnState::Closed))
+    }
+
+    /*
+    A FIN of the peer only closes its half, so data is still written in
+    CLOSE-WAIT. Once the connection has been deleted though, nothing written
+    would ever be delivered.
+    */
+    fn broken_pipe(&self) -> io::Error {
+        match self.error.lock().unwrap().take() {
+            Some(err) => err.into(),
+            None => io::Error::new(io::ErrorKind::BrokenPipe, self.closed()),
+        }
+    }
+
+    // The entry of the connection, as long as the stack keeps state for it
+    fn lock(&self) -> Result<MutexGuard<'_, StreamEntry>, Error> {
+        let entry = self.entry.lock().unwrap();
+
+        if entry.deleted {
+            return Err(self.closed());
+        }
+
+        Ok(entry)
+    }
+
+    // Has the timer loop send what has just been queued
+    fn kick(&self) {
+        kick(&mut self.manager.lock().unwrap(), self.quad);
+    }
+
     pub fn close(&mut self) {
+        self.close_write();
+
+        drop(self.wait_fin_acked());
+    }
+
+    /*
+    Shutting down the write half sends our FIN once the queued data is out,
+    but does not wait for it to be acknowledged, so the response of the
+    peer can still be read. Shutting down the read half discards whatever
+    has been received and is yet to be received.
+    */
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        let mut entry = self.lock()?;
+
+        if matches!(how, Shutdown::Read | Shutdown::Both) {
+            entry.tcb.shutdown_read();
+        }
+
+        drop(entry);
+
+        if matches!(how, Shutdown::Write | Shutdown::Both) {
+            self.close_write();
+        }
+
+        Ok(())
+    }
+
+    /*
+    Resets the connection instead of closing it gracefully. Whatever is
+    buffered in either direction is discarded and the stream is forgotten
+    right away; the reset itself is sent by the timer loop.
+    */
+    pub fn abort(&mut self) -> io::Result<()> {
         let mut manager = self.manager.lock().unwrap();
 
+        let mut entry = self.remove(&mut manager)?;
+
+        let send_reset = entry.tcb.abort();
+        entry.notify(Ready::ALL);
+
+        // Its buffers have just been cleared, so the copy is cheap
+        if send_reset {
+            manager.aborted.push(entry.tcb.clone());
+            manager.doorbell.ring();
+        }
+
+        Ok(())
+    }
+
+    // An entry that is not deleted is always in the streams of the manager
+    fn remove(&self, manager: &mut Manager) -> Result<MutexGuard<'_, StreamEntry>, Error> {
+        let mut entry = self.lock()?;
+
+        remove_stream(manager, &self.quad);
+        entry.deleted = true;
+
+        Ok(entry)
+    }
+
+    pub(crate) fn close_write(&self) {
+        if self.write_closed.load(Ordering::Acquire) {
+            return;
+        }
+
         self.write_closed.store(true, Ordering::Release);
 
-        manager.streams.get_mut(&self.quad).unwrap().tcb.close();
+        if let Ok(mut entry) = self.lock() {
+            entry.tcb.close();
+            drop(entry);
 
-        manager = self.svar.wait(manager).unwrap();
+            self.kick();
+        }
+    }
+
+    /*
+    Sends our FIN without waiting for it to be acknowledged, and leaves the
+    stream to the stack, which removes it once it has been. Dropping the
+    stream afterwards does not block.
+    */
+    pub(crate) fn detach(&self) {
+        self.close_write();
+
+        let mut manager = self.manager.lock().unwrap();
+        let Ok(mut entry) = self.lock() else { return };
 
-        drop(manager)
+        if entry.tcb.is_fin_pending() {
+            entry.detached = true;
+        } else {
+            remove_stream(&mut manager, &self.quad);
+            entry.deleted = true;
+        }
     }
 
-    pub fn set_r2(&self, r2: u64) {
-        self.r2.store(r2, Ordering::Release);
+    fn wait_fin_acked(&self) -> MutexGuard<'_, StreamEntry> {
+        self.svar
+            .wait_while(self.entry.lock().unwrap(), |entry| {
+                !entry.deleted && entry.tcb.is_fin_pending()
+            })
+            .unwrap()
     }
 
-    pub fn set_r2_syn(&self, r2: u64) {
-        self.r2_syn.store(r2, Ordering::Release);
+    pub fn set_ttl(&self, ttl: u32) -> io::Result<()> {
+        let ttl = u8::try_from(ttl).map_err(|_| Error::InvalidTtl(ttl))?;
+
+        self.lock()?.tcb.ip_opts.ttl = ttl;
+
+        Ok(())
     }
-}
 
-impl Read for TcpStream {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    pub fn ttl(&self) -> io::Result<u32> {
+        let tcb = &self.lock()?.tcb;
+
+        Ok(tcb.ip_opts.ttl as u32)
+    }
+
+    pub fn set_tos(&self, tos: u32) -> io::Result<()> {
+        let tos = u8::try_from(tos).map_err(|_| Error::InvalidTos(tos))?;
+
+        self.lock()?.tcb.ip_opts.tos = tos;
+
+        Ok(())
+    }
+
+    pub fn tos(&self) -> io::Result<u32> {
+        let tcb = &self.lock()?.tcb;
+
+        Ok(tcb.ip_opts.tos as u32)
+    }
+
+    // Clamps the send MSS, like TCP_MAXSEG
+    pub fn set_mss(&self, mss: u32) -> io::Result<()> {
+        let mss = u16::try_from(mss)
+            .ok()
+            .filter(|&mss| mss > 0)
+            .ok_or(Error::InvalidMss(mss))?;
+
+        self.lock()?.tcb.set_mss(mss);
+
+        Ok(())
+    }
+
+    pub fn mss(&self) -> io::Result<u32> {
+        let tcb = &self.lock()?.tcb;
+
+        Ok(tcb.mss() as u32)
+    }
+
+    // TOS octet of the most recently received segment
+    pub fn recv_tos(&self) -> io::Result<u32> {
+        let tcb = &self.lock()?.tcb;
+
+        Ok(tcb.recv_tos as u32)
+    }
+
+    // The addresses and ports of both ends, this one being the source
+    pub fn quad(&self) -> Quad {
+        self.quad
+    }
+
+    // The address the connection was made from or, if accepted, the one it was reached at
+    pub fn local_addr(&self) -> SocketAddrV4 {
+        self.quad.local()
+    }
+
+    pub fn peer_addr(&self) -> SocketAddrV4 {
+        self.quad.remote()
+    }
+
+    pub fn state(&self) -> ConnState {
+        self.lock()
+            .map_or(ConnState::Closed, |entry| entry.tcb.state.into())
+    }
+
+    pub fn info(&self) -> io::Result<TcpInfo> {
+        let tcb = &self.lock()?.tcb;
+
+        Ok(tcb.info())
+    }
+
+    pub fn stats(&self) -> io::Result<ConnStats> {
+        let tcb = &self.lock()?.tcb;
+
+        Ok(tcb.stats())
+    }
+
+    /*
+    The soft errors of the connection from now on, which are advice rather
+    than failures: the connection carries on whether they are received or
+    not. Whoever held the receiver before stops receiving.
+    */
+    pub fn soft_errors(&self) -> io::Result<Receiver<SoftError>> {
+        let (sender, receiver) = mpsc::channel();
+        self.lock()?.tcb.soft_errors = Some(sender);
+
+        Ok(receiver)
+    }
+
+    // See NetStack::restore
+    pub fn snapshot(&self) -> io::Result<TcbSnapshot> {
+        let tcb = &self.lock()?.tcb;
+
+        Ok(tcb.snapshot())
+    }
+
+    /*
+    While corked, only segments of full size are sent, so that several small
+    writes, like a header and a body, go out in as few segments as possible.
+    Uncorking sends whatever is left over right away.
+    */
+    pub fn cork(&self) -> io::Result<()> {
+        self.set_corked(true)
+    }
+
+    pub fn uncork(&self) -> io::Result<()> {
+        self.set_corked(false)
+    }
+
+    fn set_corked(&self, corked: bool) -> io::Result<()> {
+        self.lock()?.tcb.corked = corked;
+
+        // What was held back goes out right away
+        if !corked {
+            self.kick();
+        }
+
+        Ok(())
+    }
+
+    /*
+    Paces the data sent for the first time to rate octets per second, in
+    bursts of up to burst octets, e.g. to emulate a constrained link or to
+    spare a slow peer.
+    */
+    pub fn set_rate_limit(&self, rate: u64, burst: u64) -> io::Result<()> {
+        if rate == 0 || burst == 0 {
+            return Err(Error::InvalidRateLimit(rate, burst).into());
+        }
+
+        let tcb = &mut self.lock()?.tcb;
+        tcb.shaper = Some(TokenBucket::new(rate, burst, tcb.clock.now()));
+
+        Ok(())
+    }
+
+    pub fn clear_rate_limit(&self) -> io::Result<()> {
+        self.lock()?.tcb.shaper = None;
+
+        // What was held back goes out right away
+        self.kick();
+
+        Ok(())
+    }
+
+    // The rate and burst the stream is limited to, if any
+    pub fn rate_limit(&self) -> io::Result<Option<(u64, u64)>> {
+        let tcb = &self.lock()?.tcb;
+
+        Ok(tcb.shaper.map(|shaper| (shaper.rate(), shaper.burst())))
+    }
+
+    pub fn set_priority(&self, priority: Priority) -> io::Result<()> {
+        if priority.weight == 0 {
+            return Err(Error::InvalidWeight(priority.weight).into());
+        }
+
+        self.lock()?.tcb.opts.priority = priority;
+
+        Ok(())
+    }
+
+    pub fn priority(&self) -> io::Result<Priority> {
+        let tcb = &self.lock()?.tcb;
+
+        Ok(tcb.opts.priority)
+    }
+
+    pub fn is_corked(&self) -> io::Result<bool> {
+        let tcb = &self.lock()?.tcb;
+
+        Ok(tcb.corked)
+    }
+
+    /*
+    In record mode, a read returns no more than the octets up to the next
+    PSH received, and waits for one to arrive, so that each read returns one
+    record if the buffer is large enough. The peer decides where the PSHs
+    go, e.g. after every write.
+    */
+    pub fn set_record_mode(&self, records: bool) -> io::Result<()> {
+        let mut entry = self.lock()?;
+        entry.tcb.records = records;
+
+        // Readers blocked on a partial record may have something to read now
+        entry.notify(Ready::READ);
+
+        Ok(())
+    }
+
+    pub fn record_mode(&self) -> io::Result<bool> {
+        let tcb = &self.lock()?.tcb;
+
+        Ok(tcb.records)
+    }
+
+    /*
+    Calls `callback` whenever the connection may have become ready for
+    something, as long as the stack keeps state for it. The callback runs on
+    a thread of the stack with the stack locked, so it must return quickly
+    and must not call into the stack itself, e.g. hand the event over to
+    another thread instead.
+    */
+    pub fn on_ready(&self, callback: impl FnMut(Ready) + Send + 'static) -> io::Result<u64> {
+        let mut entry = self.lock()?;
+
+        Ok(entry.notifiers.register(Box::new(callback)))
+    }
+
+    // Returns whether a callback was registered under the id
+    pub fn remove_on_ready(&self, id: u64) -> io::Result<bool> {
+        let mut entry = self.lock()?;
+
+        Ok(entry.notifiers.deregister(id))
+    }
+
+    /*
+    A stream is readable or writable when a call to read or write would not
+    block, which includes the calls that fail or report the end of stream.
+    */
+    pub fn is_readable(&self) -> io::Result<bool> {
+        let tcb = &self.lock()?.tcb;
+
+        Ok(tcb.is_readable()
+            || self.read_closed.load(Ordering::Acquire)
+            || self.reset.load(Ordering::Acquire))
+    }
+
+    pub fn is_writable(&self) -> io::Result<bool> {
+        let tcb = &self.lock()?.tcb;
+
+        Ok(!tcb.is_outgoing_full()
+            || self.write_closed.load(Ordering::Acquire)
+            || self.reset.load(Ordering::Acquire))
+    }
+
+    /*
+    Queues the buffer itself rather than a copy of it, and the segments sent
+    are cut out of it, so the data is not copied again before it reaches the
+    device. Blocks until all of it has been queued.
+    */
+    pub fn write_bytes(&self, mut data: Bytes) -> io::Result<()> {
+        while !data.is_empty() {
+            self.queue(|outgoing, room| {
+                let chunk = data.split_to(cmp::min(data.len(), room));
+                let len = chunk.len();
+                outgoing.push(chunk);
+
+                len
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /*
+    Sends the octets of the file in `range`, reading as many whole segments
+    of it at a time as the send buffer has room for. What is read goes into
+    the send buffer without being copied again, and every read goes into
+    the same buffer, whose memory is taken back once the peer has
+    acknowledged what was read into it. Blocks until all of it has been
+    queued and returns how much that was, which falls short of the range
+    only if the file ends before it.
+    */
+    pub fn send_file(&self, file: &File, range: Range<u64>) -> io::Result<u64> {
+        let mss = self.mss()? as usize;
+        let mut offset = range.start;
+        let mut buf = BytesMut::new();
+
+        while offset < range.end {
+            // The file is read without holding up the stack
+            let room = self.wait_room()?.tcb.outgoing.room();
+            let room = if room > mss { room - room % mss } else { room };
+            let len = cmp::min(room as u64, range.end - offset) as usize;
+
+            buf.resize(len, 0);
+            let n = file.read_at(&mut buf, offset)?;
+            if n == 0 {
+                break;
+            }
+
+            buf.truncate(n);
+            self.write_bytes(buf.split().freeze())?;
+
+            offset += n as u64;
+        }
+
+        Ok(offset.saturating_sub(range.start))
+    }
+
+    // Waits for room in the send buffer and lets `queue` fill it
+    fn queue(&self, queue: impl FnOnce(&mut SendBuffer, usize) -> usize) -> io::Result<usize> {
+        let mut entry = self.wait_room()?;
+
+        let outgoing = &mut entry.tcb.outgoing;
+
+        let room = outgoing.room();
+        let len = queue(outgoing, room);
+
+        drop(entry);
+
+        if len > 0 {
+            self.kick();
+        }
+
+        Ok(len)
+    }
+
+    // The entry of the connection once its send buffer has room
+    fn wait_room(&self) -> io::Result<MutexGuard<'_, StreamEntry>> {
+        if self.write_closed.load(Ordering::Acquire) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "Write half of the stream is closed",
+            ));
+        }
+
         if self.reset.load(Ordering::Acquire) {
             return Err(io::Error::new(
                 io::ErrorKind::ConnectionReset,
@@ -52,26 +500,19 @@ impl Read for TcpStream {
             ));
         }
 
-        let mut manager = self.manager.lock().unwrap();
+        let mut entry = self.entry.lock().unwrap();
 
-        if manager
-            .streams
-            .get_mut(&self.quad)
-            .ok_or(Error::StreamClosed(self.quad.src))?
-            .tcb
-            .incoming
-            .is_empty()
-        {
-            if self.read_closed.load(Ordering::Acquire) {
-                return Ok(0);
-            }
+        if entry.deleted {
+            return Err(self.broken_pipe());
+        }
 
-            manager = self
-                .rvar
-                .wait_while(manager, |manager| {
-                    manager.streams[&self.quad].tcb.incoming.is_empty()
+        if entry.tcb.is_outgoing_full() {
+            entry = self
+                .wvar
+                .wait_while(entry, |entry| {
+                    !entry.deleted
+                        && entry.tcb.is_outgoing_full()
                         && !self.reset.load(Ordering::Acquire)
-                        && !self.read_closed.load(Ordering::Acquire)
                 })
                 .unwrap();
         }
@@ -83,30 +524,45 @@ impl Read for TcpStream {
             ));
         }
 
-        if self.read_closed.load(Ordering::Acquire) {
-            return Ok(0);
+        if entry.deleted {
+            return Err(self.broken_pipe());
         }
 
-        let len = manager
-            .streams
-            .get_mut(&self.quad)
-            .ok_or(Error::StreamClosed(self.quad.src))?
-            .tcb
-            .recv(buf);
+        Ok(entry)
+    }
+
+    // Octets written that the peer has not acknowledged yet, sent or not
+    pub fn unacked_bytes(&self) -> io::Result<usize> {
+        let tcb = &self.lock()?.tcb;
 
-        return Ok(len);
+        Ok(tcb.outgoing.len())
     }
-}
 
-impl Write for TcpStream {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        if self.write_closed.load(Ordering::Acquire) {
-            return Err(io::Error::new(
-                io::ErrorKind::NotConnected,
-                "Write half of the stream is closed",
-            ));
-        }
+    /*
+    Errors that occur outside of a call on the stream, like a reset from the
+    peer, too many retransmissions or an ICMP error, are kept until taken.
+    */
+    pub fn take_error(&self) -> io::Result<Option<io::Error>> {
+        Ok(self.error.lock().unwrap().take().map(io::Error::from))
+    }
 
+    pub fn set_r2(&self, r2: u64) {
+        self.r2.store(r2, Ordering::Release);
+    }
+
+    pub fn set_r2_syn(&self, r2: u64) {
+        self.r2_syn.store(r2, Ordering::Release);
+    }
+}
+
+/*
+A shared reference reads and writes just as well, so a stream behind an Arc
+can be read by one thread while another one writes to it. Several threads
+may then block on the same stream, which is why every wakeup reaches all
+of them and each re-checks what it waits for.
+*/
+impl Read for &TcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         if self.reset.load(Ordering::Acquire) {
             return Err(io::Error::new(
                 io::ErrorKind::ConnectionReset,
@@ -114,23 +570,17 @@ impl Write for TcpStream {
             ));
         }
 
-        let mut manager = self.manager.lock().unwrap();
+        let entry = self.entry.lock().unwrap();
 
-        if manager
-            .streams
-            .get_mut(&self.quad)
-            .ok_or(Error::StreamClosed(self.quad.src))?
-            .tcb
-            .is_outgoing_full()
-        {
-            manager = self
-                .wvar
-                .wait_while(manager, |manager| {
-                    manager.streams[&self.quad].tcb.is_outgoing_full()
-                        && !self.reset.load(Ordering::Acquire)
-                })
-                .unwrap();
-        }
+        let mut entry = self
+            .rvar
+            .wait_while(entry, |entry| {
+                !entry.deleted
+                    && !entry.tcb.is_readable()
+                    && !self.reset.load(Ordering::Acquire)
+                    && !self.read_closed.load(Ordering::Acquire)
+            })
+            .unwrap();
 
         if self.reset.load(Ordering::Acquire) {
             return Err(io::Error::new(
@@ -139,65 +589,97 @@ impl Write for TcpStream {
             ));
         }
 
-        let outgoing = &mut manager
-            .streams
-            .get_mut(&self.quad)
-            .ok_or(Error::StreamClosed(self.quad.src))?
-            .tcb
-            .outgoing;
+        /*
+        Data that arrived ahead of the FIN of the peer is read first, even
+        after the connection has been deleted. Once it has been drained, the
+        end of the stream is reported.
+        */
+        let len = entry.tcb.recv(buf);
 
-        let len = cmp::min(buf.len(), outgoing.capacity() - outgoing.len());
+        if len == 0 && entry.deleted && !self.read_closed.load(Ordering::Acquire) {
+            return Err(self.closed().into());
+        }
+
+        Ok(len)
+    }
+}
 
-        outgoing.extend(buf[..len].iter());
+impl Write for &TcpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.queue(|outgoing, room| {
+            let len = cmp::min(buf.len(), room);
+            outgoing.extend_from_slice(&buf[..len]);
 
-        return Ok(len);
+            len
+        })
     }
 
+    /*
+    Data leaves the send buffer only once it has been acknowledged, so this
+    blocks until the peer holds everything written so far, i.e. until
+    SND.UNA has caught up with the data sent.
+    */
     fn flush(&mut self) -> io::Result<()> {
-        let mut manager = self.manager.lock().unwrap();
+        let mut entry = self.lock()?;
 
-        if !manager
-            .streams
-            .get_mut(&self.quad)
-            .ok_or(Error::StreamClosed(self.quad.src))?
-            .tcb
-            .outgoing
-            .is_empty()
-        {
-            manager = self
+        if !entry.tcb.outgoing.is_empty() {
+            entry = self
                 .wvar
-                .wait_while(manager, |manager| {
-                    !manager.streams[&self.quad].tcb.outgoing.is_empty()
+                .wait_while(entry, |entry| {
+                    !entry.deleted
+                        && !entry.tcb.outgoing.is_empty()
                         && !self.reset.load(Ordering::Acquire)
                 })
                 .unwrap();
         }
 
-        drop(manager);
-
         if self.reset.load(Ordering::Acquire) {
-            Err(io::Error::new(
+            return Err(io::Error::new(
                 io::ErrorKind::ConnectionReset,
                 "Connection has been reset",
-            ))
-        } else {
-            Ok(())
+            ));
         }
+
+        // The connection was given up on before the data was acknowledged
+        if entry.deleted {
+            if let Some(err) = self.error.lock().unwrap().take() {
+                return Err(err.into());
+            }
+        }
+
+        Ok(())
     }
 }
 
-impl Drop for TcpStream {
-    fn drop(&mut self) {
-        let mut manager = self.manager.lock().unwrap();
+impl Read for TcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
 
-        if !self.write_closed.load(Ordering::Acquire) {
-            self.write_closed.store(true, Ordering::Release);
+impl Write for TcpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
 
-            manager.streams.get_mut(&self.quad).unwrap().tcb.close();
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
 
-            manager = self.svar.wait(manager).unwrap();
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        // Left to the stack to remove
+        if self.entry.lock().unwrap().detached {
+            return;
         }
 
-        manager.streams.remove(&self.quad).unwrap();
+        self.close_write();
+
+        drop(self.wait_fin_acked());
+
+        // The stream is already gone if it has been reset
+        let mut manager = self.manager.lock().unwrap();
+        drop(self.remove(&mut manager));
     }
 }
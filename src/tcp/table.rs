@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash};
+use std::net::Ipv4Addr;
+use std::ops::Deref;
+
+use hashbrown::hash_map::RawEntryMut;
+
+use super::{Quad, QuadMap, QuadState};
+
+/*
+A map of connections keyed by their quads, which also counts them by local
+port and by peer as they come and go. The backlog of a port and what a peer
+holds are looked up on every SYN, and must not take going through every
+connection of the stack. It derefs to the map for lookups, while everything
+that adds or removes a connection goes through the table.
+
+The segment loop hashes the quad of a segment once, with hash, and looks it
+up in every table with that hash. Tables made with the same state hash
+quads the same.
+*/
+#[derive(Debug)]
+pub struct QuadTable<V> {
+    map: QuadMap<V>,
+    ports: HashMap<u16, usize>,
+    peers: HashMap<Ipv4Addr, usize>,
+}
+
+impl<V> Deref for QuadTable<V> {
+    type Target = QuadMap<V>;
+
+    fn deref(&self) -> &QuadMap<V> {
+        &self.map
+    }
+}
+
+impl<V> QuadTable<V> {
+    pub fn with_hasher(state: QuadState) -> Self {
+        QuadTable {
+            map: QuadMap::with_hasher(state),
+            ports: HashMap::new(),
+            peers: HashMap::new(),
+        }
+    }
+
+    pub fn hash(&self, quad: &Quad) -> u64 {
+        self.map.hasher().hash_one(quad)
+    }
+
+    pub fn insert(&mut self, quad: Quad, value: V) -> Option<V> {
+        self.insert_hashed(self.hash(&quad), quad, value)
+    }
+
+    pub fn insert_hashed(&mut self, hash: u64, quad: Quad, value: V) -> Option<V> {
+        match self
+            .map
+            .raw_entry_mut()
+            .from_key_hashed_nocheck(hash, &quad)
+        {
+            RawEntryMut::Occupied(mut entry) => Some(entry.insert(value)),
+            RawEntryMut::Vacant(entry) => {
+                entry.insert_hashed_nocheck(hash, quad, value);
+
+                *self.ports.entry(quad.src.port).or_default() += 1;
+                *self.peers.entry(quad.dst.ipv4).or_default() += 1;
+
+                None
+            }
+        }
+    }
+
+    pub fn remove(&mut self, quad: &Quad) -> Option<V> {
+        let value = self.map.remove(quad)?;
+
+        uncount(&mut self.ports, quad.src.port);
+        uncount(&mut self.peers, quad.dst.ipv4);
+
+        Some(value)
+    }
+
+    pub fn get_mut(&mut self, quad: &Quad) -> Option<&mut V> {
+        self.map.get_mut(quad)
+    }
+
+    // hash must be what hash returns for quad
+    pub fn get_hashed(&self, hash: u64, quad: &Quad) -> Option<&V> {
+        let (_, value) = self.map.raw_entry().from_key_hashed_nocheck(hash, quad)?;
+
+        Some(value)
+    }
+
+    pub fn get_mut_hashed(&mut self, hash: u64, quad: &Quad) -> Option<&mut V> {
+        match self.map.raw_entry_mut().from_key_hashed_nocheck(hash, quad) {
+            RawEntryMut::Occupied(entry) => Some(entry.into_mut()),
+            RawEntryMut::Vacant(_) => None,
+        }
+    }
+
+    pub fn drain(&mut self) -> impl Iterator<Item = (Quad, V)> + '_ {
+        self.ports.clear();
+        self.peers.clear();
+
+        self.map.drain()
+    }
+
+    // Connections whose local port is port, whatever their local address
+    pub fn on_port(&self, port: u16) -> usize {
+        self.ports.get(&port).copied().unwrap_or(0)
+    }
+
+    pub fn of_peer(&self, peer: Ipv4Addr) -> usize {
+        self.peers.get(&peer).copied().unwrap_or(0)
+    }
+}
+
+fn uncount<K: Eq + Hash>(counts: &mut HashMap<K, usize>, key: K) {
+    if let Some(count) = counts.get_mut(&key) {
+        *count -= 1;
+
+        if *count == 0 {
+            counts.remove(&key);
+        }
+    }
+}
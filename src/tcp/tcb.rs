@@ -1,28 +1,119 @@
 use std::cmp;
 use std::collections::VecDeque;
-use std::net::Ipv4Addr;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
 use std::sync::atomic::Ordering::{self, Acquire};
 use std::sync::atomic::{AtomicBool, AtomicU64};
-use std::sync::Arc;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
+use etherparse::icmpv4::DestUnreachableHeader;
 use etherparse::{Ipv4HeaderSlice, TcpHeaderSlice, TcpOptionElement};
-use tidy_tuntap::Tun;
 
 use super::*;
+use crate::{DropReason, Error};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/*
+What a connection may send per turn. Connections with data to send take
+turns at the device in the order their timers fire, each sending at most
+one segment per turn, and deficit round robin keeps a bulk sender with large
+offloaded segments from taking more than its share: every turn adds a
+quantum, times the weight of the connection, to its deficit, and what it
+sends is taken out of it.
+*/
+pub const TX_QUANTUM: usize = 16 * 1024;
+
+// An endpoint of a connection, which orders by address and then by port
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct Dual {
     pub ipv4: Ipv4Addr,
     pub port: u16,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+impl Dual {
+    pub const fn new(ipv4: Ipv4Addr, port: u16) -> Self {
+        Dual { ipv4, port }
+    }
+}
+
+impl fmt::Display for Dual {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.ipv4, self.port)
+    }
+}
+
+impl From<SocketAddrV4> for Dual {
+    fn from(addr: SocketAddrV4) -> Self {
+        Dual::new(*addr.ip(), addr.port())
+    }
+}
+
+impl From<Dual> for SocketAddrV4 {
+    fn from(dual: Dual) -> Self {
+        SocketAddrV4::new(dual.ipv4, dual.port)
+    }
+}
+
+/*
+A connection as seen from the stack: src is the local endpoint and dst the
+remote one, whichever way the segment at hand travels. Quads order by their
+local endpoint first, so sorting them groups the connections of a port.
+*/
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Quad {
     pub src: Dual,
     pub dst: Dual,
 }
 
+impl Quad {
+    pub const fn new(src: Dual, dst: Dual) -> Self {
+        Quad { src, dst }
+    }
+
+    pub fn local(&self) -> SocketAddrV4 {
+        self.src.into()
+    }
+
+    pub fn remote(&self) -> SocketAddrV4 {
+        self.dst.into()
+    }
+}
+
+impl fmt::Display for Quad {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} -> {}", self.src, self.dst)
+    }
+}
+
+// From the local address and the remote one
+impl From<(SocketAddrV4, SocketAddrV4)> for Quad {
+    fn from((local, remote): (SocketAddrV4, SocketAddrV4)) -> Self {
+        Quad::new(local.into(), remote.into())
+    }
+}
+
+impl From<Quad> for (SocketAddrV4, SocketAddrV4) {
+    fn from(quad: Quad) -> Self {
+        (quad.local(), quad.remote())
+    }
+}
+
+// Packed into two words, so that a quad takes two rounds of the hasher
+impl Hash for Quad {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let addrs = ((u32::from(self.src.ipv4) as u64) << 32) | u32::from(self.dst.ipv4) as u64;
+        let ports = ((self.src.port as u32) << 16) | self.dst.port as u32;
+
+        state.write_u64(addrs);
+        state.write_u32(ports);
+    }
+}
+
 /*
                     RFC 9293 - S3.3.2 - Fig 5
 
@@ -71,6 +162,7 @@ pub struct Quad {
      -------------------->|TIME-WAIT|------------------->| CLOSED  |
                           +---------+                    +---------+
 */
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum State {
     Listen,
@@ -85,6 +177,112 @@ pub enum State {
     LastAck,
 }
 
+/*
+The state of a connection as seen by the application. Closed stands for
+a connection whose TCB has been deleted.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ConnState {
+    Closed,
+    Listen,
+    SynRcvd,
+    SynSent,
+    Estab,
+    FinWait1,
+    FinWait2,
+    Closing,
+    TimeWait,
+    CloseWait,
+    LastAck,
+}
+
+impl From<State> for ConnState {
+    fn from(state: State) -> Self {
+        match state {
+            State::Listen => ConnState::Listen,
+            State::SynRcvd => ConnState::SynRcvd,
+            State::SynSent => ConnState::SynSent,
+            State::Estab => ConnState::Estab,
+            State::FinWait1 => ConnState::FinWait1,
+            State::FinWait2 => ConnState::FinWait2,
+            State::Closing => ConnState::Closing,
+            State::TimeWait => ConnState::TimeWait,
+            State::CloseWait => ConnState::CloseWait,
+            State::LastAck => ConnState::LastAck,
+        }
+    }
+}
+
+// Which connection an error is about and the state it was in when it failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnContext {
+    pub local: SocketAddrV4,
+    pub remote: SocketAddrV4,
+    pub state: ConnState,
+}
+
+impl ConnContext {
+    pub(crate) fn new(quad: &Quad, state: ConnState) -> Self {
+        ConnContext {
+            local: quad.local(),
+            remote: quad.remote(),
+            state,
+        }
+    }
+}
+
+impl fmt::Display for ConnContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} -> {} in {:?}", self.local, self.remote, self.state)
+    }
+}
+
+// A snapshot of the variables of a connection, in the spirit of tcp_info
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpInfo {
+    pub state: ConnState,
+    pub srtt: Duration,
+    pub rttvar: Duration,
+    pub rto: Duration,
+    pub cwnd: u32,
+    pub ssthresh: u32,
+    pub snd_wnd: u16,
+    pub rcv_wnd: u16,
+    pub snd_mss: u16,
+    pub bytes_in_flight: u32,
+    pub retransmits: u64,
+}
+
+// What a connection has been through so far, as counted by the connection itself
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConnStats {
+    pub segments_in: u64,
+    pub segments_out: u64,
+    pub bytes_in: u64,  // Octets of data received, duplicates included
+    pub bytes_out: u64, // Octets of data sent, retransmissions included
+    pub retransmits: u64,
+    pub dup_acks: u64,
+    pub zero_windows: u64, // Times the peer closed its window
+    pub rto_expirations: u64,
+}
+
+// An entry of the connection table, as listed by NetStack::connections
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Connection {
+    pub local: SocketAddrV4,
+    pub remote: SocketAddrV4,
+    pub info: TcpInfo,
+    pub recv_queue: usize, // Received but not read yet
+    pub send_queue: usize, // Written but not acknowledged yet
+    pub segments: usize,   // Sent or queued segments awaiting acknowledgment
+    // Time left until each timer fires, if it is running
+    pub retransmit_timer: Option<Duration>,
+    pub probe_timer: Option<Duration>,
+    pub keepalive_timer: Option<Duration>,
+    pub time_wait_timer: Option<Duration>,
+}
+
 /*
                 RFC 9293 - S3.3.1 - Fig 3
 
@@ -98,6 +296,7 @@ pub enum State {
 3 - sequence numbers allowed for new data transmission
 4 - future sequence numbers that are not yet allowed
 */
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct SendSpace {
     una: u32, // send unacknowledged
@@ -109,7 +308,8 @@ pub struct SendSpace {
     iss: u32, // initial send sequence number
     mss: u16, // sender maximum segment size
 
-    max_wnd: u16, // maximum window that the receiver has advertised
+    max_wnd: u16,  // maximum window that the receiver has advertised
+    peer_mss: u16, // maximum segment size that the receiver has announced
 }
 
 /*
@@ -124,6 +324,7 @@ pub struct SendSpace {
         2 - sequence numbers allowed for new reception
         3 - future sequence numbers that are not yet allowed
 */
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct RecvSpace {
     nxt: u32, // receive next
@@ -133,6 +334,7 @@ pub struct RecvSpace {
     mss: u16, // receiver maximum segment size
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Kind {
     Active,
@@ -142,7 +344,7 @@ pub enum Kind {
 #[derive(Debug, Clone)]
 pub enum Action {
     Noop,
-    AddToPending(TCB),
+    AddToPending, // The TCB that returned it is to be moved into pending
     RemoveFromPending,
     IsEstablished,
     Reset,
@@ -178,6 +380,37 @@ impl Segment {
     fn unacked_data_len(&self) -> usize {
         (self.end().wrapping_sub(self.una) + 1) as usize - if self.fin { 1 } else { 0 }
     }
+
+    // When it was sent is kept as how long ago that was
+    pub(crate) fn snapshot(&self, now: Instant) -> SegmentSnapshot {
+        SegmentSnapshot {
+            sno: self.sno,
+            una: self.una,
+            len: self.len,
+            fin: self.fin,
+            syn: self.syn,
+            ack: self.ack,
+            retry: self.retry,
+            total_ret_time: self.total_ret_time,
+            sent: self.sent.map(|sent| now.saturating_duration_since(sent)),
+            mss: self.mss,
+        }
+    }
+
+    pub(crate) fn restore(snapshot: &SegmentSnapshot, now: Instant) -> Self {
+        Segment {
+            sno: snapshot.sno,
+            una: snapshot.una,
+            len: snapshot.len,
+            fin: snapshot.fin,
+            syn: snapshot.syn,
+            ack: snapshot.ack,
+            retry: snapshot.retry,
+            total_ret_time: snapshot.total_ret_time,
+            sent: snapshot.sent.map(|ago| now.checked_sub(ago).unwrap_or(now)),
+            mss: snapshot.mss,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -188,6 +421,7 @@ pub struct TCB {
     pub(crate) reset: Arc<AtomicBool>,
     pub(crate) write_closed: Arc<AtomicBool>,
     pub(crate) read_closed: Arc<AtomicBool>,
+    pub(crate) error: Arc<Mutex<Option<Error>>>, // Pending asynchronous error, like SO_ERROR
     pub(crate) time_wait: Option<Instant>,
 
     pub(crate) snd: SendSpace,
@@ -207,14 +441,47 @@ pub struct TCB {
     pub(crate) ssthresh: u32,
 
     pub(crate) probe_timeout: Option<Instant>,
-
-    pub(crate) incoming: VecDeque<u8>,
-    pub(crate) outgoing: VecDeque<u8>,
+    pub(crate) stats: ConnStats,
+    pub(crate) dropped: Option<DropReason>, // Why the last segment was dropped, if it was
+    pub(crate) send_error: Option<io::ErrorKind>, // Why the last segment failed to send, if one did
+
+    pub(crate) opts: TcpOptions,
+    pub(crate) keepalive_timeout: Option<Instant>,
+    pub(crate) keepalive_probes: u32,
+
+    pub(crate) ack_throttle: Arc<AckThrottle>,
+    pub(crate) memory: Arc<MemoryPool>,
+    pub(crate) clock: Arc<dyn Clock>,
+    pub(crate) observer: Arc<Observer>, // Set by the stack once the TCB is made
+    pub(crate) soft_errors: Option<Sender<SoftError>>, // Set by TcpStream::soft_errors
+    pub(crate) zero_window_since: Option<Instant>, // Until the first probe, if the window is zero
+
+    pub(crate) ip_opts: IpOpts,
+    pub(crate) recv_tos: u8,
+    pub(crate) template: Option<HeaderTemplate>, // Headers of retransmissions
+
+    pub(crate) incoming: RecvBuffer,
+    pub(crate) pushes: VecDeque<usize>, // Octets of incoming up to each PSH received
+    pub(crate) records: bool,           // Reads stop at the next PSH
+    pub(crate) corked: bool,            // Only full-sized segments are sent
+    pub(crate) shaper: Option<TokenBucket>, // Paces new data when rate limited
+    pub(crate) deficit: usize,          // Octets left of its turns at the device
+    pub(crate) outgoing: SendBuffer,
     pub(crate) segments: VecDeque<Segment>,
 }
 
 impl TCB {
-    pub fn listen(quad: Quad, iss: u32) -> Self {
+    pub fn listen(
+        quad: Quad,
+        iss: &IssGenerator,
+        ack_throttle: Arc<AckThrottle>,
+        memory: Arc<MemoryPool>,
+        clock: Arc<dyn Clock>,
+        ip_opts: IpOpts,
+        opts: TcpOptions,
+    ) -> Self {
+        let iss = iss.generate(&quad);
+
         TCB {
             quad,
             kind: Kind::Passive,
@@ -222,6 +489,7 @@ impl TCB {
             reset: Arc::new(AtomicBool::new(false)),
             write_closed: Arc::new(AtomicBool::new(false)),
             read_closed: Arc::new(AtomicBool::new(false)),
+            error: Arc::new(Mutex::new(None)),
             time_wait: None,
             snd: SendSpace {
                 una: iss,
@@ -233,10 +501,11 @@ impl TCB {
                 iss,
                 mss: 536,
                 max_wnd: 0,
+                peer_mss: 536,
             },
             rcv: RecvSpace {
                 nxt: 0,
-                wnd: 64240,
+                wnd: memory.window(opts.recv_buffer) as u16,
                 urp: 0,
                 irs: 0,
                 mss: 536,
@@ -253,7 +522,7 @@ impl TCB {
             rtt_measured: false,
             timeout: None,
             r1: 50 * 1000,
-            r2: Arc::new(AtomicU64::new(100 * 1000)),
+            r2: Arc::new(AtomicU64::new(opts.user_timeout.as_millis() as u64)),
             r1_syn: 1 * 60 * 1000,
             r2_syn: Arc::new(AtomicU64::new(3 * 60 * 1000)),
             /*
@@ -278,14 +547,47 @@ impl TCB {
             ssthresh: u32::MAX,
 
             probe_timeout: None,
-
-            incoming: VecDeque::new(),
-            outgoing: VecDeque::new(),
+            stats: ConnStats::default(),
+            dropped: None,
+            send_error: None,
+
+            opts,
+            keepalive_timeout: None,
+            keepalive_probes: 0,
+
+            ack_throttle,
+            memory: memory.clone(),
+            clock,
+            observer: Arc::default(),
+            soft_errors: None,
+            zero_window_since: None,
+
+            ip_opts,
+            recv_tos: 0,
+            template: None,
+
+            incoming: RecvBuffer::new(memory.clone()),
+            pushes: VecDeque::new(),
+            records: false,
+            corked: false,
+            shaper: None,
+            deficit: 0,
+            outgoing: SendBuffer::new(memory),
             segments: VecDeque::new(),
         }
     }
 
-    pub fn syn_sent(quad: Quad, iss: u32) -> Self {
+    pub fn syn_sent(
+        quad: Quad,
+        iss: &IssGenerator,
+        ack_throttle: Arc<AckThrottle>,
+        memory: Arc<MemoryPool>,
+        clock: Arc<dyn Clock>,
+        ip_opts: IpOpts,
+        opts: TcpOptions,
+    ) -> Self {
+        let iss = iss.generate(&quad);
+
         let mut tcb = TCB {
             quad,
             kind: Kind::Active,
@@ -293,6 +595,7 @@ impl TCB {
             reset: Arc::new(AtomicBool::new(false)),
             write_closed: Arc::new(AtomicBool::new(false)),
             read_closed: Arc::new(AtomicBool::new(false)),
+            error: Arc::new(Mutex::new(None)),
             time_wait: None,
             snd: SendSpace {
                 una: iss,
@@ -304,10 +607,11 @@ impl TCB {
                 iss,
                 mss: 536,
                 max_wnd: 0,
+                peer_mss: 536,
             },
             rcv: RecvSpace {
                 nxt: 0,
-                wnd: 64240,
+                wnd: memory.window(opts.recv_buffer) as u16,
                 urp: 0,
                 irs: 0,
                 mss: 536,
@@ -324,7 +628,7 @@ impl TCB {
             rtt_measured: false,
             timeout: None,
             r1: 50 * 1000,
-            r2: Arc::new(AtomicU64::new(100 * 1000)),
+            r2: Arc::new(AtomicU64::new(opts.user_timeout.as_millis() as u64)),
             r1_syn: 1 * 60 * 1000,
             r2_syn: Arc::new(AtomicU64::new(3 * 60 * 1000)),
             /*
@@ -349,9 +653,32 @@ impl TCB {
             ssthresh: u32::MAX,
 
             probe_timeout: None,
-
-            incoming: VecDeque::new(),
-            outgoing: VecDeque::new(),
+            stats: ConnStats::default(),
+            dropped: None,
+            send_error: None,
+
+            opts,
+            keepalive_timeout: None,
+            keepalive_probes: 0,
+
+            ack_throttle,
+            memory: memory.clone(),
+            clock,
+            observer: Arc::default(),
+            soft_errors: None,
+            zero_window_since: None,
+
+            ip_opts,
+            recv_tos: 0,
+            template: None,
+
+            incoming: RecvBuffer::new(memory.clone()),
+            pushes: VecDeque::new(),
+            records: false,
+            corked: false,
+            shaper: None,
+            deficit: 0,
+            outgoing: SendBuffer::new(memory),
             segments: VecDeque::new(),
         };
 
@@ -373,12 +700,87 @@ impl TCB {
         tcb
     }
 
+    /*
+    The MSS announced to the peer, in the SYN or SYN-ACK. Only called before
+    anything has been sent.
+    */
+    pub(crate) fn set_rcv_mss(&mut self, mss: u16) {
+        self.rcv.mss = mss;
+
+        for seg in self.segments.iter_mut().filter(|seg| seg.syn) {
+            seg.mss = Some(mss);
+        }
+    }
+
+    pub(crate) fn iss(&self) -> u32 {
+        self.snd.iss
+    }
+
+    // Only called before anything has been sent, e.g. to replay a trace
+    pub(crate) fn set_iss(&mut self, iss: u32) {
+        let sent = self.snd.nxt.wrapping_sub(self.snd.iss);
+
+        self.snd.iss = iss;
+        self.snd.una = iss;
+        self.snd.nxt = iss.wrapping_add(sent);
+
+        // The SYN of an active open is queued already
+        for seg in &mut self.segments {
+            seg.sno = iss;
+            seg.una = iss;
+        }
+    }
+
+    // A later error replaces one that has not been taken yet
+    pub fn context(&self) -> ConnContext {
+        ConnContext::new(&self.quad, self.state.into())
+    }
+
+    fn set_error(&self, err: Error) {
+        *self.error.lock().unwrap() = Some(err);
+    }
+
+    fn set_state(&mut self, state: State) {
+        println!("\t\tState <- {:?}", state);
+
+        let quad = self.quad;
+        let from = self.state.into();
+        self.state = state;
+
+        self.observer.emit(ConnEvent::StateChanged {
+            quad,
+            from,
+            to: state.into(),
+        });
+        if state == State::Estab {
+            self.observer.emit(ConnEvent::Established { quad });
+        }
+    }
+
+    // Reported to the stream, if it listens, and to the stack
+    fn advise(&self, error: SoftError) {
+        if let Some(sender) = &self.soft_errors {
+            let _ = sender.send(error);
+        }
+
+        self.observer.advise(self.quad, error);
+    }
+
+    // The connection is gone, for whoever removes it from the stack
+    pub(crate) fn closed(&self, reason: CloseReason) {
+        self.observer.emit(ConnEvent::Closed {
+            quad: self.quad,
+            reason,
+        });
+    }
+
     fn is_slow_start(&self) -> bool {
         self.cwnd < self.ssthresh
     }
 
+    // A stream with data queued waits for it to drain while memory is exhausted
     pub fn is_outgoing_full(&self) -> bool {
-        self.outgoing.capacity() == self.outgoing.len()
+        self.outgoing.is_full() || (!self.outgoing.is_empty() && self.memory.is_exhausted())
     }
 
     fn is_fin_acked(&self) -> bool {
@@ -396,13 +798,109 @@ impl TCB {
             && self.write_closed.load(Ordering::Acquire)
     }
 
+    // A FIN that has been sent occupies a sequence number but no buffer space
+    fn sent_data_len(&self) -> usize {
+        let fin_sent = self.segments.back().is_some_and(|seg| seg.fin);
+
+        self.snd.nxt.wrapping_sub(self.snd.una) as usize - if fin_sent { 1 } else { 0 }
+    }
+
     fn available_data_len(&self) -> usize {
-        let sent_len = self.snd.nxt.wrapping_sub(self.snd.una) as usize;
+        let sent_len = self.sent_data_len();
         let available_len = self.outgoing.len() - sent_len;
 
         available_len
     }
 
+    /*
+            RFC 9293 - S3.8.6. Managing the Window
+
+    A TCP sender MUST be robust against window shrinking, which may cause
+    the "usable window" (see Section 3.8.6.2.1) to become negative (MUST-34).
+
+    If this happens, the sender SHOULD NOT send new data (SHLD-15), but
+    SHOULD retransmit normally the old unacknowledged data between SND.UNA
+    and SND.UNA+SND.WND (SHLD-16). The sender MAY also retransmit old data
+    beyond SND.UNA+SND.WND (MAY-7), but SHOULD NOT time out the connection
+    if data beyond the right window edge is not acknowledged (SHLD-17). If
+    the window shrinks to zero, the TCP implementation MUST probe it in the
+    standard way (described below) (MUST-35).
+    */
+    fn right_window_edge(&self) -> u32 {
+        self.snd.una.wrapping_add(self.snd.wnd as u32)
+    }
+
+    fn usable_window(&self) -> usize {
+        let edge = self.right_window_edge();
+
+        // The peer has shrunk its window below what we have already sent.
+        if !wrapping_lt(self.snd.nxt, edge) {
+            return 0;
+        }
+
+        edge.wrapping_sub(self.snd.nxt) as usize
+    }
+
+    /*
+    How much of the data not sent yet may be sent right now. A rate limited
+    connection waits until it may send a full segment, or everything it
+    could send otherwise if that is less, instead of sending what little
+    the shaper allows.
+    */
+    fn sendable_len(&self) -> usize {
+        let len = self.unshaped_len();
+
+        let Some(shaper) = &self.shaper else {
+            return len;
+        };
+
+        let available = shaper.available(self.clock.now());
+        let needed = cmp::min(
+            cmp::min(len, self.snd.mss as usize),
+            shaper.burst() as usize,
+        );
+
+        if available < needed {
+            0
+        } else {
+            cmp::min(len, available)
+        }
+    }
+
+    fn unshaped_len(&self) -> usize {
+        if !self.sws_allows_send() {
+            return 0;
+        }
+
+        let cwnd = match self.opts.congestion {
+            Congestion::Reno => self.cwnd as usize,
+            Congestion::None => usize::MAX,
+        };
+
+        let mut len = cmp::min(
+            cmp::min(self.available_data_len(), cwnd),
+            self.usable_window(),
+        );
+
+        // The partial tail is held back until the connection is uncorked
+        if self.is_corked() {
+            len -= len % self.snd.mss as usize;
+        }
+
+        len
+    }
+
+    // Closing the write half flushes a corked connection, like TCP_CORK
+    fn is_corked(&self) -> bool {
+        self.corked && !self.write_closed.load(Ordering::Acquire)
+    }
+
+    fn is_beyond_window(&self) -> bool {
+        self.segments.front().is_some_and(|seg| {
+            !seg.syn && !seg.fin && !wrapping_lt(seg.una, self.right_window_edge())
+        })
+    }
+
     fn sws_allows_send(&self) -> bool {
         /*
                 RFC 9293 - S3.8.6.2.1. Sender's Algorithm -- When to Send Data
@@ -437,26 +935,30 @@ impl TCB {
         */
 
         let d = self.available_data_len();
-        let u = self
-            .snd
-            .una
-            .wrapping_add(self.snd.wnd as u32)
-            .wrapping_sub(self.snd.nxt) as usize;
+        let u = self.usable_window();
+
+        if u == 0 {
+            return false;
+        }
+
+        if self.is_corked() {
+            return cmp::min(d, u) >= self.snd.mss as usize;
+        }
+
+        // Nagle only lets a small segment out once everything sent has been acked
+        let idle = self.opts.nodelay || self.snd.nxt == self.snd.una;
 
         cmp::min(d, u) >= self.snd.mss as usize
-            || d <= u
-            || cmp::min(d, u) >= (0.5 * self.snd.max_wnd as f64) as usize
+            || (idle && d <= u)
+            || (idle && cmp::min(d, u) >= (0.5 * self.snd.max_wnd as f64) as usize)
     }
 
     pub fn close(&mut self) {
-        if self.state == State::Estab {
-            println!("\t\tState <- FinWait1");
-            self.state = State::FinWait1;
-        } else {
-            assert_eq!(self.state, State::CloseWait);
-
-            println!("\t\tState <- LastAck");
-            self.state = State::LastAck;
+        // The write half has been closed already, or was never open
+        match self.state {
+            State::Estab => self.set_state(State::FinWait1),
+            State::CloseWait => self.set_state(State::LastAck),
+            _ => return,
         }
 
         /*
@@ -485,12 +987,151 @@ impl TCB {
         }
     }
 
+    /*
+            RFC 9293 - S3.10.5. ABORT Call
+
+    SYN-RECEIVED STATE
+    ESTABLISHED STATE
+    FIN-WAIT-1 STATE
+    FIN-WAIT-2 STATE
+    CLOSE-WAIT STATE
+        Send a reset segment:
+
+            <SEQ=SND.NXT><CTL=RST>
+
+        All queued SENDs and RECEIVEs should be given "reset" notification;
+        all segments queued for transmission (except for the RST formed
+        above) or retransmission should be flushed. Delete the TCB, enter
+        CLOSED state, and return.
+
+    CLOSING STATE
+    LAST-ACK STATE
+    TIME-WAIT STATE
+        Respond with "ok" and delete the TCB, enter CLOSED state, and return.
+
+    Returns whether the reset segment has to be sent.
+    */
+    pub fn abort(&mut self) -> bool {
+        self.reset.store(true, Ordering::Release);
+        self.closed(CloseReason::Aborted);
+
+        self.incoming.clear();
+        self.pushes.clear();
+        self.outgoing.clear();
+        self.segments.clear();
+        self.timeout = None;
+
+        matches!(
+            self.state,
+            State::SynRcvd | State::Estab | State::FinWait1 | State::FinWait2 | State::CloseWait
+        )
+    }
+
+    pub fn write_abort(&self, out: &mut (impl Emitter + ?Sized)) {
+        write_rst(&self.quad, self.snd.nxt, self.ip_opts, out);
+    }
+
+    /*
+    The effective send MSS can be lowered below what the peer announced,
+    e.g. to leave room for an encapsulation the stack knows nothing about,
+    but never raised above it.
+    */
+    pub fn set_mss(&mut self, mss: u16) {
+        self.snd.mss = cmp::min(mss, self.snd.peer_mss);
+    }
+
+    pub fn mss(&self) -> u16 {
+        self.snd.mss
+    }
+
+    pub fn info(&self) -> TcpInfo {
+        let millis = |ms: u128| Duration::from_millis(ms as u64);
+
+        TcpInfo {
+            state: self.state.into(),
+            srtt: millis(self.srtt),
+            rttvar: millis(self.rttvar),
+            rto: millis(self.rto),
+            cwnd: self.cwnd,
+            ssthresh: self.ssthresh,
+            snd_wnd: self.snd.wnd,
+            rcv_wnd: self.rcv.wnd,
+            snd_mss: self.snd.mss,
+            bytes_in_flight: self.snd.nxt.wrapping_sub(self.snd.una),
+            retransmits: self.stats.retransmits,
+        }
+    }
+
+    pub fn stats(&self) -> ConnStats {
+        self.stats
+    }
+
+    pub fn connection(&self) -> Connection {
+        let now = self.clock.now();
+        let left = |timer: Option<Instant>| timer.map(|at| at.saturating_duration_since(now));
+
+        Connection {
+            local: self.quad.local(),
+            remote: self.quad.remote(),
+            info: self.info(),
+            recv_queue: self.incoming.len(),
+            send_queue: self.outgoing.len(),
+            segments: self.segments.len(),
+            retransmit_timer: left(self.timeout),
+            probe_timer: left(self.probe_timeout),
+            keepalive_timer: left(self.keepalive_timeout),
+            time_wait_timer: left(self.time_wait),
+        }
+    }
+
+    // Our FIN has been sent, or is about to be, but is not acknowledged yet
+    pub fn is_fin_pending(&self) -> bool {
+        matches!(
+            self.state,
+            State::FinWait1 | State::Closing | State::LastAck
+        )
+    }
+
+    /*
+    Whatever is still buffered is dropped and the window is opened up, as
+    data that arrives from now on is acknowledged but never queued.
+    */
+    pub fn shutdown_read(&mut self) {
+        self.read_closed.store(true, Ordering::Release);
+
+        self.incoming.clear();
+        self.pushes.clear();
+        self.rcv.wnd = self.memory.window(self.incoming.capacity()) as u16;
+    }
+
+    /*
+    In record mode, only a whole record is ready to be read, unless the
+    window left is too small for the peer to ever complete it, or the FIN
+    of the peer, which implies a PUSH, has arrived.
+    */
+    pub fn is_readable(&self) -> bool {
+        if !self.records || self.read_closed.load(Ordering::Acquire) {
+            return !self.incoming.is_empty();
+        }
+
+        !self.pushes.is_empty() || (!self.incoming.is_empty() && self.rcv.wnd < self.snd.mss)
+    }
+
     pub fn recv(&mut self, buf: &mut [u8]) -> usize {
-        let len = cmp::min(buf.len(), self.incoming.len());
+        let mut len = cmp::min(buf.len(), self.incoming.len());
+        if let Some(&push) = self.pushes.front().filter(|_| self.records) {
+            len = cmp::min(len, push);
+        }
 
-        let data: Vec<u8> = self.incoming.drain(..len).collect();
+        self.incoming.read(&mut buf[..len]);
 
-        buf[..data.len()].copy_from_slice(&data[..]);
+        // Outside of record mode, reads run past the PSHs
+        for push in self.pushes.iter_mut() {
+            *push = push.saturating_sub(len);
+        }
+        while self.pushes.front() == Some(&0) {
+            self.pushes.pop_front();
+        }
 
         /*
                 RFC9293 S3.8.6.2.2. Receiver's Algorithm -- When to Send a Window Update
@@ -539,54 +1180,150 @@ impl TCB {
         When the inequality is satisfied, RCV.WND is set to RCV.BUFF-RCV.USER.
         */
 
-        if self.incoming.capacity() - self.incoming.len() - self.rcv.wnd as usize
+        self.reopen_window();
+
+        len
+    }
+
+    /*
+    Under memory pressure, less than RCV.BUFF-RCV.USER is offered, so the
+    window is also reopened when the peer probes it, in case the pressure
+    has eased since the last read.
+    */
+    fn reopen_window(&mut self) {
+        let open = self
+            .memory
+            .window(self.incoming.capacity() - self.incoming.len());
+
+        if open.saturating_sub(self.rcv.wnd as usize)
             >= cmp::min(
                 (0.5 * self.incoming.capacity() as f64) as usize,
                 self.snd.mss as usize,
             )
         {
-            self.rcv.wnd = (self.incoming.capacity() - self.incoming.len()) as u16;
+            self.rcv.wnd = open as u16;
         }
+    }
 
-        len
+    pub fn on_tick(&mut self, out: &mut (impl Emitter + ?Sized)) -> bool {
+        let mut out = Counting::new(out);
+        let expired = self.tick(&mut out);
+
+        self.stats.segments_out += out.segments;
+        self.stats.bytes_out += out.bytes;
+
+        if expired {
+            self.closed(match self.state {
+                State::TimeWait => CloseReason::Graceful,
+                _ => CloseReason::TimedOut,
+            });
+        }
+
+        expired
     }
 
-    pub fn on_tick(&mut self, tun: &mut Tun) -> bool {
+    fn tick(&mut self, out: &mut (impl Emitter + ?Sized)) -> bool {
         if let Some(timeout) = self.timeout.clone() {
-            if Instant::now() >= timeout {
+            if self.clock.now() >= timeout && self.is_beyond_window() {
+                /*
+                Data beyond the right window edge is not retransmitted and its
+                timer is not backed off, so a shrunk window never times out
+                the connection. A zero window is handled by the probe timer.
+                */
+                println!("\t\tTimeout beyond the right window edge");
+                self.stats.rto_expirations += 1;
+                self.timeout = Some(self.clock.now() + Duration::from_millis(self.rto as u64));
+            } else if self.clock.now() >= timeout && self.segments.is_empty() {
+                // Nothing is left to retransmit
+                self.timeout = None;
+            } else if self.clock.now() >= timeout {
                 println!("\t\tTimeout");
-                let seg = self.segments.front_mut().unwrap();
+                let edge = self.right_window_edge();
+
+                // Serialized again only once the TTL or TOS have changed
+                if self
+                    .template
+                    .as_ref()
+                    .is_none_or(|t| t.opts() != self.ip_opts)
+                {
+                    self.template = Some(HeaderTemplate::new(&self.quad, self.ip_opts));
+                }
+
+                let Some(seg) = self.segments.front_mut() else {
+                    return false;
+                };
+
+                let in_window = if seg.syn {
+                    seg.unacked_data_len()
+                } else {
+                    cmp::min(seg.unacked_data_len(), edge.wrapping_sub(seg.una) as usize)
+                };
+                let fin = seg.fin && in_window == seg.unacked_data_len();
 
-                let data: Vec<u8> = self
-                    .outgoing
-                    .iter()
-                    .cloned()
-                    .take(seg.unacked_data_len())
-                    .collect();
+                // A SYN occupies a sequence number but carries no data
+                let data_len = cmp::min(in_window, self.outgoing.len());
 
                 println!(
                     "\t\t\tWriting {}bytes with flags: FIN: {}, SYN: {}, ACK: {}",
-                    data.len(),
-                    seg.fin,
-                    seg.syn,
-                    seg.ack
-                );
-                write_data(
-                    self.quad,
-                    seg.sno,
-                    self.rcv.nxt,
-                    self.rcv.wnd,
-                    tun,
-                    &data[..],
-                    seg.fin,
-                    seg.syn,
-                    seg.ack,
-                    seg.mss,
+                    data_len, fin, seg.syn, seg.ack
                 );
 
+                // A SYN carries options, and so do its retransmissions
+                let written = match &mut self.template {
+                    Some(template) if !seg.syn => template.write(
+                        seg.una,
+                        self.rcv.nxt,
+                        self.rcv.wnd,
+                        fin,
+                        self.outgoing.slices(0, data_len),
+                        out,
+                        (data_len > self.snd.mss as usize).then_some(self.snd.mss),
+                    ),
+                    _ => Ok(false),
+                };
+
+                let res = match written {
+                    Ok(false) => {
+                        let data = self.outgoing.range(0, data_len);
+
+                        if data_len > self.snd.mss as usize {
+                            write_gso_data(
+                                self.quad,
+                                seg.una,
+                                self.rcv.nxt,
+                                self.rcv.wnd,
+                                self.ip_opts,
+                                out,
+                                &data,
+                                fin,
+                                self.snd.mss,
+                            )
+                        } else {
+                            write_data(
+                                self.quad,
+                                seg.una,
+                                self.rcv.nxt,
+                                self.rcv.wnd,
+                                self.ip_opts,
+                                out,
+                                &data,
+                                fin,
+                                seg.syn,
+                                seg.ack,
+                                seg.mss,
+                            )
+                        }
+                    }
+                    written => written.map(drop),
+                };
+                failed(&mut self.send_error, res);
+
                 seg.retry = true;
+                self.stats.retransmits += 1;
+                self.stats.rto_expirations += 1;
+                let ret_time = seg.total_ret_time; // To tell when R1 is first crossed
                 seg.total_ret_time += self.rto;
-                seg.sent = Some(Instant::now());
+                seg.sent = Some(self.clock.now());
 
                 println!("\t\t\tBefore RTO: {}", self.rto);
                 self.rto *= 2;
@@ -645,93 +1382,138 @@ impl TCB {
                 give up on the open attempt) sooner, of course.
                 */
                 if seg.syn {
-                    if seg.total_ret_time > self.r1_syn {
-                        println!("\t\t\tThreshold Syn-R1 reached");
-                    } else if seg.total_ret_time as u64 > self.r2_syn.load(Acquire) {
+                    if seg.total_ret_time as u64 > self.r2_syn.load(Acquire) {
                         println!("\t\t\tThreshold Syn-R2 reached. Terminating connection.");
+                        self.set_error(Error::RetransmitTimeout(self.context()));
                         return true;
+                    } else if seg.total_ret_time > self.r1_syn {
+                        println!("\t\t\tThreshold Syn-R1 reached");
+
+                        if ret_time <= self.r1_syn {
+                            self.advise(SoftError::RetransmitThreshold {
+                                syn: true,
+                                elapsed: Duration::from_millis(seg.total_ret_time as u64),
+                            });
+                        }
                     }
                 } else {
-                    if seg.total_ret_time > self.r1 {
-                        println!("\t\t\tThreshold R1 reached for {:?}", self.quad);
-                    } else if seg.total_ret_time as u64 > self.r2.load(Acquire) {
+                    if seg.total_ret_time as u64 > self.r2.load(Acquire) {
                         println!("\t\t\tThreshold R2 reached. Terminating connection.");
+                        self.set_error(Error::RetransmitTimeout(self.context()));
                         return true;
+                    } else if seg.total_ret_time > self.r1 {
+                        println!("\t\t\tThreshold R1 reached for {:?}", self.quad);
+
+                        if ret_time <= self.r1 {
+                            self.advise(SoftError::RetransmitThreshold {
+                                syn: false,
+                                elapsed: Duration::from_millis(seg.total_ret_time as u64),
+                            });
+                        }
                     }
                 }
             }
         }
 
         if !self.outgoing.is_empty() {
-            if self.sws_allows_send() {
-                let sent_len = self.snd.nxt.wrapping_sub(self.snd.una) as usize;
-                let available_len = self.outgoing.len() - sent_len;
+            let sent_len = self.sent_data_len();
+            let available_len = self.outgoing.len() - sent_len;
+
+            let to_be_sent = self.sendable_len();
+
+            if to_be_sent > 0 {
+                println!("\t\tOutgoing");
+                println!("\t\t\tsent_len: {sent_len}");
+                println!("\t\t\tto_be_sent: {to_be_sent}");
+                println!("\t\t\tavailable_len: {available_len}");
+
+                // Emitters with segmentation offload cut larger segments down to SMSS
+                let max_len = out
+                    .gso_max_size()
+                    .map_or(self.snd.mss as usize, |max| max.max(self.snd.mss as usize));
+
+                // What is not used of a turn carries over, up to a segment of the largest size
+                let weight = self.opts.priority.weight as usize;
+                let quantum = cmp::max(TX_QUANTUM * weight, self.snd.mss as usize);
+                self.deficit = cmp::min(self.deficit + quantum, cmp::max(quantum, max_len));
+
+                // Cut short by the deficit, the segment is kept to whole SMSS-sized pieces
+                let mut data_len = cmp::min(cmp::min(to_be_sent, max_len), self.deficit);
+                if data_len < to_be_sent {
+                    data_len -= data_len % self.snd.mss as usize;
+                }
+                println!("\t\t\tData len: {data_len}");
 
-                let to_be_sent = cmp::min(
-                    cmp::min(available_len, self.cwnd as usize),
-                    self.snd.wnd as usize,
-                );
+                // An emptied queue gives up what is left of its deficit
+                self.deficit = if data_len == available_len {
+                    0
+                } else {
+                    self.deficit - data_len
+                };
+
+                if let Some(shaper) = &mut self.shaper {
+                    shaper.consume(data_len, self.clock.now());
+                }
+
+                let fin = data_len == to_be_sent && self.write_closed.load(Ordering::Acquire);
 
-                if to_be_sent > 0 {
-                    println!("\t\tOutgoing");
-                    println!("\t\t\tsent_len: {sent_len}");
-                    println!("\t\t\tto_be_sent: {to_be_sent}");
-                    println!("\t\t\tavailable_len: {available_len}");
-
-                    let data_len = cmp::min(to_be_sent, self.snd.mss as usize);
-                    println!("\t\t\tData len: {data_len}");
-                    let fin = data_len == to_be_sent && self.write_closed.load(Ordering::Acquire);
-
-                    let data: Vec<u8> = self
-                        .outgoing
-                        .iter()
-                        .copied()
-                        .skip(sent_len)
-                        .take(data_len)
-                        .collect();
-
-                    println!("\t\t\tWriting {}bytes with flags: FIN: {}", data.len(), fin,);
+                let data = self.outgoing.range(sent_len, data_len);
+
+                println!("\t\t\tWriting {}bytes with flags: FIN: {}", data_len, fin,);
+                let res = if data_len > self.snd.mss as usize {
+                    write_gso_data(
+                        self.quad,
+                        self.snd.nxt,
+                        self.rcv.nxt,
+                        self.rcv.wnd,
+                        self.ip_opts,
+                        out,
+                        &data,
+                        fin,
+                        self.snd.mss,
+                    )
+                } else {
                     write_data(
                         self.quad,
                         self.snd.nxt,
                         self.rcv.nxt,
                         self.rcv.wnd,
-                        tun,
-                        data.as_slice(),
+                        self.ip_opts,
+                        out,
+                        &data,
                         fin,
                         false,
                         true,
                         None,
-                    );
+                    )
+                };
+                failed(&mut self.send_error, res);
 
-                    let seg = Segment {
-                        sno: self.snd.nxt,
-                        una: self.snd.nxt,
-                        len: data_len as u32,
-                        fin,
-                        syn: false,
-                        ack: true,
-                        retry: false,
-                        total_ret_time: 0,
-                        sent: Some(Instant::now()),
-                        mss: None,
-                    };
+                let seg = Segment {
+                    sno: self.snd.nxt,
+                    una: self.snd.nxt,
+                    len: data_len as u32 + if fin { 1 } else { 0 },
+                    fin,
+                    syn: false,
+                    ack: true,
+                    retry: false,
+                    total_ret_time: 0,
+                    sent: Some(self.clock.now()),
+                    mss: None,
+                };
 
-                    self.timeout =
-                        Some(seg.sent.clone().unwrap() + Duration::from_millis(self.rto as u64));
+                self.timeout =
+                    Some(seg.sent.clone().unwrap() + Duration::from_millis(self.rto as u64));
 
-                    self.segments.push_back(seg);
+                self.segments.push_back(seg);
 
-                    self.snd.nxt = self
-                        .snd
-                        .nxt
-                        .wrapping_add(data_len as u32)
-                        .wrapping_add(if fin { 1 } else { 0 });
-                }
+                self.snd.nxt = self
+                    .snd
+                    .nxt
+                    .wrapping_add(data_len as u32)
+                    .wrapping_add(if fin { 1 } else { 0 });
             }
-        } else if !self.segments.is_empty() {
-            let seg = self.segments.front_mut().unwrap();
-
+        } else if let Some(seg) = self.segments.front_mut() {
             if seg.sent.is_none() {
                 println!("\t\tSegment");
 
@@ -739,20 +1521,22 @@ impl TCB {
                     "\t\t\tWriting segment with flags: FIN: {}, SYN: {}, ACK: {}",
                     seg.fin, seg.syn, seg.ack,
                 );
-                write_data(
+                let res = write_data(
                     self.quad,
                     seg.sno,
                     self.rcv.nxt,
                     self.rcv.wnd,
-                    tun,
+                    self.ip_opts,
+                    out,
                     &[],
                     seg.fin,
                     seg.syn,
                     seg.ack,
                     seg.mss,
                 );
+                failed(&mut self.send_error, res);
 
-                seg.sent = Some(Instant::now());
+                seg.sent = Some(self.clock.now());
 
                 if self.timeout.is_none() {
                     self.timeout =
@@ -764,12 +1548,48 @@ impl TCB {
 
         if let Some(time_wait) = self.time_wait.clone() {
             println!("\t\tTimewait");
-            if time_wait >= Instant::now() {
+            if self.clock.now() >= time_wait {
                 println!("\t\t\tTimewait reached, deleting TCB");
                 return true;
             }
         }
 
+        if let Some(keepalive_timeout) = self.keepalive_timeout {
+            /*
+                    RFC 9293 S3.8.4. TCP Keep-Alives
+
+            Keep-alive packets MUST only be sent when no sent data is
+            outstanding, and no data or acknowledgment packets have been
+            received for the connection within an interval (MUST-26).
+
+            It is extremely important to remember that ACK segments that
+            contain no data are not reliably transmitted by TCP.
+            Consequently, if a keep-alive mechanism is implemented it MUST
+            NOT interpret failure to respond to any specific probe as a dead
+            connection (MUST-27).
+            */
+            if self.is_idle() && self.clock.now() >= keepalive_timeout {
+                if self.keepalive_probes >= KEEPALIVE_PROBES {
+                    println!("\t\tKeep-alives unanswered. Terminating connection.");
+                    self.set_error(Error::KeepaliveTimeout(self.context()));
+                    return true;
+                }
+
+                println!("\t\tWriting keep-alive");
+                write_ack(
+                    &self.quad,
+                    self.snd.nxt.wrapping_sub(1),
+                    self.rcv.nxt,
+                    self.rcv.wnd,
+                    self.ip_opts,
+                    out,
+                );
+
+                self.keepalive_probes += 1;
+                self.keepalive_timeout = self.opts.keepalive.map(|idle| self.clock.now() + idle);
+            }
+        }
+
         if let Some(probe_timeout) = self.probe_timeout.clone() {
             println!("\t\tProbe");
             /*
@@ -803,76 +1623,164 @@ impl TCB {
             (SHLD-29) (Section 3.8.1), and SHOULD increase exponentially the
             interval between successive probes (SHLD-30).
             */
-            if probe_timeout >= Instant::now() {
+            if self.clock.now() >= probe_timeout {
+                if let Some(since) = self.zero_window_since.take() {
+                    let elapsed = self.clock.now().saturating_duration_since(since);
+                    self.advise(SoftError::ZeroWindow { elapsed });
+                }
+
                 println!("\t\t\tWriting data to probe zero window");
-                write_data(
+                let res = write_data(
                     self.quad,
                     self.snd.una.wrapping_sub(1),
                     self.rcv.nxt,
                     self.rcv.wnd,
-                    tun,
-                    &[0u8; 8],
+                    self.ip_opts,
+                    out,
+                    &[&[0u8; 8]],
                     false,
                     false,
                     true,
                     None,
                 );
+                failed(&mut self.send_error, res);
 
-                self.probe_timeout = Some(Instant::now() + Duration::from_millis(self.rto as u64));
+                self.probe_timeout =
+                    Some(self.clock.now() + Duration::from_millis(self.rto as u64));
             }
         }
 
         false
     }
 
+    // Nothing sent is outstanding, so keep-alives may be sent
+    fn is_idle(&self) -> bool {
+        self.segments.is_empty() && matches!(self.state, State::Estab | State::CloseWait)
+    }
+
+    /*
+    When on_tick has something to do next, which is right away if there is
+    anything it can send. None if only a segment arriving can change that.
+    */
+    pub fn deadline(&self) -> Option<Instant> {
+        let unsent = if self.outgoing.is_empty() {
+            self.segments.front().is_some_and(|seg| seg.sent.is_none())
+        } else {
+            self.sendable_len() > 0
+        };
+        if unsent {
+            return Some(self.clock.now());
+        }
+
+        // Data held back by the shaper goes out once enough tokens have accrued
+        let shaped = self.shaper.and_then(|shaper| {
+            let (len, now) = (self.unshaped_len(), self.clock.now());
+
+            (len > 0).then(|| shaper.ready_at(cmp::min(len, self.snd.mss as usize), now))
+        });
+
+        let keepalive = self.keepalive_timeout.filter(|_| self.is_idle());
+
+        [
+            self.timeout,
+            self.time_wait,
+            keepalive,
+            self.probe_timeout,
+            shaped,
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
+
+    /*
+    ACKs sent in response to unacceptable segments can be elicited by anyone
+    able to guess the quad, so they are subject to the stack-wide throttle
+    to keep the stack from being used as an amplifier.
+    */
+    fn write_throttled_ack(&self, out: &mut (impl Emitter + ?Sized)) {
+        if !self.ack_throttle.allow() {
+            println!("\t\tAck throttled");
+            return;
+        }
+
+        write_ack(
+            &self.quad,
+            self.snd.nxt,
+            self.rcv.nxt,
+            self.rcv.wnd,
+            self.ip_opts,
+            out,
+        );
+    }
+
     fn process_ack(&mut self, ackno: u32) -> (bool, Option<u128>) {
         println!("\t\tProcess Ack");
         self.snd.una = ackno;
 
         let mut compute_rto = false;
         let mut r = 0;
+        let mut spurious = false;
 
         let before_len = self.outgoing.len();
 
-        while !self.segments.is_empty() {
-            let seg = self.segments.front_mut().unwrap();
+        while let Some(seg) = self.segments.front_mut() {
             let end = seg.end();
 
-            compute_rto = seg.retry == false;
-            r = (Instant::now() - seg.sent.clone().unwrap()).as_millis();
+            // A segment queued but not sent yet has no round trip to measure
+            compute_rto = !seg.retry && seg.sent.is_some();
+            r = seg.sent.map_or(0, |sent| {
+                self.clock.now().saturating_duration_since(sent).as_millis()
+            });
 
             if is_between_wrapped(seg.una, ackno, end.wrapping_add(1)) {
                 println!("\t\t\tPartial ack");
                 // Partial acknowledgment
 
                 let acked = ackno.wrapping_sub(seg.una);
-                self.outgoing.drain(..acked as usize);
+                self.outgoing.advance(acked as usize);
 
                 seg.una = ackno;
             } else if wrapping_lt(end, ackno) {
                 println!("\t\t\tFull ack");
                 // Full acknowledgment
 
-                let seg = self.segments.pop_front().unwrap();
-                self.outgoing.drain(..seg.unacked_data_len());
+                /*
+                An acknowledgment that comes back in well under a round trip
+                after the retransmission was sent must be for the original,
+                which was only late: the retransmission timed out too early.
+                */
+                spurious |= seg.retry && self.rtt_measured && r < self.srtt / 2;
+
+                let len = seg.unacked_data_len();
+                self.segments.pop_front();
+                self.outgoing.advance(len);
+            } else {
+                break;
             }
         }
 
-        if self.segments.is_empty() {
-            println!("\t\t\tNo more segments, turning off timer");
-            self.timeout = None;
-        } else {
-            let seg = self.segments.front().unwrap();
+        if spurious {
+            println!("\t\t\tSpurious retransmission");
+            self.advise(SoftError::SpuriousRetransmit);
+        }
 
-            self.timeout = Some(seg.sent.clone().unwrap() + Duration::from_millis(self.rto as u64));
+        // The timer of a segment not sent yet is started when it is
+        self.timeout = self
+            .segments
+            .front()
+            .and_then(|seg| seg.sent)
+            .map(|sent| sent + Duration::from_millis(self.rto as u64));
+        if self.timeout.is_none() {
+            println!("\t\t\tNo segment in flight, turning off timer");
         }
 
         println!(
             "\t\t\tWrite is ready: {}, Compute RTO: {}",
-            before_len < self.outgoing.len(),
+            self.outgoing.len() < before_len,
             compute_rto
         );
-        (before_len < self.outgoing.len(), compute_rto.then_some(r))
+        (self.outgoing.len() < before_len, compute_rto.then_some(r))
     }
 
     fn congestion_control(&mut self) {
@@ -962,14 +1870,98 @@ impl TCB {
         self.rto = cmp::max(self.rto, 1000);
     }
 
+    /*
+            RFC 1122 - S4.2.3.9. ICMP Messages
+
+    TCP MUST act on an ICMP error message passed up from the IP layer,
+    directing it to the connection that created the error.
+
+    o   Destination Unreachable -- codes 0, 1, 5
+
+        Since these Unreachable messages indicate soft error conditions,
+        TCP MUST NOT abort the connection, and it SHOULD make the
+        information available to the application.
+
+    o   Destination Unreachable -- codes 2-4
+
+        These are hard error conditions, so TCP SHOULD abort the
+        connection.
+
+    Code 4 (fragmentation needed) is left to path MTU discovery, so only
+    protocol and port unreachable abort a connection that is being opened.
+    */
+    pub fn on_unreachable(&mut self, sqno: u32, code: DestUnreachableHeader) -> Action {
+        println!("\tOn Unreachable: {:?}, {:?}", self.state, code);
+
+        let hard = matches!(
+            code,
+            DestUnreachableHeader::Protocol | DestUnreachableHeader::Port
+        );
+
+        // Only our SYN could have caused the error
+        if self.state == State::SynSent && sqno == self.snd.iss && hard {
+            self.closed(CloseReason::Refused);
+            return Action::ConnectionRefused;
+        }
+
+        // Otherwise the error is only reported, soft or not
+        if self.state != State::SynSent {
+            self.set_error(Error::Unreachable(self.context(), format!("{:?}", code)));
+        }
+        self.advise(SoftError::Unreachable {
+            code: code.code_u8(),
+        });
+
+        Action::Noop
+    }
+
     pub fn on_segment(
         &mut self,
         ip4h: Ipv4HeaderSlice,
         tcph: TcpHeaderSlice,
         data: &[u8],
-        tun: &mut Tun,
+        out: &mut (impl Emitter + ?Sized),
+    ) -> Action {
+        self.stats.segments_in += 1;
+        self.stats.bytes_in += data.len() as u64;
+
+        let rst = tcph.rst();
+        let mut out = Counting::new(out);
+        let action = self.segment(ip4h, tcph, data, &mut out);
+
+        self.stats.segments_out += out.segments;
+        self.stats.bytes_out += out.bytes;
+
+        let reason = match action {
+            Action::DeleteTCB => Some(CloseReason::Graceful),
+            Action::Reset | Action::RemoveFromPending => Some(CloseReason::Reset),
+            Action::ConnectionRefused => Some(CloseReason::Refused),
+            _ => None,
+        };
+        if rst && reason.is_some() {
+            self.observer.emit(ConnEvent::Reset { quad: self.quad });
+        }
+        if let Some(reason) = reason {
+            self.closed(reason);
+        }
+
+        action
+    }
+
+    fn segment(
+        &mut self,
+        ip4h: Ipv4HeaderSlice,
+        tcph: TcpHeaderSlice,
+        data: &[u8],
+        out: &mut (impl Emitter + ?Sized),
     ) -> Action {
         println!("\tOn Segment: {:?}", self.state);
+        self.recv_tos = ip4h.dcp() << 2 | ip4h.ecn();
+
+        // Anything heard from the peer postpones the next keep-alive
+        self.keepalive_probes = 0;
+        self.keepalive_timeout = self.opts.keepalive.map(|idle| self.clock.now() + idle);
+
         if self.state == State::Listen {
             /*
             If the state is LISTEN, then
@@ -1023,11 +2015,13 @@ impl TCB {
             */
 
             if tcph.rst() {
+                self.dropped = Some(DropReason::InvalidState);
                 return Action::Noop;
             }
 
             if tcph.ack() {
-                write_reset(&ip4h, &tcph, data, tun);
+                self.dropped = Some(DropReason::InvalidState);
+                write_reset(&ip4h, &tcph, data, self.ip_opts, out);
 
                 return Action::Noop;
             }
@@ -1047,6 +2041,7 @@ impl TCB {
                 self.snd.wnd = tcph.window_size();
                 self.snd.max_wnd = tcph.window_size();
                 self.snd.mss = mss;
+                self.snd.peer_mss = mss;
 
                 self.segments.push_front(Segment {
                     sno: self.snd.nxt,
@@ -1058,15 +2053,14 @@ impl TCB {
                     retry: false,
                     total_ret_time: 0,
                     sent: None,
-                    mss: None,
+                    mss: Some(self.rcv.mss),
                 });
 
                 self.snd.nxt = self.snd.iss.wrapping_add(1);
 
-                println!("\t\tState <- SynRcvd");
-                self.state = State::SynRcvd;
+                self.set_state(State::SynRcvd);
 
-                return Action::AddToPending(self.clone());
+                return Action::AddToPending;
             }
 
             return Action::Noop;
@@ -1125,13 +2119,18 @@ impl TCB {
                     self.snd.nxt.wrapping_add(1),
                 ) {
                     if tcph.rst() {
-                        return Action::Reset;
+                        println!("\t\tConnection refused");
+                        return Action::ConnectionRefused;
                     }
                 } else {
-                    write_reset(&ip4h, &tcph, &[], tun);
+                    self.dropped = Some(DropReason::InvalidState);
+                    write_reset(&ip4h, &tcph, &[], self.ip_opts, out);
 
                     return Action::Noop;
                 }
+            } else if tcph.rst() {
+                self.dropped = Some(DropReason::InvalidState);
+                return Action::Noop;
             }
 
             if tcph.syn() {
@@ -1149,31 +2148,47 @@ impl TCB {
                         self.snd.max_wnd = self.snd.wnd;
                     }
 
-                    self.outgoing.reserve_exact(self.snd.wnd as usize);
-                    self.incoming.reserve_exact(64240);
+                    self.outgoing
+                        .set_capacity(self.opts.send_buffer.unwrap_or(self.snd.wnd as usize));
+                    self.incoming.set_capacity(self.opts.recv_buffer);
 
                     // Pop the syn segment and turn off its timer
-                    self.segments.pop_front().unwrap();
-                    assert!(self.segments.is_empty());
+                    self.segments.pop_front();
+                    debug_assert!(self.segments.is_empty());
 
                     self.timeout.take();
 
-                    println!("\t\tState <- Estab");
-                    self.state = State::Estab;
+                    self.set_state(State::Estab);
 
-                    write_ack(&self.quad, self.snd.nxt, self.rcv.nxt, self.snd.wnd, tun);
+                    write_ack(
+                        &self.quad,
+                        self.snd.nxt,
+                        self.rcv.nxt,
+                        self.snd.wnd,
+                        self.ip_opts,
+                        out,
+                    );
 
                     return Action::IsEstablished;
                 } else {
-                    println!("\t\tState <- SynRcvd");
-                    self.state = State::SynRcvd;
+                    self.set_state(State::SynRcvd);
 
-                    write_synack(&self.quad, self.snd.nxt, self.rcv.nxt, self.snd.wnd, tun);
+                    write_synack(
+                        &self.quad,
+                        self.snd.nxt,
+                        self.rcv.nxt,
+                        self.snd.wnd,
+                        self.rcv.mss,
+                        self.ip_opts,
+                        out,
+                    );
 
                     return Action::Noop;
                 }
             }
 
+            // Neither SYN nor RST
+            self.dropped = Some(DropReason::InvalidState);
             return Action::Noop;
         } else {
             /*
@@ -1188,19 +2203,40 @@ impl TCB {
                 -   LAST-ACK STATE
                 -   TIME-WAIT STATE
             */
+            if self.is_keepalive_probe(&tcph, data) {
+                println!("\t\tKeep-alive probe");
+                write_ack(
+                    &self.quad,
+                    self.snd.nxt,
+                    self.rcv.nxt,
+                    self.rcv.wnd,
+                    self.ip_opts,
+                    out,
+                );
+
+                return Action::Noop;
+            }
+
             let seg_len =
-                data.len() + if tcph.ack() { 1 } else { 0 } + if tcph.fin() { 1 } else { 0 };
+                data.len() + if tcph.syn() { 1 } else { 0 } + if tcph.fin() { 1 } else { 0 };
+
+            // A window closed under memory pressure is reopened once it has eased
+            if self.rcv.wnd == 0 {
+                self.reopen_window();
+            }
 
             // If an incoming segment is not acceptable, an acknowledgment
             // should be sent in reply (unless the RST bit is set, if so
             // drop the segment and return)
             if !self.is_segment_valid(&tcph, seg_len as u32) {
+                self.dropped = Some(DropReason::OutOfWindow);
+
                 if tcph.rst() {
                     return Action::Noop;
                 }
 
                 println!("\t\tSegment invalid");
-                write_ack(&self.quad, self.snd.nxt, self.rcv.nxt, self.rcv.wnd, tun);
+                self.write_throttled_ack(out);
 
                 // After sending the acknowledgment, drop the unacceptable
                 // segment and return.
@@ -1247,6 +2283,7 @@ impl TCB {
                     */
 
                     self.reset.store(true, Ordering::Release);
+                    self.set_error(Error::ConnectionReset(self.context()));
                     return Action::Reset;
                 }
             }
@@ -1317,7 +2354,8 @@ impl TCB {
                     */
 
                     // For now we don't implement RFC 5961 so we just send a reset.
-                    write_reset(&ip4h, &tcph, data, tun);
+                    write_reset(&ip4h, &tcph, data, self.ip_opts, out);
+                    self.set_error(Error::ConnectionReset(self.context()));
 
                     return Action::Reset;
                 }
@@ -1326,6 +2364,7 @@ impl TCB {
             // Fifth, check the ACK field:
             // -    if the ACK bit is off, drop the segment and return
             if !tcph.ack() {
+                self.dropped = Some(DropReason::InvalidState);
                 return Action::Noop;
             }
 
@@ -1355,29 +2394,32 @@ impl TCB {
                     tcph.acknowledgment_number(),
                     self.snd.nxt.wrapping_add(1),
                 ) {
-                    println!("\t\tState <- Estab");
-                    self.state = State::Estab;
+                    self.set_state(State::Estab);
 
                     self.snd.wnd = tcph.window_size();
                     self.snd.wl1 = tcph.sequence_number();
                     self.snd.wl2 = tcph.acknowledgment_number();
 
+                    // The ack covers our syn
+                    self.snd.una = tcph.acknowledgment_number();
+
                     if self.snd.wnd > self.snd.max_wnd {
                         self.snd.max_wnd = self.snd.wnd;
                     }
 
-                    self.outgoing.reserve_exact(self.snd.wnd as usize);
-                    self.incoming.reserve_exact(64240);
+                    self.outgoing
+                        .set_capacity(self.opts.send_buffer.unwrap_or(self.snd.wnd as usize));
+                    self.incoming.set_capacity(self.opts.recv_buffer);
 
                     // Pop the syn segment and turn off its timer
-                    self.segments.pop_front().unwrap();
-                    assert!(self.segments.is_empty());
+                    self.segments.pop_front();
+                    debug_assert!(self.segments.is_empty());
 
                     self.timeout.take();
 
                     return Action::IsEstablished;
                 } else {
-                    write_reset(&ip4h, &tcph, data, tun);
+                    write_reset(&ip4h, &tcph, data, self.ip_opts, out);
 
                     return Action::Noop;
                 }
@@ -1412,6 +2454,17 @@ impl TCB {
                     update the window.
                 */
 
+                // RFC 5681 - S2: acknowledges nothing new while data is outstanding
+                if tcph.acknowledgment_number() == self.snd.una
+                    && self.snd.una != self.snd.nxt
+                    && data.is_empty()
+                    && !tcph.syn()
+                    && !tcph.fin()
+                    && tcph.window_size() == self.snd.wnd
+                {
+                    self.stats.dup_acks += 1;
+                }
+
                 if is_between_wrapped(
                     self.snd.una,
                     tcph.acknowledgment_number(),
@@ -1428,7 +2481,7 @@ impl TCB {
                     wake_up_writer = can_write;
                 } else if wrapping_lt(self.snd.nxt, tcph.acknowledgment_number()) {
                     println!("\t\tInvalid Ack");
-                    write_ack(&self.quad, self.snd.nxt, self.rcv.nxt, self.rcv.wnd, tun);
+                    self.write_throttled_ack(out);
 
                     return Action::Noop;
                 }
@@ -1442,19 +2495,29 @@ impl TCB {
                         || (self.snd.wl1 == tcph.sequence_number()
                             && wrapping_lt(self.snd.wl2, tcph.sequence_number().wrapping_add(1)))
                     {
+                        if self.snd.wnd != 0 && tcph.window_size() == 0 {
+                            self.stats.zero_windows += 1;
+                            self.zero_window_since = Some(self.clock.now());
+                        }
+
                         self.snd.wnd = tcph.window_size();
                         self.snd.wl1 = tcph.sequence_number();
                         self.snd.wl2 = tcph.acknowledgment_number();
 
                         if self.snd.wnd > self.snd.max_wnd {
-                            self.snd.wnd = self.snd.max_wnd;
+                            self.snd.max_wnd = self.snd.wnd;
+                        }
+
+                        if wrapping_lt(self.right_window_edge(), self.snd.nxt) {
+                            println!("\t\tPeer shrunk the window below SND.NXT");
                         }
 
                         if self.snd.wnd == 0 {
                             self.probe_timeout =
-                                Some(Instant::now() + Duration::from_millis(self.rto as u64));
+                                Some(self.clock.now() + Duration::from_millis(self.rto as u64));
                         } else {
                             self.probe_timeout.take();
+                            self.zero_window_since = None;
                         }
                     }
                 }
@@ -1478,10 +2541,17 @@ impl TCB {
                 restart the 2 MSL timeout.
                 */
 
-                self.time_wait = Some(Instant::now() + Duration::from_secs(2 * 2 * 60));
+                self.time_wait = Some(self.clock.now() + Duration::from_secs(2 * 2 * 60));
 
                 println!("\tAck retransmitted fin");
-                write_ack(&self.quad, self.snd.nxt, self.rcv.nxt, self.rcv.wnd, tun);
+                write_ack(
+                    &self.quad,
+                    self.snd.nxt,
+                    self.rcv.nxt,
+                    self.rcv.wnd,
+                    self.ip_opts,
+                    out,
+                );
             }
 
             /*
@@ -1491,8 +2561,7 @@ impl TCB {
             */
             if self.state == State::FinWait1 {
                 if self.is_fin_acked() {
-                    println!("\t\tState <- FinWait2");
-                    self.state = State::FinWait2;
+                    self.set_state(State::FinWait2);
                 }
             }
 
@@ -1510,6 +2579,19 @@ impl TCB {
                 wake_up_closer = true;
             }
 
+            /*
+            In addition to the processing for the ESTABLISHED state,
+            if the ACK acknowledges our FIN, then enter the TIME-WAIT
+            state; otherwise, ignore the segment.
+            */
+            if self.state == State::Closing && self.is_fin_acked() {
+                self.set_state(State::TimeWait);
+                self.timeout = None;
+                self.time_wait = Some(self.clock.now() + Duration::from_secs(2 * 2 * 60));
+
+                wake_up_closer = true;
+            }
+
             let mut process_fin = tcph.fin();
 
             // Seventh, process the segment text:
@@ -1552,15 +2634,35 @@ impl TCB {
                     transmitted if possible without incurring undue delay.
                 */
 
+                // Segments are not reassembled, so one past RCV.NXT is dropped and the
+                // gap acknowledged again, for the sender to retransmit it
+                let ahead = wrapping_lt(self.rcv.nxt, tcph.sequence_number());
+                if ahead {
+                    println!("\t\tSegment out of order");
+                }
+
                 let new = (self.rcv.nxt.wrapping_sub(tcph.sequence_number())) as usize;
-                let new_len = data.len() - new;
+                let new_len = if ahead { 0 } else { data.len() - new };
                 let acc_len = cmp::min(new_len, self.rcv.wnd as usize);
 
-                let data = &data[new..new + acc_len];
+                let data = if ahead {
+                    &[]
+                } else {
+                    &data[new..new + acc_len]
+                };
+
+                process_fin &= !ahead && new_len == acc_len;
 
-                process_fin &= new_len == acc_len;
+                // The read half may have been shut down, in which case the data is dropped
+                let discard = self.read_closed.load(Ordering::Acquire);
+                if !discard {
+                    self.incoming.extend_from_slice(data);
 
-                self.incoming.extend(data.iter());
+                    // The segment has been emptied, so the user learns of its PUSH
+                    if tcph.psh() && !data.is_empty() && new_len == acc_len {
+                        self.pushes.push_back(self.incoming.len());
+                    }
+                }
 
                 let pre_nxt = self.rcv.nxt;
                 self.rcv.nxt = self
@@ -1570,15 +2672,24 @@ impl TCB {
                     .wrapping_add(if process_fin { 1 } else { 0 });
 
                 let pre_wnd = self.rcv.wnd;
-                self.rcv.wnd = self.rcv.wnd - acc_len as u16;
+                if !discard {
+                    self.rcv.wnd = self.rcv.wnd - acc_len as u16;
+                }
 
                 // Only ack if accepted new data, or the window was zero and this is a probe segment
-                if wrapping_lt(pre_nxt, self.rcv.nxt) || pre_wnd == 0 {
+                if wrapping_lt(pre_nxt, self.rcv.nxt) || pre_wnd == 0 || ahead {
                     println!("\tAck data");
-                    write_ack(&self.quad, self.snd.nxt, self.rcv.nxt, self.rcv.wnd, tun);
+                    write_ack(
+                        &self.quad,
+                        self.snd.nxt,
+                        self.rcv.nxt,
+                        self.rcv.wnd,
+                        self.ip_opts,
+                        out,
+                    );
                 }
 
-                wake_up_reader = !data.is_empty();
+                wake_up_reader = !data.is_empty() && !discard;
             } else if self.state == State::CloseWait
                 || self.state == State::Closing
                 || self.state == State::LastAck
@@ -1639,33 +2750,34 @@ impl TCB {
                 println!("\t\tProcessing FIN");
                 if self.state == State::Listen || self.state == State::SynSent {
                     return Action::Noop;
-                } else if self.state == State::SynRcvd || self.state == State::Estab {
-                    println!("\t\tState <- CloseWait");
-                    self.state = State::CloseWait;
-                    self.read_closed.store(true, Ordering::Release);
+                }
+
+                // Readers see the end of the stream once they have drained what came before
+                if !self.read_closed.swap(true, Ordering::AcqRel) {
                     wake_up_reader = true;
+                }
+
+                if self.state == State::SynRcvd || self.state == State::Estab {
+                    self.set_state(State::CloseWait);
                 } else if self.state == State::FinWait1 {
                     if self.is_fin_acked() {
-                        println!("\t\tState <- TimeWait");
-                        self.state = State::TimeWait;
+                        self.set_state(State::TimeWait);
                         self.timeout = None;
-                        self.time_wait = Some(Instant::now() + Duration::from_secs(2 * 2 * 60));
+                        self.time_wait = Some(self.clock.now() + Duration::from_secs(2 * 2 * 60));
                     } else {
-                        println!("\t\tState <- Closing");
-                        self.state = State::Closing;
+                        self.set_state(State::Closing);
                     }
                 } else if self.state == State::FinWait2 {
-                    println!("\t\tState <- TimeWait");
-                    self.state = State::TimeWait;
+                    self.set_state(State::TimeWait);
                     self.timeout = None;
-                    self.time_wait = Some(Instant::now() + Duration::from_secs(2 * 2 * 60));
+                    self.time_wait = Some(self.clock.now() + Duration::from_secs(2 * 2 * 60));
                 } else if self.state == State::CloseWait
                     || self.state == State::Closing
                     || self.state == State::LastAck
                 {
                     return Action::Noop;
                 } else if self.state == State::TimeWait {
-                    self.time_wait = Some(Instant::now() + Duration::from_secs(2 * 2 * 60));
+                    self.time_wait = Some(self.clock.now() + Duration::from_secs(2 * 2 * 60));
                 }
             }
 
@@ -1677,6 +2789,45 @@ impl TCB {
         }
     }
 
+    /*
+            RFC 9293 - S3.8.4. TCP Keep-Alives
+
+    Keep-alive packets MUST only be sent when no sent data is outstanding,
+    and no data or acknowledgment packets have been received for the
+    connection within an interval (MUST-26). This interval MUST be
+    configurable (MUST-27) and MUST default to no less than two hours
+    (MUST-28).
+
+    An implementation SHOULD send a keep-alive segment with no data
+    (SHLD-12); however, it MAY be configurable to send a keep-alive segment
+    containing one garbage octet (MAY-6), for compatibility with erroneous
+    TCP implementations.
+
+    Such a segment generally contains SEG.SEQ = SND.NXT-1 and may or may not
+    contain one garbage octet of data. It is answered with an ACK carrying
+    the current RCV.NXT, and it must not otherwise affect the connection.
+    */
+    fn is_keepalive_probe(&self, tcph: &TcpHeaderSlice, data: &[u8]) -> bool {
+        let synchronized = matches!(
+            self.state,
+            State::Estab
+                | State::FinWait1
+                | State::FinWait2
+                | State::CloseWait
+                | State::Closing
+                | State::LastAck
+                | State::TimeWait
+        );
+
+        synchronized
+            && tcph.ack()
+            && !tcph.syn()
+            && !tcph.fin()
+            && !tcph.rst()
+            && data.len() <= 1
+            && tcph.sequence_number() == self.rcv.nxt.wrapping_sub(1)
+    }
+
     /*
     There are four cases for the acceptability test for an
     incoming segment:
@@ -1725,6 +2876,17 @@ impl TCB {
     }
 }
 
+/*
+A segment that could not be sent is as good as lost on the way, and is
+retransmitted like one. The error is kept for the stack to count.
+*/
+fn failed(send_error: &mut Option<io::ErrorKind>, res: io::Result<()>) {
+    if let Err(err) = res {
+        println!("\t\t\t!!!Failed to send segment: {}!!!", err);
+        *send_error = Some(err.kind());
+    }
+}
+
 fn wrapping_lt(lhs: u32, rhs: u32) -> bool {
     // From RFC1323:
     //     TCP determines if a data segment is "old" or "new" by testing
@@ -1739,3 +2901,463 @@ fn wrapping_lt(lhs: u32, rhs: u32) -> bool {
 fn is_between_wrapped(start: u32, x: u32, end: u32) -> bool {
     wrapping_lt(start, x) && wrapping_lt(x, end)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::IoSlice;
+
+    use etherparse::{PacketBuilder, PacketBuilderStep, TcpHeader};
+
+    use super::*;
+
+    const LOCAL: Dual = Dual::new(Ipv4Addr::new(10, 0, 0, 1), 4001);
+    const REMOTE: Dual = Dual::new(Ipv4Addr::new(10, 0, 0, 2), 80);
+
+    const PEER_ISS: u32 = 7000;
+
+    // A hundred octets short of wrapping around
+    const WRAP: u32 = u32::MAX - 99;
+
+    // A connection that has sent up to nxt of a window of wnd octets starting at una
+    fn sending(una: u32, nxt: u32, wnd: u16) -> TCB {
+        let mut tcb = TCB::syn_sent(
+            Quad::new(LOCAL, REMOTE),
+            &IssGenerator::default(),
+            Arc::new(AckThrottle::default()),
+            Arc::new(MemoryPool::default()),
+            Arc::new(SystemClock),
+            IpOpts::default(),
+            TcpOptions::default(),
+        );
+        tcb.state = State::Estab;
+        tcb.snd.una = una;
+        tcb.snd.nxt = nxt;
+        tcb.snd.wnd = wnd;
+        tcb.segments.clear();
+
+        tcb
+    }
+
+    // A segment of len octets of data, of which everything from una on is unacknowledged
+    fn segment(sno: u32, una: u32, len: u32) -> Segment {
+        Segment {
+            sno,
+            una,
+            len,
+            fin: false,
+            syn: false,
+            ack: true,
+            retry: false,
+            total_ret_time: 0,
+            sent: None,
+            mss: None,
+        }
+    }
+
+    // Keeps every datagram a connection sends
+    #[derive(Debug, Default)]
+    struct Sent(Vec<Vec<u8>>);
+
+    impl Sent {
+        // The headers and data of what has been sent since last time
+        fn segments(&mut self) -> Vec<(TcpHeader, Vec<u8>)> {
+            self.0
+                .drain(..)
+                .map(|datagram| {
+                    let ip4h = Ipv4HeaderSlice::from_slice(&datagram).unwrap();
+                    let tcph = TcpHeaderSlice::from_slice(&datagram[ip4h.slice().len()..]).unwrap();
+                    let data = datagram[ip4h.slice().len() + tcph.slice().len()..].to_vec();
+
+                    (tcph.to_header(), data)
+                })
+                .collect()
+        }
+    }
+
+    impl Emitter for Sent {
+        fn emit(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+            let datagram: Vec<u8> = bufs.iter().flat_map(|b| b.iter()).copied().collect();
+            let len = datagram.len();
+            self.0.push(datagram);
+
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn right_window_edge_is_una_plus_wnd() {
+        assert_eq!(sending(1000, 1000, 500).right_window_edge(), 1500);
+        assert_eq!(sending(1000, 1000, 0).right_window_edge(), 1000);
+    }
+
+    #[test]
+    fn right_window_edge_wraps_around() {
+        assert_eq!(sending(WRAP, WRAP, 300).right_window_edge(), 200);
+    }
+
+    #[test]
+    fn usable_window_is_what_is_left_past_nxt() {
+        assert_eq!(sending(1000, 1000, 500).usable_window(), 500);
+        assert_eq!(sending(1000, 1200, 500).usable_window(), 300);
+        assert_eq!(sending(1000, 1500, 500).usable_window(), 0);
+    }
+
+    #[test]
+    fn usable_window_is_empty_with_nxt_past_the_edge() {
+        // The peer shrunk its window below what was already sent
+        assert_eq!(sending(1000, 1800, 500).usable_window(), 0);
+        assert_eq!(sending(1000, 1800, 0).usable_window(), 0);
+    }
+
+    #[test]
+    fn usable_window_wraps_around() {
+        assert_eq!(sending(WRAP, WRAP + 50, 300).usable_window(), 250);
+        assert_eq!(sending(WRAP, 50, 300).usable_window(), 150);
+
+        // SND.NXT has wrapped past an edge that has wrapped too
+        assert_eq!(sending(WRAP, 250, 300).usable_window(), 0);
+    }
+
+    #[test]
+    fn mss_is_never_raised_above_that_of_the_peer() {
+        let mut tcb = sending(1000, 1000, 0);
+        tcb.snd.peer_mss = 8960;
+
+        tcb.set_mss(9000);
+        assert_eq!(tcb.mss(), 8960);
+
+        tcb.set_mss(1460);
+        assert_eq!(tcb.mss(), 1460);
+    }
+
+    #[test]
+    fn nothing_is_beyond_the_window_without_segments() {
+        assert!(!sending(1000, 1000, 0).is_beyond_window());
+    }
+
+    #[test]
+    fn is_beyond_window_once_una_reaches_the_edge() {
+        let mut tcb = sending(1000, 1500, 200);
+        tcb.segments.push_back(segment(1000, 1000, 500));
+        assert!(!tcb.is_beyond_window());
+
+        // Everything up to the edge has been acknowledged, the rest lies past it
+        tcb.snd.una = 1200;
+        tcb.snd.wnd = 0;
+        tcb.segments[0].una = 1200;
+        assert!(tcb.is_beyond_window());
+
+        tcb.segments[0].una = 1300;
+        assert!(tcb.is_beyond_window());
+    }
+
+    #[test]
+    fn is_beyond_window_wraps_around() {
+        let mut tcb = sending(WRAP, 100, 300);
+        tcb.segments.push_back(segment(WRAP, WRAP, 200));
+        assert!(!tcb.is_beyond_window());
+
+        tcb.snd.una = 50;
+        tcb.snd.wnd = 0;
+        tcb.segments[0].una = 50;
+        assert!(tcb.is_beyond_window());
+    }
+
+    #[test]
+    fn syns_and_fins_are_never_beyond_the_window() {
+        let mut tcb = sending(1000, 1001, 0);
+
+        let mut syn = segment(1000, 1000, 1);
+        syn.syn = true;
+        tcb.segments.push_back(syn);
+        assert!(!tcb.is_beyond_window());
+
+        let mut fin = segment(1000, 1000, 1);
+        fin.fin = true;
+        tcb.segments[0] = fin;
+        assert!(!tcb.is_beyond_window());
+    }
+
+    // 600 octets are out and 400 more are waiting when the peer shrinks its window to 200
+    fn shrunk(una: u32) -> TCB {
+        let mut tcb = sending(una, una.wrapping_add(600), 200);
+        tcb.outgoing.extend_from_slice(&[7; 1000]);
+        tcb.segments.push_back(segment(una, una, 600));
+        tcb.timeout = Some(tcb.clock.now());
+
+        tcb
+    }
+
+    #[test]
+    fn nothing_past_a_shrunk_window_is_retransmitted() {
+        for una in [1000, WRAP] {
+            let mut tcb = shrunk(una);
+
+            let mut out = Sent::default();
+            assert!(!tcb.on_tick(&mut out));
+
+            // Only what lies below the right edge, and nothing new
+            let sent = out.segments();
+            assert_eq!(sent.len(), 1);
+            let (tcph, data) = &sent[0];
+            assert_eq!(tcph.sequence_number, una);
+            assert_eq!(data.len(), 200);
+            assert!(!tcph.fin);
+
+            assert_eq!(tcb.snd.nxt, una.wrapping_add(600));
+        }
+    }
+
+    #[test]
+    fn data_wholly_past_a_shrunk_window_times_out_without_being_sent() {
+        let mut tcb = shrunk(1000);
+        tcb.snd.wnd = 0;
+        let rto = tcb.rto;
+
+        let mut out = Sent::default();
+        assert!(!tcb.on_tick(&mut out));
+
+        // The timer is armed again but not backed off
+        assert!(out.segments().is_empty());
+        assert_eq!(tcb.rto, rto);
+        assert!(tcb.timeout.is_some_and(|timeout| timeout > tcb.clock.now()));
+        assert_eq!(tcb.stats.retransmits, 0);
+    }
+
+    fn from_peer(seq: u32) -> PacketBuilderStep<TcpHeader> {
+        from_peer_with_window(seq, u16::MAX)
+    }
+
+    fn from_peer_with_window(seq: u32, window: u16) -> PacketBuilderStep<TcpHeader> {
+        PacketBuilder::ipv4(REMOTE.ipv4.octets(), LOCAL.ipv4.octets(), 64).tcp(
+            REMOTE.port,
+            LOCAL.port,
+            seq,
+            window,
+        )
+    }
+
+    fn deliver(
+        tcb: &mut TCB,
+        builder: PacketBuilderStep<TcpHeader>,
+        data: &[u8],
+        out: &mut Sent,
+    ) -> Action {
+        let mut buf = Vec::with_capacity(builder.size(data.len()));
+        builder.write(&mut buf, data).unwrap();
+
+        let ip4h = Ipv4HeaderSlice::from_slice(&buf).unwrap();
+        let tcph = TcpHeaderSlice::from_slice(&buf[ip4h.slice().len()..]).unwrap();
+        let data = &buf[ip4h.slice().len() + tcph.slice().len()..];
+
+        tcb.on_segment(ip4h, tcph, data, out)
+    }
+
+    fn mss(size: u16) -> [TcpOptionElement; 1] {
+        [TcpOptionElement::MaximumSegmentSize(size)]
+    }
+
+    // An active open, established with a peer whose ISS is PEER_ISS, on a clock that stands still
+    fn established() -> (TCB, MockClock) {
+        let clock = MockClock::new();
+        let mut tcb = TCB::syn_sent(
+            Quad::new(LOCAL, REMOTE),
+            &IssGenerator::default(),
+            Arc::new(AckThrottle::default()),
+            Arc::new(MemoryPool::default()),
+            Arc::new(clock.clone()),
+            IpOpts::default(),
+            TcpOptions::default(),
+        );
+        let iss = tcb.iss();
+
+        let mut out = Sent::default();
+        assert!(!tcb.on_tick(&mut out));
+
+        let sent = out.segments();
+        assert_eq!(sent.len(), 1);
+        let (syn, _) = &sent[0];
+        assert!(syn.syn && !syn.ack);
+        assert_eq!(syn.sequence_number, iss);
+
+        let syn_ack = from_peer(PEER_ISS).syn().ack(iss.wrapping_add(1));
+        let syn_ack = syn_ack.options(&mss(1460)).unwrap();
+        let action = deliver(&mut tcb, syn_ack, &[], &mut out);
+        assert!(matches!(action, Action::IsEstablished));
+        assert_eq!(tcb.state, State::Estab);
+
+        // The SYN-ACK is acknowledged right away
+        let sent = out.segments();
+        assert_eq!(sent.len(), 1);
+        let (ack, _) = &sent[0];
+        assert!(ack.ack && !ack.syn);
+        assert_eq!(ack.sequence_number, iss.wrapping_add(1));
+        assert_eq!(ack.acknowledgment_number, PEER_ISS + 1);
+
+        (tcb, clock)
+    }
+
+    #[test]
+    fn active_open_is_established_by_a_syn_ack() {
+        let (tcb, _) = established();
+
+        assert_eq!(tcb.snd.una, tcb.iss().wrapping_add(1));
+        assert_eq!(tcb.rcv.nxt, PEER_ISS + 1);
+        assert_eq!(tcb.snd.peer_mss, 1460);
+    }
+
+    #[test]
+    fn passive_open_is_established_by_the_ack_of_its_syn_ack() {
+        let mut tcb = TCB::listen(
+            Quad::new(LOCAL, REMOTE),
+            &IssGenerator::default(),
+            Arc::new(AckThrottle::default()),
+            Arc::new(MemoryPool::default()),
+            Arc::new(SystemClock),
+            IpOpts::default(),
+            TcpOptions::default(),
+        );
+        let iss = tcb.iss();
+
+        let mut out = Sent::default();
+        let syn = from_peer(PEER_ISS).syn().options(&mss(1460)).unwrap();
+        let action = deliver(&mut tcb, syn, &[], &mut out);
+        assert!(matches!(action, Action::AddToPending));
+        assert_eq!(tcb.state, State::SynRcvd);
+
+        // The SYN-ACK is queued, and goes out on the next tick
+        assert!(out.segments().is_empty());
+        assert!(!tcb.on_tick(&mut out));
+
+        let sent = out.segments();
+        assert_eq!(sent.len(), 1);
+        let (syn_ack, _) = &sent[0];
+        assert!(syn_ack.syn && syn_ack.ack);
+        assert_eq!(syn_ack.sequence_number, iss);
+        assert_eq!(syn_ack.acknowledgment_number, PEER_ISS + 1);
+
+        let ack = from_peer(PEER_ISS + 1).ack(iss.wrapping_add(1));
+        let action = deliver(&mut tcb, ack, &[], &mut out);
+        assert!(matches!(action, Action::IsEstablished));
+        assert_eq!(tcb.state, State::Estab);
+        assert!(out.segments().is_empty());
+    }
+
+    #[test]
+    fn data_is_exchanged_both_ways() {
+        let (mut tcb, _) = established();
+        let iss = tcb.iss();
+        let mut out = Sent::default();
+
+        // What the peer sends is acknowledged and handed to the user
+        let push = from_peer(PEER_ISS + 1).ack(iss.wrapping_add(1)).psh();
+        deliver(&mut tcb, push, b"hello", &mut out);
+
+        let sent = out.segments();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0.acknowledgment_number, PEER_ISS + 6);
+
+        let mut buf = [0; 16];
+        assert_eq!(tcb.recv(&mut buf), 5);
+        assert_eq!(&buf[..5], b"hello");
+
+        // What the user queues goes out on the next tick
+        tcb.outgoing.extend_from_slice(b"world");
+        assert!(!tcb.on_tick(&mut out));
+
+        let sent = out.segments();
+        assert_eq!(sent.len(), 1);
+        let (tcph, data) = &sent[0];
+        assert_eq!(tcph.sequence_number, iss.wrapping_add(1));
+        assert_eq!(tcph.acknowledgment_number, PEER_ISS + 6);
+        assert_eq!(data, b"world");
+        assert_eq!(tcb.snd.nxt, iss.wrapping_add(6));
+
+        // Until the peer acknowledges it
+        let ack = from_peer(PEER_ISS + 6).ack(iss.wrapping_add(6));
+        deliver(&mut tcb, ack, &[], &mut out);
+
+        assert_eq!(tcb.snd.una, iss.wrapping_add(6));
+        assert!(tcb.segments.is_empty());
+        assert!(out.segments().is_empty());
+    }
+
+    #[test]
+    fn unacknowledged_data_is_retransmitted_once_the_rto_has_passed() {
+        let (mut tcb, clock) = established();
+        let iss = tcb.iss();
+        let rto = tcb.rto;
+        let mut out = Sent::default();
+
+        tcb.outgoing.extend_from_slice(b"hello");
+        assert!(!tcb.on_tick(&mut out));
+        assert_eq!(out.segments().len(), 1);
+
+        // Nothing is sent again before the timeout
+        clock.advance(Duration::from_millis(rto as u64 - 1));
+        assert!(!tcb.on_tick(&mut out));
+        assert!(out.segments().is_empty());
+
+        clock.advance(Duration::from_millis(1));
+        assert!(!tcb.on_tick(&mut out));
+
+        let sent = out.segments();
+        assert_eq!(sent.len(), 1);
+        let (tcph, data) = &sent[0];
+        assert_eq!(tcph.sequence_number, iss.wrapping_add(1));
+        assert_eq!(data, b"hello");
+
+        // And the timer is backed off
+        assert_eq!(tcb.stats.retransmits, 1);
+        assert_eq!(tcb.rto, 2 * rto);
+    }
+
+    #[test]
+    fn time_wait_expires_after_twice_the_msl() {
+        let (mut tcb, clock) = established();
+        let iss = tcb.iss();
+        let mut out = Sent::default();
+
+        tcb.close();
+        assert!(!tcb.on_tick(&mut out));
+        let sent = out.segments();
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0].0.fin);
+
+        // The peer acknowledges the FIN and closes its own end at once
+        let fin = from_peer(PEER_ISS + 1).ack(iss.wrapping_add(2)).fin();
+        deliver(&mut tcb, fin, &[], &mut out);
+        assert_eq!(tcb.state, State::TimeWait);
+
+        clock.advance(Duration::from_secs(4 * 60) - Duration::from_millis(1));
+        assert!(!tcb.on_tick(&mut out));
+
+        clock.advance(Duration::from_millis(1));
+        assert!(tcb.on_tick(&mut out));
+    }
+
+    #[test]
+    fn a_zero_window_is_probed_once_the_rto_has_passed() {
+        let (mut tcb, clock) = established();
+        let iss = tcb.iss();
+        let mut out = Sent::default();
+
+        let closed = from_peer_with_window(PEER_ISS + 1, 0).ack(iss.wrapping_add(1));
+        deliver(&mut tcb, closed, &[], &mut out);
+        assert_eq!(tcb.snd.wnd, 0);
+
+        // Nothing is sent into the closed window
+        tcb.outgoing.extend_from_slice(b"hello");
+        assert!(!tcb.on_tick(&mut out));
+        assert!(out.segments().is_empty());
+
+        clock.advance(Duration::from_millis(tcb.rto as u64));
+        assert!(!tcb.on_tick(&mut out));
+
+        // The probe is below SND.UNA, so that the peer answers it with its window
+        let sent = out.segments();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0.sequence_number, iss);
+        assert_eq!(tcb.snd.nxt, iss.wrapping_add(1));
+    }
+}
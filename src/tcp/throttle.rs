@@ -0,0 +1,65 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/*
+        RFC 5961 - S7. ACK Throttling
+
+In order to alleviate multiple RSTs/SYNs from triggering multiple
+challenge ACKs, an ACK throttling mechanism is suggested as follows:
+
+1)  The system administrator can configure the number of challenge ACKs
+    that can be sent out in a specified time interval.
+
+2)  Alternatively, a throttling mechanism may be employed to limit the
+    number of challenge ACKs. One suggested mechanism is to use a
+    counter that resets itself after each interval, limiting the number
+    of ACKs sent in response to the same flood.
+
+An implementation SHOULD include an ACK throttling mechanism to be
+conservative. While we have not encountered a case where the lack of ACK
+throttling can be exploited, as a fail-safe mechanism we recommend its
+use.
+*/
+pub const ACK_THROTTLE_LIMIT: u32 = 100;
+pub const ACK_THROTTLE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+pub struct AckThrottle {
+    limit: u32,
+    interval: Duration,
+    window: Mutex<(Instant, u32)>,
+}
+
+impl AckThrottle {
+    pub fn new(limit: u32, interval: Duration) -> Self {
+        AckThrottle {
+            limit,
+            interval,
+            window: Mutex::new((Instant::now(), 0)),
+        }
+    }
+
+    pub fn allow(&self) -> bool {
+        let mut window = self.window.lock().unwrap();
+        let (start, sent) = &mut *window;
+
+        if start.elapsed() >= self.interval {
+            *start = Instant::now();
+            *sent = 0;
+        }
+
+        if *sent >= self.limit {
+            return false;
+        }
+
+        *sent += 1;
+
+        true
+    }
+}
+
+impl Default for AckThrottle {
+    fn default() -> Self {
+        AckThrottle::new(ACK_THROTTLE_LIMIT, ACK_THROTTLE_INTERVAL)
+    }
+}
@@ -0,0 +1,160 @@
+use std::io;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, ClientConnection, ServerConfig, ServerConnection, StreamOwned};
+
+use crate::tcp::TcpStream;
+use crate::{Error, NetStack};
+
+pub type TlsClientStream = StreamOwned<ClientConnection, TcpStream>;
+pub type TlsServerStream = StreamOwned<ServerConnection, TcpStream>;
+
+/*
+Both ends of the handshake are driven to completion before the stream is
+handed back, so certificate errors show up here rather than on the first
+read or write.
+*/
+impl TcpStream {
+    pub fn tls_connect(
+        mut self,
+        config: Arc<ClientConfig>,
+        server_name: &str,
+    ) -> Result<TlsClientStream, Error> {
+        let name = ServerName::try_from(server_name.to_owned())
+            .map_err(|_| Error::InvalidServerName(server_name.to_owned()))?;
+
+        let mut conn = ClientConnection::new(config, name)?;
+        handshake(&mut conn, &mut self)?;
+
+        Ok(StreamOwned::new(conn, self))
+    }
+
+    pub fn tls_accept(mut self, config: Arc<ServerConfig>) -> Result<TlsServerStream, Error> {
+        let mut conn = ServerConnection::new(config)?;
+        handshake(&mut conn, &mut self)?;
+
+        Ok(StreamOwned::new(conn, self))
+    }
+}
+
+impl NetStack {
+    pub fn connect_tls(
+        &mut self,
+        addr: Ipv4Addr,
+        port: u16,
+        config: Arc<ClientConfig>,
+        server_name: &str,
+    ) -> Result<TlsClientStream, Error> {
+        self.connect(addr, port)?.tls_connect(config, server_name)
+    }
+}
+
+fn handshake<D>(conn: &mut rustls::ConnectionCommon<D>, stream: &mut TcpStream) -> io::Result<()> {
+    while conn.is_handshaking() {
+        conn.complete_io(stream)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::thread;
+
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+    use rustls::RootCertStore;
+
+    use super::*;
+
+    const ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+    const MASK: Ipv4Addr = Ipv4Addr::new(255, 255, 255, 0);
+    const PORT: u16 = 443;
+    const NAME: &str = "handshake.test";
+
+    const MESSAGE: &[u8] = b"Hello over TLS, through the loopback device";
+
+    // A server with a certificate of its own signing, and a client that trusts the given one
+    fn server_config() -> (Arc<ServerConfig>, CertificateDer<'static>) {
+        let key = rcgen::generate_simple_self_signed(vec![NAME.to_owned()]).unwrap();
+        let cert = key.cert.der().clone();
+
+        let config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![cert.clone()],
+                PrivateKeyDer::Pkcs8(key.signing_key.serialize_der().into()),
+            )
+            .unwrap();
+
+        (Arc::new(config), cert)
+    }
+
+    fn client_config(trusted: CertificateDer<'static>) -> Arc<ClientConfig> {
+        let mut roots = RootCertStore::empty();
+        roots.add(trusted).unwrap();
+
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        Arc::new(config)
+    }
+
+    #[test]
+    fn echo_over_tls() {
+        let (server, cert) = server_config();
+
+        let mut stack = NetStack::loopback(ADDR, MASK).unwrap();
+        let listener = stack.bind(PORT).unwrap();
+
+        let echo = thread::spawn(move || {
+            let mut tls = listener.accept().unwrap().tls_accept(server).unwrap();
+
+            let mut buf = vec![0; MESSAGE.len()];
+            tls.read_exact(&mut buf).unwrap();
+            tls.write_all(&buf).unwrap();
+        });
+
+        let mut tls = stack
+            .connect_tls(ADDR, PORT, client_config(cert), NAME)
+            .unwrap();
+        tls.write_all(MESSAGE).unwrap();
+
+        let mut echoed = vec![0; MESSAGE.len()];
+        tls.read_exact(&mut echoed).unwrap();
+        assert_eq!(echoed, MESSAGE);
+
+        echo.join().unwrap();
+    }
+
+    #[test]
+    fn untrusted_certificates_fail_the_handshake() {
+        let (server, _) = server_config();
+        let (_, other) = server_config();
+
+        let mut stack = NetStack::loopback(ADDR, MASK).unwrap();
+        let listener = stack.bind(PORT).unwrap();
+
+        let accept = thread::spawn(move || listener.accept().unwrap().tls_accept(server).is_err());
+
+        let res = stack.connect_tls(ADDR, PORT, client_config(other), NAME);
+        assert!(res.is_err());
+
+        assert!(accept.join().unwrap());
+    }
+
+    #[test]
+    fn invalid_server_names_are_refused() {
+        let (_, cert) = server_config();
+
+        // The name is checked before anything is sent, so the connection is never accepted
+        let mut stack = NetStack::loopback(ADDR, MASK).unwrap();
+        let _listener = stack.bind(PORT).unwrap();
+
+        let res = stack.connect_tls(ADDR, PORT, client_config(cert), "not a name!");
+        assert!(matches!(res, Err(Error::InvalidServerName(_))));
+    }
+}
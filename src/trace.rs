@@ -0,0 +1,585 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, IoSlice, Read, Write};
+use std::net::SocketAddrV4;
+use std::os::fd::RawFd;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use etherparse::{ip_number, Ipv4HeaderSlice, TcpHeaderSlice};
+
+use crate::tcp::{AckThrottle, IpOpts, IssGenerator, MemoryPool, MockClock, TcpOptions};
+use crate::{Device, Dual, Emitter, Quad, State, TCB};
+
+// Traces are pcap files of raw IPv4 datagrams, which tcpdump and Wireshark read
+const PCAP_MAGIC: u32 = 0xa1b2c3d4;
+const PCAP_MAGIC_NANOS: u32 = 0xa1b23c4d;
+const LINKTYPE_RAW: u32 = 101;
+const SNAPLEN: u32 = 65535;
+
+/*
+Sits between the stack and a device, recording every datagram received and
+sent, along with when it was, to a trace that Trace::open reads back. Like
+with NetemDevice, segmentation offload is not passed through, so that each
+segment is recorded as it went on the wire.
+
+A datagram that cannot be recorded is still passed on, the trace only
+missing it.
+*/
+#[derive(Debug)]
+pub struct TraceDevice {
+    inner: Box<dyn Device>,
+    out: BufWriter<File>,
+}
+
+impl TraceDevice {
+    pub fn new<D: Device + 'static>(inner: D, path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+
+        out.write_all(&PCAP_MAGIC.to_le_bytes())?;
+        out.write_all(&2u16.to_le_bytes())?;
+        out.write_all(&4u16.to_le_bytes())?;
+        out.write_all(&0i32.to_le_bytes())?; // Timestamps are in UTC
+        out.write_all(&0u32.to_le_bytes())?;
+        out.write_all(&SNAPLEN.to_le_bytes())?;
+        out.write_all(&LINKTYPE_RAW.to_le_bytes())?;
+
+        Ok(TraceDevice {
+            inner: Box::new(inner),
+            out,
+        })
+    }
+
+    fn record(&mut self, datagram: &[u8]) {
+        let at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let len = datagram.len() as u32;
+
+        let res = (|| {
+            self.out.write_all(&(at.as_secs() as u32).to_le_bytes())?;
+            self.out.write_all(&at.subsec_micros().to_le_bytes())?;
+            self.out.write_all(&len.to_le_bytes())?;
+            self.out.write_all(&len.to_le_bytes())?;
+            self.out.write_all(datagram)
+        })();
+
+        if let Err(err) = res {
+            println!("Recording a datagram failed: {}", err);
+        }
+    }
+}
+
+impl Device for TraceDevice {
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.recv(buf)?;
+        self.record(&buf[..n]);
+
+        Ok(n)
+    }
+
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.record(buf);
+
+        self.inner.send(buf)
+    }
+
+    fn send_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        let buf: Vec<u8> = bufs.iter().flat_map(|b| b.iter()).copied().collect();
+        self.record(&buf);
+
+        self.inner.send_vectored(bufs)
+    }
+
+    fn mtu(&self) -> io::Result<usize> {
+        self.inner.mtu()
+    }
+
+    // The trace is written out whenever the device is
+    fn flush(&mut self) -> io::Result<()> {
+        if let Err(err) = self.out.flush() {
+            println!("Writing the trace failed: {}", err);
+        }
+
+        self.inner.flush()
+    }
+
+    fn raw_fd(&self) -> RawFd {
+        self.inner.raw_fd()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub at: SystemTime,
+    pub datagram: Vec<u8>,
+}
+
+// What replaying a trace compares of the segments sent, leaving the options out
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceSegment {
+    pub seq: u32,
+    pub ack: Option<u32>,
+    pub syn: bool,
+    pub fin: bool,
+    pub rst: bool,
+    pub psh: bool,
+    pub window: u16,
+    pub data: Vec<u8>,
+}
+
+/*
+Where a replay parted from the trace. Either may be missing: expected, when
+the connection sent more than the trace has, and produced, when it did not
+send a segment the trace has.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    pub index: Option<usize>, // Of the expected record in the trace
+    pub expected: Option<TraceSegment>,
+    pub produced: Option<TraceSegment>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trace {
+    records: Vec<TraceRecord>,
+}
+
+impl Trace {
+    // Takes pcap files of raw IPv4, in either byte order and timestamp precision
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let mut file = BufReader::new(File::open(path)?);
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+
+        let mut header = [0u8; 24];
+        file.read_exact(&mut header)?;
+
+        let magic = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let (le, nanos) = match magic {
+            PCAP_MAGIC => (true, false),
+            PCAP_MAGIC_NANOS => (true, true),
+            _ if magic.swap_bytes() == PCAP_MAGIC => (false, false),
+            _ if magic.swap_bytes() == PCAP_MAGIC_NANOS => (false, true),
+            _ => return Err(invalid("not a pcap file")),
+        };
+        let u32_at = |buf: &[u8], at: usize| {
+            let bytes = buf[at..at + 4].try_into().unwrap();
+            if le {
+                u32::from_le_bytes(bytes)
+            } else {
+                u32::from_be_bytes(bytes)
+            }
+        };
+
+        if u32_at(&header, 20) != LINKTYPE_RAW {
+            return Err(invalid("not a capture of raw IP datagrams"));
+        }
+
+        let mut records = Vec::new();
+        loop {
+            let mut header = [0u8; 16];
+            match file.read_exact(&mut header) {
+                Ok(()) => {}
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(err) => return Err(err),
+            }
+
+            let secs = u32_at(&header, 0) as u64;
+            let frac = u32_at(&header, 4);
+            let since = if nanos {
+                Duration::new(secs, frac)
+            } else {
+                Duration::new(secs, 0) + Duration::from_micros(frac as u64)
+            };
+
+            let mut datagram = vec![0u8; u32_at(&header, 8) as usize];
+            file.read_exact(&mut datagram)?;
+
+            records.push(TraceRecord {
+                at: UNIX_EPOCH + since,
+                datagram,
+            });
+        }
+
+        Ok(Trace { records })
+    }
+
+    pub fn records(&self) -> &[TraceRecord] {
+        &self.records
+    }
+
+    /*
+    Feeds what the trace has the connection between local and remote receive
+    into a fresh TCB, and checks that it sends what the trace has it send, in
+    the same order. Returns how many segments were checked.
+
+    The TCB takes the ISS of the trace, and the application is played back
+    from the trace as well: data that the trace has sent for the first time
+    is written, and a FIN closes the connection, only once the TCB has
+    nothing else to send. Every segment received is followed by a tick, like
+    the stack does. Time stands still on the clock of the TCB, so timers do
+    not fire, and a trace with retransmissions or keep-alives parts from the
+    replay there.
+    */
+    pub fn replay(
+        &self,
+        local: SocketAddrV4,
+        remote: SocketAddrV4,
+        opts: TcpOptions,
+    ) -> Result<usize, Mismatch> {
+        let quad = Quad::from((local, remote));
+
+        // Whether each segment of the connection was received, in order
+        let segments: Vec<(usize, bool, &[u8])> = self
+            .records
+            .iter()
+            .enumerate()
+            .filter_map(|(index, record)| {
+                let dir = direction(&record.datagram, &quad)?;
+                Some((index, dir, record.datagram.as_slice()))
+            })
+            .collect();
+
+        // The ISS is that of the first SYN sent, without which nothing can be checked
+        let Some(iss) = segments
+            .iter()
+            .filter(|(_, inbound, _)| !inbound)
+            .find_map(|(_, _, datagram)| summarize(datagram).filter(|seg| seg.syn))
+            .map(|seg| seg.seq)
+        else {
+            return Ok(0);
+        };
+
+        let iss_gen = IssGenerator::default();
+        let throttle = Arc::new(AckThrottle::default());
+        let memory = Arc::new(MemoryPool::default());
+        let clock = Arc::new(MockClock::new());
+        let ip_opts = IpOpts::default();
+
+        // Passive if the peer sent the first SYN
+        let passive = segments
+            .first()
+            .is_some_and(|(_, inbound, datagram)| *inbound && is_syn(datagram));
+        let mut tcb = if passive {
+            TCB::listen(quad, &iss_gen, throttle, memory, clock, ip_opts, opts)
+        } else {
+            TCB::syn_sent(quad, &iss_gen, throttle, memory, clock, ip_opts, opts)
+        };
+        tcb.set_iss(iss);
+
+        let mut out = Collector::default();
+        let mut written = iss.wrapping_add(1); // Where the data written so far ends
+        let mut checked = 0;
+
+        for &(index, inbound, datagram) in &segments {
+            if inbound {
+                let Ok(ip4h) = Ipv4HeaderSlice::from_slice(datagram) else {
+                    continue;
+                };
+                let ihl = ip4h.ihl() as usize * 4;
+                let Ok(tcph) = TcpHeaderSlice::from_slice(&datagram[ihl..]) else {
+                    continue;
+                };
+                let data = payload(datagram, &ip4h, &tcph);
+
+                tcb.on_segment(ip4h, tcph, data, &mut out);
+                tcb.on_tick(&mut out);
+
+                continue;
+            }
+
+            let expected = summarize(datagram);
+
+            if out.segments.is_empty() {
+                if let Some(seg) = &expected {
+                    play_application(&mut tcb, seg, &mut written);
+                }
+                tcb.on_tick(&mut out);
+            }
+
+            let produced = out.segments.pop_front();
+            if produced != expected {
+                return Err(Mismatch {
+                    index: Some(index),
+                    expected,
+                    produced,
+                });
+            }
+
+            checked += 1;
+        }
+
+        if let Some(produced) = out.segments.pop_front() {
+            return Err(Mismatch {
+                index: None,
+                expected: None,
+                produced: Some(produced),
+            });
+        }
+
+        Ok(checked)
+    }
+}
+
+// Writes and closes what the application must have for seg to be sent
+fn play_application(tcb: &mut TCB, seg: &TraceSegment, written: &mut u32) {
+    let end = seg
+        .seq
+        .wrapping_add(seg.syn as u32)
+        .wrapping_add(seg.data.len() as u32);
+    let new = end.wrapping_sub(*written) as i32;
+
+    if new > 0 && new as usize <= seg.data.len() {
+        tcb.outgoing
+            .extend_from_slice(&seg.data[seg.data.len() - new as usize..]);
+        *written = end;
+    }
+
+    if seg.fin && matches!(tcb.state, State::Estab | State::CloseWait) {
+        tcb.close();
+    }
+}
+
+// True if the datagram was received on the connection, false if it was sent
+fn direction(datagram: &[u8], quad: &Quad) -> Option<bool> {
+    let ip4h = Ipv4HeaderSlice::from_slice(datagram).ok()?;
+    if ip4h.protocol() != ip_number::TCP {
+        return None;
+    }
+
+    let tcph = TcpHeaderSlice::from_slice(&datagram[ip4h.ihl() as usize * 4..]).ok()?;
+    let src = Dual {
+        ipv4: ip4h.source_addr(),
+        port: tcph.source_port(),
+    };
+    let dst = Dual {
+        ipv4: ip4h.destination_addr(),
+        port: tcph.destination_port(),
+    };
+
+    if src == quad.dst && dst == quad.src {
+        Some(true)
+    } else if src == quad.src && dst == quad.dst {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+fn is_syn(datagram: &[u8]) -> bool {
+    summarize(datagram).is_some_and(|seg| seg.syn && seg.ack.is_none())
+}
+
+// The data ends where the datagram says, not where the capture does
+fn payload<'a>(datagram: &'a [u8], ip4h: &Ipv4HeaderSlice, tcph: &TcpHeaderSlice) -> &'a [u8] {
+    let start = ip4h.ihl() as usize * 4 + tcph.data_offset() as usize * 4;
+    let end = (ip4h.total_len() as usize).clamp(start, datagram.len());
+
+    datagram.get(start..end).unwrap_or(&[])
+}
+
+fn summarize(datagram: &[u8]) -> Option<TraceSegment> {
+    let ip4h = Ipv4HeaderSlice::from_slice(datagram).ok()?;
+    let tcph = TcpHeaderSlice::from_slice(&datagram[ip4h.ihl() as usize * 4..]).ok()?;
+
+    Some(TraceSegment {
+        seq: tcph.sequence_number(),
+        ack: tcph.ack().then(|| tcph.acknowledgment_number()),
+        syn: tcph.syn(),
+        fin: tcph.fin(),
+        rst: tcph.rst(),
+        psh: tcph.psh(),
+        window: tcph.window_size(),
+        data: payload(datagram, &ip4h, &tcph).to_vec(),
+    })
+}
+
+// Takes the segments a replayed connection sends
+#[derive(Debug, Default)]
+struct Collector {
+    segments: VecDeque<TraceSegment>,
+}
+
+impl Emitter for Collector {
+    fn emit(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        let buf: Vec<u8> = bufs.iter().flat_map(|b| b.iter()).copied().collect();
+
+        if let Some(seg) = summarize(&buf) {
+            self.segments.push_back(seg);
+        }
+
+        Ok(buf.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::path::PathBuf;
+    use std::{env, fs, process};
+
+    use super::*;
+    use crate::tcp::Counting;
+    use crate::{LinkConfig, PipeDevice};
+
+    const LOCAL: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 4001);
+    const REMOTE: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 80);
+
+    // Keeps what the peer sends, to be carried over the pipe to the traced end
+    #[derive(Debug, Default)]
+    struct Answers(Vec<Vec<u8>>);
+
+    impl Emitter for Answers {
+        fn emit(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+            let datagram: Vec<u8> = bufs.iter().flat_map(|b| b.iter()).copied().collect();
+            let len = datagram.len();
+            self.0.push(datagram);
+
+            Ok(len)
+        }
+    }
+
+    fn tcb(local: SocketAddrV4, remote: SocketAddrV4, active: bool) -> TCB {
+        let quad = Quad::from((local, remote));
+        let iss = IssGenerator::default();
+        let throttle = Arc::new(AckThrottle::default());
+        let memory = Arc::new(MemoryPool::default());
+        let clock = Arc::new(MockClock::new());
+        let (ip_opts, opts) = (IpOpts::default(), TcpOptions::default());
+
+        if active {
+            TCB::syn_sent(quad, &iss, throttle, memory, clock, ip_opts, opts)
+        } else {
+            TCB::listen(quad, &iss, throttle, memory, clock, ip_opts, opts)
+        }
+    }
+
+    // Every segment received is followed by a tick, like replay does
+    fn deliver(tcb: &mut TCB, datagram: &[u8], out: &mut (impl Emitter + ?Sized)) {
+        let ip4h = Ipv4HeaderSlice::from_slice(datagram).unwrap();
+        let tcph = TcpHeaderSlice::from_slice(&datagram[ip4h.slice().len()..]).unwrap();
+        let data = payload(datagram, &ip4h, &tcph);
+
+        tcb.on_segment(ip4h, tcph, data, out);
+        tcb.on_tick(out);
+    }
+
+    /*
+    A connection opened on the traced end of a pipe, against a peer on the
+    other end. Both are driven by hand, and time stands still on their
+    clocks, so that the same trace is recorded every time.
+    */
+    struct Recording {
+        trace: TraceDevice,
+        far: PipeDevice,
+        local: TCB,
+        remote: TCB,
+        sent: usize, // Segments sent by the connection, which replay checks
+    }
+
+    impl Recording {
+        fn new(path: &Path) -> Self {
+            let (near, far) = PipeDevice::pair(LinkConfig::default()).unwrap();
+
+            Recording {
+                trace: TraceDevice::new(near, path).unwrap(),
+                far,
+                local: tcb(LOCAL, REMOTE, true),
+                remote: tcb(REMOTE, LOCAL, false),
+                sent: 0,
+            }
+        }
+
+        // Ticks the connection, then carries segments back and forth until both ends are quiet
+        fn settle(&mut self) {
+            let mut out = Counting::new(&mut self.trace);
+            self.local.on_tick(&mut out);
+            let mut pending = out.segments as usize;
+
+            let mut buf = [0u8; 1500];
+            while pending > 0 {
+                self.sent += pending;
+
+                let mut answers = Answers::default();
+                for _ in 0..pending {
+                    let n = self.far.recv(&mut buf).unwrap();
+                    deliver(&mut self.remote, &buf[..n], &mut answers);
+                }
+
+                pending = 0;
+                for datagram in answers.0 {
+                    self.far.send(&datagram).unwrap();
+                    let n = self.trace.recv(&mut buf).unwrap();
+
+                    let mut out = Counting::new(&mut self.trace);
+                    deliver(&mut self.local, &buf[..n], &mut out);
+                    pending += out.segments as usize;
+                }
+            }
+
+            self.trace.flush().unwrap();
+        }
+    }
+
+    // The handshake, some data, and a FIN, recorded to path
+    fn record(path: &Path) -> usize {
+        let mut recording = Recording::new(path);
+        recording.settle();
+        assert_eq!(recording.local.state, State::Estab);
+
+        recording.local.outgoing.extend_from_slice(b"hello");
+        recording.settle();
+
+        recording.local.close();
+        recording.settle();
+        assert_eq!(recording.local.state, State::FinWait2);
+
+        recording.sent
+    }
+
+    fn path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("handshake-{}-{name}.pcap", process::id()))
+    }
+
+    #[test]
+    fn a_recorded_connection_replays() {
+        let path = path("replay");
+        let sent = record(&path);
+        let trace = Trace::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert!(sent > 0);
+        assert_eq!(trace.replay(LOCAL, REMOTE, TcpOptions::default()), Ok(sent));
+    }
+
+    #[test]
+    fn a_tampered_trace_does_not_replay() {
+        let path = path("tampered");
+        record(&path);
+        let mut trace = Trace::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        // The window the connection announced in its FIN is not what it would announce
+        let quad = Quad::from((LOCAL, REMOTE));
+        let index = trace
+            .records
+            .iter()
+            .rposition(|record| {
+                direction(&record.datagram, &quad) == Some(false)
+                    && summarize(&record.datagram).is_some_and(|seg| seg.fin)
+            })
+            .unwrap();
+        let datagram = &mut trace.records[index].datagram;
+        let ihl = Ipv4HeaderSlice::from_slice(datagram).unwrap().slice().len();
+        datagram[ihl + 14] ^= 0xff;
+
+        let res = trace.replay(LOCAL, REMOTE, TcpOptions::default());
+        let Err(mismatch) = res else {
+            panic!("the tampered trace replayed: {res:?}");
+        };
+        assert_eq!(mismatch.index, Some(index));
+        assert!(mismatch.expected.is_some_and(|seg| seg.fin));
+        assert!(mismatch.produced.is_some_and(|seg| seg.fin));
+    }
+}
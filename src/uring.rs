@@ -0,0 +1,321 @@
+use std::collections::VecDeque;
+use std::io::{self, IoSlice};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+use io_uring::{cqueue, opcode, squeue, types, IoUring};
+use tidy_tuntap::Tun;
+
+use crate::Device;
+
+const RX_GROUP: u16 = 0;
+const RX: u64 = u64::MAX; // User data of reads, the rest are indices of transmit slots
+const PROVIDE: u64 = u64::MAX - 1;
+
+#[derive(Debug, Clone)]
+pub struct UringConfig {
+    pub entries: u32,
+    pub rx_buffers: u16,
+    pub tx_buffers: u16,
+    pub buf_size: u32,
+    pub batch: u32,
+    pub multishot: bool, // Needs Linux 6.7, single reads are kept in flight otherwise
+}
+
+impl Default for UringConfig {
+    fn default() -> Self {
+        UringConfig {
+            entries: 256,
+            rx_buffers: 256,
+            tx_buffers: 256,
+            buf_size: 2048,
+            batch: 64,
+            multishot: true,
+        }
+    }
+}
+
+/*
+A TUN device driven through io_uring instead of read and write calls.
+
+Reads pick a buffer of their own from a group provided to the kernel, which
+is handed back once the datagram has been copied out. A single multishot
+read keeps receiving until the kernel ends it, otherwise batch single reads
+are kept in flight. Writes are copied into buffers registered with the
+ring, and submitted together once batch of them are queued or the device is
+flushed.
+
+The ring signals completions on an eventfd, which is what the stack polls.
+*/
+pub struct UringDevice {
+    ring: IoUring, // Dropped first, as the kernel refers to the buffers below
+    tun: Tun,
+    event: OwnedFd,
+    rx_bufs: Box<[u8]>,
+    tx_bufs: Box<[u8]>,
+    free: Vec<u16>,                // Transmit slots not in flight
+    ready: VecDeque<(u16, usize)>, // Buffers read into, with their length
+    queued: u32,                   // Writes pushed but not submitted yet
+    cfg: UringConfig,
+}
+
+impl UringDevice {
+    pub fn new(tun: Tun, cfg: UringConfig) -> io::Result<Self> {
+        let ring = IoUring::new(cfg.entries)?;
+
+        let event = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+        if event < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let event = unsafe { OwnedFd::from_raw_fd(event) };
+
+        ring.submitter().register_eventfd(event.as_raw_fd())?;
+
+        let size = cfg.buf_size as usize;
+        let rx_bufs = vec![0u8; cfg.rx_buffers as usize * size].into_boxed_slice();
+        let mut tx_bufs = vec![0u8; cfg.tx_buffers as usize * size].into_boxed_slice();
+
+        let iovecs: Vec<libc::iovec> = tx_bufs
+            .chunks_exact_mut(size)
+            .map(|buf| libc::iovec {
+                iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+                iov_len: buf.len(),
+            })
+            .collect();
+        unsafe { ring.submitter().register_buffers(&iovecs)? };
+
+        let mut dev = UringDevice {
+            ring,
+            tun,
+            event,
+            rx_bufs,
+            tx_bufs,
+            free: (0..cfg.tx_buffers).rev().collect(),
+            ready: VecDeque::new(),
+            queued: 0,
+            cfg,
+        };
+
+        let provide = opcode::ProvideBuffers::new(
+            dev.rx_bufs.as_mut_ptr(),
+            dev.cfg.buf_size as i32,
+            dev.cfg.rx_buffers,
+            RX_GROUP,
+            0,
+        )
+        .build()
+        .user_data(PROVIDE);
+        dev.push(provide)?;
+
+        if dev.cfg.multishot {
+            dev.arm_multishot()?;
+        } else {
+            for _ in 0..dev.cfg.batch {
+                dev.arm_read()?;
+            }
+        }
+        dev.ring.submit()?;
+
+        Ok(dev)
+    }
+
+    fn fd(&self) -> types::Fd {
+        types::Fd(self.tun.as_raw_fd())
+    }
+
+    fn push(&mut self, entry: squeue::Entry) -> io::Result<()> {
+        // A full submission queue is emptied into the kernel first
+        if unsafe { self.ring.submission().push(&entry) }.is_err() {
+            self.ring.submit()?;
+            self.queued = 0;
+
+            unsafe { self.ring.submission().push(&entry) }
+                .map_err(|_| io::Error::from(io::ErrorKind::WouldBlock))?;
+        }
+
+        Ok(())
+    }
+
+    fn arm_multishot(&mut self) -> io::Result<()> {
+        let read = opcode::ReadMulti::new(self.fd(), 0, RX_GROUP)
+            .build()
+            .user_data(RX);
+
+        self.push(read)
+    }
+
+    fn arm_read(&mut self) -> io::Result<()> {
+        let read = opcode::Read::new(self.fd(), std::ptr::null_mut(), self.cfg.buf_size)
+            .buf_group(RX_GROUP)
+            .build()
+            .flags(squeue::Flags::BUFFER_SELECT)
+            .user_data(RX);
+
+        self.push(read)
+    }
+
+    // The buffer of a datagram that has been copied out goes back to the kernel
+    fn provide(&mut self, bid: u16) -> io::Result<()> {
+        let size = self.cfg.buf_size as usize;
+        let buf = self.rx_bufs[bid as usize * size..].as_mut_ptr();
+
+        let provide = opcode::ProvideBuffers::new(buf, size as i32, 1, RX_GROUP, bid)
+            .build()
+            .user_data(PROVIDE);
+
+        self.push(provide)
+    }
+
+    fn reap(&mut self) -> io::Result<()> {
+        let completions: Vec<cqueue::Entry> = self.ring.completion().collect();
+
+        for cqe in completions {
+            match cqe.user_data() {
+                RX => {
+                    let res = cqe.result();
+                    let mut reads = 1;
+
+                    if res >= 0 {
+                        let bid = cqueue::buffer_select(cqe.flags()).unwrap();
+                        self.ready.push_back((bid, res as usize));
+                    } else if res == -libc::EINVAL && self.cfg.multishot {
+                        // Multishot reads are not supported by this kernel
+                        println!("Falling back to single reads");
+                        self.cfg.multishot = false;
+                        reads = self.cfg.batch;
+                    } else if res != -libc::ENOBUFS {
+                        println!("Read failed: {}", io::Error::from_raw_os_error(-res));
+                    }
+
+                    if cqueue::more(cqe.flags()) {
+                        continue;
+                    }
+
+                    if self.cfg.multishot {
+                        self.arm_multishot()?;
+                    } else {
+                        for _ in 0..reads {
+                            self.arm_read()?;
+                        }
+                    }
+                }
+                PROVIDE => {}
+                slot => {
+                    if cqe.result() < 0 {
+                        println!(
+                            "Write failed: {}",
+                            io::Error::from_raw_os_error(-cqe.result())
+                        );
+                    }
+
+                    self.free.push(slot as u16);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Device for UringDevice {
+    /*
+    The eventfd is drained before the completion queue is looked at one last
+    time, so that a completion arriving in between still makes it readable.
+    */
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.ready.is_empty() {
+            self.reap()?;
+        }
+        if self.ready.is_empty() {
+            let mut count = 0u64;
+            unsafe { libc::read(self.event.as_raw_fd(), &mut count as *mut u64 as *mut _, 8) };
+
+            self.reap()?;
+        }
+
+        let Some((bid, len)) = self.ready.pop_front() else {
+            self.ring.submit()?;
+            return Err(io::ErrorKind::WouldBlock.into());
+        };
+
+        let start = bid as usize * self.cfg.buf_size as usize;
+        let n = len.min(buf.len());
+        buf[..n].copy_from_slice(&self.rx_bufs[start..start + n]);
+
+        self.provide(bid)?;
+
+        // Buffers are handed back in one submission once all that was read is taken
+        if self.ready.is_empty() {
+            self.ring.submit()?;
+        }
+
+        Ok(n)
+    }
+
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.send_vectored(&[IoSlice::new(buf)])
+    }
+
+    fn send_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        let len: usize = bufs.iter().map(|b| b.len()).sum();
+        let size = self.cfg.buf_size as usize;
+
+        if len > size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "datagram exceeds the buffer size",
+            ));
+        }
+
+        // Waits for a write to complete when every slot is in flight
+        if self.free.is_empty() {
+            self.reap()?;
+        }
+        if self.free.is_empty() {
+            self.ring.submit_and_wait(1)?;
+            self.queued = 0;
+            self.reap()?;
+        }
+        let Some(slot) = self.free.pop() else {
+            return Err(io::ErrorKind::WouldBlock.into());
+        };
+
+        let start = slot as usize * size;
+        let mut at = start;
+        for buf in bufs {
+            self.tx_bufs[at..at + buf.len()].copy_from_slice(buf);
+            at += buf.len();
+        }
+
+        let ptr = self.tx_bufs[start..].as_ptr();
+        let write = opcode::WriteFixed::new(self.fd(), ptr, len as u32, slot)
+            .build()
+            .user_data(slot as u64);
+        self.push(write)?;
+        self.queued += 1;
+
+        if self.queued >= self.cfg.batch {
+            self.flush()?;
+        }
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.queued == 0 {
+            return Ok(());
+        }
+
+        self.ring.submit()?;
+        self.queued = 0;
+
+        Ok(())
+    }
+
+    fn mtu(&self) -> io::Result<usize> {
+        Ok(self.tun.get_mtu()? as usize)
+    }
+
+    fn raw_fd(&self) -> RawFd {
+        self.event.as_raw_fd()
+    }
+}
@@ -0,0 +1,318 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, IoSlice, Read, Write};
+use std::mem;
+use std::net::Ipv4Addr;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+use nix::libc;
+
+use crate::Device;
+
+const VNET_HDR_LEN: usize = 10;
+
+const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 1;
+const VIRTIO_NET_HDR_GSO_NONE: u8 = 0;
+const VIRTIO_NET_HDR_GSO_TCPV4: u8 = 1;
+
+// Largest amount of data handed over in a single TCP super-segment
+pub const GSO_MAX_SIZE: usize = 65535 - 20 - 60;
+
+/*
+        virtio 1.1 - S5.1.6 Device Operation
+
+    struct virtio_net_hdr {
+        u8 flags;
+        u8 gso_type;
+        le16 hdr_len;
+        le16 gso_size;
+        le16 csum_start;
+        le16 csum_offset;
+    };
+
+If flags has VIRTIO_NET_HDR_F_NEEDS_CSUM set, the checksum of the packet
+from csum_start to the end still has to be computed and stored at offset
+csum_offset from csum_start. The field already holds the checksum of the
+pseudo-header.
+
+If gso_type differs from VIRTIO_NET_HDR_GSO_NONE, the packet is larger than
+the MTU and is cut into segments carrying gso_size octets of data each,
+every one of them repeating the first hdr_len octets of headers.
+*/
+#[derive(Debug, Default, Clone, Copy)]
+struct VnetHdr {
+    flags: u8,
+    gso_type: u8,
+    hdr_len: u16,
+    gso_size: u16,
+    csum_start: u16,
+    csum_offset: u16,
+}
+
+impl VnetHdr {
+    fn from_bytes(b: &[u8]) -> Self {
+        let u16_at = |i: usize| u16::from_le_bytes([b[i], b[i + 1]]);
+
+        VnetHdr {
+            flags: b[0],
+            gso_type: b[1],
+            hdr_len: u16_at(2),
+            gso_size: u16_at(4),
+            csum_start: u16_at(6),
+            csum_offset: u16_at(8),
+        }
+    }
+
+    fn to_bytes(self) -> [u8; VNET_HDR_LEN] {
+        let mut b = [0u8; VNET_HDR_LEN];
+
+        b[0] = self.flags;
+        b[1] = self.gso_type;
+        b[2..4].copy_from_slice(&self.hdr_len.to_le_bytes());
+        b[4..6].copy_from_slice(&self.gso_size.to_le_bytes());
+        b[6..8].copy_from_slice(&self.csum_start.to_le_bytes());
+        b[8..10].copy_from_slice(&self.csum_offset.to_le_bytes());
+
+        b
+    }
+}
+
+fn sum16(data: &[u8], mut sum: u32) -> u32 {
+    let mut chunks = data.chunks_exact(2);
+    for c in &mut chunks {
+        sum += u16::from_be_bytes([c[0], c[1]]) as u32;
+    }
+    if let [last] = chunks.remainder() {
+        sum += (*last as u32) << 8;
+    }
+
+    sum
+}
+
+fn fold(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    sum as u16
+}
+
+/*
+A TUN device opened with IFF_VNET_HDR, so every datagram is preceded by a
+virtio-net header. The kernel is told that we can take partially
+checksummed datagrams and coalesced TCP super-segments, which are passed up
+as one large segment once their checksum has been completed. In the other
+direction, segments of up to GSO_MAX_SIZE octets are handed to the kernel to
+be cut into MSS sized ones.
+*/
+#[derive(Debug)]
+pub struct VnetTun {
+    file: File,
+    sock: OwnedFd, // Only used for configuring the interface
+    name: [libc::c_char; libc::IFNAMSIZ],
+    rbuf: Vec<u8>,
+}
+
+impl VnetTun {
+    pub fn new(name: &str) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/net/tun")?;
+
+        let mut ifreq = VnetTun::ifreq_for(name)?;
+        ifreq.ifr_ifru.ifru_flags = (libc::IFF_TUN | libc::IFF_NO_PI | libc::IFF_VNET_HDR) as _;
+
+        if unsafe { libc::ioctl(file.as_raw_fd(), libc::TUNSETIFF, &mut ifreq) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let offload = (libc::TUN_F_CSUM | libc::TUN_F_TSO4) as libc::c_ulong;
+        if unsafe { libc::ioctl(file.as_raw_fd(), libc::TUNSETOFFLOAD, offload) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let sock = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+        if sock < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(VnetTun {
+            file,
+            sock: unsafe { OwnedFd::from_raw_fd(sock) },
+            name: ifreq.ifr_name,
+            rbuf: vec![0u8; VNET_HDR_LEN + 65535],
+        })
+    }
+
+    fn ifreq_for(name: &str) -> io::Result<libc::ifreq> {
+        if name.len() >= libc::IFNAMSIZ {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "interface name is too long",
+            ));
+        }
+
+        let mut ifreq: libc::ifreq = unsafe { mem::zeroed() };
+        for (dst, src) in ifreq.ifr_name.iter_mut().zip(name.bytes()) {
+            *dst = src as libc::c_char;
+        }
+
+        Ok(ifreq)
+    }
+
+    fn ifreq(&self) -> libc::ifreq {
+        let mut ifreq: libc::ifreq = unsafe { mem::zeroed() };
+        ifreq.ifr_name = self.name;
+
+        ifreq
+    }
+
+    fn ioctl(&self, req: libc::c_ulong, ifreq: &mut libc::ifreq) -> io::Result<()> {
+        if unsafe { libc::ioctl(self.sock.as_raw_fd(), req, ifreq) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    fn set_sockaddr(&self, req: libc::c_ulong, addr: Ipv4Addr) -> io::Result<()> {
+        let mut ifreq = self.ifreq();
+
+        let sin = libc::sockaddr_in {
+            sin_family: libc::AF_INET as libc::sa_family_t,
+            sin_port: 0,
+            sin_addr: libc::in_addr {
+                s_addr: u32::from(addr).to_be(),
+            },
+            sin_zero: [0; 8],
+        };
+        ifreq.ifr_ifru.ifru_addr =
+            unsafe { mem::transmute::<libc::sockaddr_in, libc::sockaddr>(sin) };
+
+        self.ioctl(req, &mut ifreq)
+    }
+
+    pub fn set_addr(&self, addr: Ipv4Addr) -> io::Result<()> {
+        self.set_sockaddr(libc::SIOCSIFADDR, addr)
+    }
+
+    pub fn set_netmask(&self, mask: Ipv4Addr) -> io::Result<()> {
+        self.set_sockaddr(libc::SIOCSIFNETMASK, mask)
+    }
+
+    pub fn bring_up(&self) -> io::Result<()> {
+        let mut ifreq = self.ifreq();
+        self.ioctl(libc::SIOCGIFFLAGS, &mut ifreq)?;
+
+        unsafe { ifreq.ifr_ifru.ifru_flags |= (libc::IFF_UP | libc::IFF_RUNNING) as libc::c_short };
+        self.ioctl(libc::SIOCSIFFLAGS, &mut ifreq)
+    }
+
+    pub fn name(&self) -> String {
+        self.name
+            .iter()
+            .map_while(|&c| (c != 0).then_some(c as u8 as char))
+            .collect()
+    }
+
+    fn write_with_hdr(&mut self, hdr: VnetHdr, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        let hdr = hdr.to_bytes();
+
+        // A single writev keeps the header and the datagram in one packet
+        let mut iov = Vec::with_capacity(bufs.len() + 1);
+        iov.push(IoSlice::new(&hdr));
+        iov.extend_from_slice(bufs);
+
+        let n = self.file.write_vectored(&iov)?;
+
+        Ok(n.saturating_sub(VNET_HDR_LEN))
+    }
+}
+
+impl Device for VnetTun {
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.file.read(&mut self.rbuf)?;
+        if n < VNET_HDR_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "datagram without virtio-net header",
+            ));
+        }
+
+        let hdr = VnetHdr::from_bytes(&self.rbuf[..VNET_HDR_LEN]);
+        let pkt = &mut self.rbuf[VNET_HDR_LEN..n];
+
+        if hdr.flags & VIRTIO_NET_HDR_F_NEEDS_CSUM != 0 {
+            let start = hdr.csum_start as usize;
+            let at = start + hdr.csum_offset as usize;
+
+            if at + 2 <= pkt.len() {
+                let csum = !fold(sum16(&pkt[start..], 0));
+                pkt[at..at + 2].copy_from_slice(&csum.to_be_bytes());
+            }
+        }
+
+        let len = pkt.len().min(buf.len());
+        buf[..len].copy_from_slice(&pkt[..len]);
+
+        Ok(len)
+    }
+
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.send_vectored(&[IoSlice::new(buf)])
+    }
+
+    fn send_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        let hdr = VnetHdr {
+            gso_type: VIRTIO_NET_HDR_GSO_NONE,
+            ..Default::default()
+        };
+
+        self.write_with_hdr(hdr, bufs)
+    }
+
+    /*
+    The TCP checksum field is set to the checksum of the pseudo-header, as
+    the kernel completes it for every segment it cuts the datagram into.
+    */
+    fn send_gso(&mut self, bufs: &[IoSlice<'_>], mss: u16) -> io::Result<usize> {
+        let ihl = (bufs[0][0] & 0x0f) as usize * 4;
+        let doff = (bufs[0][ihl + 12] >> 4) as usize * 4;
+
+        // Only the headers are copied to fill in the checksum
+        let mut hdrs = bufs[0][..ihl + doff].to_vec();
+
+        let tcp_len = (bufs.iter().map(|b| b.len()).sum::<usize>() - ihl) as u32;
+        let pseudo = sum16(&hdrs[12..20], 6 + tcp_len);
+        hdrs[ihl + 16..ihl + 18].copy_from_slice(&fold(pseudo).to_be_bytes());
+
+        let hdr = VnetHdr {
+            flags: VIRTIO_NET_HDR_F_NEEDS_CSUM,
+            gso_type: VIRTIO_NET_HDR_GSO_TCPV4,
+            hdr_len: (ihl + doff) as u16,
+            gso_size: mss,
+            csum_start: ihl as u16,
+            csum_offset: 16,
+        };
+
+        let mut iov = Vec::with_capacity(bufs.len() + 1);
+        iov.push(IoSlice::new(&hdrs));
+        iov.push(IoSlice::new(&bufs[0][ihl + doff..]));
+        iov.extend_from_slice(&bufs[1..]);
+
+        self.write_with_hdr(hdr, &iov)
+    }
+
+    fn gso_max_size(&self) -> Option<usize> {
+        Some(GSO_MAX_SIZE)
+    }
+
+    // Coalesced super-segments can be as large as an IPv4 datagram gets
+    fn mtu(&self) -> io::Result<usize> {
+        Ok(self.rbuf.len() - VNET_HDR_LEN)
+    }
+
+    fn raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
@@ -0,0 +1,132 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::time::{Duration, Instant};
+
+const SLOTS: u64 = 4096;
+const TICK: Duration = Duration::from_millis(1);
+
+/*
+A hashed timer wheel. Every deadline is kept in the slot of the tick it
+falls in, modulo the number of slots, so expiring what is due only looks at
+the slots passed since the last time rather than at every timer. A key has
+at most one deadline, rescheduling it takes the old entry out of its slot.
+Keys that are gone are not cancelled, they fire once more and are skipped
+by the caller.
+*/
+pub struct TimerWheel<K, S = RandomState> {
+    start: Instant,
+    current: u64, // The tick up to which the slots have been expired
+    slots: Vec<Vec<(K, Instant)>>,
+    deadlines: HashMap<K, Instant, S>,
+}
+
+impl<K: Copy + Eq + Hash, S: BuildHasher + Default> Default for TimerWheel<K, S> {
+    fn default() -> Self {
+        TimerWheel {
+            start: Instant::now(),
+            current: 0,
+            slots: vec![Vec::new(); SLOTS as usize],
+            deadlines: HashMap::default(),
+        }
+    }
+}
+
+impl<K, S> fmt::Debug for TimerWheel<K, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TimerWheel")
+            .field("current", &self.current)
+            .field("timers", &self.deadlines.len())
+            .finish()
+    }
+}
+
+impl<K: Copy + Eq + Hash, S: BuildHasher> TimerWheel<K, S> {
+    fn tick_of(&self, at: Instant) -> u64 {
+        (at.saturating_duration_since(self.start).as_nanos() / TICK.as_nanos()) as u64
+    }
+
+    fn slot(&mut self, tick: u64) -> &mut Vec<(K, Instant)> {
+        &mut self.slots[(tick % SLOTS) as usize]
+    }
+
+    // Deadlines in ticks that have been expired already go in the current one
+    fn tick_in_wheel(&self, at: Instant) -> u64 {
+        self.tick_of(at).max(self.current)
+    }
+
+    // Replaces whatever deadline the key had
+    pub fn schedule(&mut self, key: K, at: Instant) {
+        match self.deadlines.insert(key, at) {
+            Some(old) if old == at => return,
+            Some(old) => {
+                let tick = self.tick_in_wheel(old);
+                self.slot(tick).retain(|entry| *entry != (key, old));
+            }
+            None => {}
+        }
+
+        let tick = self.tick_in_wheel(at);
+        self.slot(tick).push((key, at));
+    }
+
+    // Removes the keys whose deadlines have passed and returns them
+    pub fn expire(&mut self, now: Instant) -> Vec<K> {
+        let mut expired = Vec::new();
+
+        let end = self.tick_of(now);
+
+        // One turn visits every slot, however long it has been
+        for tick in self.current..=end.min(self.current + SLOTS - 1) {
+            let slot = &mut self.slots[(tick % SLOTS) as usize];
+            let deadlines = &mut self.deadlines;
+
+            slot.retain(|(key, at)| {
+                // Due in a later turn, or later in the current tick
+                if *at > now {
+                    return true;
+                }
+
+                deadlines.remove(key);
+                expired.push(*key);
+
+                false
+            });
+        }
+
+        self.current = end;
+
+        expired
+    }
+
+    /*
+    The first slot from the current tick on that holds a deadline of its
+    own turn holds the earliest one. Beyond a whole turn, all are looked at.
+    */
+    pub fn next_deadline(&self) -> Option<Instant> {
+        if self.deadlines.is_empty() {
+            return None;
+        }
+
+        for tick in self.current..self.current + SLOTS {
+            let slot = &self.slots[(tick % SLOTS) as usize];
+
+            if slot.is_empty() {
+                continue;
+            }
+
+            let earliest = slot
+                .iter()
+                .filter(|(_, at)| self.tick_of(*at) <= tick)
+                .map(|(_, at)| *at)
+                .min();
+
+            if earliest.is_some() {
+                return earliest;
+            }
+        }
+
+        self.deadlines.values().min().copied()
+    }
+}
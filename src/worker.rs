@@ -0,0 +1,328 @@
+use std::io::{self, IoSlice};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+
+use etherparse::icmpv4::DestUnreachableHeader;
+use etherparse::{Ipv4HeaderSlice, TcpHeaderSlice};
+
+use crate::{
+    apply_action, count_drop, enter_time_wait, notify_ready, remove_stream, tick_soon, Action,
+    BufPool, CloseReason, Doorbell, Emitter, Error, Manager, Quad, Ready, State, StreamEntry, TCB,
+};
+
+/*
+Established connections are processed by a pool of workers instead of the
+loops of the stack, which only hand each connection what concerns it. All the
+work of a connection goes to the same worker, so its segments are processed
+in the order they arrived, while other connections are processed in
+parallel on the other workers.
+*/
+#[derive(Debug, Clone)]
+pub struct Workers {
+    senders: Vec<Sender<Job>>,
+}
+
+#[derive(Debug)]
+pub enum Work {
+    // A datagram validated by the segment loop, the first so many octets of a buffer of the pool
+    Segment(Vec<u8>, usize),
+    Unreachable(u32, DestUnreachableHeader),
+    Tick,
+}
+
+#[derive(Debug)]
+pub struct Job {
+    pub quad: Quad,
+    pub hash: u64, // Of the quad, as the tables of connections hash it, which picks the worker
+    pub entry: Arc<Mutex<StreamEntry>>,
+    pub iface: usize,
+    pub gso: Option<usize>, // What the device of the interface takes in one go
+    pub work: Work,
+}
+
+// Where the datagrams of the workers come from and go back to
+#[derive(Debug)]
+struct Pools {
+    rx: Arc<BufPool>,
+    tx: Arc<BufPool>,
+}
+
+// Where the datagrams of the workers go to, without going through the manager
+#[derive(Debug)]
+struct Handoff {
+    outbox: Sender<Vec<Datagram>>,
+    doorbell: Arc<Doorbell>, // Wakes the timer loop to send them
+}
+
+// Written by a worker or the timer loop, and sent by the timer loop with only the devices held
+#[derive(Debug)]
+pub struct Datagram {
+    pub iface: usize,
+    pub buf: Vec<u8>,
+    pub mss: Option<u16>, // Set for super-segments that the device cuts up
+    pub class: u8,        // Priority class of the connection that sent it
+}
+
+impl Workers {
+    pub fn spawn(
+        count: usize,
+        shared: &Arc<Mutex<Manager>>,
+        outbox: Sender<Vec<Datagram>>,
+    ) -> Self {
+        let (rx_bufs, tx_bufs, doorbell) = {
+            let manager = shared.lock().unwrap();
+
+            (
+                manager.rx_bufs.clone(),
+                manager.tx_bufs.clone(),
+                manager.doorbell.clone(),
+            )
+        };
+
+        let senders = (0..count.max(1))
+            .map(|_| {
+                let (tx, rx) = mpsc::channel();
+                let shared = shared.clone();
+                let pools = Pools {
+                    rx: rx_bufs.clone(),
+                    tx: tx_bufs.clone(),
+                };
+                let handoff = Handoff {
+                    outbox: outbox.clone(),
+                    doorbell: doorbell.clone(),
+                };
+
+                // Stops once both loops, which hold the senders, have returned
+                thread::spawn(move || {
+                    for job in rx {
+                        let (quad, entry) = (job.quad, job.entry.clone());
+                        let work = AssertUnwindSafe(|| run(&shared, &pools, &handoff, job));
+
+                        if panic::catch_unwind(work).is_err() {
+                            let manager = shared.lock();
+                            let mut manager = manager.unwrap_or_else(PoisonError::into_inner);
+                            shared.clear_poison();
+
+                            fail(&mut manager, quad, &entry);
+                        }
+                    }
+                });
+
+                tx
+            })
+            .collect();
+
+        Workers { senders }
+    }
+
+    // Gives the job back if its worker is gone, which only happens on the way out
+    pub fn dispatch(&self, job: Job) -> Result<(), Job> {
+        let idx = job.hash as usize % self.senders.len();
+
+        self.senders[idx].send(job).map_err(|err| err.0)
+    }
+}
+
+/*
+A job that panicked, or that no worker was left to take, only takes its own
+connection down: the peer is reset, and the stream fails with
+ConnectionFailed. The lock of the stream is taken back from the panic, as
+what it guards is only left for this connection to clean up.
+*/
+pub fn fail(manager: &mut Manager, quad: Quad, entry: &Arc<Mutex<StreamEntry>>) {
+    let mut locked = entry.lock().unwrap_or_else(PoisonError::into_inner);
+    entry.clear_poison();
+
+    if locked.deleted {
+        return;
+    }
+
+    *locked
+        .tcb
+        .error
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner) =
+        Some(Error::ConnectionFailed(locked.tcb.context()));
+    locked.tcb.closed(CloseReason::Aborted);
+    manager.aborted.push(locked.tcb.clone());
+    manager.stats.worker_failures += 1;
+    locked.delete(Ready::ALL);
+    drop(locked);
+
+    if manager
+        .streams
+        .get(&quad)
+        .is_some_and(|current| Arc::ptr_eq(current, entry))
+    {
+        remove_stream(manager, &quad);
+    }
+
+    notify_ready(&mut manager);
+    manager.doorbell.ring();
+}
+
+fn run(shared: &Arc<Mutex<Manager>>, pools: &Pools, handoff: &Handoff, job: Job) {
+    let Job {
+        quad,
+        entry,
+        iface,
+        gso,
+        work,
+        ..
+    } = job;
+
+    let mut locked = entry.lock().unwrap();
+
+    // The stream may have been aborted or dropped since the job was handed out
+    if locked.deleted {
+        return;
+    }
+
+    let mut outbox = Outbox::new(iface, gso, locked.tcb.opts.priority.class, &pools.tx);
+
+    let tick = matches!(work, Work::Tick);
+    let time_wait = locked.tcb.state == State::TimeWait;
+    let (action, expired) = match work {
+        Work::Segment(buf, n) => {
+            let action = on_datagram(&mut locked.tcb, &buf[..n], &mut outbox);
+            pools.rx.give(buf);
+
+            let Some(action) = action else { return };
+            (action, false)
+        }
+        Work::Unreachable(sqno, code) => (locked.tcb.on_unreachable(sqno, code), false),
+        Work::Tick => (Action::Noop, locked.tcb.on_tick(&mut outbox)),
+    };
+    let dropped = locked.tcb.dropped.take();
+    let failed = locked.tcb.send_error.take().is_some();
+    let deadline = locked.tcb.deadline();
+    let entered_time_wait = !time_wait && locked.tcb.state == State::TimeWait;
+
+    // The manager is always taken before a stream, never after
+    drop(locked);
+
+    // The timer loop is only gone on the way out, when nothing is sent anymore
+    if !outbox.datagrams.is_empty() && handoff.outbox.send(outbox.datagrams).is_ok() {
+        handoff.doorbell.ring();
+    }
+
+    /*
+    Only what concerns the stack as a whole is left for the manager, and a
+    tick that changed nothing of it does not take it at all.
+    */
+    let quiet = dropped.is_none() && !failed && !expired && !entered_time_wait;
+    if quiet && tick && matches!(action, Action::Noop) {
+        if let Some(at) = deadline {
+            shared.lock().unwrap().timers.schedule(quad, at);
+        }
+
+        return;
+    }
+
+    let mut manager = shared.lock().unwrap();
+
+    if let Some(reason) = dropped {
+        count_drop(&mut manager, reason, Some(quad));
+    }
+    if failed {
+        manager.stats.device_errors += 1;
+    }
+
+    // Whoever removed the stream in the meantime has already woken its waiters
+    let current = manager.streams.get(&quad);
+    if !current.is_some_and(|current| Arc::ptr_eq(current, &entry)) {
+        return;
+    }
+
+    if expired {
+        remove_stream(&mut manager, &quad);
+
+        // Anyone blocked on the stream learns about it through take_error
+        entry.lock().unwrap().delete(Ready::ALL);
+        notify_ready(&mut manager);
+
+        return;
+    }
+
+    apply_action(&mut manager, quad, action);
+
+    if entered_time_wait {
+        enter_time_wait(&mut manager, quad);
+    }
+
+    // Whatever the segment changed, like the window or the timers, is acted upon
+    if !tick {
+        tick_soon(&mut manager, quad);
+    } else if let Some(at) = deadline {
+        manager.timers.schedule(quad, at);
+    }
+}
+
+// Both headers were parsed by the segment loop already
+fn on_datagram(tcb: &mut TCB, buf: &[u8], outbox: &mut Outbox) -> Option<Action> {
+    let ip4h = Ipv4HeaderSlice::from_slice(buf).ok()?;
+    let ihl = ip4h.ihl() as usize * 4;
+    let tcph = TcpHeaderSlice::from_slice(&buf[ihl..]).ok()?;
+    let data = &buf[ihl + tcph.data_offset() as usize * 4..];
+
+    Some(tcb.on_segment(ip4h, tcph, data, outbox))
+}
+
+/*
+Takes what a connection sends, keeping it until the timer loop puts it on the
+wire through the device of the interface. Each datagram is gathered into a
+buffer of the pool, which the timer loop gives back once it has been sent.
+*/
+#[derive(Debug)]
+pub struct Outbox<'a> {
+    iface: usize,
+    gso: Option<usize>,
+    class: u8,
+    bufs: &'a BufPool,
+    pub datagrams: Vec<Datagram>,
+}
+
+impl<'a> Outbox<'a> {
+    pub fn new(iface: usize, gso: Option<usize>, class: u8, bufs: &'a BufPool) -> Self {
+        Outbox {
+            iface,
+            gso,
+            class,
+            bufs,
+            datagrams: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, bufs: &[IoSlice<'_>], mss: Option<u16>) -> io::Result<usize> {
+        let mut buf = self.bufs.take(0);
+        for slice in bufs {
+            buf.extend_from_slice(slice);
+        }
+        let len = buf.len();
+
+        self.datagrams.push(Datagram {
+            iface: self.iface,
+            buf,
+            mss,
+            class: self.class,
+        });
+
+        Ok(len)
+    }
+}
+
+impl Emitter for Outbox<'_> {
+    fn emit(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        self.push(bufs, None)
+    }
+
+    fn gso_max_size(&self) -> Option<usize> {
+        self.gso
+    }
+
+    fn emit_gso(&mut self, bufs: &[IoSlice<'_>], mss: u16) -> io::Result<usize> {
+        self.push(bufs, Some(mss))
+    }
+}
@@ -0,0 +1,515 @@
+use std::ffi::CString;
+use std::io::{self, IoSlice};
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use libc::{c_void, sockaddr_xdp, socklen_t, xdp_desc, xdp_mmap_offsets, xdp_ring_offset};
+
+use crate::Device;
+
+const ETH_HLEN: usize = 14;
+const ETH_P_IP: [u8; 2] = [0x08, 0x00];
+
+#[derive(Debug, Clone)]
+pub struct XdpConfig {
+    pub ifname: String,
+    pub queue: u32,
+    pub src_mac: [u8; 6],
+    pub dst_mac: [u8; 6], // MAC of the next hop, there is no ARP
+    pub frames: u32,
+    pub frame_size: u32,
+    pub batch: u32,
+    pub zero_copy: bool,
+}
+
+impl XdpConfig {
+    pub fn new(ifname: &str, queue: u32, src_mac: [u8; 6], dst_mac: [u8; 6]) -> Self {
+        XdpConfig {
+            ifname: ifname.to_string(),
+            queue,
+            src_mac,
+            dst_mac,
+            frames: 4096,
+            frame_size: 2048,
+            batch: 64,
+            zero_copy: false,
+        }
+    }
+}
+
+/*
+A single producer/single consumer ring shared with the kernel. The producer
+and consumer indices are free running and only masked when indexing the
+descriptors. Our side keeps cached copies of both and only publishes its own
+index once a whole batch has been handled.
+*/
+struct Ring<T> {
+    producer: *const AtomicU32,
+    consumer: *const AtomicU32,
+    flags: *const u32,
+    descs: *mut T,
+    mask: u32,
+    size: u32,
+    cached_prod: u32,
+    cached_cons: u32,
+    map: *mut c_void,
+    map_len: usize,
+}
+
+impl<T> Ring<T> {
+    fn map(fd: RawFd, off: &xdp_ring_offset, size: u32, pgoff: u64) -> io::Result<Self> {
+        let map_len = off.desc as usize + size as usize * mem::size_of::<T>();
+
+        let map = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                map_len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd,
+                pgoff as libc::off_t,
+            )
+        };
+        if map == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        let at = |offset: u64| unsafe { (map as *mut u8).add(offset as usize) };
+
+        Ok(Ring {
+            producer: at(off.producer) as *const AtomicU32,
+            consumer: at(off.consumer) as *const AtomicU32,
+            flags: at(off.flags) as *const u32,
+            descs: at(off.desc) as *mut T,
+            mask: size - 1,
+            size,
+            cached_prod: 0,
+            cached_cons: 0,
+            map,
+            map_len,
+        })
+    }
+
+    fn producer(&self) -> u32 {
+        unsafe { (*self.producer).load(Ordering::Acquire) }
+    }
+
+    fn consumer(&self) -> u32 {
+        unsafe { (*self.consumer).load(Ordering::Acquire) }
+    }
+
+    fn needs_wakeup(&self) -> bool {
+        unsafe { ptr::read_volatile(self.flags) & libc::XDP_RING_NEED_WAKEUP != 0 }
+    }
+
+    fn slot(&mut self, idx: u32) -> *mut T {
+        unsafe { self.descs.add((idx & self.mask) as usize) }
+    }
+
+    // Consumer side
+    fn peek(&mut self) -> Option<u32> {
+        if self.cached_cons == self.cached_prod {
+            self.cached_prod = self.producer();
+        }
+
+        (self.cached_cons != self.cached_prod).then_some(self.cached_cons)
+    }
+
+    fn release(&mut self) {
+        unsafe { (*self.consumer).store(self.cached_cons, Ordering::Release) };
+    }
+
+    // Producer side
+    fn reserve(&mut self) -> Option<u32> {
+        if self.cached_prod.wrapping_sub(self.cached_cons) == self.size {
+            self.cached_cons = self.consumer();
+        }
+
+        (self.cached_prod.wrapping_sub(self.cached_cons) < self.size).then_some(self.cached_prod)
+    }
+
+    fn submit(&mut self) {
+        unsafe { (*self.producer).store(self.cached_prod, Ordering::Release) };
+    }
+
+    fn pending(&self) -> u32 {
+        self.cached_prod.wrapping_sub(self.producer())
+    }
+}
+
+impl<T> Drop for Ring<T> {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.map, self.map_len) };
+    }
+}
+
+/*
+An AF_XDP socket bound to a single queue of a NIC. Frames live in a umem
+region shared with the driver: half of the frames are handed to the kernel
+through the fill ring for reception and the other half are kept for
+transmission, returning through the completion ring once sent.
+
+The stack speaks IPv4, so the Ethernet header is stripped on receive and
+added with the configured addresses on transmit. An XDP program that
+redirects the IPv4 traffic of our address into this socket (through an
+XSKMAP) has to be attached to the interface separately, ARP and everything
+else is left to the kernel.
+*/
+pub struct XdpDevice {
+    fd: OwnedFd,
+    umem: *mut u8,
+    umem_len: usize,
+    fill: Ring<u64>,
+    comp: Ring<u64>,
+    rx: Ring<xdp_desc>,
+    tx: Ring<xdp_desc>,
+    free: Vec<u64>,
+    cfg: XdpConfig,
+    rx_seen: u32,
+}
+
+// The rings and the umem are only ever touched through &mut self
+unsafe impl Send for XdpDevice {}
+
+fn setsockopt<T>(fd: RawFd, opt: libc::c_int, val: &T) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_XDP,
+            opt,
+            val as *const T as *const c_void,
+            mem::size_of::<T>() as socklen_t,
+        )
+    };
+
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+impl XdpDevice {
+    pub fn new(cfg: XdpConfig) -> io::Result<Self> {
+        if !cfg.frames.is_power_of_two() || cfg.frames < 4 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "frame count must be a power of two",
+            ));
+        }
+
+        let ifname = CString::new(cfg.ifname.as_str())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        let ifindex = unsafe { libc::if_nametoindex(ifname.as_ptr()) };
+        if ifindex == 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let fd = unsafe { libc::socket(libc::AF_XDP, libc::SOCK_RAW, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let umem_len = cfg.frames as usize * cfg.frame_size as usize;
+        let umem = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                umem_len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_POPULATE,
+                -1,
+                0,
+            )
+        };
+        if umem == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        XdpDevice::setup(fd, umem as *mut u8, umem_len, cfg, ifindex)
+    }
+
+    fn setup(
+        fd: OwnedFd,
+        umem: *mut u8,
+        umem_len: usize,
+        cfg: XdpConfig,
+        ifindex: u32,
+    ) -> io::Result<Self> {
+        let raw = fd.as_raw_fd();
+        let size = cfg.frames / 2;
+
+        // Unmaps the umem if any of the steps below fails
+        let guard = UmemGuard(umem, umem_len);
+
+        let mut reg: libc::xdp_umem_reg = unsafe { mem::zeroed() };
+        reg.addr = umem as u64;
+        reg.len = umem_len as u64;
+        reg.chunk_size = cfg.frame_size;
+        setsockopt(raw, libc::XDP_UMEM_REG, &reg)?;
+
+        setsockopt(raw, libc::XDP_UMEM_FILL_RING, &size)?;
+        setsockopt(raw, libc::XDP_UMEM_COMPLETION_RING, &size)?;
+        setsockopt(raw, libc::XDP_RX_RING, &size)?;
+        setsockopt(raw, libc::XDP_TX_RING, &size)?;
+
+        let mut off: xdp_mmap_offsets = unsafe { mem::zeroed() };
+        let mut len = mem::size_of::<xdp_mmap_offsets>() as socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                raw,
+                libc::SOL_XDP,
+                libc::XDP_MMAP_OFFSETS,
+                &mut off as *mut xdp_mmap_offsets as *mut c_void,
+                &mut len,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let fill = Ring::map(raw, &off.fr, size, libc::XDP_UMEM_PGOFF_FILL_RING)?;
+        let comp = Ring::map(raw, &off.cr, size, libc::XDP_UMEM_PGOFF_COMPLETION_RING)?;
+        let rx = Ring::map(raw, &off.rx, size, libc::XDP_PGOFF_RX_RING as u64)?;
+        let tx = Ring::map(raw, &off.tx, size, libc::XDP_PGOFF_TX_RING as u64)?;
+
+        let mut sxdp: sockaddr_xdp = unsafe { mem::zeroed() };
+        sxdp.sxdp_family = libc::AF_XDP as u16;
+        sxdp.sxdp_ifindex = ifindex;
+        sxdp.sxdp_queue_id = cfg.queue;
+        sxdp.sxdp_flags = libc::XDP_USE_NEED_WAKEUP
+            | if cfg.zero_copy {
+                libc::XDP_ZEROCOPY
+            } else {
+                libc::XDP_COPY
+            };
+
+        let ret = unsafe {
+            libc::bind(
+                raw,
+                &sxdp as *const sockaddr_xdp as *const libc::sockaddr,
+                mem::size_of::<sockaddr_xdp>() as socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        mem::forget(guard);
+
+        // The upper half of the frames is reserved for transmission
+        let free = (size..cfg.frames)
+            .map(|frame| frame as u64 * cfg.frame_size as u64)
+            .collect();
+
+        let mut dev = XdpDevice {
+            fd,
+            umem,
+            umem_len,
+            fill,
+            comp,
+            rx,
+            tx,
+            free,
+            cfg,
+            rx_seen: 0,
+        };
+        dev.populate_fill();
+
+        Ok(dev)
+    }
+
+    fn frame(&mut self, addr: u64, len: usize) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.umem.add(addr as usize), len) }
+    }
+
+    // The lower half of the frames is handed to the kernel for reception
+    fn populate_fill(&mut self) {
+        for frame in 0..self.cfg.frames / 2 {
+            let Some(idx) = self.fill.reserve() else {
+                break;
+            };
+
+            unsafe { *self.fill.slot(idx) = frame as u64 * self.cfg.frame_size as u64 };
+            self.fill.cached_prod += 1;
+        }
+
+        self.fill.submit();
+    }
+
+    fn wakeup(&self, send: bool) {
+        let fd = self.fd.as_raw_fd();
+
+        unsafe {
+            if send {
+                libc::sendto(fd, ptr::null(), 0, libc::MSG_DONTWAIT, ptr::null(), 0);
+            } else {
+                libc::recvfrom(
+                    fd,
+                    ptr::null_mut(),
+                    0,
+                    libc::MSG_DONTWAIT,
+                    ptr::null_mut(),
+                    ptr::null_mut(),
+                );
+            }
+        }
+    }
+
+    // Frames whose transmission completed can be reused
+    fn reclaim(&mut self) {
+        while let Some(idx) = self.comp.peek() {
+            let addr = unsafe { *self.comp.slot(idx) };
+
+            self.free.push(addr);
+            self.comp.cached_cons += 1;
+        }
+
+        self.comp.release();
+    }
+}
+
+struct UmemGuard(*mut u8, usize);
+
+impl Drop for UmemGuard {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.0 as *mut c_void, self.1) };
+    }
+}
+
+impl Drop for XdpDevice {
+    fn drop(&mut self) {
+        drop(UmemGuard(self.umem, self.umem_len));
+    }
+}
+
+impl Device for XdpDevice {
+    /*
+    Received descriptors are consumed one by one, but the consumer index is
+    only published and the frames are only handed back through the fill ring
+    once a batch is done or the ring is drained. Until then the ring stays
+    non-empty, so polling the socket keeps reporting it as readable.
+    */
+    fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let Some(idx) = self.rx.peek() else {
+                self.rx.release();
+                return Err(io::ErrorKind::WouldBlock.into());
+            };
+
+            let desc = unsafe { ptr::read(self.rx.slot(idx)) };
+            self.rx.cached_cons += 1;
+            self.rx_seen += 1;
+
+            let len = desc.len as usize;
+            let frame = self.frame(desc.addr, len);
+
+            let n = if len >= ETH_HLEN && frame[12..14] == ETH_P_IP {
+                let n = (len - ETH_HLEN).min(buf.len());
+                buf[..n].copy_from_slice(&frame[ETH_HLEN..ETH_HLEN + n]);
+
+                Some(n)
+            } else {
+                None
+            };
+
+            // The frame can only be recycled once its content was copied out
+            let fidx = self.fill.reserve().expect("fill ring holds every rx frame");
+            unsafe { *self.fill.slot(fidx) = desc.addr };
+            self.fill.cached_prod += 1;
+
+            if self.rx_seen >= self.cfg.batch || self.rx.peek().is_none() {
+                self.rx_seen = 0;
+                self.rx.release();
+                self.fill.submit();
+
+                if self.fill.needs_wakeup() {
+                    self.wakeup(false);
+                }
+            }
+
+            if let Some(n) = n {
+                return Ok(n);
+            }
+        }
+    }
+
+    fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.send_vectored(&[IoSlice::new(buf)])
+    }
+
+    // The slices are gathered straight into the UMEM frame
+    fn send_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        let len: usize = bufs.iter().map(|b| b.len()).sum();
+
+        if ETH_HLEN + len > self.cfg.frame_size as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "datagram exceeds the frame size",
+            ));
+        }
+
+        if self.free.is_empty() {
+            self.reclaim();
+        }
+        if self.free.is_empty() || self.tx.reserve().is_none() {
+            self.flush()?;
+            self.reclaim();
+        }
+
+        let (Some(addr), Some(idx)) = (self.free.pop(), self.tx.reserve()) else {
+            return Err(io::ErrorKind::WouldBlock.into());
+        };
+
+        let (dst, src) = (self.cfg.dst_mac, self.cfg.src_mac);
+        let frame = self.frame(addr, ETH_HLEN + len);
+        frame[0..6].copy_from_slice(&dst);
+        frame[6..12].copy_from_slice(&src);
+        frame[12..14].copy_from_slice(&ETH_P_IP);
+
+        let mut at = ETH_HLEN;
+        for buf in bufs {
+            frame[at..at + buf.len()].copy_from_slice(buf);
+            at += buf.len();
+        }
+
+        unsafe {
+            *self.tx.slot(idx) = xdp_desc {
+                addr,
+                len: (ETH_HLEN + len) as u32,
+                options: 0,
+            };
+        }
+        self.tx.cached_prod += 1;
+
+        if self.tx.pending() >= self.cfg.batch {
+            self.flush()?;
+        }
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.tx.pending() == 0 {
+            return Ok(());
+        }
+
+        self.tx.submit();
+
+        if self.tx.needs_wakeup() {
+            self.wakeup(true);
+        }
+
+        Ok(())
+    }
+
+    fn mtu(&self) -> io::Result<usize> {
+        Ok(self.cfg.frame_size as usize - ETH_HLEN)
+    }
+
+    fn raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::net::Ipv4Addr;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use handshake::{LinkConfig, NetStack, Overflow, TcpStream};
+use nix::unistd::{sysconf, SysconfVar};
+
+/*
+Opens thousands of connections between two stacks joined by an in-memory
+link, with a bounded number of handshakes under way at a time, holds them
+open and idle for a while, then closes them. Reports the memory each
+connection takes, how long connections wait to be accepted, and the CPU
+time the loops of both stacks use while connections open and while they sit
+idle; run with --nocapture to see it. HANDSHAKE_STRESS_CONNECTIONS sets how
+many connections are opened, for a longer run.
+*/
+const SERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+const CLIENT: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+const MASK: Ipv4Addr = Ipv4Addr::new(255, 255, 255, 0);
+const PORT: u16 = 5000;
+
+const CONNECTIONS: usize = 2000;
+const CONCURRENCY: usize = 256;
+const HOLD: Duration = Duration::from_secs(1);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Bounds far above what the stack takes, which only catch it falling over
+const MAX_RESIDENT_PER_CONNECTION: usize = 256 * 1024;
+const MAX_ACCEPT_LATENCY: Duration = Duration::from_secs(5);
+const MAX_IDLE_CPU_SHARE: f64 = 50.0;
+
+#[test]
+fn thousands_of_connections() {
+    let connections = env::var("HANDSHAKE_STRESS_CONNECTIONS")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(CONNECTIONS);
+
+    let (mut server, mut client) =
+        NetStack::pair(SERVER, CLIENT, MASK, LinkConfig::default()).unwrap();
+    let listener = server
+        .bind_with_backlog(PORT, CONCURRENCY, Overflow::Drop)
+        .unwrap();
+
+    // Accepted streams, with when each was accepted by the port of its peer
+    let (accepted, acceptances) = mpsc::channel();
+    let acceptor = thread::spawn(move || {
+        let mut streams = Vec::with_capacity(connections);
+        let mut at = HashMap::with_capacity(connections);
+
+        for _ in 0..connections {
+            let stream = listener.accept().unwrap();
+            at.insert(stream.peer_addr().port(), Instant::now());
+            streams.push(stream);
+        }
+
+        accepted.send(at).unwrap();
+        streams
+    });
+
+    let (rss, cpu) = (resident(), cpu_time());
+    let opening = Instant::now();
+
+    // When each handshake began, by the port it was started from
+    let mut started = HashMap::with_capacity(connections);
+    let mut streams: Vec<TcpStream> = Vec::with_capacity(connections);
+
+    while streams.len() < connections {
+        let wave = CONCURRENCY.min(connections - streams.len());
+
+        let mut connecting: Vec<_> = (0..wave)
+            .map(|_| {
+                let connecting = client.connect_start(SERVER, PORT).unwrap();
+                started.insert(connecting.quad().src.port, Instant::now());
+                connecting
+            })
+            .collect();
+
+        for connecting in &mut connecting {
+            let stream = connecting.wait(HANDSHAKE_TIMEOUT).unwrap();
+            streams.push(stream.expect("handshake timed out"));
+        }
+    }
+
+    let accepted_at = acceptances.recv().expect("acceptor panicked");
+    let opened = opening.elapsed();
+    let (open_rss, open_cpu) = (resident(), cpu_time());
+
+    thread::sleep(HOLD);
+    let idle_cpu = cpu_time();
+
+    let mut latencies: Vec<Duration> = started
+        .iter()
+        .filter_map(|(port, start)| Some(accepted_at.get(port)?.duration_since(*start)))
+        .collect();
+    latencies.sort();
+    assert_eq!(latencies.len(), connections);
+
+    let buffered = server.memory_used() + client.memory_used();
+
+    let closing = Instant::now();
+    let accepted = acceptor.join().expect("acceptor panicked");
+    drop(streams);
+    drop(accepted);
+    let closed = closing.elapsed();
+
+    let per_connection = open_rss.saturating_sub(rss) / connections;
+    let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100];
+    let millis = |d: Duration| d.as_secs_f64() * 1000.0;
+    let share = |cpu: Duration, wall: Duration| cpu.as_secs_f64() / wall.as_secs_f64() * 100.0;
+    let idle_share = share(idle_cpu - open_cpu, HOLD);
+
+    eprintln!(
+        "Opened {} connections in {:.3}s ({:.0}/s)",
+        connections,
+        opened.as_secs_f64(),
+        connections as f64 / opened.as_secs_f64()
+    );
+    eprintln!(
+        "Memory: {} octets resident per connection (both ends), {} octets buffered",
+        per_connection, buffered
+    );
+    eprintln!(
+        "Accept latency: p50 {:.3}ms, p99 {:.3}ms, max {:.3}ms",
+        millis(percentile(50)),
+        millis(percentile(99)),
+        millis(percentile(100))
+    );
+    eprintln!(
+        "CPU: {:.1}% while opening, {:.1}% while idle for {:?}",
+        share(open_cpu - cpu, opened),
+        idle_share,
+        HOLD
+    );
+    eprintln!("Closed them in {:.3}s", closed.as_secs_f64());
+
+    assert!(per_connection <= MAX_RESIDENT_PER_CONNECTION);
+    assert!(percentile(99) <= MAX_ACCEPT_LATENCY);
+    assert!(idle_share <= MAX_IDLE_CPU_SHARE);
+}
+
+// Octets of the process resident in memory
+fn resident() -> usize {
+    let statm = fs::read_to_string("/proc/self/statm").unwrap();
+    let pages: usize = statm.split_whitespace().nth(1).unwrap().parse().unwrap();
+
+    let page_size = sysconf(SysconfVar::PAGE_SIZE)
+        .ok()
+        .flatten()
+        .unwrap_or(4096);
+
+    pages * page_size as usize
+}
+
+// User and system time of the whole process, which is mostly that of the loops
+fn cpu_time() -> Duration {
+    let stat = fs::read_to_string("/proc/self/stat").unwrap();
+
+    // The name of the command may hold spaces, the fields after it do not
+    let fields: Vec<&str> = stat[stat.rfind(')').unwrap() + 1..]
+        .split_whitespace()
+        .collect();
+    let ticks: u64 = fields[11..13]
+        .iter()
+        .map(|field| field.parse::<u64>().unwrap())
+        .sum();
+
+    let per_second = sysconf(SysconfVar::CLK_TCK).ok().flatten().unwrap_or(100);
+
+    Duration::from_secs_f64(ticks as f64 / per_second as f64)
+}